@@ -0,0 +1,199 @@
+use rand::rngs::StdRng;
+use rand::Rng;
+use std::str::FromStr;
+use westiny_common::components::{EntityType, Input, InputFlags};
+use westiny_common::metric_dimension::length::{Meter, MeterVec2};
+use westiny_common::network::EntityState;
+
+/// Waypoints are chosen within this distance from the center of the map
+const WANDER_BOUND: f32 = 30.0;
+/// A new waypoint is chosen when the bot gets this close to the current one
+const WAYPOINT_REACHED: f32 = 1.0;
+/// Players closer than this are shot at, about the range of the revolver
+const FIGHT_RANGE: f32 = 7.0;
+/// Chance of switching weapon or reloading in an input
+const WEAPON_ACTION_CHANCE: f64 = 0.005;
+
+const WEAPON_SELECTIONS: [InputFlags; 3] = [
+    InputFlags::SELECT1,
+    InputFlags::SELECT2,
+    InputFlags::SELECT3,
+];
+
+/// What the bot does in the game
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Behavior {
+    /// Stands still, only receives the updates
+    Idle,
+    /// Walks between random waypoints
+    Walk,
+    /// Walks, shoots at the players nearby, switches weapons and reloads
+    Fight,
+}
+
+impl FromStr for Behavior {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "idle" => Ok(Behavior::Idle),
+            "walk" => Ok(Behavior::Walk),
+            "fight" => Ok(Behavior::Fight),
+            other => Err(format!(
+                "Unknown behavior: {}, expected idle, walk or fight",
+                other
+            )),
+        }
+    }
+}
+
+/// Makes the inputs of a bot according to its behavior
+pub struct Brain {
+    behavior: Behavior,
+    rng: StdRng,
+    waypoint: Option<MeterVec2>,
+    shooting: bool,
+}
+
+impl Brain {
+    pub fn new(behavior: Behavior, rng: StdRng) -> Self {
+        Brain {
+            behavior,
+            rng,
+            waypoint: None,
+            shooting: false,
+        }
+    }
+
+    /// Input for the next tick, given the state of the bot's player and the other entities seen
+    pub fn next_input<'a, I>(&mut self, own: &EntityState, others: I) -> Input
+    where
+        I: IntoIterator<Item = &'a EntityState>,
+    {
+        let mut input = Input {
+            // The cursor must not be on the player, its direction would be undefined
+            cursor: offset(own.position, 0.0, 1.0),
+            ..Input::default()
+        };
+        if self.behavior == Behavior::Idle {
+            return input;
+        }
+
+        let waypoint = match self.waypoint {
+            Some(waypoint) if distance(own.position, waypoint) > WAYPOINT_REACHED => waypoint,
+            _ => self.choose_waypoint(),
+        };
+        input.cursor = waypoint;
+        input.flags.insert(InputFlags::FORWARD);
+
+        if self.behavior == Behavior::Fight {
+            let target = others
+                .into_iter()
+                .filter(|state| state.network_id.entity_type == EntityType::Player)
+                .filter(|state| state.network_id != own.network_id)
+                .map(|state| (distance(own.position, state.position), state.position))
+                .filter(|(distance, _)| *distance > 0.0 && *distance < FIGHT_RANGE)
+                .min_by(|(a, _), (b, _)| a.total_cmp(b));
+
+            if let Some((_, position)) = target {
+                input.cursor = position;
+                input.flags.remove(InputFlags::FORWARD);
+                // Weapons in single mode shoot once per press
+                self.shooting = !self.shooting;
+                input.flags.set(InputFlags::SHOOT, self.shooting);
+            }
+
+            if self.rng.gen_bool(WEAPON_ACTION_CHANCE) {
+                let selection = WEAPON_SELECTIONS[self.rng.gen_range(0..WEAPON_SELECTIONS.len())];
+                input.flags.insert(selection);
+            }
+            if self.rng.gen_bool(WEAPON_ACTION_CHANCE) {
+                input.flags.insert(InputFlags::RELOAD);
+            }
+        }
+        input
+    }
+
+    fn choose_waypoint(&mut self) -> MeterVec2 {
+        let waypoint = MeterVec2::from_raw(
+            self.rng.gen_range(-WANDER_BOUND..WANDER_BOUND),
+            self.rng.gen_range(-WANDER_BOUND..WANDER_BOUND),
+        );
+        self.waypoint = Some(waypoint);
+        waypoint
+    }
+}
+
+fn offset(position: MeterVec2, x: f32, y: f32) -> MeterVec2 {
+    MeterVec2 {
+        x: Meter(position.x.0 + x),
+        y: Meter(position.y.0 + y),
+    }
+}
+
+fn distance(a: MeterVec2, b: MeterVec2) -> f32 {
+    (a.x.0 - b.x.0).hypot(a.y.0 - b.y.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use westiny_common::components::NetworkId;
+
+    fn player(id: u32, x: f32, y: f32) -> EntityState {
+        EntityState {
+            network_id: NetworkId::new(EntityType::Player, id),
+            position: MeterVec2::from_raw(x, y),
+            angle: 0.0,
+        }
+    }
+
+    #[test]
+    fn idle_bot_does_nothing() {
+        let mut brain = Brain::new(Behavior::Idle, StdRng::seed_from_u64(0));
+        let own = player(0, 0.0, 0.0);
+
+        let input = brain.next_input(&own, &[player(1, 1.0, 0.0)]);
+
+        assert!(input.flags.is_empty());
+        assert_ne!(input.cursor, own.position);
+    }
+
+    #[test]
+    fn walking_bot_keeps_its_waypoint_until_reached() {
+        let mut brain = Brain::new(Behavior::Walk, StdRng::seed_from_u64(0));
+        let own = player(0, 0.0, 0.0);
+
+        let first = brain.next_input(&own, &[]);
+        let second = brain.next_input(&own, &[]);
+        assert!(first.flags.contains(InputFlags::FORWARD));
+        assert_eq!(first.cursor, second.cursor);
+
+        let arrived = EntityState {
+            position: first.cursor,
+            ..own
+        };
+        let third = brain.next_input(&arrived, &[]);
+        assert_ne!(third.cursor, first.cursor);
+    }
+
+    #[test]
+    fn fighting_bot_shoots_nearest_player_in_range() {
+        let mut brain = Brain::new(Behavior::Fight, StdRng::seed_from_u64(0));
+        let own = player(0, 0.0, 0.0);
+        let others = [
+            own.clone(),
+            player(1, 5.0, 0.0),
+            player(2, 0.0, 3.0),
+            player(3, 0.0, 20.0),
+        ];
+
+        let first = brain.next_input(&own, &others);
+        let second = brain.next_input(&own, &others);
+
+        assert_eq!(first.cursor, MeterVec2::from_raw(0.0, 3.0));
+        assert!(first.flags.contains(InputFlags::SHOOT));
+        assert!(!second.flags.contains(InputFlags::SHOOT));
+    }
+}
@@ -0,0 +1,324 @@
+use crate::behavior::Brain;
+use crate::stats::SharedStats;
+use bevy::prelude::{EventReader, Res, ResMut, Resource, Time};
+use blaminar::simulation::{
+    DeliveryRequirement, NetworkSimulationEvent, TransportResource, UrgencyRequirement,
+};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+use westiny_common::auth::{prove, Credential};
+use westiny_common::components::{Input, NetworkId};
+use westiny_common::network::{
+    EntityStateUpdate, InputSequence, PacketType, SequencedInput, SessionToken, BUILD_HASH,
+};
+use westiny_common::secure_channel::SecureChannels;
+use westiny_common::serialization::{deserialize_all, serialize, PacketCodec};
+use westiny_common::snapshot::{ReceivedSnapshots, Snapshot};
+
+/// Same streams as the real client, for the sequenced deliveries to behave the same
+const INPUT_STREAM: u8 = 0;
+const SNAPSHOT_ACK_STREAM: u8 = 1;
+
+/// Number of inputs sent in each packet, like the real client does
+const SENT_INPUT_COUNT: usize = 3;
+
+const CONNECTION_RETRY: Duration = Duration::from_secs(1);
+
+/// Inputs waiting for acknowledgement are forgotten beyond this, e.g. while the player is dead
+const MAX_UNACKNOWLEDGED_INPUTS: usize = 256;
+
+enum Connection {
+    Connecting { last_request: Option<Duration> },
+    Connected(Player),
+}
+
+struct Player {
+    network_id: NetworkId,
+    tick_step: Duration,
+    snapshots: ReceivedSnapshots,
+    world: Snapshot,
+    sent_inputs: VecDeque<SequencedInput>,
+    /// Sequence and send time of the inputs not acknowledged yet, for measuring the round trip
+    unacknowledged: VecDeque<(InputSequence, Duration)>,
+    unsent_time: Duration,
+}
+
+/// A simulated player, speaking the game protocol like the real client does
+#[derive(Resource)]
+pub struct Bot {
+    player_name: String,
+    server: SocketAddr,
+    /// Password of the server, the bots have no player secrets
+    server_password: Option<String>,
+    brain: Brain,
+    connection: Connection,
+    /// The bot reconnects to its session after the connection has been lost
+    session_token: Option<SessionToken>,
+    /// Kept over reconnections, as the server drops the inputs of the session it has already had
+    next_sequence: InputSequence,
+    stats: SharedStats,
+}
+
+impl Bot {
+    pub fn new(
+        player_name: String,
+        server: SocketAddr,
+        server_password: Option<String>,
+        brain: Brain,
+        stats: SharedStats,
+    ) -> Self {
+        Bot {
+            player_name,
+            server,
+            server_password,
+            brain,
+            connection: Connection::Connecting { last_request: None },
+            session_token: None,
+            next_sequence: 0,
+            stats,
+        }
+    }
+}
+
+pub fn send_connection_request(
+    mut bot: ResMut<Bot>,
+    mut secure_channels: ResMut<SecureChannels>,
+    mut net: ResMut<TransportResource>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed();
+    match &mut bot.connection {
+        Connection::Connecting { last_request } => {
+            if last_request.is_some_and(|last| now - last < CONNECTION_RETRY) {
+                return;
+            }
+            *last_request = Some(now);
+        }
+        Connection::Connected(_) => return,
+    }
+
+    let message = serialize(&PacketType::ConnectionRequest {
+        player_name: bot.player_name.clone(),
+        build_hash: BUILD_HASH.to_string(),
+        resume_session: bot.session_token.is_some(),
+        // Everything is sent with `serialize`
+        codec: PacketCodec::default(),
+        compression: true,
+        key_share: secure_channels.key_share(bot.server),
+    })
+    .expect("ConnectionRequest could not be serialized");
+    net.send_with_requirements(
+        bot.server,
+        &message,
+        DeliveryRequirement::ReliableSequenced(None),
+        UrgencyRequirement::OnTick,
+    );
+}
+
+pub fn receive_packets(
+    mut bot: ResMut<Bot>,
+    mut net: ResMut<TransportResource>,
+    mut network_events: EventReader<NetworkSimulationEvent>,
+    time: Res<Time>,
+) {
+    let bot = &mut *bot;
+    let mut acknowledged = None;
+    for event in network_events.iter() {
+        let payload = match event {
+            NetworkSimulationEvent::Message(addr, payload) if *addr == bot.server => payload,
+            NetworkSimulationEvent::Disconnect(addr) if *addr == bot.server => {
+                if let Connection::Connected(_) = bot.connection {
+                    log::warn!("{} lost the connection", bot.player_name);
+                    bot.stats.record_disconnect();
+                }
+                bot.connection = Connection::Connecting { last_request: None };
+                continue;
+            }
+            _ => continue,
+        };
+        bot.stats.record_received(payload.len());
+
+        // The packets of the same delivery may be bundled by the server
+        let packets = match deserialize_all(payload) {
+            Ok(packets) => packets,
+            Err(err) => {
+                log::error!("{} could not deserialize packet: {}", bot.player_name, err);
+                continue;
+            }
+        };
+
+        for packet in packets {
+            match (packet, &mut bot.connection) {
+                (PacketType::ConnectionResponse(Ok(data)), Connection::Connecting { .. }) => {
+                    log::info!("{} connected", bot.player_name);
+                    bot.stats.record_connected();
+                    bot.session_token = Some(data.session_token);
+                    bot.connection = Connection::Connected(Player {
+                        network_id: data.player_network_id,
+                        tick_step: Duration::from_secs(1) / data.tick_rate.max(1),
+                        snapshots: ReceivedSnapshots::default(),
+                        world: Snapshot::default(),
+                        sent_inputs: VecDeque::new(),
+                        unacknowledged: VecDeque::new(),
+                        unsent_time: Duration::ZERO,
+                    });
+                }
+                (PacketType::AuthChallenge(challenge), Connection::Connecting { .. }) => {
+                    let password_proof = bot
+                        .server_password
+                        .as_ref()
+                        .filter(|_| challenge.password_required)
+                        .map(|password| {
+                            prove(
+                                Credential::ServerPassword,
+                                password,
+                                &challenge.nonce,
+                                &bot.player_name,
+                            )
+                        });
+                    let session_proof = bot
+                        .session_token
+                        .filter(|_| challenge.session_required)
+                        .map(|token| {
+                            prove(
+                                Credential::SessionToken,
+                                &token.to_string(),
+                                &challenge.nonce,
+                                &bot.player_name,
+                            )
+                        });
+                    let message = serialize(&PacketType::AuthResponse {
+                        password_proof,
+                        secret_proof: None,
+                        session_proof,
+                    })
+                    .expect("AuthResponse could not be serialized");
+                    net.send_with_requirements(
+                        bot.server,
+                        &message,
+                        DeliveryRequirement::ReliableSequenced(None),
+                        UrgencyRequirement::OnTick,
+                    );
+                }
+                (PacketType::ConnectionResponse(Err(err)), Connection::Connecting { .. }) => {
+                    log::warn!("{} was refused: {}", bot.player_name, err);
+                    bot.stats.record_refusal();
+                }
+                (PacketType::Disconnect { reason }, Connection::Connected(_)) => {
+                    log::warn!("{} was disconnected: {}", bot.player_name, reason);
+                    bot.stats.record_disconnect();
+                    bot.connection = Connection::Connecting { last_request: None };
+                }
+                (PacketType::Ping { sequence }, Connection::Connected(_)) => {
+                    let message = serialize(&PacketType::Pong {
+                        sequence,
+                        time: time.elapsed(),
+                    })
+                    .expect("Pong could not be serialized");
+                    net.send_with_requirements(
+                        bot.server,
+                        &message,
+                        DeliveryRequirement::Unreliable,
+                        UrgencyRequirement::OnTick,
+                    );
+                }
+                (PacketType::EntityStateUpdate(update), Connection::Connected(player)) => {
+                    let completed = player
+                        .snapshots
+                        .receive(&update)
+                        .and_then(|received| received.completed);
+                    if let Some((snapshot, _)) = completed {
+                        player.world = snapshot.clone();
+                        acknowledged = Some(update.snapshot);
+                    }
+                    measure_round_trip(player, &update, time.elapsed(), &bot.stats);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(snapshot) = acknowledged {
+        let message = serialize(&PacketType::SnapshotAck { snapshot })
+            .expect("SnapshotAck could not be serialized");
+        net.send_with_requirements(
+            bot.server,
+            &message,
+            DeliveryRequirement::UnreliableSequenced(Some(SNAPSHOT_ACK_STREAM)),
+            UrgencyRequirement::OnTick,
+        );
+    }
+}
+
+/// There is no ping in the protocol, so the round trip is measured from sending an input until
+/// a snapshot acknowledges it. This includes the time the input waits in the queue of the server
+/// and the time until the next snapshot.
+fn measure_round_trip(
+    player: &mut Player,
+    update: &EntityStateUpdate,
+    now: Duration,
+    stats: &SharedStats,
+) {
+    if let Some(last_processed) = update.last_processed_input {
+        while let Some(&(sequence, sent_at)) = player.unacknowledged.front() {
+            if sequence > last_processed {
+                break;
+            }
+            player.unacknowledged.pop_front();
+            if sequence == last_processed {
+                stats.record_rtt(now.saturating_sub(sent_at));
+            }
+        }
+    }
+}
+
+/// Makes one input per server tick, like the real client, and sends the latest ones
+pub fn send_input(mut bot: ResMut<Bot>, mut net: ResMut<TransportResource>, time: Res<Time>) {
+    let bot = &mut *bot;
+    let player = match &mut bot.connection {
+        Connection::Connected(player) => player,
+        Connection::Connecting { .. } => return,
+    };
+
+    player.unsent_time =
+        (player.unsent_time + time.delta()).min(player.tick_step * SENT_INPUT_COUNT as u32);
+    let mut input_made = false;
+    while player.unsent_time >= player.tick_step {
+        player.unsent_time -= player.tick_step;
+
+        // Without its own state (e.g. while dead) the bot has nothing to act on
+        let input = match player.world.get(&player.network_id) {
+            Some(own) => bot.brain.next_input(own, player.world.states()),
+            None => Input::default(),
+        };
+        let sequence = bot.next_sequence;
+        bot.next_sequence = bot.next_sequence.wrapping_add(1);
+
+        if player.sent_inputs.len() >= SENT_INPUT_COUNT {
+            player.sent_inputs.pop_front();
+        }
+        player
+            .sent_inputs
+            .push_back(SequencedInput { sequence, input });
+        if player.unacknowledged.len() >= MAX_UNACKNOWLEDGED_INPUTS {
+            player.unacknowledged.pop_front();
+        }
+        player.unacknowledged.push_back((sequence, time.elapsed()));
+        input_made = true;
+    }
+
+    if input_made {
+        let message = serialize(&PacketType::InputState {
+            inputs: player.sent_inputs.iter().copied().collect(),
+            view_time: None,
+        })
+        .expect("InputState could not be serialized");
+        net.send_with_requirements(
+            bot.server,
+            &message,
+            DeliveryRequirement::UnreliableSequenced(Some(INPUT_STREAM)),
+            UrgencyRequirement::OnTick,
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use westiny_common::resources::ServerAddress;
+use westiny_common::secure_channel::{self, SecureChannels, ServerTrust};
+use westiny_common::utilities::read_ron;
+use westiny_common::NetworkConfig;
+
+use blaminar::prelude::{LaminarLabel, LaminarPlugin};
+
+use bevy::app::ScheduleRunnerSettings;
+use bevy::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::behavior::{Behavior, Brain};
+use crate::stats::SharedStats;
+
+mod behavior;
+mod bot;
+mod stats;
+
+/// The bots run at about the default tick rate of the server
+const FRAME_TIME: Duration = Duration::from_millis(16);
+
+const USAGE: &str = "\
+Connects simulated players to a westiny server.
+
+Usage: westiny_bot [options]
+
+Options:
+    --bots <count>          Number of bots [default: 10]
+    --behavior <behavior>   idle, walk or fight [default: fight]
+    --first-port <port>     Local port of the first bot, the others use the following ones [default: 4600]
+    --duration <seconds>    Stop after this long, runs until stopped if not given
+    --report <seconds>      Interval of the reports [default: 5]
+    --seed <seed>           Seed of the bots' decisions [default: random]
+
+The server address is read from WESTINY_SERVER_ADDRESS, the password of the server from
+WESTINY_SERVER_PASSWORD and its identity key from WESTINY_SERVER_KEY or resources/server_key.txt,
+like the client does. Set WESTINY_TRUST_ANY_SERVER to connect to a server of any identity.";
+
+struct BotConfig {
+    bots: usize,
+    behavior: Behavior,
+    first_port: u16,
+    duration: Option<Duration>,
+    report_interval: Duration,
+    seed: u64,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        BotConfig {
+            bots: 10,
+            behavior: Behavior::Fight,
+            first_port: 4600,
+            duration: None,
+            report_interval: Duration::from_secs(5),
+            seed: rand::random(),
+        }
+    }
+}
+
+impl BotConfig {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut config = BotConfig::default();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value of {}", arg))
+            };
+            match arg.as_str() {
+                "--bots" => config.bots = parse_value(&value()?)?,
+                "--behavior" => config.behavior = value()?.parse()?,
+                "--first-port" => config.first_port = parse_value(&value()?)?,
+                "--duration" => {
+                    config.duration = Some(Duration::from_secs(parse_value(&value()?)?))
+                }
+                "--report" => config.report_interval = Duration::from_secs(parse_value(&value()?)?),
+                "--seed" => config.seed = parse_value(&value()?)?,
+                other => return Err(format!("Unknown argument: {}", other)),
+            }
+        }
+
+        if usize::from(config.first_port) + config.bots > usize::from(u16::MAX) + 1 {
+            return Err("Not enough ports for the bots".to_string());
+        }
+        if config.report_interval.is_zero() {
+            return Err("Report interval must be positive".to_string());
+        }
+        Ok(config)
+    }
+}
+
+fn parse_value<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value: {}", value))
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{}", USAGE);
+        return;
+    }
+    let config = BotConfig::parse(args.into_iter()).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        std::process::exit(1);
+    });
+
+    let resources_dir = PathBuf::from("resources");
+    let network_config = {
+        let ron_path = resources_dir.join("protocol.ron");
+        read_ron::<NetworkConfig>(&ron_path).unwrap_or_else(|_| {
+            panic!(
+                "Failed to load Laminar protocol configuration file: {}",
+                ron_path.as_os_str().to_str().unwrap()
+            )
+        })
+    };
+    let trust = ServerTrust::from_env(&resources_dir).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let server = get_server_address();
+    let server_password = std::env::var("WESTINY_SERVER_PASSWORD").ok();
+    let stats = SharedStats::default();
+
+    println!(
+        "Starting {} {:?} bots against {}, seed: {}",
+        config.bots, config.behavior, server, config.seed
+    );
+    for index in 0..config.bots {
+        let port = config.first_port + index as u16;
+        let brain = Brain::new(
+            config.behavior,
+            StdRng::seed_from_u64(config.seed.wrapping_add(index as u64)),
+        );
+        let bot = bot::Bot::new(
+            format!("bot-{}", index),
+            server,
+            server_password.clone(),
+            brain,
+            stats.clone(),
+        );
+        let network_config = network_config.clone();
+
+        // Each bot has its own socket and app, so they look like separate clients to the server
+        std::thread::Builder::new()
+            .name(format!("bot-{}", index))
+            .spawn(move || run_bot(bot, port, network_config, trust))
+            .expect("Could not start bot thread");
+    }
+
+    let start = Instant::now();
+    let mut last_report = start;
+    loop {
+        std::thread::sleep(config.report_interval);
+        let now = Instant::now();
+        println!(
+            "[{:>5}s] {}",
+            (now - start).as_secs(),
+            stats.take_report(now - last_report)
+        );
+        last_report = now;
+
+        if config
+            .duration
+            .is_some_and(|duration| now - start >= duration)
+        {
+            break;
+        }
+    }
+}
+
+fn run_bot(bot: bot::Bot, port: u16, network_config: NetworkConfig, trust: ServerTrust) {
+    let socket_address = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+
+    App::new()
+        .insert_resource(ScheduleRunnerSettings::run_loop(FRAME_TIME))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(LaminarPlugin::new(socket_address, network_config.into()))
+        .insert_resource(bot)
+        .insert_resource(SecureChannels::client(trust))
+        .add_system(
+            secure_channel::open_packets
+                .label("open_packets")
+                .after(LaminarLabel),
+        )
+        .add_system(
+            bot::receive_packets
+                .label("receive_packets")
+                .after("open_packets"),
+        )
+        .add_system(bot::send_connection_request.after("receive_packets"))
+        .add_system(bot::send_input.after("receive_packets"))
+        .add_system_to_stage(CoreStage::Last, secure_channel::seal_packets)
+        .run();
+}
+
+fn get_server_address() -> SocketAddr {
+    std::env::var("WESTINY_SERVER_ADDRESS")
+        .ok()
+        .and_then(|env| SocketAddr::from_str(&env).ok())
+        .unwrap_or_else(|| {
+            let address = ServerAddress::default().address;
+            log::warn!(
+                "WESTINY_SERVER_ADDRESS is not set or invalid, using default address: {}",
+                address
+            );
+            address
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<BotConfig, String> {
+        BotConfig::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn arguments_override_defaults() {
+        let config = parse(&["--bots", "32", "--behavior", "walk", "--duration", "60"]).unwrap();
+
+        assert_eq!(config.bots, 32);
+        assert_eq!(config.behavior, Behavior::Walk);
+        assert_eq!(config.duration, Some(Duration::from_secs(60)));
+        assert_eq!(config.first_port, 4600);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        assert!(parse(&["--bots"]).is_err());
+        assert!(parse(&["--bots", "many"]).is_err());
+        assert!(parse(&["--behavior", "dance"]).is_err());
+        assert!(parse(&["--first-port", "65535", "--bots", "2"]).is_err());
+        assert!(parse(&["--unknown"]).is_err());
+    }
+}
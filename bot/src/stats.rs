@@ -0,0 +1,150 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Counters {
+    connected: usize,
+    received_bytes: u64,
+    received_packets: u64,
+    rtt_sum: Duration,
+    rtt_samples: u32,
+    rtt_max: Duration,
+    disconnects: u32,
+    refusals: u32,
+}
+
+/// Measurements of all the bots, shared between their threads.
+/// The counters are reset by each report, except the number of connected bots.
+#[derive(Clone, Default, bevy::prelude::Resource)]
+pub struct SharedStats(Arc<Mutex<Counters>>);
+
+impl SharedStats {
+    fn update<F: FnOnce(&mut Counters)>(&self, f: F) {
+        f(&mut self.0.lock().expect("Bot statistics are poisoned"))
+    }
+
+    pub fn record_connected(&self) {
+        self.update(|counters| counters.connected += 1);
+    }
+
+    /// The connection has been lost
+    pub fn record_disconnect(&self) {
+        self.update(|counters| {
+            counters.connected = counters.connected.saturating_sub(1);
+            counters.disconnects += 1;
+        });
+    }
+
+    pub fn record_refusal(&self) {
+        self.update(|counters| counters.refusals += 1);
+    }
+
+    pub fn record_received(&self, bytes: usize) {
+        self.update(|counters| {
+            counters.received_bytes += bytes as u64;
+            counters.received_packets += 1;
+        });
+    }
+
+    pub fn record_rtt(&self, rtt: Duration) {
+        self.update(|counters| {
+            counters.rtt_sum += rtt;
+            counters.rtt_samples += 1;
+            counters.rtt_max = counters.rtt_max.max(rtt);
+        });
+    }
+
+    /// Makes the report of the past `interval` and starts a new one
+    pub fn take_report(&self, interval: Duration) -> Report {
+        let mut current = self.0.lock().expect("Bot statistics are poisoned");
+        let connected = current.connected;
+        let counters = std::mem::replace(
+            &mut *current,
+            Counters {
+                connected,
+                ..Counters::default()
+            },
+        );
+        drop(current);
+
+        let seconds = interval.as_secs_f64().max(f64::EPSILON);
+        Report {
+            connected: counters.connected,
+            received_bytes_per_sec: counters.received_bytes as f64 / seconds,
+            received_packets_per_sec: counters.received_packets as f64 / seconds,
+            rtt_avg: (counters.rtt_samples > 0).then(|| counters.rtt_sum / counters.rtt_samples),
+            rtt_max: counters.rtt_max,
+            disconnects: counters.disconnects,
+            refusals: counters.refusals,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    pub connected: usize,
+    pub received_bytes_per_sec: f64,
+    pub received_packets_per_sec: f64,
+    pub rtt_avg: Option<Duration>,
+    pub rtt_max: Duration,
+    pub disconnects: u32,
+    pub refusals: u32,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connected: {}, received: {:.1} KiB/s ({:.0} packets/s), ",
+            self.connected,
+            self.received_bytes_per_sec / 1024.0,
+            self.received_packets_per_sec
+        )?;
+        match self.rtt_avg {
+            Some(avg) => write!(
+                f,
+                "rtt: avg {} ms, max {} ms, ",
+                avg.as_millis(),
+                self.rtt_max.as_millis()
+            )?,
+            None => write!(f, "rtt: -, ")?,
+        }
+        write!(
+            f,
+            "disconnects: {}, refused: {}",
+            self.disconnects, self.refusals
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report_averages_over_interval_and_resets() {
+        let stats = SharedStats::default();
+        stats.record_connected();
+        stats.record_connected();
+        stats.record_received(1000);
+        stats.record_received(3000);
+        stats.record_rtt(Duration::from_millis(40));
+        stats.record_rtt(Duration::from_millis(60));
+        stats.record_disconnect();
+
+        let report = stats.take_report(Duration::from_secs(2));
+        assert_eq!(report.connected, 1);
+        assert_eq!(report.received_bytes_per_sec, 2000.0);
+        assert_eq!(report.received_packets_per_sec, 1.0);
+        assert_eq!(report.rtt_avg, Some(Duration::from_millis(50)));
+        assert_eq!(report.rtt_max, Duration::from_millis(60));
+        assert_eq!(report.disconnects, 1);
+
+        let next = stats.take_report(Duration::from_secs(2));
+        assert_eq!(next.connected, 1);
+        assert_eq!(next.received_bytes_per_sec, 0.0);
+        assert_eq!(next.rtt_avg, None);
+        assert_eq!(next.disconnects, 0);
+    }
+}
@@ -1,6 +1,8 @@
+pub use snapshot_buffer::{EntitySample, SnapshotBuffer};
 pub use weapon_info::WeaponInfo;
 pub use westiny_common::components::*;
 
+mod snapshot_buffer;
 mod weapon_info;
 
 pub mod hud {
@@ -0,0 +1,186 @@
+use bevy::prelude::Component;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::time::Duration;
+use westiny_common::metric_dimension::length::{Meter, MeterVec2};
+
+const BUFFER_CAPACITY: usize = 32;
+
+/// Position and rotation of a network entity at a given server time
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EntitySample {
+    pub server_time: Duration,
+    pub position: MeterVec2,
+    pub angle: f32,
+}
+
+/// Timestamped states of a remote entity received from the server.
+/// The entity is rendered somewhat in the past, between two received states.
+#[derive(Debug, Default, Component)]
+pub struct SnapshotBuffer {
+    samples: VecDeque<EntitySample>,
+}
+
+impl SnapshotBuffer {
+    /// Samples not newer than the latest one are dropped
+    pub fn push(&mut self, sample: EntitySample) {
+        if let Some(latest) = self.samples.back() {
+            if sample.server_time <= latest.server_time {
+                return;
+            }
+        }
+        if self.samples.len() >= BUFFER_CAPACITY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Returns the interpolated position and angle at `render_time`.
+    /// When `render_time` is past the latest sample, the movement is extrapolated from the last
+    /// two samples, for at most `max_extrapolation`.
+    pub fn sample_at(
+        &self,
+        render_time: Duration,
+        max_extrapolation: Duration,
+    ) -> Option<(MeterVec2, f32)> {
+        let latest = self.samples.back()?;
+
+        if render_time <= self.samples[0].server_time {
+            let first = &self.samples[0];
+            return Some((first.position, first.angle));
+        }
+
+        if render_time >= latest.server_time {
+            if self.samples.len() < 2 {
+                return Some((latest.position, latest.angle));
+            }
+            let previous = &self.samples[self.samples.len() - 2];
+            let render_time = render_time.min(latest.server_time + max_extrapolation);
+            let t = ratio(previous.server_time, latest.server_time, render_time);
+            return Some(interpolate(previous, latest, t));
+        }
+
+        self.samples
+            .iter()
+            .zip(self.samples.iter().skip(1))
+            .find(|(_, to)| render_time < to.server_time)
+            .map(|(from, to)| {
+                interpolate(
+                    from,
+                    to,
+                    ratio(from.server_time, to.server_time, render_time),
+                )
+            })
+    }
+}
+
+fn ratio(from: Duration, to: Duration, at: Duration) -> f32 {
+    (at.as_secs_f32() - from.as_secs_f32()) / (to.as_secs_f32() - from.as_secs_f32())
+}
+
+/// `t` may be greater than 1 for extrapolation
+fn interpolate(from: &EntitySample, to: &EntitySample, t: f32) -> (MeterVec2, f32) {
+    let lerp = |a: Meter, b: Meter| Meter(a.0 + (b.0 - a.0) * t);
+    let position = MeterVec2 {
+        x: lerp(from.position.x, to.position.x),
+        y: lerp(from.position.y, to.position.y),
+    };
+
+    // Rotate along the shorter arc
+    let mut angle_diff = (to.angle - from.angle) % (2.0 * PI);
+    if angle_diff > PI {
+        angle_diff -= 2.0 * PI;
+    } else if angle_diff < -PI {
+        angle_diff += 2.0 * PI;
+    }
+
+    (position, from.angle + angle_diff * t)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(millis: u64, x: f32, angle: f32) -> EntitySample {
+        EntitySample {
+            server_time: Duration::from_millis(millis),
+            position: MeterVec2::from_raw(x, 0.0),
+            angle,
+        }
+    }
+
+    fn buffer(samples: &[EntitySample]) -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::default();
+        samples.iter().for_each(|sample| buffer.push(*sample));
+        buffer
+    }
+
+    fn assert_approx(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} is not approximately {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn empty_buffer_has_no_sample() {
+        assert!(SnapshotBuffer::default()
+            .sample_at(Duration::from_millis(100), Duration::ZERO)
+            .is_none());
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        let buffer = buffer(&[
+            sample(0, 0.0, 0.0),
+            sample(100, 10.0, 1.0),
+            sample(200, 10.0, 1.0),
+        ]);
+
+        let (position, angle) = buffer
+            .sample_at(Duration::from_millis(25), Duration::ZERO)
+            .unwrap();
+
+        assert_approx(position.x.0, 2.5);
+        assert_approx(angle, 0.25);
+    }
+
+    #[test]
+    fn rotates_along_shorter_arc() {
+        let buffer = buffer(&[sample(0, 0.0, PI - 0.1), sample(100, 0.0, -PI + 0.1)]);
+
+        let (_, angle) = buffer
+            .sample_at(Duration::from_millis(50), Duration::ZERO)
+            .unwrap();
+
+        assert_approx(angle, PI);
+    }
+
+    #[test]
+    fn extrapolates_for_limited_time() {
+        let buffer = buffer(&[sample(0, 0.0, 0.0), sample(100, 10.0, 0.0)]);
+
+        let (position, _) = buffer
+            .sample_at(Duration::from_millis(150), Duration::from_millis(200))
+            .unwrap();
+        assert_approx(position.x.0, 15.0);
+
+        let (position, _) = buffer
+            .sample_at(Duration::from_millis(1000), Duration::from_millis(200))
+            .unwrap();
+        assert_approx(position.x.0, 30.0);
+    }
+
+    #[test]
+    fn old_samples_are_dropped() {
+        let buffer = buffer(&[sample(100, 10.0, 0.0), sample(50, 0.0, 0.0)]);
+
+        let (position, _) = buffer
+            .sample_at(Duration::from_millis(50), Duration::ZERO)
+            .unwrap();
+
+        assert_approx(position.x.0, 10.0);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::components::{BoundingCircle, Health, Input, NetworkId, Player, SpriteId, WeaponInfo};
+use crate::components::{BoundingCircle, Input, NetworkId, Player, SpriteId, WeaponInfo};
 use bevy::prelude::{BuildChildren, Bundle, Commands, Entity, Transform};
 use westiny_common::entities::SimpleSpriteSheetBundle;
 use westiny_common::metric_dimension::length::Meter;
@@ -25,10 +25,10 @@ impl PlayerCharacterBundle {
     }
 }
 
+/// The health of this player is replicated by the server like the one of the others
 #[derive(Bundle)]
 pub struct ThisPlayerBundle {
     pub player: Player,
-    pub health: Health,
     pub input: Input,
     pub weapon_info: WeaponInfo,
 }
@@ -37,7 +37,6 @@ impl ThisPlayerBundle {
     pub fn new() -> Self {
         ThisPlayerBundle {
             player: Player,
-            health: Health(100),
             input: Input::default(),
             weapon_info: WeaponInfo {
                 magazine_size: 6,
@@ -48,13 +47,20 @@ impl ThisPlayerBundle {
     }
 }
 
+/// Completes the `replicated` entity of the network id if its components have arrived earlier,
+/// spawns a new one otherwise
 pub fn create_player_character(
     commands: &mut Commands,
+    replicated: Option<Entity>,
     net_id: NetworkId,
     transform: Transform,
 ) -> Entity {
-    commands
-        .spawn(PlayerCharacterBundle::new(net_id, transform))
+    let mut entity = match replicated {
+        Some(entity) => commands.entity(entity),
+        None => commands.spawn_empty(),
+    };
+    entity
+        .insert(PlayerCharacterBundle::new(net_id, transform))
         .with_children(|parent| {
             // hand with pistol
             parent.spawn(SimpleSpriteSheetBundle::new(
@@ -65,8 +71,13 @@ pub fn create_player_character(
         .id()
 }
 
-pub fn create_this_player(commands: &mut Commands, net_id: NetworkId, transform: Transform) {
-    let entity = create_player_character(commands, net_id, transform);
+pub fn create_this_player(
+    commands: &mut Commands,
+    replicated: Option<Entity>,
+    net_id: NetworkId,
+    transform: Transform,
+) {
+    let entity = create_player_character(commands, replicated, net_id, transform);
     commands
         .entity(entity)
         .insert(ThisPlayerBundle::new());
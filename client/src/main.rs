@@ -5,10 +5,14 @@ use std::str::FromStr;
 use crate::resources::ServerAddress;
 use westiny_common::{
     network::{
-        EntityState, NetworkEntityDelete, PlayerDeath, PlayerNotification, PlayerUpdate, ShotEvent,
+        ComponentUpdate, EntityStateUpdate, NetworkEntityDelete, PlayerDeath, PlayerNotification,
+        PlayerUpdate, ShotEvent,
     },
+    demo::Demo,
     events::EntityDelete,
-    utilities::read_ron,
+    replication,
+    secure_channel,
+    utilities::{load_config, read_ron},
     NetworkConfig,
 };
 
@@ -61,10 +65,51 @@ fn main() {
             })
     };
 
+    let interpolation_config: resources::InterpolationConfig =
+        load_config(&resources_dir, "interpolation.ron", "interpolation");
+
     let mut app = App::new();
-    app.add_plugins(DefaultPlugins)
-        .add_plugin(LaminarPlugin::new(client_socket, laminar_config))
-        .add_plugin(bevy_ecs_tilemap::TilemapPlugin)
+    // The window is closed only after the server has been told that the player quit
+    app.add_plugins(DefaultPlugins.set(WindowPlugin {
+        close_when_requested: false,
+        ..default()
+    }));
+
+    // A demo is played instead of connecting to a server
+    match std::env::var_os("WESTINY_PLAY_DEMO") {
+        Some(demo_path) => {
+            let player_name = std::env::var("WESTINY_DEMO_PLAYER").ok();
+            let playback = Demo::load(demo_path.as_ref())
+                .and_then(|demo| resources::DemoPlayback::new(&demo, player_name.as_deref()))
+                .unwrap_or_else(|err| panic!("Unable to play demo {:?}: {:#}", demo_path, err));
+            log::info!(
+                "Playing demo {:?} of {}, length: {:?}",
+                demo_path,
+                playback.player_name(),
+                playback.length()
+            );
+
+            // Nothing is received from a server, there is none to authenticate
+            app.insert_resource(playback)
+                .insert_resource(secure_channel::SecureChannels::client(
+                    secure_channel::ServerTrust::Any,
+                ))
+                .add_system(systems::control_demo_playback.label("demo_control"))
+                .add_system(
+                    systems::play_demo
+                        .label("demo_playback")
+                        .after("demo_control")
+                        .before("connection_response")
+                        .before("network_reception"),
+                );
+        }
+        None => {
+            app.add_plugin(LaminarPlugin::new(client_socket, laminar_config))
+                .insert_resource(resources::secure_channels_from_env(&common_resources_dir));
+        }
+    }
+
+    app.add_plugin(bevy_ecs_tilemap::TilemapPlugin)
         .insert_resource(get_server_address())
         .insert_resource(resources::Seed(10))
         .insert_resource(resources::ResourcesDir {
@@ -74,24 +119,40 @@ fn main() {
         .init_resource::<TransportResource>()
         .init_resource::<resources::SpriteResource>()
         .init_resource::<resources::PlayerNetworkId>()
+        .init_resource::<resources::ClientSession>()
+        .insert_resource(resources::Credentials::from_env())
+        .insert_resource(resources::ServerCodec::from_env())
+        .init_resource::<resources::ServerTickRate>()
+        .init_resource::<resources::ReceivedSnapshots>()
+        .init_resource::<resources::ServerTimeEstimate>()
+        .init_resource::<resources::ServerLink>()
+        .init_resource::<resources::PendingInputs>()
+        .init_resource::<resources::SimulationTime>()
+        .insert_resource(interpolation_config)
         .init_resource::<resources::Sounds>()
         .init_resource::<resources::AudioQueue>()
+        .init_resource::<replication::ReplicatedEntities>()
         .add_event::<NetworkSimulationEvent>()
-        .add_event::<Vec<EntityState>>()
+        .add_event::<EntityStateUpdate>()
+        .add_event::<systems::EntityStates>()
         .add_event::<PlayerUpdate>()
         .add_event::<PlayerDeath>()
         .add_event::<PlayerNotification>()
         .add_event::<EntityDelete>()
         .add_event::<NetworkEntityDelete>()
+        .add_event::<ComponentUpdate>()
         .add_event::<ShotEvent>()
         .add_state(states::AppState::Connect)
         .add_startup_system(resources::initialize_sprite_resource.label("init_sprite_resource"))
         .add_startup_system(resources::initialize_audio.label("init_audio"))
         .add_system_to_stage(CoreStage::PostUpdate, systems::add_sprite_to_new_sprite_id)
         .add_system(entities::tilemap::set_texture_filters_to_nearest) // Boilerplate to tilemap
+        .add_system(systems::disconnect_on_window_close)
+        .add_system(secure_channel::open_packets.label("open_packets").after(LaminarLabel))
+        .add_system_to_stage(CoreStage::Last, secure_channel::seal_packets)
 
         // connect state
-        .add_system_set(states::connection::connect_state_systems().after(LaminarLabel))
+        .add_system_set(states::connection::connect_state_systems().after("open_packets"))
         .add_system_set(
             SystemSet::on_enter(states::AppState::Connect)
                 .with_system(|| log::debug!("Entering Connect AppState")))
@@ -106,7 +167,7 @@ fn main() {
                 .with_system(|| log::debug!("Entering PlayInit AppState")))
 
         // play state
-        .add_system_set(states::play::system_set().after(LaminarLabel))
+        .add_system_set(states::play::system_set().after("open_packets"))
         .add_system_set(
             SystemSet::on_enter(states::AppState::Play)
                 .with_system(|| log::debug!("Entering Play AppState")))
@@ -114,9 +175,10 @@ fn main() {
 
         .add_system_to_stage(
             CoreStage::PostUpdate,
-            systems::network_entity_delete::delete_entities);
+            systems::network_entity_delete::delete_entities.label("network_entity_delete"));
         
         register_reflected_types(&mut app);
+        add_replication_systems(&mut app);
 
         app.run();
 }
@@ -127,6 +189,27 @@ fn register_reflected_types(app: &mut App) {
     app.register_type::<components::BoundingCircle>();
 }
 
+/// The received components are inserted after the entities of the frame have been spawned,
+/// but before the deleted ones are despawned. The map entities are completed in the next frame.
+fn add_replication_systems(app: &mut App) {
+    let replicated = replication::replicated_components();
+    app.add_system_to_stage(
+        CoreStage::PostUpdate,
+        replication::spawn_replicated_entities
+            .label("spawn_replicated")
+            .before("network_entity_delete"),
+    );
+    for system in replicated.apply_systems() {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            system.after("spawn_replicated").before("network_entity_delete"),
+        );
+    }
+    app.insert_resource(replicated)
+        .add_system(systems::spawn_map_entities)
+        .add_system(systems::move_map_entities);
+}
+
 #[derive(Deserialize)]
 pub struct ClientPort(pub u16);
 impl Default for ClientPort {
@@ -0,0 +1,311 @@
+use anyhow::{bail, Context, Result};
+use blaminar::Bytes;
+use std::time::Duration;
+use westiny_common::demo::{Demo, DemoRecord};
+use westiny_common::network::PROTOCOL_VERSION;
+
+/// Packets skipped over by seeking are delivered at once when the playback gets past them.
+/// The ones older than this are only needed for their effect on the state of the world.
+const STALE_PACKET_AGE: Duration = Duration::from_millis(500);
+const MIN_SPEED: f32 = 0.125;
+const MAX_SPEED: f32 = 8.0;
+
+pub struct DemoPacket {
+    pub server_time: Duration,
+    pub payload: Bytes,
+    /// The playback is already past this packet
+    pub stale: bool,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Seek {
+    Forward,
+    /// The world must be cleared, the demo is replayed from the start
+    Backward,
+}
+
+/// Replays the packets a player received during a recorded session
+#[derive(bevy::prelude::Resource)]
+pub struct DemoPlayback {
+    player_name: String,
+    /// Server time and payload of the packets sent to the player
+    packets: Vec<(Duration, Bytes)>,
+    next: usize,
+    time: Duration,
+    seek: Option<Seek>,
+    paused: bool,
+    speed: f32,
+}
+
+impl DemoPlayback {
+    /// Plays the demo from the view of the given player, or of the first one joined
+    pub fn new(demo: &Demo, player_name: Option<&str>) -> Result<Self> {
+        if demo.header.protocol_version != PROTOCOL_VERSION {
+            bail!(
+                "Demo has been recorded with protocol version {}, expected: {}",
+                demo.header.protocol_version,
+                PROTOCOL_VERSION
+            );
+        }
+
+        let player_name = match player_name {
+            Some(name) => name.to_string(),
+            None => demo
+                .players()
+                .next()
+                .map(|(name, _)| name.to_string())
+                .context("Nobody has joined in the demo")?,
+        };
+        // The player may have reconnected from another address
+        let addresses: Vec<_> = demo
+            .players()
+            .filter(|(name, _)| *name == player_name)
+            .map(|(_, address)| address)
+            .collect();
+
+        let packets: Vec<_> = demo
+            .frames
+            .iter()
+            .filter_map(|frame| match &frame.record {
+                DemoRecord::Outbound {
+                    destination,
+                    payload,
+                } if addresses.contains(destination) => {
+                    Some((frame.server_time, Bytes::copy_from_slice(payload)))
+                }
+                _ => None,
+            })
+            .collect();
+        let start = match packets.first() {
+            Some((server_time, _)) => *server_time,
+            None => bail!("No packets have been recorded for {}", player_name),
+        };
+
+        Ok(DemoPlayback {
+            player_name,
+            packets,
+            next: 0,
+            time: start,
+            seek: None,
+            paused: false,
+            speed: 1.0,
+        })
+    }
+
+    pub fn player_name(&self) -> &str {
+        &self.player_name
+    }
+
+    fn start(&self) -> Duration {
+        self.packets[0].0
+    }
+
+    /// Time of the playback since the start of the demo
+    pub fn position(&self) -> Duration {
+        self.time - self.start()
+    }
+
+    pub fn length(&self) -> Duration {
+        self.packets[self.packets.len() - 1].0 - self.start()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.packets.len()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    /// The speed is clamped between 1/8 and 8 times the recorded one
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// Plays `frame_time` long at the current speed, returns the packets due
+    pub fn play(&mut self, frame_time: Duration) -> Vec<DemoPacket> {
+        let delta = if self.paused {
+            Duration::ZERO
+        } else {
+            frame_time.mul_f32(self.speed)
+        };
+        self.advance(delta)
+    }
+
+    /// Moves the playback forward, returns the packets due
+    pub fn advance(&mut self, delta: Duration) -> Vec<DemoPacket> {
+        self.time += delta;
+        let mut due = Vec::new();
+        while let Some((server_time, payload)) = self.packets.get(self.next) {
+            if *server_time > self.time {
+                break;
+            }
+            due.push(DemoPacket {
+                server_time: *server_time,
+                payload: payload.clone(),
+                stale: self.time - *server_time > STALE_PACKET_AGE,
+            });
+            self.next += 1;
+        }
+        due
+    }
+
+    /// Jumps to `position` from the start of the demo
+    pub fn seek(&mut self, position: Duration) {
+        let time = self.start() + position.min(self.length());
+        let seek = if time < self.time {
+            self.next = 0;
+            Seek::Backward
+        } else {
+            Seek::Forward
+        };
+        // A backward seek must not be lost to a forward one in the same frame
+        if self.seek != Some(Seek::Backward) {
+            self.seek = Some(seek);
+        }
+        self.time = time;
+    }
+
+    /// The seek since the last call, if any
+    pub fn take_seek(&mut self) -> Option<Seek> {
+        self.seek.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::SocketAddr;
+    use westiny_common::demo::{DemoFrame, DemoHeader};
+    use westiny_common::resources::Seed;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn frame(millis: u64, record: DemoRecord) -> DemoFrame {
+        DemoFrame {
+            tick: 0,
+            server_time: Duration::from_millis(millis),
+            record,
+        }
+    }
+
+    fn joined(millis: u64, name: &str, port: u16) -> DemoFrame {
+        frame(
+            millis,
+            DemoRecord::ClientJoined {
+                address: address(port),
+                player_name: name.to_string(),
+            },
+        )
+    }
+
+    fn packet(millis: u64, port: u16, payload: u8) -> DemoFrame {
+        frame(
+            millis,
+            DemoRecord::Outbound {
+                destination: address(port),
+                payload: vec![payload],
+            },
+        )
+    }
+
+    fn demo() -> Demo {
+        Demo {
+            header: DemoHeader {
+                protocol_version: PROTOCOL_VERSION,
+                tick_rate: 60,
+                map_seed: Seed(0),
+                rng_seed: 0,
+            },
+            frames: vec![
+                joined(1000, "Clint", 1),
+                packet(1000, 1, 0),
+                joined(1500, "Tuco", 2),
+                packet(1500, 2, 100),
+                packet(2000, 1, 1),
+                packet(3000, 1, 2),
+                packet(4000, 1, 3),
+            ],
+        }
+    }
+
+    fn payloads(packets: &[DemoPacket]) -> Vec<u8> {
+        packets.iter().map(|packet| packet.payload[0]).collect()
+    }
+
+    #[test]
+    fn packets_of_first_player_are_played_in_time() {
+        let mut playback = DemoPlayback::new(&demo(), None).unwrap();
+
+        assert_eq!(playback.player_name(), "Clint");
+        assert_eq!(playback.length(), Duration::from_secs(3));
+        assert_eq!(payloads(&playback.advance(Duration::ZERO)), vec![0]);
+        assert_eq!(payloads(&playback.advance(Duration::from_millis(999))), vec![]);
+        assert_eq!(payloads(&playback.advance(Duration::from_millis(1))), vec![1]);
+        assert_eq!(payloads(&playback.advance(Duration::from_secs(2))), vec![2, 3]);
+        assert!(playback.is_finished());
+    }
+
+    #[test]
+    fn packets_of_chosen_player_are_played() {
+        let mut playback = DemoPlayback::new(&demo(), Some("Tuco")).unwrap();
+
+        assert_eq!(payloads(&playback.advance(Duration::from_secs(10))), vec![100]);
+        assert!(DemoPlayback::new(&demo(), Some("Angel Eyes")).is_err());
+    }
+
+    #[test]
+    fn seeking_forward_delivers_skipped_packets_as_stale() {
+        let mut playback = DemoPlayback::new(&demo(), None).unwrap();
+        playback.advance(Duration::ZERO);
+
+        playback.seek(Duration::from_millis(2800));
+        let packets = playback.advance(Duration::ZERO);
+
+        assert_eq!(playback.take_seek(), Some(Seek::Forward));
+        assert_eq!(payloads(&packets), vec![1, 2]);
+        assert!(packets[0].stale);
+        assert!(!packets[1].stale);
+    }
+
+    #[test]
+    fn seeking_backward_restarts_playback() {
+        let mut playback = DemoPlayback::new(&demo(), None).unwrap();
+        playback.advance(Duration::from_secs(3));
+
+        playback.seek(Duration::from_millis(1500));
+        playback.seek(Duration::from_millis(1600));
+
+        assert_eq!(playback.take_seek(), Some(Seek::Backward));
+        assert_eq!(playback.take_seek(), None);
+        assert_eq!(playback.position(), Duration::from_millis(1600));
+        assert_eq!(payloads(&playback.advance(Duration::ZERO)), vec![0, 1]);
+    }
+
+    #[test]
+    fn playback_follows_pause_and_speed() {
+        let mut playback = DemoPlayback::new(&demo(), None).unwrap();
+        playback.play(Duration::ZERO);
+
+        playback.set_paused(true);
+        assert_eq!(payloads(&playback.play(Duration::from_secs(5))), vec![]);
+
+        playback.set_paused(false);
+        playback.set_speed(2.0);
+        assert_eq!(payloads(&playback.play(Duration::from_millis(500))), vec![1]);
+        assert_eq!(playback.position(), Duration::from_secs(1));
+
+        playback.set_speed(100.0);
+        assert_eq!(playback.speed(), 8.0);
+    }
+}
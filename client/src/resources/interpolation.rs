@@ -0,0 +1,104 @@
+use serde::Deserialize;
+use std::time::Duration;
+use westiny_common::metric_dimension::Second;
+
+/// Weight of a new measurement in the estimated clock offset
+const OFFSET_SMOOTHING: f64 = 0.1;
+
+#[derive(Copy, Clone, Debug, Deserialize, bevy::prelude::Resource)]
+pub struct InterpolationConfig {
+    /// Remote entities are rendered this much in the past
+    pub delay: Second,
+    /// Remote entities are moved further at most this long when no new states arrive
+    pub max_extrapolation: Second,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        InterpolationConfig {
+            delay: Second(0.1),
+            max_extrapolation: Second(0.25),
+        }
+    }
+}
+
+/// Estimates the elapsed time of the server from the timestamps of the received snapshots,
+/// and from the answers to the pings
+#[derive(Debug, Default, bevy::prelude::Resource)]
+pub struct ServerTimeEstimate {
+    /// Server time minus local time in seconds
+    offset: Option<f64>,
+    /// Same as `offset`, corrected with the latency measured by the pings
+    clock_offset: Option<f64>,
+}
+
+impl ServerTimeEstimate {
+    pub fn update(&mut self, server_time: Duration, local_time: Duration) {
+        let measured = server_time.as_secs_f64() - local_time.as_secs_f64();
+        self.offset = Some(match self.offset {
+            Some(offset) => offset + (measured - offset) * OFFSET_SMOOTHING,
+            None => measured,
+        });
+    }
+
+    /// The answer to a ping was sent half the round trip ago
+    pub fn synchronize(&mut self, server_time: Duration, rtt: Duration, local_time: Duration) {
+        let measured =
+            server_time.as_secs_f64() + rtt.as_secs_f64() / 2.0 - local_time.as_secs_f64();
+        self.clock_offset = Some(match self.clock_offset {
+            Some(offset) => offset + (measured - offset) * OFFSET_SMOOTHING,
+            None => measured,
+        });
+    }
+
+    /// Server time of the snapshots arriving now, i.e. the server time delayed by the latency.
+    /// The remote entities are displayed on this timeline.
+    pub fn server_time(&self, local_time: Duration) -> Option<Duration> {
+        self.offset.map(|offset| add_offset(local_time, offset))
+    }
+
+    /// Elapsed time on the server now, known after the first answer to a ping
+    pub fn server_clock(&self, local_time: Duration) -> Option<Duration> {
+        self.clock_offset.map(|offset| add_offset(local_time, offset))
+    }
+
+    /// Age of a snapshot taken at `server_time`
+    pub fn snapshot_age(&self, server_time: Duration, local_time: Duration) -> Option<Duration> {
+        self.server_clock(local_time)
+            .map(|now| now.saturating_sub(server_time))
+    }
+}
+
+fn add_offset(local_time: Duration, offset: f64) -> Duration {
+    Duration::from_secs_f64((local_time.as_secs_f64() + offset).max(0.0))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn clock_is_corrected_with_latency() {
+        let mut estimate = ServerTimeEstimate::default();
+        estimate.update(Duration::from_secs(10), Duration::from_secs(5));
+        estimate.synchronize(
+            Duration::from_secs(10),
+            Duration::from_millis(500),
+            Duration::from_secs(5),
+        );
+
+        let local_time = Duration::from_secs(6);
+        assert_eq!(
+            estimate.server_time(local_time),
+            Some(Duration::from_secs(11))
+        );
+        assert_eq!(
+            estimate.server_clock(local_time),
+            Some(Duration::from_millis(11250))
+        );
+        assert_eq!(
+            estimate.snapshot_age(Duration::from_secs(11), local_time),
+            Some(Duration::from_millis(250))
+        );
+    }
+}
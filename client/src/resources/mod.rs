@@ -1,12 +1,24 @@
 pub use audio::{initialize_audio, Sounds};
+pub use demo::{DemoPlayback, Seek};
+pub use interpolation::{InterpolationConfig, ServerTimeEstimate};
 pub use network_stream_id::StreamId;
+pub use prediction::{PendingInput, PendingInputs};
 pub use sprite_resource::{initialize_sprite_resource, SpriteResource};
 pub use westiny_common::resources::*;
+pub use westiny_common::snapshot::ReceivedSnapshots;
 
+use std::path::Path;
 use westiny_common::components::{EntityType, NetworkId};
+use westiny_common::network::SessionToken;
+use westiny_common::ping::PingTracker;
+use westiny_common::secure_channel::{SecureChannels, ServerTrust};
+use westiny_common::serialization::PacketCodec;
 
 mod audio;
+mod demo;
+mod interpolation;
 mod network_stream_id;
+mod prediction;
 mod sprite_resource;
 
 #[derive(Debug, bevy::prelude::Resource)]
@@ -21,3 +33,80 @@ impl std::default::Default for PlayerNetworkId {
         })
     }
 }
+
+/// Session of this client on the server, it can reconnect to the session with the token
+#[derive(Debug, Default, bevy::prelude::Resource)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct ClientSession(pub Option<SessionToken>);
+
+/// Codec of the packets to the server. `requested` is asked for in the connection request,
+/// `current` is the one accepted by the server, the default one until then.
+#[derive(Debug, Default, bevy::prelude::Resource)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct ServerCodec {
+    pub requested: PacketCodec,
+    pub current: PacketCodec,
+}
+
+impl ServerCodec {
+    /// Reads `WESTINY_CODEC` (`messagepack`, `binary` or `json`)
+    pub fn from_env() -> Self {
+        let requested = match std::env::var("WESTINY_CODEC") {
+            Ok(name) => name.parse().unwrap_or_else(|err| {
+                log::warn!("{}, using the default codec", err);
+                PacketCodec::default()
+            }),
+            Err(_) => PacketCodec::default(),
+        };
+        ServerCodec {
+            requested,
+            current: PacketCodec::default(),
+        }
+    }
+}
+
+/// The server is pinned as `ServerTrust::from_env` reads it, the client does not start without
+/// a valid key unless any server is trusted explicitly
+pub fn secure_channels_from_env(resources_dir: &Path) -> SecureChannels {
+    let trust = ServerTrust::from_env(resources_dir).unwrap_or_else(|err| panic!("{}", err));
+    SecureChannels::client(trust)
+}
+
+/// Round trip time, jitter and loss of the connection to the server
+#[derive(Debug, Default, bevy::prelude::Resource)]
+pub struct ServerLink(pub PingTracker);
+
+/// Answers of the authentication challenge of the server, never sent as they are
+#[derive(Debug, Default, bevy::prelude::Resource)]
+pub struct Credentials {
+    pub server_password: Option<String>,
+    pub player_secret: Option<String>,
+}
+
+impl Credentials {
+    /// Reads `WESTINY_SERVER_PASSWORD` and `WESTINY_PLAYER_SECRET`
+    pub fn from_env() -> Self {
+        Credentials {
+            server_password: std::env::var("WESTINY_SERVER_PASSWORD").ok(),
+            player_secret: std::env::var("WESTINY_PLAYER_SECRET").ok(),
+        }
+    }
+}
+
+/// Simulation ticks per second on the server.
+/// The server applies one input per tick, so inputs are sent at this rate.
+#[derive(Debug, bevy::prelude::Resource)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub struct ServerTickRate(pub u32);
+
+impl ServerTickRate {
+    pub fn step(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(1.0 / self.0.max(1) as f64)
+    }
+}
+
+impl std::default::Default for ServerTickRate {
+    fn default() -> Self {
+        ServerTickRate(60)
+    }
+}
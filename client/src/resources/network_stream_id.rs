@@ -2,6 +2,7 @@
 #[repr(u8)]
 pub enum StreamId {
     InputState,
+    SnapshotAck,
 }
 
 impl From<StreamId> for Option<u8> {
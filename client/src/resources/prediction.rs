@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+use westiny_common::components::Input;
+use westiny_common::metric_dimension::Second;
+use westiny_common::network::{InputSequence, SequencedInput};
+
+/// Upper limit of stored inputs, in case the server stops acknowledging them
+const MAX_PENDING_INPUTS: usize = 256;
+
+#[derive(Copy, Clone, Debug)]
+pub struct PendingInput {
+    pub sequence: InputSequence,
+    pub input: Input,
+    /// Duration the input has been predicted for
+    pub delta_time: Second,
+}
+
+/// Inputs sent to the server which have not been processed by it yet.
+/// They are replayed on top of the authoritative state of this player.
+#[derive(Debug, Default, bevy::prelude::Resource)]
+pub struct PendingInputs {
+    next_sequence: InputSequence,
+    inputs: VecDeque<PendingInput>,
+}
+
+impl PendingInputs {
+    /// Stores the input and returns its sequence number
+    pub fn push(&mut self, input: Input, delta_time: Second) -> InputSequence {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        if self.inputs.len() >= MAX_PENDING_INPUTS {
+            self.inputs.pop_front();
+        }
+        self.inputs.push_back(PendingInput {
+            sequence,
+            input,
+            delta_time,
+        });
+        sequence
+    }
+
+    /// Drops the inputs up to (and including) the given sequence number
+    pub fn acknowledge(&mut self, sequence: InputSequence) {
+        while let Some(pending) = self.inputs.front() {
+            if pending.sequence > sequence {
+                break;
+            }
+            self.inputs.pop_front();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PendingInput> {
+        self.inputs.iter()
+    }
+
+    /// The latest `count` inputs in ascending sequence order
+    pub fn latest(&self, count: usize) -> Vec<SequencedInput> {
+        self.inputs
+            .iter()
+            .skip(self.inputs.len().saturating_sub(count))
+            .map(|pending| SequencedInput {
+                sequence: pending.sequence,
+                input: pending.input,
+            })
+            .collect()
+    }
+}
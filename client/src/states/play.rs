@@ -5,10 +5,10 @@ use bevy::prelude::*;
 
 pub fn setup_system_set() -> SystemSet {
     SystemSet::on_enter(AppState::PlayInit)
-        .with_system(systems::build_map)
         .with_system(systems::camera::setup)
         .with_system(systems::hud::setup)
         .with_system(systems::notification_bar::setup)
+        .with_system(systems::debug_overlay::setup)
         .with_system(initialize_tilemap)
 }
 
@@ -18,8 +18,12 @@ pub fn init_system_set() -> SystemSet {
             systems::receive_network_messages
                 .label("network_reception"))
         .with_system(
-            systems::spawn_this_player
+            systems::receive_snapshots
+                .label("snapshot_reception")
                 .after("network_reception"))
+        .with_system(
+            systems::spawn_this_player
+                .after("snapshot_reception"))
 }
 
 pub fn system_set() -> SystemSet {
@@ -27,19 +31,39 @@ pub fn system_set() -> SystemSet {
         .with_system(
             systems::receive_network_messages
                 .label("network_reception"))
+        .with_system(
+            systems::reconnect_to_server)
+        .with_system(
+            systems::exchange_pings)
+        .with_system(
+            systems::debug_overlay::update_debug_overlay)
         .with_system(
             systems::play_audio)
+        .with_system(
+            systems::receive_snapshots
+                .label("snapshot_reception")
+                .after("network_reception"))
         .with_system(
             systems::update_network_entities
                 .label("update_network_entities")
-                .after("network_reception"))
+                .after("snapshot_reception"))
+        .with_system(
+            systems::interpolate_network_entities
+                .label("interpolate_network_entities")
+                .after("update_network_entities"))
         .with_system(
             systems::camera::follow_player
                 .label("camera_follow_player")
-                .after("update_network_entities"))
+                .after("update_network_entities")
+                .after("user_input_handler"))
+        .with_system(
+            systems::reconcile_player
+                .label("reconcile_player")
+                .after("snapshot_reception"))
         .with_system(
             systems::handle_user_inputs
-                .label("user_input_handler"))
+                .label("user_input_handler")
+                .after("reconcile_player"))
         .with_system(
             systems::spawn_bullets
                 .label("shooter")
@@ -49,6 +73,10 @@ pub fn system_set() -> SystemSet {
                 .label("lifespan")
                 .before("physics")
             )
+        .with_system(
+            systems::advance_simulation_time
+                .label("simulation_time")
+                .before("physics"))
         .with_system(
             systems::physics
                 .label("physics"))
@@ -56,6 +84,8 @@ pub fn system_set() -> SystemSet {
             systems::update_player
                 .label("update_player")
                 .after("network_reception"))
+        .with_system(
+            systems::notice_health_change)
         .with_system(
             systems::hud::update_hud
                 .after("update_player")
@@ -1,11 +1,19 @@
-use crate::resources::{PlayerNetworkId, Seed, ServerAddress};
+use crate::resources::{
+    ClientSession, Credentials, DemoPlayback, PlayerNetworkId, Seed, ServerAddress, ServerCodec,
+    ServerTickRate,
+};
 use crate::states::AppState;
 use bevy::prelude::{EventReader, Local, Res, ResMut, State, Time};
 use blaminar::simulation::{
     DeliveryRequirement, NetworkSimulationEvent, TransportResource, UrgencyRequirement,
 };
-use westiny_common::network::PacketType::{ConnectionRequest, ConnectionResponse};
-use westiny_common::serialization::{deserialize, serialize};
+use westiny_common::auth::{prove, Credential};
+use westiny_common::network::PacketType::{
+    AuthChallenge, AuthResponse, ConnectionRequest, ConnectionResponse,
+};
+use westiny_common::network::{self, BUILD_HASH};
+use westiny_common::secure_channel::{KeyShare, SecureChannels};
+use westiny_common::serialization::{deserialize, serialize, PacketCodec};
 use std::time::Duration;
 
 const PLAYER_NAME_MAGIC: &str = "Narancsos_Feco";
@@ -14,24 +22,65 @@ fn get_player_name() -> String {
     std::env::var("USER").unwrap_or_else(|_| PLAYER_NAME_MAGIC.to_string())
 }
 
+/// The packets of the server are trusted only within the secure session, as anyone could send
+/// them unsealed. A demo is played back locally without a session, its packets are trusted.
+pub(crate) fn is_trusted(
+    secure_channels: &SecureChannels,
+    server_addr: &ServerAddress,
+    demo: Option<&DemoPlayback>,
+) -> bool {
+    demo.is_some() || secure_channels.has_session(&server_addr.address)
+}
+
 #[derive(Default)]
 pub struct LastRun(Duration);
 
+impl LastRun {
+    /// Returns true at most once a second
+    fn is_due(&mut self, now: Duration) -> bool {
+        // First condition is to avoid 1sec dead time after first system run
+        if self.0 != Duration::ZERO && now - self.0 < Duration::from_secs(1u64) {
+            return false;
+        }
+        self.0 = now;
+        true
+    }
+}
+
 pub fn send_connection_request(
     server_addr: Res<ServerAddress>,
+    session: Res<ClientSession>,
+    codec: Res<ServerCodec>,
+    mut secure_channels: ResMut<SecureChannels>,
     mut net: ResMut<TransportResource>,
     time: Res<Time>,
     mut last_run: Local<LastRun>,
 ) {
-    // First condition is to avoid 1sec dead time after first system run
-    if last_run.0 != Duration::ZERO && time.elapsed() - last_run.0 < Duration::from_secs(1u64) {
+    if !last_run.is_due(time.elapsed()) {
         return;
     }
-    last_run.0 = time.elapsed();
 
     log::info!("Trying to connect to server: {:?}", server_addr.address);
+    let key_share = secure_channels.key_share(server_addr.address);
+    send_request(&mut net, &server_addr, &session, codec.requested, key_share);
+}
+
+fn send_request(
+    net: &mut TransportResource,
+    server_addr: &ServerAddress,
+    session: &ClientSession,
+    codec: PacketCodec,
+    key_share: KeyShare,
+) {
     let msg = serialize(&ConnectionRequest {
         player_name: get_player_name(),
+        build_hash: BUILD_HASH.to_string(),
+        // The token is proven when the server challenges the client, it is never sent
+        resume_session: session.0.is_some(),
+        codec,
+        // Every packet is decompressed by `deserialize` if the server has compressed it
+        compression: true,
+        key_share,
     })
     .expect("ConnectionRequest could not be serialized");
     net.send_with_requirements(
@@ -42,12 +91,140 @@ pub fn send_connection_request(
     );
 }
 
+/// Proves the credentials and the session token asked by the server without sending them.
+/// A missing credential is not proven, so the server refuses the connection.
+fn answer_challenge(
+    net: &mut TransportResource,
+    server_addr: &ServerAddress,
+    credentials: &Credentials,
+    session: &ClientSession,
+    challenge: &network::AuthChallenge,
+) {
+    let player_name = get_player_name();
+    let proof = |required, credential, key: &Option<String>, env_var| {
+        if !required {
+            return None;
+        }
+        match key {
+            Some(key) => Some(prove(credential, key, &challenge.nonce, &player_name)),
+            None => {
+                log::error!("The server asks for a credential, {} is not set", env_var);
+                None
+            }
+        }
+    };
+    let msg = serialize(&AuthResponse {
+        password_proof: proof(
+            challenge.password_required,
+            Credential::ServerPassword,
+            &credentials.server_password,
+            "WESTINY_SERVER_PASSWORD",
+        ),
+        secret_proof: proof(
+            challenge.secret_required,
+            Credential::PlayerSecret,
+            &credentials.player_secret,
+            "WESTINY_PLAYER_SECRET",
+        ),
+        session_proof: session.0.filter(|_| challenge.session_required).map(|token| {
+            prove(
+                Credential::SessionToken,
+                &token.to_string(),
+                &challenge.nonce,
+                &player_name,
+            )
+        }),
+    })
+    .expect("AuthResponse could not be serialized");
+    net.send_with_requirements(
+        server_addr.address,
+        &msg,
+        DeliveryRequirement::ReliableSequenced(None),
+        UrgencyRequirement::OnTick,
+    );
+}
+
+/// Asks for the session back while the connection to the server is lost (e.g. the address of
+/// the client has changed after a network drop), so the player is not lost.
+/// When the session has already expired, the server gives a new player.
+#[allow(clippy::too_many_arguments)]
+pub fn reconnect_to_server(
+    server_addr: Res<ServerAddress>,
+    mut session: ResMut<ClientSession>,
+    mut codec: ResMut<ServerCodec>,
+    credentials: Res<Credentials>,
+    mut player_network_id: ResMut<PlayerNetworkId>,
+    mut secure_channels: ResMut<SecureChannels>,
+    demo: Option<Res<DemoPlayback>>,
+    mut net_event: EventReader<NetworkSimulationEvent>,
+    mut net: ResMut<TransportResource>,
+    time: Res<Time>,
+    mut connection_lost: Local<bool>,
+    mut last_run: Local<LastRun>,
+) {
+    for event in net_event.iter() {
+        match event {
+            NetworkSimulationEvent::Disconnect(addr) if *addr == server_addr.address => {
+                *connection_lost = true;
+                // The server may see a new address, which has no session yet
+                secure_channels.forget(addr);
+            }
+            NetworkSimulationEvent::Message(addr, msg) if *addr == server_addr.address => {
+                *connection_lost = false;
+                match deserialize(msg) {
+                    // Only the refusals may come unsealed, the rest could be injected by anyone
+                    Ok(ConnectionResponse(Ok(_)) | AuthChallenge(_))
+                        if !is_trusted(&secure_channels, &server_addr, demo.as_deref()) =>
+                    {
+                        log::warn!("Unsealed response of the server is ignored")
+                    }
+                    Ok(ConnectionResponse(Ok(init_data))) => {
+                        log::info!("Reconnected to server");
+                        session.0 = Some(init_data.session_token);
+                        codec.current = init_data.codec;
+                        if player_network_id.0 != init_data.player_network_id {
+                            log::warn!("Session has expired, playing with a new player");
+                            player_network_id.0 = init_data.player_network_id;
+                        }
+                    }
+                    Ok(ConnectionResponse(Err(err))) => {
+                        log::error!("Reconnection refused. Reason: {}", err)
+                    }
+                    Ok(AuthChallenge(challenge)) => answer_challenge(
+                        &mut net,
+                        &server_addr,
+                        &credentials,
+                        &session,
+                        &challenge,
+                    ),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if *connection_lost && last_run.is_due(time.elapsed()) {
+        log::info!("Trying to reconnect to server: {:?}", server_addr.address);
+        let key_share = secure_channels.key_share(server_addr.address);
+        send_request(&mut net, &server_addr, &session, codec.requested, key_share);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn receive_connection_response(
     server_addr: Res<ServerAddress>,
+    credentials: Res<Credentials>,
+    secure_channels: Res<SecureChannels>,
+    demo: Option<Res<DemoPlayback>>,
+    mut net: ResMut<TransportResource>,
     mut net_event: EventReader<NetworkSimulationEvent>,
     mut app_state: ResMut<State<AppState>>,
     mut seed: ResMut<Seed>,
     mut player_network_id: ResMut<PlayerNetworkId>,
+    mut server_tick_rate: ResMut<ServerTickRate>,
+    mut session: ResMut<ClientSession>,
+    mut codec: ResMut<ServerCodec>,
 ) {
     for event in net_event.iter() {
         match event {
@@ -60,6 +237,12 @@ pub fn receive_connection_response(
 
                 match deserialize(msg) {
                     Ok(packet) => match packet {
+                        // Only the refusals may come unsealed, the rest could be injected by anyone
+                        ConnectionResponse(Ok(_)) | AuthChallenge(_)
+                            if !is_trusted(&secure_channels, &server_addr, demo.as_deref()) =>
+                        {
+                            log::warn!("Unsealed response of the server is ignored")
+                        }
                         ConnectionResponse(Ok(init_data)) => {
                             log::info!("Connection established");
                             app_state
@@ -67,11 +250,31 @@ pub fn receive_connection_response(
                                 .expect("Failed to set AppState to PlayInit");
                             *seed = init_data.seed;
                             player_network_id.0 = init_data.player_network_id;
+                            server_tick_rate.0 = init_data.tick_rate;
+                            session.0 = Some(init_data.session_token);
+                            if init_data.codec != codec.requested {
+                                log::warn!(
+                                    "Server does not accept codec {:?}, using {:?}",
+                                    codec.requested,
+                                    init_data.codec
+                                );
+                            }
+                            codec.current = init_data.codec;
                             return;
                         }
                         ConnectionResponse(Err(err)) => {
                             log::error!("Conection refused. Reason: {}", err)
                         }
+                        AuthChallenge(challenge) => {
+                            log::info!("Server asks for authentication");
+                            answer_challenge(
+                                &mut net,
+                                &server_addr,
+                                &credentials,
+                                &session,
+                                &challenge,
+                            )
+                        }
                         _ => log::error!("Unexpected package from server: {:02x?}", packet),
                     },
                     Err(err) => log::error!(
@@ -92,23 +295,68 @@ mod test {
     use std::net::SocketAddr;
     use w_bevy_test::*;
     use westiny_common::components::{EntityType, NetworkId};
+    use westiny_common::compression::Compression;
     use westiny_common::network::{self, PacketType};
     use westiny_common::resources::Seed;
+    use westiny_common::secure_channel::{ServerIdentity, ServerTrust};
 
     const SOCKET_ADDRESS: ([u8; 4], u16) = ([127, 0, 0, 1], 9999);
 
+    /// Channels of the client after the offer of the server has been received
+    fn secure_channels() -> SecureChannels {
+        let client_addr = SocketAddr::from(([127, 0, 0, 1], 4557));
+        let mut channels = SecureChannels::client(ServerTrust::Any);
+        let mut server = SecureChannels::server(ServerIdentity::generate());
+        let share = channels.key_share(SocketAddr::from(SOCKET_ADDRESS));
+        server.accept(client_addr, share, Duration::ZERO).unwrap();
+        let offered = server
+            .seal(&client_addr, &[], DeliveryRequirement::Reliable)
+            .unwrap();
+        channels
+            .open(&SocketAddr::from(SOCKET_ADDRESS), &offered)
+            .unwrap();
+        channels
+    }
+
+    fn auth_challenge() -> NetworkSimulationEvent {
+        NetworkSimulationEvent::Message(
+            SocketAddr::from(SOCKET_ADDRESS),
+            serialize(&PacketType::AuthChallenge(network::AuthChallenge {
+                nonce: [7; 32],
+                password_required: true,
+                secret_required: false,
+                session_required: false,
+            }))
+            .unwrap()
+            .into(),
+        )
+    }
+
     #[test]
     fn sends_connection_request() {
         std::env::set_var("USER", "abcd1234");
 
+        let mut secure_channels = SecureChannels::client(ServerTrust::Any);
+        let key_share = secure_channels.key_share(SocketAddr::from(SOCKET_ADDRESS));
         let expected_payload = serialize(&ConnectionRequest {
             player_name: "abcd1234".to_string(),
+            build_hash: BUILD_HASH.to_string(),
+            resume_session: false,
+            codec: PacketCodec::Binary,
+            compression: true,
+            key_share,
         }).unwrap();
 
         App::new()
             .add_plugin(bevy::core::CorePlugin::default())
             .add_plugin(bevy::time::TimePlugin::default())
             .init_resource::<TransportResource>()
+            .init_resource::<ClientSession>()
+            .insert_resource(ServerCodec {
+                requested: PacketCodec::Binary,
+                current: PacketCodec::MessagePack,
+            })
+            .insert_resource(secure_channels)
             .insert_resource(ServerAddress {
                 address: SocketAddr::from(SOCKET_ADDRESS),
             })
@@ -133,6 +381,10 @@ mod test {
         Ok(network::ClientInitialData {
             player_network_id: NetworkId::new(EntityType::Player, 1234),
             seed: Seed(100),
+            tick_rate: 30,
+            session_token: 42,
+            codec: PacketCodec::Binary,
+            compression: Some(Compression { threshold: 256 }),
         })
     }
 
@@ -143,6 +395,14 @@ mod test {
             .init_resource::<TransportResource>()
             .init_resource::<Seed>()
             .init_resource::<PlayerNetworkId>()
+            .init_resource::<ServerTickRate>()
+            .init_resource::<ClientSession>()
+            .insert_resource(ServerCodec {
+                requested: PacketCodec::Binary,
+                current: PacketCodec::MessagePack,
+            })
+            .init_resource::<Credentials>()
+            .insert_resource(secure_channels())
             .insert_resource(ServerAddress {
                 address: SocketAddr::from(SOCKET_ADDRESS),
             })
@@ -155,6 +415,85 @@ mod test {
             .add_assert_system(assertion::assert_current_state(AppState::PlayInit))
             .add_assert_system(assertion::assert_resource(Seed(100)))
             .add_assert_system(assertion::assert_resource(PlayerNetworkId(NetworkId::new(EntityType::Player, 1234))))
+            .add_assert_system(assertion::assert_resource(ServerTickRate(30)))
+            .add_assert_system(assertion::assert_resource(ClientSession(Some(42))))
+            .add_assert_system(assertion::assert_resource(ServerCodec {
+                requested: PacketCodec::Binary,
+                current: PacketCodec::Binary,
+            }))
+            .add_system_set(
+                SystemSet::on_update(AppState::Connect).with_system(receive_connection_response))
+            .run();
+    }
+
+    #[test]
+    fn answers_authentication_challenge() {
+        App::new()
+            .add_state(AppState::Connect)
+            .init_resource::<TransportResource>()
+            .init_resource::<Seed>()
+            .init_resource::<PlayerNetworkId>()
+            .init_resource::<ServerTickRate>()
+            .init_resource::<ClientSession>()
+            .init_resource::<ServerCodec>()
+            .insert_resource(Credentials {
+                server_password: Some("sesame".to_string()),
+                player_secret: Some("poncho".to_string()),
+            })
+            .insert_resource(secure_channels())
+            .insert_resource(ServerAddress {
+                address: SocketAddr::from(SOCKET_ADDRESS),
+            })
+            .send_event(auth_challenge())
+            .add_assert_system(assertion::assert_current_state(AppState::Connect))
+            .add_assert_system(|net: Res<TransportResource>| {
+                let messages = net.get_messages();
+                assert_eq!(messages.len(), 1);
+                match deserialize(&messages[0].payload).unwrap() {
+                    PacketType::AuthResponse {
+                        password_proof,
+                        secret_proof,
+                        session_proof,
+                    } => {
+                        assert!(password_proof.is_some());
+                        assert_eq!(secret_proof, None);
+                        assert_eq!(session_proof, None);
+                    }
+                    other => panic!("Unexpected message: {:?}", other),
+                }
+            })
+            .add_system_set(
+                SystemSet::on_update(AppState::Connect).with_system(receive_connection_response))
+            .run();
+    }
+
+    #[test]
+    fn unsealed_challenge_is_not_answered() {
+        // The offer of the server has not arrived, the challenge may come from anyone
+        let mut secure_channels = SecureChannels::client(ServerTrust::Any);
+        secure_channels.key_share(SocketAddr::from(SOCKET_ADDRESS));
+
+        App::new()
+            .add_state(AppState::Connect)
+            .init_resource::<TransportResource>()
+            .init_resource::<Seed>()
+            .init_resource::<PlayerNetworkId>()
+            .init_resource::<ServerTickRate>()
+            .init_resource::<ClientSession>()
+            .init_resource::<ServerCodec>()
+            .insert_resource(Credentials {
+                server_password: Some("sesame".to_string()),
+                player_secret: Some("poncho".to_string()),
+            })
+            .insert_resource(secure_channels)
+            .insert_resource(ServerAddress {
+                address: SocketAddr::from(SOCKET_ADDRESS),
+            })
+            .send_event(auth_challenge())
+            .add_assert_system(assertion::assert_current_state(AppState::Connect))
+            .add_assert_system(|net: Res<TransportResource>| {
+                assert!(net.get_messages().is_empty())
+            })
             .add_system_set(
                 SystemSet::on_update(AppState::Connect).with_system(receive_connection_response))
             .run();
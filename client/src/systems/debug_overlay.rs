@@ -0,0 +1,70 @@
+use crate::resources::{ServerLink, ServerTimeEstimate};
+use bevy::prelude::*;
+
+/// Shows the quality of the connection to the server, toggled with F3
+#[derive(Component)]
+pub struct DebugOverlay;
+
+pub fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    bottom: Val::Px(45.0),
+                    left: Val::Px(5.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("fonts/carnevalee_freakshow.ttf"),
+                    font_size: 15.0,
+                    color: Color::WHITE,
+                },
+            ),
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(DebugOverlay);
+}
+
+pub fn update_debug_overlay(
+    keyboard_input: Res<Input<KeyCode>>,
+    link: Res<ServerLink>,
+    server_time: Res<ServerTimeEstimate>,
+    time: Res<Time>,
+    mut overlay: Query<(&mut Text, &mut Visibility), With<DebugOverlay>>,
+) {
+    let (mut text, mut visibility) = overlay.single_mut();
+    if keyboard_input.just_pressed(KeyCode::F3) {
+        visibility.is_visible = !visibility.is_visible;
+    }
+    if visibility.is_visible {
+        text.sections[0].value = format_link(&link, &server_time, time.elapsed());
+    }
+}
+
+fn format_link(
+    link: &ServerLink,
+    server_time: &ServerTimeEstimate,
+    local_time: std::time::Duration,
+) -> String {
+    let rtt = match link.0.rtt() {
+        Some(rtt) => format!("{} ms", rtt.as_millis()),
+        None => "-".to_string(),
+    };
+    let server_clock = match server_time.server_clock(local_time) {
+        Some(clock) => format!("{:.1} s", clock.as_secs_f32()),
+        None => "-".to_string(),
+    };
+    format!(
+        "RTT {} (jitter {} ms), loss {:.0}%\nServer time {}",
+        rtt,
+        link.0.jitter().as_millis(),
+        link.0.loss() * 100.0,
+        server_clock
+    )
+}
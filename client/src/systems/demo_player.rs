@@ -0,0 +1,119 @@
+use crate::components::{Lifespan, NetworkId};
+use crate::resources::{
+    DemoPlayback, PlayerNetworkId, ReceivedSnapshots, Seek, ServerAddress, ServerTimeEstimate,
+};
+use crate::states::AppState;
+use bevy::prelude::*;
+use blaminar::prelude::{NetworkSimulationEvent, TransportResource};
+use blaminar::Bytes;
+use std::time::Duration;
+use westiny_common::network::{EncodedPacket, PacketType};
+use westiny_common::serialization::deserialize;
+
+/// A seek jumps this much
+const SEEK_STEP: Duration = Duration::from_secs(5);
+
+/// Controls of the playback:
+/// space pauses, left and right arrows seek, up and down arrows change the speed
+pub fn control_demo_playback(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut playback: ResMut<DemoPlayback>,
+) {
+    if keyboard_input.just_pressed(KeyCode::Space) {
+        let paused = !playback.is_paused();
+        playback.set_paused(paused);
+        log::info!("Demo {}", if paused { "paused" } else { "resumed" });
+    }
+    if keyboard_input.just_pressed(KeyCode::Up) {
+        let speed = playback.speed() * 2.0;
+        playback.set_speed(speed);
+        log::info!("Demo speed: {}x", playback.speed());
+    }
+    if keyboard_input.just_pressed(KeyCode::Down) {
+        let speed = playback.speed() / 2.0;
+        playback.set_speed(speed);
+        log::info!("Demo speed: {}x", playback.speed());
+    }
+    if keyboard_input.just_pressed(KeyCode::Right) {
+        let position = playback.position() + SEEK_STEP;
+        playback.seek(position);
+        log::info!("Demo position: {:?}", playback.position());
+    }
+    if keyboard_input.just_pressed(KeyCode::Left) {
+        let position = playback.position().saturating_sub(SEEK_STEP);
+        playback.seek(position);
+        log::info!("Demo position: {:?}", playback.position());
+    }
+}
+
+/// Feeds the recorded packets to the network reception as if they had been sent by the server.
+/// The packets sent by the client are dropped, there is nobody to receive them.
+#[allow(clippy::too_many_arguments)]
+pub fn play_demo(
+    mut commands: Commands,
+    mut playback: ResMut<DemoPlayback>,
+    mut network_events: EventWriter<NetworkSimulationEvent>,
+    mut net: ResMut<TransportResource>,
+    server_address: Res<ServerAddress>,
+    app_state: Res<State<AppState>>,
+    player_net_id: Res<PlayerNetworkId>,
+    mut snapshots: ResMut<ReceivedSnapshots>,
+    mut server_time: ResMut<ServerTimeEstimate>,
+    network_entities: Query<(Entity, &NetworkId)>,
+    lifespan_entities: Query<Entity, With<Lifespan>>,
+    time: Res<Time>,
+) {
+    net.drain_messages(|_| true);
+
+    match playback.take_seek() {
+        Some(Seek::Backward) => {
+            // The world is rebuilt from the first snapshot, this player is kept so it is not
+            // spawned again
+            network_entities
+                .iter()
+                .filter(|(_, network_id)| **network_id != player_net_id.0)
+                .map(|(entity, _)| entity)
+                .chain(lifespan_entities.iter())
+                .for_each(|entity| commands.entity(entity).despawn());
+            *snapshots = ReceivedSnapshots::default();
+            *server_time = ServerTimeEstimate::default();
+        }
+        // The clock estimate would catch up with the jump only slowly
+        Some(Seek::Forward) => *server_time = ServerTimeEstimate::default(),
+        None => {}
+    }
+
+    let connecting = app_state.current() == &AppState::Connect;
+    for packet in playback.play(time.delta()) {
+        for payload in unbundle(packet.payload) {
+            let deliver = match deserialize(&payload) {
+                Ok(PacketType::ConnectionResponse(_)) => connecting,
+                // Only the current events are worth showing
+                Ok(PacketType::ShotEvent(_)) | Ok(PacketType::Notification(_)) => !packet.stale,
+                _ => true,
+            };
+            if deliver {
+                network_events.send(NetworkSimulationEvent::Message(
+                    server_address.address,
+                    payload,
+                ));
+            }
+        }
+    }
+
+    if playback.is_finished() && !playback.is_paused() {
+        log::info!("End of demo of {}", playback.player_name());
+        playback.set_paused(true);
+    }
+}
+
+/// The packets of a bundle are filtered one by one
+fn unbundle(payload: Bytes) -> Vec<Bytes> {
+    match deserialize(&payload) {
+        Ok(PacketType::Bundle(packets)) => packets
+            .into_iter()
+            .map(|EncodedPacket(payload)| Bytes::from(payload))
+            .collect(),
+        _ => vec![payload],
+    }
+}
@@ -0,0 +1,51 @@
+use crate::resources::{ServerAddress, ServerCodec};
+use crate::states::AppState;
+use bevy::app::AppExit;
+use bevy::prelude::{EventReader, EventWriter, Local, Res, ResMut, State};
+use bevy::window::WindowCloseRequested;
+use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
+use westiny_common::network::{DisconnectReason, PacketType};
+use westiny_common::serialization::serialize_with;
+
+/// The transport sends the queued packets in the next frame, the client exits only after that
+const FRAMES_BEFORE_EXIT: u32 = 2;
+
+/// Tells the server that the player has quit when the window is closed, then exits.
+/// Bevy must not close the window by itself, see `WindowPlugin::close_when_requested`.
+pub fn disconnect_on_window_close(
+    mut close_requests: EventReader<WindowCloseRequested>,
+    server_addr: Res<ServerAddress>,
+    codec: Res<ServerCodec>,
+    app_state: Res<State<AppState>>,
+    mut net: ResMut<TransportResource>,
+    mut app_exit: EventWriter<AppExit>,
+    mut frames_since_disconnect: Local<Option<u32>>,
+) {
+    match *frames_since_disconnect {
+        None if close_requests.iter().next().is_some() => {
+            if app_state.current() == &AppState::Connect {
+                app_exit.send(AppExit);
+                return;
+            }
+
+            log::info!("Disconnecting from server: {:?}", server_addr.address);
+            let message = serialize_with(
+                codec.current,
+                &PacketType::Disconnect {
+                    reason: DisconnectReason::ClientQuit,
+                },
+            )
+            .expect("Disconnect could not be serialized");
+            net.send_with_requirements(
+                server_addr.address,
+                &message,
+                DeliveryRequirement::Reliable,
+                UrgencyRequirement::OnTick,
+            );
+            *frames_since_disconnect = Some(0);
+        }
+        None => {}
+        Some(frames) if frames + 1 >= FRAMES_BEFORE_EXIT => app_exit.send(AppExit),
+        Some(frames) => *frames_since_disconnect = Some(frames + 1),
+    }
+}
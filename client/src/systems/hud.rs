@@ -6,7 +6,10 @@ pub fn update_hud(
     query: Query<&Health, With<Player>>,
     mut hud_health: Query<&mut Text, With<HudHealth>>
 ) {
-    hud_health.single_mut().sections[0].value = format_health(query.single().0);
+    // Until the replicated health of the player arrives
+    if let Ok(health) = query.get_single() {
+        hud_health.single_mut().sections[0].value = format_health(health.0);
+    }
 }
 
 pub fn update_hud_w(
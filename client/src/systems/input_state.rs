@@ -1,11 +1,18 @@
-use crate::resources::StreamId;
+use crate::resources::{
+    DemoPlayback, InterpolationConfig, PendingInputs, ServerCodec, ServerTickRate,
+    ServerTimeEstimate, StreamId,
+};
 
 use westiny_common::components::{self, InputFlags};
 use westiny_common::resources::ServerAddress;
-use westiny_common::{metric_dimension::length::MeterVec2, network, serialization::serialize};
+use westiny_common::metric_dimension::{length::MeterVec2, Second};
+use westiny_common::network::{self, SequencedInput};
+use westiny_common::serialization::{serialize_with, PacketCodec};
+use westiny_common::systems::simulate_input;
 use crate::systems::camera::PlayCamera;
 
 use bevy::input::{keyboard::KeyCode, mouse::MouseButton};
+use std::time::Duration;
 use bevy::prelude::*;
 use bevy::render::camera::RenderTarget;
 use blaminar::prelude::*;
@@ -18,6 +25,11 @@ enum Control {
 
 use Control::*;
 
+/// Number of inputs sent in each packet: the latest one and the previous not yet acknowledged ones,
+/// so a lost packet does not lose the input.
+/// This is also the most inputs made in one frame, the rest of a long frame is not sent.
+const SENT_INPUT_COUNT: usize = 3;
+
 const INPUT_FLAG_MAPPING : [(InputFlags, Control); 13] = [
     (InputFlags::FORWARD,  Keyboard(KeyCode::W)),
     (InputFlags::BACKWARD, Keyboard(KeyCode::S)),
@@ -79,28 +91,73 @@ fn update_cursor_position(
     }
 }
 
+/// Sends the input of this player to the server and predicts its movement.
+/// The server applies one input per tick, so an input is made for every server tick passed.
+/// A demo being played moves this player as recorded, the user has no control over it.
+#[allow(clippy::too_many_arguments)]
 pub fn handle_user_inputs(
-    mut input_qry: Query<&mut components::Input>,
+    mut input_qry: Query<(&mut components::Input, &mut Transform)>,
     camera_query: Query<(&Camera, &GlobalTransform), With<PlayCamera>>,
     keyboard_input: Res<Input<KeyCode>>,
     mouse_button_input: Res<Input<MouseButton>>,
     windows: Res<Windows>,
     server_address: Res<ServerAddress>,
+    codec: Res<ServerCodec>,
     mut net: ResMut<TransportResource>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    server_clock: Res<ServerTimeEstimate>,
+    interpolation_config: Res<InterpolationConfig>,
+    server_tick_rate: Res<ServerTickRate>,
+    time: Res<Time>,
+    mut unsent_time: Local<Duration>,
+    demo: Option<Res<DemoPlayback>>,
 ) {
+    if demo.is_some() {
+        return;
+    }
+
     // NOTE: Only one Input component exists on the client
-    if let Some(mut input) = input_qry.iter_mut().next() {
+    if let Some((mut input, mut transform)) = input_qry.iter_mut().next() {
         update_input_keys(&mut input, &keyboard_input, &mouse_button_input);
 
         let (camera, camera_transform) = camera_query.single();
         update_cursor_position(&mut input, &windows, camera, camera_transform);
 
-        send_to_server(&mut net, &server_address, &input);
+        let step = server_tick_rate.step();
+        *unsent_time = (*unsent_time + time.delta()).min(step * SENT_INPUT_COUNT as u32);
+        let mut input_made = false;
+        while *unsent_time >= step {
+            *unsent_time -= step;
+            pending_inputs.push(*input, Second(step.as_secs_f32()));
+            input_made = true;
+        }
+
+        if input_made {
+            // Remote entities are displayed in the past, this is sent for lag compensation
+            let view_time = server_clock.server_time(time.elapsed()).map(|server_time| {
+                server_time.saturating_sub(interpolation_config.delay.into_duration())
+            });
+            send_to_server(
+                &mut net,
+                &server_address,
+                codec.current,
+                pending_inputs.latest(SENT_INPUT_COUNT),
+                view_time,
+            );
+        }
+
+        simulate_input(&mut transform, &input, Second(time.delta_seconds()));
     }
 }
 
-fn send_to_server(net: &mut TransportResource, server: &ServerAddress, input: &components::Input) {
-    let message = serialize(&network::PacketType::InputState { input: *input })
+fn send_to_server(
+    net: &mut TransportResource,
+    server: &ServerAddress,
+    codec: PacketCodec,
+    inputs: Vec<SequencedInput>,
+    view_time: Option<Duration>,
+) {
+    let message = serialize_with(codec, &network::PacketType::InputState { inputs, view_time })
         .expect("InputState could not be serialized");
 
     net.send_with_requirements(
@@ -1,25 +1,42 @@
 pub use audio_player::play_audio;
+pub use demo_player::{control_demo_playback, play_demo};
+pub use disconnect::disconnect_on_window_close;
 pub use input_state::handle_user_inputs;
 //pub use notification_bar::NotificationBarSystemDesc;
 pub use network_entity_delete::delete_entities;
-pub use network_entity_update::{update_network_entities, spawn_this_player};
+pub use network_entity_update::{
+    interpolate_network_entities, spawn_map_entities, spawn_this_player, update_network_entities,
+};
 pub use network_messenger::receive_network_messages;
+pub use ping::exchange_pings;
+pub use reconciliation::reconcile_player;
+pub use snapshot_receiver::{receive_snapshots, EntityStates};
 pub use shooter::spawn_bullets;
+pub use simulation_time::advance_simulation_time;
 pub use westiny_common::systems::*;
-pub use player_update::update_player;
-pub use client_connect::{receive_connection_response, send_connection_request};
+pub use player_update::{notice_health_change, update_player};
+pub use client_connect::{
+    receive_connection_response, reconnect_to_server, send_connection_request,
+};
 pub use sprite::add_sprite_to_new_sprite_id;
 
 mod audio_player;
+mod demo_player;
+mod disconnect;
+pub mod debug_overlay;
 pub mod hud;
 pub mod notification_bar;
 mod network_entity_update;
 mod network_messenger;
+mod ping;
+mod reconciliation;
+mod snapshot_receiver;
 pub mod network_entity_delete;
 //mod notification_bar;
 pub mod camera;
 mod input_state;
 mod client_connect;
 mod shooter;
+mod simulation_time;
 mod player_update;
 mod sprite;
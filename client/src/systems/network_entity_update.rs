@@ -1,8 +1,13 @@
-use crate::components::{EntityType, NetworkId};
+use crate::components::{
+    EntityType, EntitySample, MapEntityType, MapPosition, NetworkId, SnapshotBuffer,
+};
 use crate::entities::{create_player_character, create_this_player, CorpseBundle};
-use crate::resources::PlayerNetworkId;
+use crate::resources::{InterpolationConfig, PlayerNetworkId, ServerTimeEstimate};
 use crate::states::AppState;
+use crate::systems::EntityStates;
 use std::collections::HashMap;
+use std::time::Duration;
+use westiny_common::entities::insert_map_entity;
 use westiny_common::metric_dimension::length::Meter;
 use westiny_common::network::{EntityState, PlayerDeath};
 
@@ -10,19 +15,21 @@ use bevy::prelude::*;
 
 pub fn spawn_this_player(
     mut commands: Commands,
-    mut entity_states_events: EventReader<Vec<EntityState>>,
+    mut entity_states_events: EventReader<EntityStates>,
+    replicated: Query<(Entity, &NetworkId), Without<Transform>>,
     player_net_id: Res<PlayerNetworkId>,
     mut app_state: ResMut<State<AppState>>
 ) {
     let maybe_this_player = entity_states_events
         .iter()
-        .flat_map(|vec| vec.iter())
+        .flat_map(|entity_states| entity_states.states.iter())
         .filter(|state| state.network_id == player_net_id.0)
         .last();
 
     if let Some(entity_state) = maybe_this_player {
         create_this_player(
             &mut commands,
+            replicated_entity(&replicated, &player_net_id.0),
             player_net_id.0,
             entity_state.position.into_transform(Meter(0.0)),
         );
@@ -31,34 +38,88 @@ pub fn spawn_this_player(
             .expect("Unable to set app state to Play");
     }
 }
+
+/// The map entities are placed by the server, they are completed once their position is replicated.
+/// Only the entities which have just got their position are looked at.
+pub fn spawn_map_entities(
+    mut commands: Commands,
+    new_map_entities: Query<
+        (Entity, &NetworkId, &MapPosition),
+        (Added<MapPosition>, Without<Transform>),
+    >,
+) {
+    for (entity, net_id, position) in new_map_entities.iter() {
+        match MapEntityType::from_entity_type(net_id.entity_type) {
+            Some(map_entity_type) => {
+                insert_map_entity(&mut commands.entity(entity), map_entity_type, position.0)
+            }
+            None => log::error!("{:?} has a map position, but it is not a map entity", net_id),
+        }
+    }
+}
+
+/// The states of remote entities are stored in their snapshot buffer to be interpolated.
+/// This player is only spawned here when respawned, its movement is reconciled separately.
 pub fn update_network_entities(
     mut commands: Commands,
-    mut entity_states_events: EventReader<Vec<EntityState>>,
+    mut entity_states_events: EventReader<EntityStates>,
     mut player_death: EventReader<PlayerDeath>,
-    mut network_transforms: Query<(&NetworkId, &mut Transform)>,
+    mut network_transforms: Query<(&NetworkId, &mut Transform, Option<&mut SnapshotBuffer>)>,
+    replicated: Query<(Entity, &NetworkId), Without<Transform>>,
+    player_net_id: Res<PlayerNetworkId>,
     time: Res<Time>,
 ) {
-    let mut entity_states: HashMap<_, _> = entity_states_events
-        .iter()
-        .flat_map(|vec| vec.iter())
-        .map(|entity_state| (entity_state.network_id, entity_state))
-        .collect();
-
-    for (net_id, mut transform) in network_transforms.iter_mut() {
-        if let Some(state) = entity_states.get(net_id) {
-            update_transform(&mut transform, state);
-            entity_states.remove(net_id);
+    let mut entity_states: HashMap<_, Vec<_>> = HashMap::new();
+    for entity_states_event in entity_states_events.iter() {
+        for entity_state in entity_states_event.states.iter() {
+            entity_states
+                .entry(entity_state.network_id)
+                .or_default()
+                .push(to_sample(entity_states_event.server_time, entity_state));
+        }
+    }
+
+    // This player is moved by the reconciliation
+    let this_player_states = entity_states.remove(&player_net_id.0);
+
+    for (net_id, mut transform, maybe_buffer) in network_transforms.iter_mut() {
+        if net_id == &player_net_id.0 {
+            continue;
+        }
+        if let Some(samples) = entity_states.remove(net_id) {
+            match maybe_buffer {
+                Some(mut buffer) => samples.into_iter().for_each(|sample| buffer.push(sample)),
+                None => update_transform(&mut transform, samples.last().unwrap()),
+            }
         }
     }
 
-    for (net_id, entity_state) in entity_states {
+    // This player has been respawned
+    if let Some(samples) = this_player_states {
+        if !network_transforms.iter().any(|(net_id, _, _)| net_id == &player_net_id.0) {
+            let mut transform = Transform::default();
+            update_transform(&mut transform, samples.last().unwrap());
+            let entity = replicated_entity(&replicated, &player_net_id.0);
+            create_this_player(&mut commands, entity, player_net_id.0, transform);
+        }
+    }
+
+    for (net_id, samples) in entity_states {
         let mut transform = Transform::default();
-        update_transform(&mut transform, entity_state);
+        update_transform(&mut transform, samples.last().unwrap());
 
-        // Yeah it looks silly but there will be more network entities
-        match net_id.entity_type {
-            EntityType::Player => create_player_character(&mut commands, net_id, transform),
+        let existing = replicated_entity(&replicated, &net_id);
+        let entity = match net_id.entity_type {
+            EntityType::Player => {
+                create_player_character(&mut commands, existing, net_id, transform)
+            }
+            // The map entities are not in the snapshots, they are spawned when replicated
+            EntityType::Barrel => continue,
         };
+
+        let mut buffer = SnapshotBuffer::default();
+        samples.into_iter().for_each(|sample| buffer.push(sample));
+        commands.entity(entity).insert(buffer);
     }
 
     player_death
@@ -69,8 +130,55 @@ pub fn update_network_entities(
         });
 }
 
-fn update_transform(transform: &mut Transform, entity_state: &EntityState) {
-    transform.translation.x = entity_state.position.x.into_pixel();
-    transform.translation.y = entity_state.position.y.into_pixel();
-    transform.rotation = Quat::from_rotation_z(entity_state.angle);
+/// Moves the remote entities to their interpolated state at the current render time,
+/// which is the estimated server time minus the configured interpolation delay
+pub fn interpolate_network_entities(
+    mut buffered_transforms: Query<(&SnapshotBuffer, &mut Transform)>,
+    server_clock: Res<ServerTimeEstimate>,
+    config: Res<InterpolationConfig>,
+    time: Res<Time>,
+) {
+    let render_time = match server_clock.server_time(time.elapsed()) {
+        Some(server_time) => server_time.saturating_sub(config.delay.into_duration()),
+        None => return,
+    };
+    let max_extrapolation = config.max_extrapolation.into_duration();
+
+    for (buffer, mut transform) in buffered_transforms.iter_mut() {
+        if let Some((position, angle)) = buffer.sample_at(render_time, max_extrapolation) {
+            update_transform(
+                &mut transform,
+                &EntitySample {
+                    server_time: render_time,
+                    position,
+                    angle,
+                },
+            );
+        }
+    }
+}
+
+/// The entity spawned for the network id by the replication, before its state has arrived
+fn replicated_entity(
+    replicated: &Query<(Entity, &NetworkId), Without<Transform>>,
+    net_id: &NetworkId,
+) -> Option<Entity> {
+    replicated
+        .iter()
+        .find(|(_, id)| *id == net_id)
+        .map(|(entity, _)| entity)
+}
+
+fn to_sample(server_time: Duration, entity_state: &EntityState) -> EntitySample {
+    EntitySample {
+        server_time,
+        position: entity_state.position,
+        angle: entity_state.angle,
+    }
+}
+
+fn update_transform(transform: &mut Transform, sample: &EntitySample) {
+    transform.translation.x = sample.position.x.into_pixel();
+    transform.translation.y = sample.position.y.into_pixel();
+    transform.rotation = Quat::from_rotation_z(sample.angle);
 }
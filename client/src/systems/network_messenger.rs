@@ -1,18 +1,29 @@
+use super::client_connect::is_trusted;
+use crate::resources::{DemoPlayback, ServerAddress};
 use crate::NetworkSimulationEvent;
 use anyhow::Result;
 use bevy::prelude::*;
 use std::net::SocketAddr;
+use westiny_common::secure_channel::SecureChannels;
 use westiny_common::network::{
-    EntityState, NetworkEntityDelete, PacketType, PlayerNotification, PlayerUpdate, ShotEvent,
+    ComponentUpdate, EntityStateUpdate, NetworkEntityDelete, PacketType, PlayerNotification,
+    PlayerUpdate, ShotEvent,
 };
-use westiny_common::{network::PlayerDeath, serialization::deserialize};
+use westiny_common::{network::PlayerDeath, serialization::deserialize_all};
 
+/// Only the packets of the server within the secure session are processed, anyone else could
+/// send anything to the client
+#[allow(clippy::too_many_arguments)]
 pub fn receive_network_messages(
+    server_addr: Res<ServerAddress>,
+    secure_channels: Res<SecureChannels>,
+    demo: Option<Res<DemoPlayback>>,
     mut network_event: EventReader<NetworkSimulationEvent>,
     // mut app_event: EventWriter<AppEvent>,
-    mut entity_states: EventWriter<Vec<EntityState>>,
+    mut entity_states: EventWriter<EntityStateUpdate>,
     mut player_update: EventWriter<PlayerUpdate>,
     mut entity_delete: EventWriter<NetworkEntityDelete>,
+    mut component_update: EventWriter<ComponentUpdate>,
     mut notification: EventWriter<PlayerNotification>,
     mut shot: EventWriter<ShotEvent>,
     mut player_death: EventWriter<PlayerDeath>,
@@ -28,6 +39,15 @@ pub fn receive_network_messages(
                 });
                 //app_event.send(AppEvent::Disconnect);
             }
+            NetworkSimulationEvent::Message(addr, _) if *addr != server_addr.address => {
+                log::debug!("Message from {}, which is not the server, dropped", addr)
+            }
+            // E.g. a datagram sent unsealed after the session has been forgotten on reconnection
+            NetworkSimulationEvent::Message(addr, _)
+                if !is_trusted(&secure_channels, &server_addr, demo.as_deref()) =>
+            {
+                log::debug!("Message from {} outside of the secure session dropped", addr)
+            }
             NetworkSimulationEvent::Message(addr, payload) => {
                 match process_payload(
                     addr,
@@ -35,6 +55,7 @@ pub fn receive_network_messages(
                     &mut entity_states,
                     &mut player_update,
                     &mut entity_delete,
+                    &mut component_update,
                     &mut notification,
                     &mut shot,
                     &mut player_death,
@@ -58,18 +79,47 @@ pub fn receive_network_messages(
 fn process_payload(
     addr: &SocketAddr,
     payload: &[u8],
-    entity_update_channel: &mut EventWriter<Vec<EntityState>>,
+    entity_update_channel: &mut EventWriter<EntityStateUpdate>,
     player_update_channel: &mut EventWriter<PlayerUpdate>,
     entity_delete_channel: &mut EventWriter<NetworkEntityDelete>,
+    component_update_channel: &mut EventWriter<ComponentUpdate>,
     message_channel: &mut EventWriter<PlayerNotification>,
     shot_event_channel: &mut EventWriter<ShotEvent>,
     death_event_channel: &mut EventWriter<PlayerDeath>,
 ) -> Result<()> {
     log::debug!("Message: {:02x?}", payload);
-    match deserialize(payload)? {
-        PacketType::EntityStateUpdate(state) => {
-            log::debug!("Entity State update, state={:?}", state);
-            entity_update_channel.send(state);
+    for packet in deserialize_all(payload)? {
+        process_packet(
+            addr,
+            packet,
+            entity_update_channel,
+            player_update_channel,
+            entity_delete_channel,
+            component_update_channel,
+            message_channel,
+            shot_event_channel,
+            death_event_channel,
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn process_packet(
+    addr: &SocketAddr,
+    packet: PacketType,
+    entity_update_channel: &mut EventWriter<EntityStateUpdate>,
+    player_update_channel: &mut EventWriter<PlayerUpdate>,
+    entity_delete_channel: &mut EventWriter<NetworkEntityDelete>,
+    component_update_channel: &mut EventWriter<ComponentUpdate>,
+    message_channel: &mut EventWriter<PlayerNotification>,
+    shot_event_channel: &mut EventWriter<ShotEvent>,
+    death_event_channel: &mut EventWriter<PlayerDeath>,
+) -> Result<()> {
+    match packet {
+        PacketType::EntityStateUpdate(update) => {
+            log::debug!("Entity State update, update={:?}", update);
+            entity_update_channel.send(update);
             Ok(())
         }
         PacketType::EntityDelete(delete) => {
@@ -77,6 +127,11 @@ fn process_payload(
             entity_delete_channel.send(delete);
             Ok(())
         }
+        PacketType::ComponentUpdate(update) => {
+            log::debug!("Component update, {:?}", update);
+            component_update_channel.send(update);
+            Ok(())
+        }
         PacketType::PlayerUpdate(player_update) => {
             log::debug!("Player update, {:?}", player_update);
             player_update_channel.send(player_update);
@@ -100,10 +155,101 @@ fn process_payload(
             death_event_channel.send(death);
             Ok(())
         }
-        _ => Err(anyhow::anyhow!(
-            "Unexpected message from {}, payload={:02x?}",
+        // Handled by the reconnection
+        PacketType::ConnectionResponse(_) | PacketType::AuthChallenge(_) => Ok(()),
+        // Handled by the ping exchange
+        PacketType::Ping { .. } | PacketType::Pong { .. } => Ok(()),
+        PacketType::Disconnect { reason } => {
+            log::info!("Disconnected by the server: {}", reason);
+            message_channel.send(PlayerNotification {
+                message: format!("Disconnected: {}", reason),
+            });
+            Ok(())
+        }
+        other => Err(anyhow::anyhow!(
+            "Unexpected message from {}, packet={:?}",
             addr,
-            payload
+            other
         )),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blaminar::simulation::DeliveryRequirement;
+    use std::time::Duration;
+    use w_bevy_test::{assertion, TestApp};
+    use westiny_common::secure_channel::{ServerIdentity, ServerTrust};
+    use westiny_common::serialization::serialize;
+
+    const CLIENT: ([u8; 4], u16) = ([127, 0, 0, 1], 4557);
+
+    /// Channels of the client after the offer of the server has been received
+    fn secure_channels(server: SocketAddr) -> SecureChannels {
+        let mut channels = SecureChannels::client(ServerTrust::Any);
+        let mut server_channels = SecureChannels::server(ServerIdentity::generate());
+        let share = channels.key_share(server);
+        server_channels
+            .accept(CLIENT.into(), share, Duration::ZERO)
+            .unwrap();
+        let offered = server_channels
+            .seal(&CLIENT.into(), &[], DeliveryRequirement::Reliable)
+            .unwrap();
+        channels.open(&server, &offered).unwrap();
+        channels
+    }
+
+    fn notification_from(addr: SocketAddr) -> NetworkSimulationEvent {
+        let payload = serialize(&PacketType::Notification(PlayerNotification {
+            message: "Westwood was shot".to_string(),
+        }))
+        .unwrap();
+        NetworkSimulationEvent::Message(addr, payload.into())
+    }
+
+    fn make_testapp(secure_channels: SecureChannels, event: NetworkSimulationEvent) -> App {
+        let mut app = App::new();
+        app.insert_resource(ServerAddress::default())
+            .insert_resource(secure_channels)
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<EntityStateUpdate>()
+            .add_event::<PlayerUpdate>()
+            .add_event::<NetworkEntityDelete>()
+            .add_event::<ComponentUpdate>()
+            .add_event::<PlayerNotification>()
+            .add_event::<ShotEvent>()
+            .add_event::<PlayerDeath>()
+            .send_events(vec![Some(event)])
+            .add_system(receive_network_messages);
+        app
+    }
+
+    #[test]
+    fn message_of_server_within_session_is_processed() {
+        let server = ServerAddress::default().address;
+        make_testapp(secure_channels(server), notification_from(server))
+            .add_assert_system(assertion::assert_event_count::<PlayerNotification>(1))
+            .run();
+    }
+
+    #[test]
+    fn message_from_another_address_is_dropped() {
+        let server = ServerAddress::default().address;
+        let stranger = SocketAddr::from(([10, 0, 0, 1], 4242));
+        make_testapp(secure_channels(server), notification_from(stranger))
+            .add_assert_system(assertion::assert_event_count::<PlayerNotification>(0))
+            .run();
+    }
+
+    #[test]
+    fn message_of_server_outside_of_session_is_dropped() {
+        let server = ServerAddress::default().address;
+        make_testapp(
+            SecureChannels::client(ServerTrust::Any),
+            notification_from(server),
+        )
+        .add_assert_system(assertion::assert_event_count::<PlayerNotification>(0))
+        .run();
+    }
+}
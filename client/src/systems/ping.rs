@@ -0,0 +1,124 @@
+use crate::resources::{DemoPlayback, ServerAddress, ServerCodec, ServerLink, ServerTimeEstimate};
+use bevy::prelude::{EventReader, Res, ResMut, Time};
+use blaminar::simulation::{
+    DeliveryRequirement, NetworkSimulationEvent, TransportResource, UrgencyRequirement,
+};
+use westiny_common::network::PacketType;
+use westiny_common::serialization::{deserialize_all, serialize_with, PacketCodec};
+
+/// Pings the server, answers the pings of the server and synchronizes the server clock estimate
+/// with the answers. The recorded pings of a demo are not answered, there is nobody to measure.
+pub fn exchange_pings(
+    server_addr: Res<ServerAddress>,
+    codec: Res<ServerCodec>,
+    mut link: ResMut<ServerLink>,
+    mut server_time: ResMut<ServerTimeEstimate>,
+    mut net_event: EventReader<NetworkSimulationEvent>,
+    mut net: ResMut<TransportResource>,
+    time: Res<Time>,
+    demo: Option<Res<DemoPlayback>>,
+) {
+    if demo.is_some() {
+        return;
+    }
+
+    let now = time.elapsed();
+    for event in net_event.iter() {
+        let payload = match event {
+            NetworkSimulationEvent::Message(addr, payload) if *addr == server_addr.address => {
+                payload
+            }
+            _ => continue,
+        };
+        // The server may bundle a ping with the answer to ours
+        for packet in deserialize_all(payload).unwrap_or_default() {
+            match packet {
+                PacketType::Ping { sequence } => send(
+                    &mut net,
+                    &server_addr,
+                    codec.current,
+                    &PacketType::Pong {
+                        sequence,
+                        time: now,
+                    },
+                ),
+                PacketType::Pong {
+                    sequence,
+                    time: server_clock,
+                } => {
+                    if let Some(rtt) = link.0.pong(sequence, now) {
+                        server_time.synchronize(server_clock, rtt, now);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(sequence) = link.0.next_ping(now) {
+        send(
+            &mut net,
+            &server_addr,
+            codec.current,
+            &PacketType::Ping { sequence },
+        );
+    }
+}
+
+/// Unreliable, so the lost pings show the loss of the connection
+fn send(
+    net: &mut TransportResource,
+    server_addr: &ServerAddress,
+    codec: PacketCodec,
+    packet: &PacketType,
+) {
+    let message = serialize_with(codec, packet).expect("Ping could not be serialized");
+    net.send_with_requirements(
+        server_addr.address,
+        &message,
+        DeliveryRequirement::Unreliable,
+        UrgencyRequirement::OnTick,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::prelude::App;
+    use std::net::SocketAddr;
+    use w_bevy_test::TestApp;
+    use westiny_common::serialization::{deserialize, serialize};
+
+    const SERVER_ADDRESS: ([u8; 4], u16) = ([127, 0, 0, 1], 9999);
+
+    #[test]
+    fn pings_of_server_are_answered() {
+        App::new()
+            .init_resource::<TransportResource>()
+            .init_resource::<ServerCodec>()
+            .init_resource::<ServerLink>()
+            .init_resource::<ServerTimeEstimate>()
+            .init_resource::<Time>()
+            .insert_resource(ServerAddress {
+                address: SocketAddr::from(SERVER_ADDRESS),
+            })
+            .send_event(NetworkSimulationEvent::Message(
+                SocketAddr::from(SERVER_ADDRESS),
+                serialize(&PacketType::Ping { sequence: 42 })
+                    .unwrap()
+                    .into(),
+            ))
+            .add_system(exchange_pings)
+            .add_assert_system(|net: Res<TransportResource>| {
+                let packets: Vec<_> = net
+                    .get_messages()
+                    .iter()
+                    .map(|message| deserialize(&message.payload).unwrap())
+                    .collect();
+                assert_eq!(packets.len(), 2);
+                assert!(matches!(packets[0], PacketType::Pong { sequence: 42, .. }));
+                assert!(matches!(packets[1], PacketType::Ping { sequence: 0 }));
+            })
+            .run();
+    }
+}
@@ -6,21 +6,14 @@ use westiny_common::resources::{AudioQueue, SoundId};
 
 pub fn update_player(
     mut update_events: EventReader<PlayerUpdate>,
-    mut player_state: Query<(&mut Health, &mut WeaponInfo), With<Player>>,
+    mut player_state: Query<&mut WeaponInfo, With<Player>>,
     mut audio: ResMut<AudioQueue>,
     mut notification: EventWriter<PlayerNotification>
 ) {
-    let (mut health, mut weapon_info) = player_state.single_mut();
+    let mut weapon_info = player_state.single_mut();
 
     for player_update in update_events.iter() {
         match player_update {
-            PlayerUpdate::HealthUpdate(new_health) => {
-                if new_health.0 < health.0 {
-                    audio.play(SoundId::Ouch, 1.0);
-                }
-                health.0 = new_health.0;
-                log::debug!("Health updated to {:?}", new_health);
-            }
             PlayerUpdate::AmmoUpdate { ammo_in_magazine } => {
                 if ammo_in_magazine > &weapon_info.bullets_in_magazine {
                     audio.play(SoundId::WeaponReady, 1.0);
@@ -39,3 +32,18 @@ pub fn update_player(
         }
     }
 }
+
+/// The health of the player is replicated, it is hurt when it gets lower
+pub fn notice_health_change(
+    health: Query<&Health, (With<Player>, Changed<Health>)>,
+    mut last_health: Local<Option<u16>>,
+    mut audio: ResMut<AudioQueue>,
+) {
+    if let Ok(health) = health.get_single() {
+        if last_health.is_some_and(|last| health.0 < last) {
+            audio.play(SoundId::Ouch, 1.0);
+        }
+        *last_health = Some(health.0);
+        log::debug!("Health updated to {:?}", health);
+    }
+}
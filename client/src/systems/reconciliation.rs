@@ -0,0 +1,41 @@
+use crate::components::Player;
+use crate::resources::{PendingInputs, PlayerNetworkId};
+use crate::systems::EntityStates;
+use bevy::prelude::*;
+use westiny_common::systems::simulate_input;
+
+/// Resets this player to its latest authoritative state received from the server,
+/// then replays the inputs which had not been processed by the server at that state.
+pub fn reconcile_player(
+    mut entity_states_events: EventReader<EntityStates>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    player_net_id: Res<PlayerNetworkId>,
+    mut player: Query<&mut Transform, With<Player>>,
+) {
+    let authoritative = entity_states_events
+        .iter()
+        .filter_map(|entity_states| {
+            entity_states
+                .states
+                .iter()
+                .find(|state| state.network_id == player_net_id.0)
+                .map(|state| (entity_states.last_processed_input, state.clone()))
+        })
+        .last();
+
+    if let (Some((last_processed_input, state)), Ok(mut transform)) =
+        (authoritative, player.get_single_mut())
+    {
+        if let Some(sequence) = last_processed_input {
+            pending_inputs.acknowledge(sequence);
+        }
+
+        transform.translation.x = state.position.x.into_pixel();
+        transform.translation.y = state.position.y.into_pixel();
+        transform.rotation = Quat::from_rotation_z(state.angle);
+
+        for pending in pending_inputs.iter() {
+            simulate_input(&mut transform, &pending.input, pending.delta_time);
+        }
+    }
+}
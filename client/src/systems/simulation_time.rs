@@ -0,0 +1,7 @@
+use bevy::prelude::{Res, ResMut, Time};
+use westiny_common::resources::SimulationTime;
+
+/// The client simulates the bullets and the movement of this player with the frame time
+pub fn advance_simulation_time(time: Res<Time>, mut simulation_time: ResMut<SimulationTime>) {
+    simulation_time.advance(time.delta());
+}
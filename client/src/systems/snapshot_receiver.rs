@@ -0,0 +1,84 @@
+use crate::resources::{ReceivedSnapshots, ServerCodec, ServerTimeEstimate, StreamId};
+use bevy::prelude::*;
+use blaminar::prelude::*;
+use std::time::Duration;
+use westiny_common::network::{
+    EntityState, EntityStateUpdate, InputSequence, NetworkEntityDelete, PacketType, SnapshotId,
+};
+use westiny_common::resources::ServerAddress;
+use westiny_common::serialization::{serialize_with, PacketCodec};
+
+/// States of entities at the given time of the server. A chunk of a snapshot carries only a part
+/// of the entities.
+pub struct EntityStates {
+    pub server_time: Duration,
+    pub states: Vec<EntityState>,
+    /// Last input of this client processed by the server at `server_time`
+    pub last_processed_input: Option<InputSequence>,
+}
+
+/// Reconstructs the entity states from the delta compressed updates of the server
+/// and acknowledges the completely received snapshots so the server can use them as baseline.
+/// The states of a chunk are passed on as soon as it arrives.
+/// Entities missing from the new snapshot (e.g. out of the interest radius) are deleted.
+pub fn receive_snapshots(
+    mut updates: EventReader<EntityStateUpdate>,
+    mut snapshots: ResMut<ReceivedSnapshots>,
+    mut entity_states: EventWriter<EntityStates>,
+    mut entity_delete: EventWriter<NetworkEntityDelete>,
+    mut server_time: ResMut<ServerTimeEstimate>,
+    time: Res<Time>,
+    server_address: Res<ServerAddress>,
+    codec: Res<ServerCodec>,
+    mut net: ResMut<TransportResource>,
+) {
+    let mut received = None;
+    for update in updates.iter() {
+        match snapshots.receive(update) {
+            Some(chunk) => {
+                server_time.update(update.server_time, time.elapsed());
+                entity_states.send(EntityStates {
+                    server_time: update.server_time,
+                    states: chunk.states,
+                    last_processed_input: update.last_processed_input,
+                });
+                if let Some((_, disappeared)) = chunk.completed {
+                    disappeared.into_iter().for_each(|network_id| {
+                        entity_delete.send(NetworkEntityDelete {
+                            network_id,
+                            tick: update.tick,
+                        })
+                    });
+                    received = Some(update.snapshot);
+                }
+            }
+            None => log::debug!(
+                "Entity state update dropped, snapshot={}, chunk={:?}, baseline={:?}",
+                update.snapshot,
+                update.chunk,
+                update.baseline
+            ),
+        }
+    }
+
+    if let Some(snapshot) = received {
+        send_ack(&mut net, &server_address, codec.current, snapshot);
+    }
+}
+
+fn send_ack(
+    net: &mut TransportResource,
+    server: &ServerAddress,
+    codec: PacketCodec,
+    snapshot: SnapshotId,
+) {
+    let message = serialize_with(codec, &PacketType::SnapshotAck { snapshot })
+        .expect("SnapshotAck could not be serialized");
+
+    net.send_with_requirements(
+        server.address,
+        &message,
+        DeliveryRequirement::UnreliableSequenced(StreamId::SnapshotAck.into()),
+        UrgencyRequirement::OnTick,
+    );
+}
@@ -0,0 +1,84 @@
+//! Challenge-response authentication of the connecting clients.
+//!
+//! The server sends a random nonce, the client answers with HMAC-SHA256 digests of the nonce and
+//! its player name, keyed by the password of the server, the secret of the player and the token
+//! of the session the client resumes. Neither of them is sent by the client.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+pub type Nonce = [u8; 32];
+pub type AuthProof = [u8; 32];
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Distinguishes the proofs of the different credentials
+#[derive(Copy, Clone, Debug)]
+pub enum Credential {
+    ServerPassword,
+    PlayerSecret,
+    SessionToken,
+}
+
+impl Credential {
+    fn label(&self) -> &'static [u8] {
+        match self {
+            Credential::ServerPassword => b"server-password",
+            Credential::PlayerSecret => b"player-secret",
+            Credential::SessionToken => b"session-token",
+        }
+    }
+}
+
+fn mac(credential: Credential, key: &str, nonce: &Nonce, player_name: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(credential.label());
+    mac.update(nonce);
+    mac.update(player_name.as_bytes());
+    mac
+}
+
+pub fn prove(credential: Credential, key: &str, nonce: &Nonce, player_name: &str) -> AuthProof {
+    mac(credential, key, nonce, player_name)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// The comparison takes the same time wherever the proof differs
+pub fn verify(
+    credential: Credential,
+    key: &str,
+    nonce: &Nonce,
+    player_name: &str,
+    proof: &AuthProof,
+) -> bool {
+    mac(credential, key, nonce, player_name)
+        .verify_slice(proof)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NONCE: Nonce = [7; 32];
+
+    #[test]
+    fn proof_is_verified_with_same_key() {
+        let proof = prove(Credential::ServerPassword, "sesame", &NONCE, "Clint");
+
+        assert!(verify(Credential::ServerPassword, "sesame", &NONCE, "Clint", &proof));
+    }
+
+    #[test]
+    fn proof_is_bound_to_key_nonce_name_and_credential() {
+        let proof = prove(Credential::ServerPassword, "sesame", &NONCE, "Clint");
+
+        assert!(!verify(Credential::ServerPassword, "simsim", &NONCE, "Clint", &proof));
+        assert!(!verify(Credential::ServerPassword, "sesame", &[8; 32], "Clint", &proof));
+        assert!(!verify(Credential::ServerPassword, "sesame", &NONCE, "Tuco", &proof));
+        assert!(!verify(Credential::PlayerSecret, "sesame", &NONCE, "Clint", &proof));
+    }
+}
@@ -0,0 +1,8 @@
+use crate::metric_dimension::length::MeterVec2;
+use bevy::ecs::component::Component;
+use serde::{Deserialize, Serialize};
+
+/// Position of an entity placed on the map. The map entities rarely move, so they are left out of
+/// the entity state snapshots and their position is replicated instead.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize, Component)]
+pub struct MapPosition(pub MeterVec2);
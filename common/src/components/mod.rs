@@ -3,9 +3,12 @@ pub use damage::Damage;
 pub use eliminate::Eliminated;
 pub use health::Health;
 pub use input::{Input, InputFlags};
-pub use network_id::{EntityType, NetworkId};
+pub use map_position::MapPosition;
+pub use network_id::{EntityType, MapEntityType, NetworkId};
 pub use player::Player;
+pub use position_history::{LagCompensation, PositionHistory};
 pub use projectile::Projectile;
+pub use replicate::Replicate;
 pub use respawn::Respawn;
 pub use sprite_id::*;
 pub use time_limit::Lifespan;
@@ -16,9 +19,12 @@ mod damage;
 mod eliminate;
 mod health;
 mod input;
+mod map_position;
 mod network_id;
 mod player;
+mod position_history;
 mod projectile;
+mod replicate;
 mod respawn;
 mod sprite_id;
 mod time_limit;
@@ -11,4 +11,29 @@ pub struct NetworkId {
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum EntityType {
     Player,
+    Barrel,
+}
+
+/// The types of the entities placed on the map
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MapEntityType {
+    Barrel,
+}
+
+impl MapEntityType {
+    /// None for the entities which are not placed on the map, like the players
+    pub fn from_entity_type(entity_type: EntityType) -> Option<Self> {
+        match entity_type {
+            EntityType::Barrel => Some(MapEntityType::Barrel),
+            EntityType::Player => None,
+        }
+    }
+}
+
+impl From<MapEntityType> for EntityType {
+    fn from(map_entity_type: MapEntityType) -> EntityType {
+        match map_entity_type {
+            MapEntityType::Barrel => EntityType::Barrel,
+        }
+    }
 }
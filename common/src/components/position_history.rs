@@ -0,0 +1,105 @@
+use bevy::ecs::component::Component;
+use bevy::prelude::{Entity, Vec3};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Recent positions of a moving body, used to resolve hits at a past moment (lag compensation).
+#[derive(Debug, Default, Component)]
+pub struct PositionHistory {
+    positions: VecDeque<(Duration, Vec3)>,
+}
+
+impl PositionHistory {
+    /// Records the position at `time` and drops the positions older than `time - keep`
+    pub fn record(&mut self, time: Duration, translation: Vec3, keep: Duration) {
+        let oldest_kept = time.saturating_sub(keep);
+        while self.positions.len() > 1 && self.positions[1].0 <= oldest_kept {
+            self.positions.pop_front();
+        }
+        self.positions.push_back((time, translation));
+    }
+
+    /// Returns the interpolated position at `time`, or the oldest one if `time` is not covered.
+    /// Returns `None` if nothing has been recorded yet.
+    pub fn position_at(&self, time: Duration) -> Option<Vec3> {
+        let (oldest_time, oldest) = *self.positions.front()?;
+        if time <= oldest_time {
+            return Some(oldest);
+        }
+
+        self.positions
+            .iter()
+            .zip(self.positions.iter().skip(1))
+            .find(|(_, (to_time, _))| time <= *to_time)
+            .map(|((from_time, from), (to_time, to))| {
+                let ratio =
+                    (time - *from_time).as_secs_f32() / (*to_time - *from_time).as_secs_f32();
+                from.lerp(*to, ratio)
+            })
+            .or_else(|| self.positions.back().map(|(_, latest)| *latest))
+    }
+}
+
+/// Marks a projectile shot by a client. Its hits are checked against the positions
+/// of the bodies `rewind` earlier, as the shooter saw them on its screen.
+#[derive(Debug, Copy, Clone, Component)]
+pub struct LagCompensation {
+    pub rewind: Duration,
+    /// The shooter is always checked at its current position
+    pub shooter: Entity,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEEP: Duration = Duration::from_secs(1);
+
+    fn history(entries: &[(u64, f32)]) -> PositionHistory {
+        let mut history = PositionHistory::default();
+        entries.iter().for_each(|&(millis, x)| {
+            history.record(Duration::from_millis(millis), Vec3::new(x, 0.0, 0.0), KEEP)
+        });
+        history
+    }
+
+    #[test]
+    fn position_is_interpolated_between_records() {
+        let history = history(&[(0, 0.0), (100, 10.0), (200, 30.0)]);
+
+        assert_eq!(
+            history.position_at(Duration::from_millis(50)),
+            Some(Vec3::new(5.0, 0.0, 0.0))
+        );
+        assert_eq!(
+            history.position_at(Duration::from_millis(150)),
+            Some(Vec3::new(20.0, 0.0, 0.0))
+        );
+    }
+
+    #[test]
+    fn uncovered_times_are_clamped() {
+        let history = history(&[(100, 10.0), (200, 30.0)]);
+
+        assert_eq!(
+            history.position_at(Duration::from_millis(0)),
+            Some(Vec3::new(10.0, 0.0, 0.0))
+        );
+        assert_eq!(
+            history.position_at(Duration::from_millis(300)),
+            Some(Vec3::new(30.0, 0.0, 0.0))
+        );
+        assert_eq!(PositionHistory::default().position_at(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn old_records_are_dropped() {
+        let history = history(&[(0, 0.0), (500, 10.0), (1000, 20.0), (1600, 30.0)]);
+
+        // the record at 500ms is kept to cover the full period
+        assert_eq!(
+            history.position_at(Duration::from_millis(0)),
+            Some(Vec3::new(10.0, 0.0, 0.0))
+        );
+    }
+}
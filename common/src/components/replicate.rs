@@ -0,0 +1,5 @@
+use bevy::ecs::component::Component;
+
+/// The registered components of the entity are replicated to the clients, see `replication`
+#[derive(Copy, Clone, Debug, Default, Component)]
+pub struct Replicate;
@@ -0,0 +1,161 @@
+use crate::serialization::DecodeError;
+use bevy::prelude::Resource;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// First byte of a compressed datagram, followed by the original length as a little endian
+/// `u32` and the LZ4 block of the original datagram
+pub const COMPRESSED_TAG: u8 = b'Z';
+
+/// Larger payloads are rejected without decompressing them
+const MAX_DECOMPRESSED_SIZE: usize = 65536;
+
+const LENGTH_SIZE: usize = std::mem::size_of::<u32>();
+
+/// Datagrams of at least `threshold` bytes are compressed, when they get smaller by it.
+/// Agreed on connection, the server answers the clients asking for it with its threshold.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Compression {
+    pub threshold: u32,
+}
+
+/// Compression results since the server has started
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CompressionStats {
+    /// Datagrams at or above the threshold
+    pub attempted: u64,
+    /// Datagrams sent compressed, the others did not get smaller
+    pub compressed: u64,
+    /// Size of the attempted datagrams before and after, the uncompressed ones counted as they are
+    pub raw_bytes: u64,
+    pub sent_bytes: u64,
+    /// Time spent in compressing
+    pub time: Duration,
+}
+
+impl CompressionStats {
+    /// Sent size relative to the raw size
+    pub fn ratio(&self) -> Option<f64> {
+        (self.raw_bytes > 0).then(|| self.sent_bytes as f64 / self.raw_bytes as f64)
+    }
+
+    pub fn mean_time(&self) -> Option<Duration> {
+        (self.attempted > 0).then(|| self.time / self.attempted as u32)
+    }
+}
+
+/// Compresses the outgoing datagrams and measures how it pays off
+#[derive(Debug, Default, Resource)]
+pub struct Compressor {
+    stats: CompressionStats,
+}
+
+impl Compressor {
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    /// Compresses the serialized packet if it is enabled for the peer and the payload is large
+    /// enough. Returns the payload as it is otherwise.
+    pub fn compress(&mut self, payload: Vec<u8>, compression: Option<Compression>) -> Vec<u8> {
+        let compression = match compression {
+            Some(compression) if payload.len() >= compression.threshold as usize => compression,
+            _ => return payload,
+        };
+        debug_assert!(compression.threshold > 0);
+
+        let start = Instant::now();
+        let mut compressed = Vec::with_capacity(1 + LENGTH_SIZE + payload.len());
+        compressed.push(COMPRESSED_TAG);
+        compressed.extend((payload.len() as u32).to_le_bytes());
+        compressed.extend(lz4_flex::block::compress(&payload));
+        self.stats.time += start.elapsed();
+
+        self.stats.attempted += 1;
+        self.stats.raw_bytes += payload.len() as u64;
+        if compressed.len() < payload.len() {
+            self.stats.compressed += 1;
+            self.stats.sent_bytes += compressed.len() as u64;
+            compressed
+        } else {
+            self.stats.sent_bytes += payload.len() as u64;
+            payload
+        }
+    }
+}
+
+/// The original datagram of the body following `COMPRESSED_TAG`
+pub fn decompress(body: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    if body.len() < LENGTH_SIZE {
+        return Err(DecodeError::Malformed("Compressed packet is truncated".into()));
+    }
+    let (length, block) = body.split_at(LENGTH_SIZE);
+    let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+    if length > MAX_DECOMPRESSED_SIZE {
+        return Err(DecodeError::Malformed(
+            format!("Compressed packet is too large: {} bytes", length).into(),
+        ));
+    }
+    lz4_flex::block::decompress(block, length).map_err(|err| DecodeError::Malformed(err.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COMPRESSION: Option<Compression> = Some(Compression { threshold: 64 });
+
+    #[test]
+    fn large_payload_is_compressed() {
+        let mut compressor = Compressor::default();
+        let payload = b"westiny".repeat(100);
+
+        let compressed = compressor.compress(payload.clone(), COMPRESSION);
+        assert_eq!(compressed[0], COMPRESSED_TAG);
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress(&compressed[1..]).unwrap(), payload);
+
+        let stats = compressor.stats();
+        assert_eq!(stats.attempted, 1);
+        assert_eq!(stats.compressed, 1);
+        assert_eq!(stats.raw_bytes, payload.len() as u64);
+        assert_eq!(stats.sent_bytes, compressed.len() as u64);
+        assert!(stats.ratio().unwrap() < 1.0);
+    }
+
+    #[test]
+    fn small_payload_is_sent_as_it_is() {
+        let mut compressor = Compressor::default();
+        let payload = b"westiny".repeat(2);
+
+        assert_eq!(compressor.compress(payload.clone(), COMPRESSION), payload);
+        assert_eq!(compressor.compress(b"x".repeat(100), None), b"x".repeat(100));
+        assert_eq!(compressor.stats().attempted, 0);
+    }
+
+    #[test]
+    fn incompressible_payload_is_sent_as_it_is() {
+        let mut compressor = Compressor::default();
+        // Bytes of a simple LCG, no repetition for LZ4 to find
+        let payload: Vec<u8> = (0..200_u32)
+            .scan(7_u32, |state, _| {
+                *state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                Some((*state >> 16) as u8)
+            })
+            .collect();
+
+        assert_eq!(compressor.compress(payload.clone(), COMPRESSION), payload);
+        let stats = compressor.stats();
+        assert_eq!(stats.attempted, 1);
+        assert_eq!(stats.compressed, 0);
+        assert_eq!(stats.ratio(), Some(1.0));
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut body = (MAX_DECOMPRESSED_SIZE as u32 + 1).to_le_bytes().to_vec();
+        body.extend([0; 8]);
+        assert!(decompress(&body).is_err());
+        assert!(decompress(&[1, 0]).is_err());
+    }
+}
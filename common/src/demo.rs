@@ -0,0 +1,238 @@
+//! Recording of a server session: the packets sent by the server, the inputs received by it and
+//! everything needed to reproduce it.
+//!
+//! A `.wdemo` file starts with `DEMO_MAGIC`, the format version (u16, little endian) and the
+//! `DemoHeader`, followed by `DemoFrame`s. The header and each frame are MessagePack encoded and
+//! prefixed by their length (u32, little endian).
+
+use crate::network::{SequencedInput, Tick};
+use crate::resources::Seed;
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+pub const DEMO_EXTENSION: &str = "wdemo";
+const DEMO_MAGIC: &[u8; 5] = b"WDEMO";
+/// Must be increased whenever the layout of the file or of the records changes
+const DEMO_FORMAT_VERSION: u16 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DemoHeader {
+    /// The packets of the demo can be read only with this version of the protocol
+    pub protocol_version: u16,
+    pub tick_rate: u32,
+    pub map_seed: Seed,
+    /// Seed of the randomness of the game logic
+    pub rng_seed: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum DemoRecord {
+    /// A client has been accepted by the server
+    ClientJoined {
+        address: SocketAddr,
+        player_name: String,
+    },
+    /// Serialized `PacketType` sent by the server
+    Outbound {
+        destination: SocketAddr,
+        payload: Vec<u8>,
+    },
+    /// Inputs received from a client
+    Input {
+        source: SocketAddr,
+        inputs: Vec<SequencedInput>,
+        view_time: Option<Duration>,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct DemoFrame {
+    pub tick: Tick,
+    pub server_time: Duration,
+    pub record: DemoRecord,
+}
+
+pub struct DemoWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DemoWriter<W> {
+    pub fn new(mut writer: W, header: &DemoHeader) -> Result<Self> {
+        writer.write_all(DEMO_MAGIC)?;
+        writer.write_all(&DEMO_FORMAT_VERSION.to_le_bytes())?;
+        write_chunk(&mut writer, header)?;
+        Ok(DemoWriter { writer })
+    }
+
+    pub fn write(&mut self, frame: &DemoFrame) -> Result<()> {
+        write_chunk(&mut self.writer, frame)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn write_chunk<W: Write, T: Serialize>(writer: &mut W, value: &T) -> Result<()> {
+    let bytes = rmp_serde::to_vec(value)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads the next chunk, returns `None` at the end of the file.
+/// A chunk cut off at the end (e.g. the server has been killed while writing it) is ignored.
+fn read_chunk<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let mut bytes = vec![0u8; u32::from_le_bytes(length) as usize];
+    match reader.read_exact(&mut bytes) {
+        Ok(()) => Ok(Some(rmp_serde::from_read_ref(&bytes)?)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Demo {
+    pub header: DemoHeader,
+    pub frames: Vec<DemoFrame>,
+}
+
+impl Demo {
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 5];
+        reader
+            .read_exact(&mut magic)
+            .context("Demo file is too short")?;
+        if &magic != DEMO_MAGIC {
+            bail!("Not a demo file");
+        }
+        let mut version = [0u8; 2];
+        reader.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != DEMO_FORMAT_VERSION {
+            bail!(
+                "Unsupported demo format version: {}, expected: {}",
+                version,
+                DEMO_FORMAT_VERSION
+            );
+        }
+
+        let header = read_chunk(&mut reader)?.context("Demo header is missing")?;
+        let mut frames = Vec::new();
+        while let Some(frame) = read_chunk(&mut reader)? {
+            frames.push(frame);
+        }
+        Ok(Demo { header, frames })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Could not open demo file: {}", path.display()))?;
+        Demo::read(io::BufReader::new(file))
+    }
+
+    /// Players who joined during the recording, with their addresses, in the order of joining
+    pub fn players(&self) -> impl Iterator<Item = (&str, SocketAddr)> {
+        self.frames.iter().filter_map(|frame| match &frame.record {
+            DemoRecord::ClientJoined {
+                address,
+                player_name,
+            } => Some((player_name.as_str(), *address)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::Input;
+
+    fn header() -> DemoHeader {
+        DemoHeader {
+            protocol_version: 1,
+            tick_rate: 60,
+            map_seed: Seed(3),
+            rng_seed: 42,
+        }
+    }
+
+    fn frames() -> Vec<DemoFrame> {
+        let address = SocketAddr::from(([127, 0, 0, 1], 4557));
+        vec![
+            DemoFrame {
+                tick: 0,
+                server_time: Duration::from_millis(10),
+                record: DemoRecord::ClientJoined {
+                    address,
+                    player_name: "Clint".to_string(),
+                },
+            },
+            DemoFrame {
+                tick: 1,
+                server_time: Duration::from_millis(20),
+                record: DemoRecord::Input {
+                    source: address,
+                    inputs: vec![SequencedInput {
+                        sequence: 0,
+                        input: Input::default(),
+                    }],
+                    view_time: None,
+                },
+            },
+            DemoFrame {
+                tick: 1,
+                server_time: Duration::from_millis(20),
+                record: DemoRecord::Outbound {
+                    destination: address,
+                    payload: vec![1, 2, 3],
+                },
+            },
+        ]
+    }
+
+    fn write_demo() -> Vec<u8> {
+        let mut writer = DemoWriter::new(Vec::new(), &header()).unwrap();
+        for frame in frames() {
+            writer.write(&frame).unwrap();
+        }
+        writer.writer
+    }
+
+    #[test]
+    fn written_demo_is_read_back() {
+        let demo = Demo::read(write_demo().as_slice()).unwrap();
+
+        assert_eq!(demo.header, header());
+        assert_eq!(demo.frames, frames());
+        assert_eq!(
+            demo.players().collect::<Vec<_>>(),
+            vec![("Clint", SocketAddr::from(([127, 0, 0, 1], 4557)))]
+        );
+    }
+
+    #[test]
+    fn truncated_frame_is_ignored() {
+        let bytes = write_demo();
+        let demo = Demo::read(&bytes[..bytes.len() - 2]).unwrap();
+
+        assert_eq!(demo.frames, frames()[..2]);
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(Demo::read(&b"WDEMX\x01\x00"[..]).is_err());
+        assert!(Demo::read(&b"WD"[..]).is_err());
+    }
+}
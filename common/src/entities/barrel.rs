@@ -1,24 +1,9 @@
 use crate::components::{BoundingCircle, SpriteId};
-use crate::metric_dimension::length::Meter;
-use bevy::prelude::{Bundle, Commands, Transform, Vec2};
+use crate::metric_dimension::length::{Meter, MeterVec2};
+use bevy::prelude::{Bundle, Transform};
 
 const BARREL_HEIGHT: f32 = 1.0;
-const BARREL_DIAMETER: Meter = Meter(1.0);
-
-pub fn place_barrel(commands: &mut Commands, pos: Vec2) {
-    let transform = Transform::from_xyz(
-        pos.x * BARREL_DIAMETER.into_pixel(),
-        pos.y * BARREL_DIAMETER.into_pixel(),
-        BARREL_HEIGHT,
-    );
-
-    commands.spawn(BarrelBundle {
-        bounding_circle: BoundingCircle {
-            radius: BARREL_DIAMETER / 2f32,
-        },
-        sprite_sheet_bundle: super::SimpleSpriteSheetBundle::new(transform, SpriteId::Barrel),
-    });
-}
+pub const BARREL_DIAMETER: Meter = Meter(1.0);
 
 #[derive(Bundle)]
 pub struct BarrelBundle {
@@ -26,3 +11,20 @@ pub struct BarrelBundle {
     #[bundle]
     sprite_sheet_bundle: super::SimpleSpriteSheetBundle,
 }
+
+impl BarrelBundle {
+    pub fn new(position: MeterVec2) -> Self {
+        let transform = Transform::from_xyz(
+            position.x.into_pixel(),
+            position.y.into_pixel(),
+            BARREL_HEIGHT,
+        );
+
+        BarrelBundle {
+            bounding_circle: BoundingCircle {
+                radius: BARREL_DIAMETER / 2f32,
+            },
+            sprite_sheet_bundle: super::SimpleSpriteSheetBundle::new(transform, SpriteId::Barrel),
+        }
+    }
+}
@@ -1,10 +1,12 @@
-pub use barrel::{place_barrel, BarrelBundle};
+pub use barrel::{BarrelBundle, BARREL_DIAMETER};
 pub use bullet::BulletBundle;
 
 mod barrel;
 mod bullet;
 
-use crate::components::SpriteId;
+use crate::components::{MapEntityType, SpriteId};
+use crate::metric_dimension::length::MeterVec2;
+use bevy::ecs::system::EntityCommands;
 use bevy::prelude::{Bundle, SpatialBundle, Transform};
 
 #[derive(Bundle)]
@@ -23,3 +25,17 @@ impl SimpleSpriteSheetBundle {
         }
     }
 }
+
+/// Inserts the components of a map entity of the type at the position. The server places the map
+/// entities, the clients complete them the same way when they are replicated.
+pub fn insert_map_entity(
+    entity: &mut EntityCommands,
+    entity_type: MapEntityType,
+    position: MeterVec2,
+) {
+    match entity_type {
+        MapEntityType::Barrel => {
+            entity.insert(BarrelBundle::new(position));
+        }
+    }
+}
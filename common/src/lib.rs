@@ -3,14 +3,22 @@ use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::time::Duration;
 
+pub mod auth;
 pub mod collision;
 pub mod components;
+pub mod compression;
+pub mod demo;
 pub mod entities;
 pub mod events;
 pub mod metric_dimension;
 pub mod network;
+pub mod outbox;
+pub mod ping;
+pub mod replication;
 pub mod resources;
+pub mod secure_channel;
 pub mod serialization;
+pub mod snapshot;
 pub mod systems;
 pub mod utilities;
 
@@ -23,7 +31,7 @@ pub enum MoveDirection {
     StrafeRight,
 }
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 pub struct NetworkConfig {
     hartbeat_interval: u8,
 }
@@ -1,43 +1,201 @@
-use crate::components::{Health, Input, NetworkId};
+use crate::auth::{AuthProof, Nonce};
+use crate::components::{Input, NetworkId};
+use crate::compression::Compression;
 use crate::metric_dimension::{length::MeterVec2, MeterPerSecVec2, Second};
 use crate::resources::Seed;
+use crate::secure_channel::KeyShare;
+use crate::serialization::PacketCodec;
 use crate::PlayerName;
 use derive_new::new;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Display, Formatter};
+use std::time::Duration;
+
+/// Version of the wire protocol.
+/// Must be increased whenever `PacketType` or any of its payloads changes in an incompatible way.
+/// A version is never lowered or reused, as the builds of the earlier ones are out there.
+pub const PROTOCOL_VERSION: u16 = 20;
+
+/// Identifies the build of `westiny_common`.
+/// Can be set at compile time through the `WESTINY_BUILD_HASH` environment variable (e.g. to the
+/// git commit hash), otherwise the crate version is used.
+pub const BUILD_HASH: &str = match option_env!("WESTINY_BUILD_HASH") {
+    Some(hash) => hash,
+    None => env!("CARGO_PKG_VERSION"),
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(test, derive(Clone, PartialEq))]
 pub enum PacketType {
-    ConnectionRequest { player_name: String },
+    /// Always preceded by the protocol version of the client, see `CONNECTION_REQUEST_TAG`
+    ConnectionRequest {
+        player_name: String,
+        build_hash: String,
+        /// The client has a previous session, it gets back the player after the connection has
+        /// been lost (e.g. the address of the client has changed) by proving it has the token.
+        /// The token itself is never sent by the client.
+        resume_session: bool,
+        /// Codec the client asks for, the packets after the handshake are encoded with it
+        codec: PacketCodec,
+        /// The client can decompress the packets compressed by the server
+        compression: bool,
+        /// Key exchange of the secure session, the packets after the handshake are sealed with it
+        key_share: KeyShare,
+    },
+    /// Sent instead of accepting the connection request, when the server has a password, the
+    /// player has a secret or the client resumes its session
+    AuthChallenge(AuthChallenge),
+    /// Answer to the `AuthChallenge`, with a proof of each credential required
+    AuthResponse {
+        password_proof: Option<AuthProof>,
+        secret_proof: Option<AuthProof>,
+        /// Proof of the session token, keyed by the token
+        session_proof: Option<AuthProof>,
+    },
     ConnectionResponse(Result<ClientInitialData>),
-    InputState { input: Input },
-    EntityStateUpdate(Vec<EntityState>),
+    InputState {
+        /// The latest input and a few previous ones, in ascending sequence order,
+        /// so an input is not lost when a packet is
+        inputs: Vec<SequencedInput>,
+        /// Estimated server time of the world displayed by the client when the input was made
+        view_time: Option<Duration>,
+    },
+    EntityStateUpdate(EntityStateUpdate),
+    SnapshotAck { snapshot: SnapshotId },
     EntityDelete(NetworkEntityDelete),
+    ComponentUpdate(ComponentUpdate),
     PlayerUpdate(PlayerUpdate),
     Notification(PlayerNotification),
     ShotEvent(ShotEvent),
     PlayerDeath(PlayerDeath),
+    /// The sender leaves, it does not wait for the connection to time out
+    Disconnect { reason: DisconnectReason },
+    /// Sent periodically by both peers, answered with a `Pong` of the same sequence
+    Ping { sequence: PingSequence },
+    Pong {
+        sequence: PingSequence,
+        /// Elapsed time of the answering peer, the same clock as `EntityStateUpdate::server_time`
+        time: Duration,
+    },
+    /// Packets of the same delivery to the same peer, sent in one datagram
+    Bundle(Vec<EncodedPacket>),
+}
+
+/// A serialized packet inside a `Bundle`
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct EncodedPacket(#[serde(with = "serde_bytes")] pub Vec<u8>);
+
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AuthChallenge {
+    pub nonce: Nonce,
+    pub password_required: bool,
+    pub secret_required: bool,
+    pub session_required: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct ClientInitialData {
     pub player_network_id: NetworkId,
     pub seed: Seed,
+    /// Simulation ticks per second on the server, the client sends its inputs at this rate
+    pub tick_rate: u32,
+    /// The client can reconnect to this session with it
+    pub session_token: SessionToken,
+    /// Codec accepted by the server, both peers encode their packets with it from now on
+    pub codec: PacketCodec,
+    /// Set if the client has asked for it and the server compresses its large packets
+    pub compression: Option<Compression>,
+}
+
+/// Secret identifying the session of a client, given by the server on connection
+pub type SessionToken = u64;
+
+/// Sequence number of the entity state snapshots sent to a client
+pub type SnapshotId = u32;
+
+/// Number of the server simulation tick in which a replicated packet has been created
+pub type Tick = u32;
+
+/// Identifies a replicated component type, its position in the `ReplicationRegistry`
+pub type ComponentKind = u16;
+
+/// Sequence number of the inputs sent by a client
+pub type InputSequence = u32;
+
+/// Sequence number of the pings sent by a peer
+pub type PingSequence = u32;
+
+/// Input of a client with its sequence number
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SequencedInput {
+    pub sequence: InputSequence,
+    pub input: Input,
 }
 
+/// Entity states of a snapshot, delta compressed against a baseline snapshot which has
+/// been acknowledged by the client.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
+pub struct EntityStateUpdate {
+    pub snapshot: SnapshotId,
+    pub tick: Tick,
+    /// Elapsed time on the server when the snapshot was taken
+    pub server_time: Duration,
+    /// When `None`, `states` contains every entity of the snapshot
+    pub baseline: Option<SnapshotId>,
+    /// States which are new or changed since baseline
+    pub states: Vec<EntityState>,
+    /// Entities which are present in baseline, but not in this snapshot
+    pub removed: Vec<NetworkId>,
+    /// Sequence number of the last input of the receiving client which has been applied
+    /// to its player in this snapshot
+    pub last_processed_input: Option<InputSequence>,
+    /// Position of this update among the chunks of the snapshot
+    pub chunk: SnapshotChunk,
+}
+
+/// A snapshot too large for one packet is sent in chunks. Each chunk is a delta from the same
+/// baseline for a part of the entities, so it can be applied without the others.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SnapshotChunk {
+    pub index: u8,
+    pub count: u8,
+}
+
+impl SnapshotChunk {
+    /// The snapshot is sent in a single update
+    pub const WHOLE: SnapshotChunk = SnapshotChunk { index: 0, count: 1 };
+}
+
+/// Serialized in a quantized, bit-packed form by `serialization::ENTITY_STATE_CODEC`
+#[derive(Clone, Debug, PartialEq)]
 pub struct EntityState {
     pub network_id: NetworkId,
     pub position: MeterVec2,
     pub angle: f32,
 }
 
+/// Values of replicated components, sent with the same delivery
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ComponentUpdate {
+    pub tick: Tick,
+    pub components: Vec<ComponentValue>,
+}
+
+/// A replicated component of an entity, encoded on its own so it can be decoded by its kind
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ComponentValue {
+    pub network_id: NetworkId,
+    pub kind: ComponentKind,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub struct NetworkEntityDelete {
     pub network_id: NetworkId,
+    pub tick: Tick,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -52,6 +210,7 @@ pub struct ShotEvent {
     pub position: MeterVec2,
     pub velocity: MeterPerSecVec2,
     pub bullet_time_limit_secs: Second,
+    pub tick: Tick,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -59,12 +218,12 @@ pub struct ShotEvent {
 pub struct PlayerDeath {
     pub player_name: PlayerName,
     pub position: MeterVec2,
+    pub tick: Tick,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(test, derive(PartialEq))]
 pub enum PlayerUpdate {
-    HealthUpdate(Health),
     AmmoUpdate {
         ammo_in_magazine: u32,
     },
@@ -75,20 +234,48 @@ pub enum PlayerUpdate {
     },
 }
 
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum DisconnectReason {
+    /// The player has quit the game
+    ClientQuit,
+    ServerShutdown,
+}
+
+impl Display for DisconnectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::ClientQuit => write!(f, "Player quit"),
+            DisconnectReason::ServerShutdown => write!(f, "Server is shutting down"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub enum ErrorKind {
     AlreadyConnected,
+    VersionMismatch { server_version: u16 },
+    ServerFull,
+    NameTaken,
+    Banned,
+    AuthenticationFailed,
     Other,
 }
 
 impl Display for ErrorKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let literal = match self {
-            ErrorKind::AlreadyConnected => "Client already connected",
-            ErrorKind::Other => "Other error",
-        };
-
-        write!(f, "{}", literal)
+        match self {
+            ErrorKind::AlreadyConnected => write!(f, "Client already connected"),
+            ErrorKind::VersionMismatch { server_version } => write!(
+                f,
+                "Protocol version mismatch (server: {}, client: {})",
+                server_version, PROTOCOL_VERSION
+            ),
+            ErrorKind::ServerFull => write!(f, "Server is full"),
+            ErrorKind::NameTaken => write!(f, "Player name is already taken"),
+            ErrorKind::Banned => write!(f, "Banned from the server"),
+            ErrorKind::AuthenticationFailed => write!(f, "Authentication failed"),
+            ErrorKind::Other => write!(f, "Other error"),
+        }
     }
 }
 
@@ -97,6 +284,12 @@ pub struct Error {
     error_kind: ErrorKind,
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        self.error_kind
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "Westiny network error: {}", self.error_kind)
@@ -0,0 +1,539 @@
+use crate::network::{
+    DisconnectReason, EncodedPacket, EntityStateUpdate, NetworkEntityDelete, PacketType,
+    PingSequence, PlayerDeath, PlayerNotification, PlayerUpdate, ShotEvent,
+};
+use crate::compression::{Compression, Compressor};
+use crate::replication::{ReplicatedComponents, ReplicationDelivery};
+use crate::secure_channel::MAX_SEAL_OVERHEAD;
+use crate::serialization::{encoded_size, serialize_with, PacketCodec, WireFormat};
+use bevy::prelude::Resource;
+use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Streams of the sequenced packets sent by the server
+#[derive(Copy, Clone, Debug)]
+#[repr(u8)]
+pub enum StreamId {
+    EntityStateUpdate,
+    AmmoUpdate,
+    WeaponSwitch,
+    ShotEvent,
+    PlayerDeath,
+    ReliableReplication,
+    UnreliableReplication,
+}
+
+impl From<StreamId> for Option<u8> {
+    fn from(id: StreamId) -> Option<u8> {
+        Some(id as u8)
+    }
+}
+
+/// A packet content which knows how it is delivered
+pub trait Message {
+    fn delivery(&self) -> DeliveryRequirement;
+    fn into_packet(self) -> PacketType;
+}
+
+/// The packets measuring the round trip time
+#[derive(Copy, Clone, Debug)]
+pub enum LinkProbe {
+    Ping {
+        sequence: PingSequence,
+    },
+    Pong {
+        sequence: PingSequence,
+        time: Duration,
+    },
+}
+
+impl Message for LinkProbe {
+    /// Unreliable, so the lost pings show the loss of the connection
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::Unreliable
+    }
+
+    fn into_packet(self) -> PacketType {
+        match self {
+            LinkProbe::Ping { sequence } => PacketType::Ping { sequence },
+            LinkProbe::Pong { sequence, time } => PacketType::Pong { sequence, time },
+        }
+    }
+}
+
+impl Message for DisconnectReason {
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::Reliable
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::Disconnect { reason: self }
+    }
+}
+
+impl Message for EntityStateUpdate {
+    /// A newer snapshot supersedes the older ones
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::UnreliableSequenced(StreamId::EntityStateUpdate.into())
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::EntityStateUpdate(self)
+    }
+}
+
+impl Message for PlayerUpdate {
+    fn delivery(&self) -> DeliveryRequirement {
+        let stream = match self {
+            PlayerUpdate::AmmoUpdate { .. } => StreamId::AmmoUpdate,
+            PlayerUpdate::WeaponSwitch { .. } => StreamId::WeaponSwitch,
+        };
+        DeliveryRequirement::ReliableSequenced(stream.into())
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::PlayerUpdate(self)
+    }
+}
+
+impl Message for PlayerNotification {
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::Reliable
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::Notification(self)
+    }
+}
+
+impl Message for ShotEvent {
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::ReliableSequenced(StreamId::ShotEvent.into())
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::ShotEvent(self)
+    }
+}
+
+impl Message for PlayerDeath {
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::ReliableSequenced(StreamId::PlayerDeath.into())
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::PlayerDeath(self)
+    }
+}
+
+impl Message for NetworkEntityDelete {
+    fn delivery(&self) -> DeliveryRequirement {
+        DeliveryRequirement::Reliable
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::EntityDelete(self)
+    }
+}
+
+impl Message for ReplicatedComponents {
+    /// Ordered or sequenced, so an older value never overwrites a newer one
+    fn delivery(&self) -> DeliveryRequirement {
+        match self.delivery {
+            ReplicationDelivery::Reliable => {
+                DeliveryRequirement::ReliableOrdered(StreamId::ReliableReplication.into())
+            }
+            ReplicationDelivery::Unreliable => {
+                DeliveryRequirement::UnreliableSequenced(StreamId::UnreliableReplication.into())
+            }
+        }
+    }
+
+    fn into_packet(self) -> PacketType {
+        PacketType::ComponentUpdate(self.update)
+    }
+}
+
+/// A packet waiting in the `Outbox`
+#[derive(Debug)]
+pub struct OutgoingMessage {
+    pub destinations: Vec<SocketAddr>,
+    pub packet: PacketType,
+    pub delivery: DeliveryRequirement,
+}
+
+/// Packets queued by the systems, serialized once per codec and handed to the transport by
+/// `flush`, bundled per destination. Until then they can be inspected as they are.
+#[derive(Debug, Default, Resource)]
+pub struct Outbox {
+    queued: Vec<OutgoingMessage>,
+}
+
+impl Outbox {
+    pub fn send<M: Message>(&mut self, destination: SocketAddr, message: M) {
+        self.send_to_many(std::iter::once(destination), message);
+    }
+
+    pub fn send_to_many<M, I>(&mut self, destinations: I, message: M)
+    where
+        M: Message,
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        let destinations: Vec<_> = destinations.into_iter().collect();
+        if destinations.is_empty() {
+            return;
+        }
+        self.queued.push(OutgoingMessage {
+            destinations,
+            delivery: message.delivery(),
+            packet: message.into_packet(),
+        });
+    }
+
+    pub fn queued(&self) -> &[OutgoingMessage] {
+        &self.queued
+    }
+
+    /// The packets queued for the destination
+    pub fn queued_for(&self, destination: SocketAddr) -> impl Iterator<Item = &PacketType> {
+        self.queued
+            .iter()
+            .filter(move |message| message.destinations.contains(&destination))
+            .map(|message| &message.packet)
+    }
+
+    /// Sends the queued messages, encoded in the wire format of each destination. The messages
+    /// of the same delivery to the same destination are packed into `Bundle`s of at most
+    /// `MAX_BUNDLE_SIZE` bytes once sealed, in the order they were queued, then compressed if it
+    /// is enabled.
+    pub fn flush<F>(
+        &mut self,
+        net: &mut TransportResource,
+        compressor: &mut Compressor,
+        format_of: F,
+    ) where
+        F: Fn(&SocketAddr) -> WireFormat,
+    {
+        let mut bundles: Vec<PendingBundle> = Vec::new();
+        for message in self.queued.drain(..) {
+            // Each codec encodes the packet once
+            let mut payloads: Vec<(PacketCodec, Vec<u8>)> = Vec::new();
+            for destination in message.destinations {
+                let WireFormat { codec, compression } = format_of(&destination);
+                let encoded = payloads.iter().find(|(used, _)| *used == codec);
+                let payload = match encoded {
+                    Some((_, payload)) => payload.clone(),
+                    None => match serialize_with(codec, &message.packet) {
+                        Ok(payload) => {
+                            payloads.push((codec, payload.clone()));
+                            payload
+                        }
+                        Err(err) => {
+                            log::error!("{}, packet: {:?}", err, message.packet);
+                            continue;
+                        }
+                    },
+                };
+                let pending = bundles.iter_mut().find(|bundle| {
+                    bundle.destination == destination && bundle.delivery == message.delivery
+                });
+                let packet = BundledPacket::new(codec, payload);
+                match pending {
+                    Some(bundle) if bundle.fits(&packet) => bundle.push(packet),
+                    Some(bundle) => {
+                        let next = PendingBundle::new(
+                            destination,
+                            codec,
+                            compression,
+                            message.delivery,
+                            packet,
+                        );
+                        std::mem::replace(bundle, next).send(net, compressor);
+                    }
+                    None => bundles.push(PendingBundle::new(
+                        destination,
+                        codec,
+                        compression,
+                        message.delivery,
+                        packet,
+                    )),
+                }
+            }
+        }
+        for bundle in bundles {
+            bundle.send(net, compressor);
+        }
+    }
+}
+
+/// Size limit of the datagram of a `Bundle`, sealed, so it fits in one datagram
+pub const MAX_BUNDLE_SIZE: usize = 1200;
+
+/// Upper bound of the growth of the encoding of the packet count as packets are added to a `Bundle`
+const PACKET_COUNT_SIZE: usize = 5;
+/// Upper bound of the separator between the packets of a `Bundle`, a comma in JSON
+const SEPARATOR_SIZE: usize = 1;
+
+/// A serialized packet with its size inside a `Bundle` of the codec
+struct BundledPacket {
+    payload: Vec<u8>,
+    size: usize,
+}
+
+impl BundledPacket {
+    fn new(codec: PacketCodec, payload: Vec<u8>) -> Self {
+        // As bytes, the payload may be encoded much larger than it is, e.g. in JSON
+        let payload = EncodedPacket(payload);
+        let size = encoded_size(codec, &payload)
+            .expect("EncodedPacket could not be encoded")
+            + SEPARATOR_SIZE;
+        BundledPacket {
+            payload: payload.0,
+            size,
+        }
+    }
+}
+
+struct PendingBundle {
+    destination: SocketAddr,
+    codec: PacketCodec,
+    compression: Option<Compression>,
+    delivery: DeliveryRequirement,
+    packets: Vec<EncodedPacket>,
+    size: usize,
+}
+
+impl PendingBundle {
+    fn new(
+        destination: SocketAddr,
+        codec: PacketCodec,
+        compression: Option<Compression>,
+        delivery: DeliveryRequirement,
+        packet: BundledPacket,
+    ) -> Self {
+        // The codec tag, then the encoding of the `Bundle` without its packets
+        let header_size = 1 + encoded_size(codec, &PacketType::Bundle(Vec::new()))
+            .expect("Bundle could not be encoded")
+            + PACKET_COUNT_SIZE;
+        let mut bundle = PendingBundle {
+            destination,
+            codec,
+            compression,
+            delivery,
+            packets: Vec::new(),
+            size: MAX_SEAL_OVERHEAD + header_size,
+        };
+        bundle.push(packet);
+        bundle
+    }
+
+    fn fits(&self, packet: &BundledPacket) -> bool {
+        self.size + packet.size <= MAX_BUNDLE_SIZE
+    }
+
+    fn push(&mut self, packet: BundledPacket) {
+        self.size += packet.size;
+        self.packets.push(EncodedPacket(packet.payload));
+    }
+
+    /// A single packet is sent as it is
+    fn send(mut self, net: &mut TransportResource, compressor: &mut Compressor) {
+        let payload = if self.packets.len() == 1 {
+            self.packets.pop().unwrap().0
+        } else {
+            match serialize_with(self.codec, &PacketType::Bundle(self.packets)) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    log::error!("{}, bundle to {}", err, self.destination);
+                    return;
+                }
+            }
+        };
+        let payload = compressor.compress(payload, self.compression);
+        net.send_with_requirements(
+            self.destination,
+            &payload,
+            self.delivery,
+            UrgencyRequirement::OnTick,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serialization::{deserialize, deserialize_all};
+
+    #[test]
+    fn message_is_queued_with_its_delivery() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        let mut outbox = Outbox::default();
+        outbox.send(addr, PlayerUpdate::AmmoUpdate { ammo_in_magazine: 4 });
+
+        assert_eq!(outbox.queued().len(), 1);
+        let ammo_stream: Option<u8> = StreamId::AmmoUpdate.into();
+        assert!(matches!(
+            outbox.queued()[0].delivery,
+            DeliveryRequirement::ReliableSequenced(stream) if stream == ammo_stream
+        ));
+        assert_eq!(
+            outbox.queued_for(addr).collect::<Vec<_>>(),
+            vec![&PacketType::PlayerUpdate(PlayerUpdate::AmmoUpdate { ammo_in_magazine: 4 })]
+        );
+    }
+
+    #[test]
+    fn flush_sends_to_every_destination() {
+        let addrs = [
+            SocketAddr::from(([127, 0, 0, 1], 1111)),
+            SocketAddr::from(([127, 0, 0, 1], 2222)),
+        ];
+        let mut outbox = Outbox::default();
+        let mut net = TransportResource::new();
+        outbox.send_to_many(
+            addrs,
+            PlayerNotification {
+                message: "Hello".to_string(),
+            },
+        );
+        outbox.flush(&mut net, &mut Compressor::default(), |_| WireFormat::default());
+
+        assert!(outbox.queued().is_empty());
+        let messages = net.get_messages();
+        assert_eq!(messages.len(), 2);
+        for (message, addr) in messages.iter().zip(addrs) {
+            assert_eq!(message.destination, addr);
+            assert!(matches!(message.delivery, DeliveryRequirement::Reliable));
+            assert!(matches!(
+                deserialize(&message.payload).unwrap(),
+                PacketType::Notification(notification) if notification.message == "Hello"
+            ));
+        }
+    }
+
+    #[test]
+    fn destinations_get_their_own_codec() {
+        let msgpack_addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        let json_addr = SocketAddr::from(([127, 0, 0, 1], 2222));
+        let mut outbox = Outbox::default();
+        let mut net = TransportResource::new();
+        outbox.send_to_many(
+            [msgpack_addr, json_addr],
+            PlayerNotification {
+                message: "Hello".to_string(),
+            },
+        );
+        outbox.flush(&mut net, &mut Compressor::default(), |addr| WireFormat {
+            codec: if *addr == json_addr {
+                PacketCodec::Json
+            } else {
+                PacketCodec::MessagePack
+            },
+            compression: None,
+        });
+
+        let messages = net.get_messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].payload[0], PacketCodec::MessagePack.tag());
+        assert_eq!(messages[1].payload[0], PacketCodec::Json.tag());
+        for message in messages.iter() {
+            assert!(matches!(
+                deserialize(&message.payload).unwrap(),
+                PacketType::Notification(notification) if notification.message == "Hello"
+            ));
+        }
+    }
+
+    #[test]
+    fn messages_of_same_delivery_are_bundled() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        let mut outbox = Outbox::default();
+        let mut net = TransportResource::new();
+        for message in ["first", "second"] {
+            outbox.send(
+                addr,
+                PlayerNotification {
+                    message: message.to_string(),
+                },
+            );
+        }
+        outbox.send(addr, PlayerUpdate::AmmoUpdate { ammo_in_magazine: 4 });
+        outbox.flush(&mut net, &mut Compressor::default(), |_| WireFormat::default());
+
+        let messages = net.get_messages();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0].delivery, DeliveryRequirement::Reliable));
+        let notifications: Vec<_> = deserialize_all(&messages[0].payload)
+            .unwrap()
+            .into_iter()
+            .map(|packet| match packet {
+                PacketType::Notification(notification) => notification.message,
+                other => panic!("Unexpected packet: {:?}", other),
+            })
+            .collect();
+        assert_eq!(notifications, vec!["first", "second"]);
+        assert!(matches!(
+            deserialize(&messages[1].payload).unwrap(),
+            PacketType::PlayerUpdate(PlayerUpdate::AmmoUpdate { ammo_in_magazine: 4 })
+        ));
+    }
+
+    #[test]
+    fn bundle_is_compressed_when_enabled() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        let mut outbox = Outbox::default();
+        let mut net = TransportResource::new();
+        let mut compressor = Compressor::default();
+        for _ in 0..3 {
+            outbox.send(
+                addr,
+                PlayerNotification {
+                    message: "x".repeat(200),
+                },
+            );
+        }
+        outbox.flush(&mut net, &mut compressor, |_| WireFormat {
+            codec: PacketCodec::default(),
+            compression: Some(Compression { threshold: 128 }),
+        });
+
+        let messages = net.get_messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload[0], crate::compression::COMPRESSED_TAG);
+        assert_eq!(deserialize_all(&messages[0].payload).unwrap().len(), 3);
+        assert_eq!(compressor.stats().compressed, 1);
+    }
+
+    #[test]
+    fn bundle_is_split_at_size_limit() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        for codec in PacketCodec::ALL {
+            let mut outbox = Outbox::default();
+            let mut net = TransportResource::new();
+            for _ in 0..10 {
+                outbox.send(
+                    addr,
+                    PlayerNotification {
+                        message: "x".repeat(200),
+                    },
+                );
+            }
+            outbox.flush(&mut net, &mut Compressor::default(), |_| WireFormat {
+                codec,
+                compression: None,
+            });
+
+            let messages = net.get_messages();
+            assert!(messages.len() > 1);
+            assert!(messages
+                .iter()
+                .all(|message| message.payload.len() + MAX_SEAL_OVERHEAD <= MAX_BUNDLE_SIZE));
+            let count: usize = messages
+                .iter()
+                .map(|message| deserialize_all(&message.payload).unwrap().len())
+                .sum();
+            assert_eq!(count, 10);
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use crate::network::PingSequence;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Both peers ping each other this often
+pub const PING_INTERVAL: Duration = Duration::from_millis(500);
+/// A ping not answered within this is counted as lost
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+/// The loss is measured over this many pings
+const LOSS_WINDOW: usize = 20;
+
+/// Weights of a new sample in the smoothed round trip time and its deviation, like TCP does
+const RTT_SMOOTHING: f64 = 0.125;
+const JITTER_SMOOTHING: f64 = 0.25;
+
+/// Round trip time, jitter and loss of the link to a peer, measured by pings
+#[derive(Debug, Default)]
+pub struct PingTracker {
+    next_sequence: PingSequence,
+    last_ping: Option<Duration>,
+    /// Pings waiting for their answer, and when they were sent
+    unanswered: VecDeque<(PingSequence, Duration)>,
+    /// Whether the recent pings have been lost, the latest at the back
+    lost: VecDeque<bool>,
+    rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl PingTracker {
+    /// Returns the sequence of the ping to be sent, if it is time for one
+    pub fn next_ping(&mut self, now: Duration) -> Option<PingSequence> {
+        self.expire(now);
+        if self
+            .last_ping
+            .is_some_and(|last| now.saturating_sub(last) < PING_INTERVAL)
+        {
+            return None;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.last_ping = Some(now);
+        self.unanswered.push_back((sequence, now));
+        Some(sequence)
+    }
+
+    /// Returns the round trip time of the answered ping,
+    /// or None if it is unknown (e.g. it has already been counted as lost)
+    pub fn pong(&mut self, sequence: PingSequence, now: Duration) -> Option<Duration> {
+        let index = self
+            .unanswered
+            .iter()
+            .position(|(unanswered, _)| *unanswered == sequence)?;
+        let (_, sent_at) = self.unanswered.remove(index)?;
+        let sample = now.saturating_sub(sent_at);
+
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => {
+                let deviation = (sample.as_secs_f64() - rtt.as_secs_f64()).abs();
+                self.jitter = Duration::from_secs_f64(
+                    self.jitter.as_secs_f64()
+                        + (deviation - self.jitter.as_secs_f64()) * JITTER_SMOOTHING,
+                );
+                Duration::from_secs_f64(
+                    rtt.as_secs_f64() + (sample.as_secs_f64() - rtt.as_secs_f64()) * RTT_SMOOTHING,
+                )
+            }
+            None => {
+                self.jitter = sample / 2;
+                sample
+            }
+        });
+        self.record(false);
+        Some(sample)
+    }
+
+    /// Smoothed round trip time, None until the first answer
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Smoothed deviation of the round trip time
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Ratio of the recent pings lost, between 0 and 1
+    pub fn loss(&self) -> f32 {
+        if self.lost.is_empty() {
+            return 0.0;
+        }
+        self.lost.iter().filter(|lost| **lost).count() as f32 / self.lost.len() as f32
+    }
+
+    fn expire(&mut self, now: Duration) {
+        while let Some(&(_, sent_at)) = self.unanswered.front() {
+            if now.saturating_sub(sent_at) < PING_TIMEOUT {
+                break;
+            }
+            self.unanswered.pop_front();
+            self.record(true);
+        }
+    }
+
+    fn record(&mut self, lost: bool) {
+        if self.lost.len() >= LOSS_WINDOW {
+            self.lost.pop_front();
+        }
+        self.lost.push_back(lost);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn pings_are_sent_at_interval() {
+        let mut tracker = PingTracker::default();
+
+        assert_eq!(tracker.next_ping(ms(0)), Some(0));
+        assert_eq!(tracker.next_ping(ms(499)), None);
+        assert_eq!(tracker.next_ping(ms(500)), Some(1));
+    }
+
+    #[test]
+    fn rtt_is_smoothed() {
+        let mut tracker = PingTracker::default();
+        let first = tracker.next_ping(ms(0)).unwrap();
+        assert_eq!(tracker.pong(first, ms(100)), Some(ms(100)));
+        assert_eq!(tracker.rtt(), Some(ms(100)));
+
+        let second = tracker.next_ping(ms(500)).unwrap();
+        assert_eq!(tracker.pong(second, ms(680)), Some(ms(180)));
+        let rtt = tracker.rtt().unwrap();
+        assert!(rtt > ms(109) && rtt < ms(111));
+        assert!(tracker.jitter() > ms(50) && tracker.jitter() < ms(80));
+    }
+
+    #[test]
+    fn unanswered_pings_are_lost() {
+        let mut tracker = PingTracker::default();
+        let lost = tracker.next_ping(ms(0)).unwrap();
+        let answered = tracker.next_ping(ms(500)).unwrap();
+        tracker.pong(answered, ms(600));
+        tracker.next_ping(ms(2000));
+
+        assert_eq!(tracker.loss(), 0.5);
+        // Too late, it has already been counted as lost
+        assert_eq!(tracker.pong(lost, ms(2100)), None);
+    }
+}
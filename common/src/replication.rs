@@ -0,0 +1,468 @@
+//! Replication of components from the server to the clients.
+//!
+//! A component type is replicated once it is registered in the `ReplicationRegistry` with a
+//! `ReplicationRule`. The server collects the values of the registered components of the entities
+//! marked with `Replicate` (and having a `NetworkId`), then sends them in `ComponentUpdate`s.
+//! The clients insert the values into the entity of the same `NetworkId`, which is spawned if
+//! there is none yet.
+//!
+//! The kind of a component on the wire is its position in the registry, so the server and the
+//! clients must register the same components in the same order (see `replicated_components`).
+
+use crate::components::{Health, MapPosition, NetworkId, Replicate};
+use crate::network::{ComponentKind, ComponentUpdate, ComponentValue};
+use bevy::ecs::schedule::SystemDescriptor;
+use bevy::prelude::*;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::TypeId;
+use std::collections::HashMap;
+
+/// How the values of a component are delivered
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ReplicationDelivery {
+    /// Every value arrives, in order
+    Reliable,
+    /// Values may be lost, an older one is dropped if a newer has arrived.
+    /// For the components changing often, the next value fixes a lost one.
+    Unreliable,
+}
+
+/// When the value of a component is sent
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UpdateRule {
+    /// Whenever it has changed
+    OnChange,
+    /// Only when it is added to the entity, its later changes are not sent
+    OnAdd,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReplicationRule {
+    pub delivery: ReplicationDelivery,
+    pub update: UpdateRule,
+}
+
+/// Values of replicated components queued with their delivery, see `Message`
+#[derive(Debug)]
+pub struct ReplicatedComponents {
+    pub delivery: ReplicationDelivery,
+    pub update: ComponentUpdate,
+}
+
+/// The components replicated by the game. The server and the clients both take them from here,
+/// so they agree on their kinds.
+pub fn replicated_components() -> ReplicationRegistry {
+    let mut registry = ReplicationRegistry::default();
+    registry.register::<Health>(ReplicationRule {
+        delivery: ReplicationDelivery::Reliable,
+        update: UpdateRule::OnChange,
+    });
+    registry.register::<MapPosition>(ReplicationRule {
+        delivery: ReplicationDelivery::Reliable,
+        update: UpdateRule::OnChange,
+    });
+    registry
+}
+
+struct Registration {
+    type_id: TypeId,
+    name: &'static str,
+    rule: ReplicationRule,
+    collect_system: fn() -> SystemDescriptor,
+    apply_system: fn() -> SystemDescriptor,
+}
+
+/// The replicated component types, their kind is the order of registration
+#[derive(Default, Resource)]
+pub struct ReplicationRegistry {
+    registrations: Vec<Registration>,
+}
+
+impl ReplicationRegistry {
+    pub fn register<C>(&mut self, rule: ReplicationRule) -> ComponentKind
+    where
+        C: Component + Serialize + DeserializeOwned,
+    {
+        let name = std::any::type_name::<C>();
+        assert!(self.kind_of::<C>().is_none(), "{} is already replicated", name);
+        let kind = ComponentKind::try_from(self.registrations.len())
+            .expect("Too many replicated components");
+        self.registrations.push(Registration {
+            type_id: TypeId::of::<C>(),
+            name,
+            rule,
+            collect_system: || collect_component_changes::<C>.into_descriptor(),
+            apply_system: || apply_component_updates::<C>.into_descriptor(),
+        });
+        kind
+    }
+
+    pub fn kind_of<C: Component>(&self) -> Option<ComponentKind> {
+        self.registrations
+            .iter()
+            .position(|registration| registration.type_id == TypeId::of::<C>())
+            .map(|index| index as ComponentKind)
+    }
+
+    pub fn rule(&self, kind: ComponentKind) -> Option<ReplicationRule> {
+        self.registration(kind).map(|registration| registration.rule)
+    }
+
+    /// Type name of the component, for the logs
+    pub fn name(&self, kind: ComponentKind) -> &str {
+        self.registration(kind)
+            .map_or("unknown component", |registration| registration.name)
+    }
+
+    /// A system for each component, collecting its values to be sent into the
+    /// `ReplicationBuffer`. Added to the server.
+    pub fn collect_systems(&self) -> Vec<SystemDescriptor> {
+        self.registrations
+            .iter()
+            .map(|registration| (registration.collect_system)())
+            .collect()
+    }
+
+    /// A system for each component, inserting its received values into the entities listed in
+    /// `ReplicatedEntities`. Added to the clients after `spawn_replicated_entities`.
+    pub fn apply_systems(&self) -> Vec<SystemDescriptor> {
+        self.registrations
+            .iter()
+            .map(|registration| (registration.apply_system)())
+            .collect()
+    }
+
+    fn registration(&self, kind: ComponentKind) -> Option<&Registration> {
+        self.registrations.get(kind as usize)
+    }
+}
+
+impl ComponentValue {
+    pub fn encode<C: Serialize>(
+        network_id: NetworkId,
+        kind: ComponentKind,
+        component: &C,
+    ) -> bincode::Result<Self> {
+        Ok(ComponentValue {
+            network_id,
+            kind,
+            data: bincode::DefaultOptions::new().serialize(component)?,
+        })
+    }
+
+    pub fn decode<C: DeserializeOwned>(&self) -> bincode::Result<C> {
+        bincode::DefaultOptions::new().deserialize(&self.data)
+    }
+}
+
+/// Component values to be sent by the server. The latest value of each component is kept until
+/// its entity is deleted, for the clients connecting later.
+#[derive(Debug, Default, Resource)]
+pub struct ReplicationBuffer {
+    changed: Vec<(ReplicationDelivery, ComponentValue)>,
+    latest: HashMap<(NetworkId, ComponentKind), (ReplicationDelivery, ComponentValue)>,
+}
+
+impl ReplicationBuffer {
+    pub fn push(&mut self, delivery: ReplicationDelivery, value: ComponentValue) {
+        self.latest
+            .insert((value.network_id, value.kind), (delivery, value.clone()));
+        self.changed.push((delivery, value));
+    }
+
+    /// The values pushed since the last call, grouped by their delivery
+    pub fn take_changed(&mut self) -> Vec<(ReplicationDelivery, Vec<ComponentValue>)> {
+        group_by_delivery(self.changed.drain(..))
+    }
+
+    /// The latest value of every replicated component, grouped by its delivery
+    pub fn current(&self) -> Vec<(ReplicationDelivery, Vec<ComponentValue>)> {
+        group_by_delivery(self.latest.values().cloned())
+    }
+
+    /// The entity has been deleted, its components are not sent anymore
+    pub fn forget(&mut self, network_id: &NetworkId) {
+        self.latest.retain(|(id, _), _| id != network_id);
+        self.changed.retain(|(_, value)| value.network_id != *network_id);
+    }
+}
+
+fn group_by_delivery(
+    values: impl Iterator<Item = (ReplicationDelivery, ComponentValue)>,
+) -> Vec<(ReplicationDelivery, Vec<ComponentValue>)> {
+    let mut grouped: Vec<(ReplicationDelivery, Vec<ComponentValue>)> = Vec::new();
+    for (delivery, value) in values {
+        match grouped.iter_mut().find(|(group, _)| *group == delivery) {
+            Some((_, values)) => values.push(value),
+            None => grouped.push((delivery, vec![value])),
+        }
+    }
+    grouped
+}
+
+/// Entities of the network ids on the client, updated by `spawn_replicated_entities`
+#[derive(Debug, Default, Resource)]
+pub struct ReplicatedEntities(HashMap<NetworkId, Entity>);
+
+impl ReplicatedEntities {
+    pub fn get(&self, network_id: &NetworkId) -> Option<Entity> {
+        self.0.get(network_id).copied()
+    }
+}
+
+/// Pushes the values of the component to be sent, as its rule says
+pub fn collect_component_changes<C: Component + Serialize>(
+    registry: Res<ReplicationRegistry>,
+    mut buffer: ResMut<ReplicationBuffer>,
+    components: Query<(&NetworkId, &C, ChangeTrackers<C>), With<Replicate>>,
+) {
+    let Some(kind) = registry.kind_of::<C>() else {
+        return;
+    };
+    let rule = registry.rule(kind).unwrap();
+
+    for (network_id, component, tracker) in components.iter() {
+        let send = match rule.update {
+            UpdateRule::OnChange => tracker.is_changed(),
+            UpdateRule::OnAdd => tracker.is_added(),
+        };
+        if !send {
+            continue;
+        }
+        match ComponentValue::encode(*network_id, kind, component) {
+            Ok(value) => buffer.push(rule.delivery, value),
+            Err(err) => log::error!(
+                "{} of {:?} could not be encoded: {}",
+                registry.name(kind),
+                network_id,
+                err
+            ),
+        }
+    }
+}
+
+/// Spawns an entity for each network id of the received updates, which is unknown to the client
+pub fn spawn_replicated_entities(
+    mut commands: Commands,
+    mut received: EventReader<ComponentUpdate>,
+    network_ids: Query<(Entity, &NetworkId)>,
+    mut entities: ResMut<ReplicatedEntities>,
+) {
+    let mut updates = received.iter().peekable();
+    if updates.peek().is_none() {
+        return;
+    }
+
+    entities.0 = network_ids
+        .iter()
+        .map(|(entity, network_id)| (*network_id, entity))
+        .collect();
+    for value in updates.flat_map(|update| update.components.iter()) {
+        entities
+            .0
+            .entry(value.network_id)
+            .or_insert_with(|| commands.spawn(value.network_id).id());
+    }
+}
+
+/// Inserts the received values of the component into their entities
+pub fn apply_component_updates<C: Component + DeserializeOwned>(
+    mut commands: Commands,
+    registry: Res<ReplicationRegistry>,
+    entities: Res<ReplicatedEntities>,
+    mut updates: EventReader<ComponentUpdate>,
+) {
+    let Some(kind) = registry.kind_of::<C>() else {
+        return;
+    };
+
+    let values = updates
+        .iter()
+        .flat_map(|update| update.components.iter())
+        .filter(|value| value.kind == kind);
+    for value in values {
+        let Some(entity) = entities.get(&value.network_id) else {
+            continue;
+        };
+        match value.decode::<C>() {
+            Ok(component) => {
+                commands.entity(entity).insert(component);
+            }
+            Err(err) => log::error!(
+                "{} of {:?} could not be decoded: {}",
+                registry.name(kind),
+                value.network_id,
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::EntityType;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Component, Serialize, Deserialize)]
+    struct Ammo(u32);
+
+    const RELIABLE_ON_CHANGE: ReplicationRule = ReplicationRule {
+        delivery: ReplicationDelivery::Reliable,
+        update: UpdateRule::OnChange,
+    };
+
+    fn registry() -> ReplicationRegistry {
+        let mut registry = ReplicationRegistry::default();
+        registry.register::<Health>(RELIABLE_ON_CHANGE);
+        registry.register::<Ammo>(ReplicationRule {
+            delivery: ReplicationDelivery::Unreliable,
+            update: UpdateRule::OnAdd,
+        });
+        registry
+    }
+
+    fn player(id: u32) -> NetworkId {
+        NetworkId::new(EntityType::Player, id)
+    }
+
+    fn value<C: Serialize>(id: u32, kind: ComponentKind, component: &C) -> ComponentValue {
+        ComponentValue::encode(player(id), kind, component).unwrap()
+    }
+
+    #[test]
+    fn components_are_kinded_in_order_of_registration() {
+        let registry = registry();
+
+        assert_eq!(registry.kind_of::<Health>(), Some(0));
+        assert_eq!(registry.kind_of::<Ammo>(), Some(1));
+        assert_eq!(registry.kind_of::<NetworkId>(), None);
+        assert_eq!(registry.rule(0), Some(RELIABLE_ON_CHANGE));
+        assert_eq!(registry.rule(2), None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn component_is_registered_once() {
+        let mut registry = registry();
+        registry.register::<Health>(RELIABLE_ON_CHANGE);
+    }
+
+    #[test]
+    fn component_value_round_trip() {
+        let value = value(1, 0, &Health(42));
+
+        assert_eq!(value.decode::<Health>().unwrap(), Health(42));
+    }
+
+    #[test]
+    fn changed_values_are_taken_by_delivery() {
+        let mut buffer = ReplicationBuffer::default();
+        buffer.push(ReplicationDelivery::Reliable, value(1, 0, &Health(100)));
+        buffer.push(ReplicationDelivery::Unreliable, value(1, 1, &Ammo(6)));
+        buffer.push(ReplicationDelivery::Reliable, value(1, 0, &Health(42)));
+
+        assert_eq!(
+            buffer.take_changed(),
+            vec![
+                (
+                    ReplicationDelivery::Reliable,
+                    vec![value(1, 0, &Health(100)), value(1, 0, &Health(42))]
+                ),
+                (ReplicationDelivery::Unreliable, vec![value(1, 1, &Ammo(6))]),
+            ]
+        );
+        assert!(buffer.take_changed().is_empty());
+    }
+
+    #[test]
+    fn only_latest_values_of_living_entities_are_current() {
+        let mut buffer = ReplicationBuffer::default();
+        buffer.push(ReplicationDelivery::Reliable, value(1, 0, &Health(100)));
+        buffer.push(ReplicationDelivery::Reliable, value(1, 0, &Health(42)));
+        buffer.push(ReplicationDelivery::Reliable, value(2, 0, &Health(7)));
+        buffer.forget(&player(2));
+        buffer.push(ReplicationDelivery::Unreliable, value(1, 1, &Ammo(6)));
+
+        let current = buffer.current();
+        assert_eq!(current.len(), 2);
+        assert!(current.contains(&(ReplicationDelivery::Reliable, vec![value(1, 0, &Health(42))])));
+        assert!(current.contains(&(ReplicationDelivery::Unreliable, vec![value(1, 1, &Ammo(6))])));
+        assert_eq!(
+            buffer.take_changed(),
+            vec![
+                (
+                    ReplicationDelivery::Reliable,
+                    vec![value(1, 0, &Health(100)), value(1, 0, &Health(42))]
+                ),
+                (ReplicationDelivery::Unreliable, vec![value(1, 1, &Ammo(6))]),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_of_replicated_entities_are_collected() {
+        let registry = registry();
+        let mut app = App::new();
+        for system in registry.collect_systems() {
+            app.add_system(system);
+        }
+        app.insert_resource(registry).init_resource::<ReplicationBuffer>();
+        let replicated = app.world.spawn((player(1), Health(100), Ammo(6), Replicate)).id();
+        app.world.spawn((player(2), Health(100)));
+
+        app.update();
+        let mut buffer = app.world.resource_mut::<ReplicationBuffer>();
+        assert_eq!(
+            buffer.take_changed(),
+            vec![
+                (ReplicationDelivery::Reliable, vec![value(1, 0, &Health(100))]),
+                (ReplicationDelivery::Unreliable, vec![value(1, 1, &Ammo(6))]),
+            ]
+        );
+
+        app.update();
+        assert!(app.world.resource_mut::<ReplicationBuffer>().take_changed().is_empty());
+
+        // Ammo is sent only when added
+        app.world.entity_mut(replicated).insert((Health(42), Ammo(5)));
+        app.update();
+        assert_eq!(
+            app.world.resource_mut::<ReplicationBuffer>().take_changed(),
+            vec![(ReplicationDelivery::Reliable, vec![value(1, 0, &Health(42))])]
+        );
+    }
+
+    #[test]
+    fn received_values_are_inserted_into_entities_spawned_if_unknown() {
+        let registry = registry();
+        let mut app = App::new();
+        app.add_event::<ComponentUpdate>()
+            .init_resource::<ReplicatedEntities>()
+            .add_system(spawn_replicated_entities.label("spawn"));
+        for system in registry.apply_systems() {
+            app.add_system(system.after("spawn"));
+        }
+        app.insert_resource(registry);
+        let known = app.world.spawn((player(1), Health(100))).id();
+
+        app.world.send_event(ComponentUpdate {
+            tick: 10,
+            components: vec![
+                value(1, 0, &Health(42)),
+                value(2, 0, &Health(7)),
+                value(2, 1, &Ammo(3)),
+            ],
+        });
+        app.update();
+
+        assert_eq!(app.world.get::<Health>(known), Some(&Health(42)));
+        let spawned = app.world.resource::<ReplicatedEntities>().get(&player(2)).unwrap();
+        assert_eq!(app.world.get::<NetworkId>(spawned), Some(&player(2)));
+        assert_eq!(app.world.get::<Health>(spawned), Some(&Health(7)));
+        assert_eq!(app.world.get::<Ammo>(spawned), Some(&Ammo(3)));
+        assert_eq!(app.world.query::<&NetworkId>().iter(&app.world).count(), 2);
+    }
+}
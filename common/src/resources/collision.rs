@@ -1,5 +1,7 @@
 use crate::metric_dimension::length::MeterVec2;
+use crate::metric_dimension::Second;
 use bevy::prelude::{Entity, Resource};
+use serde::Deserialize;
 
 pub struct Collision {
     pub collider: Entity, // moving
@@ -18,3 +20,17 @@ pub struct ProjectileCollision {
 
 #[derive(Default, Resource)]
 pub struct ProjectileCollisions(pub Vec<ProjectileCollision>);
+
+/// Limits how far back in time projectile hits are resolved
+#[derive(Copy, Clone, Debug, Deserialize, Resource)]
+pub struct LagCompensationConfig {
+    pub max_rewind: Second,
+}
+
+impl Default for LagCompensationConfig {
+    fn default() -> Self {
+        LagCompensationConfig {
+            max_rewind: Second(0.25),
+        }
+    }
+}
@@ -1,7 +1,9 @@
-use crate::entities;
+use crate::components::MapEntityType;
+use crate::entities::BARREL_DIAMETER;
+use crate::metric_dimension::length::MeterVec2;
 use crate::resources::map::MapError::{InvalidMapCharacter, SeedError};
 use crate::resources::Seed;
-use bevy::prelude::{Commands, Vec2};
+use bevy::prelude::Vec2;
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::{BufReader, Read};
@@ -13,7 +15,15 @@ const EMPTY_CHAR: char = ' ';
 
 const MAP_OFFSET: (i32, i32) = (-32, -32);
 
-pub fn build_map(mut commands: Commands, seed: Seed, map_files_dir: &Path) -> Result<(), MapError> {
+/// An entity placed on the map
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MapEntity {
+    pub entity_type: MapEntityType,
+    pub position: MeterVec2,
+}
+
+/// Reads the entities of the map generated from the seed
+pub fn read_map(seed: Seed, map_files_dir: &Path) -> Result<Vec<MapEntity>, MapError> {
     if seed.0 == 0 {
         let map_file_path = map_files_dir.join("rust2.wmap");
         let open_file_result = File::open(&map_file_path);
@@ -21,39 +31,45 @@ pub fn build_map(mut commands: Commands, seed: Seed, map_files_dir: &Path) -> Re
             return Err(MapError::MapFileError(map_file_path, err));
         }
 
-        let map_bytes = BufReader::new(open_file_result.unwrap()).bytes();
+        parse_map(BufReader::new(open_file_result.unwrap()), &map_file_path)
+    } else {
+        Err(SeedError(seed))
+    }
+}
 
-        let mut x = 0;
-        let mut y = 0;
-        for byte in map_bytes {
-            if let Err(err) = byte {
-                return Err(MapError::MapFileError(map_file_path, err));
+fn parse_map(map: impl Read, map_file_path: &Path) -> Result<Vec<MapEntity>, MapError> {
+    let mut map_entities = Vec::new();
+    let mut x = 0;
+    let mut y = 0;
+    for byte in map.bytes() {
+        if let Err(err) = byte {
+            return Err(MapError::MapFileError(map_file_path.to_path_buf(), err));
+        }
+        match byte.unwrap() as char {
+            BARREL_CHAR => {
+                // place a barrel
+                let pos = Vec2::new((x + MAP_OFFSET.0) as f32, -(y + MAP_OFFSET.1) as f32);
+                map_entities.push(MapEntity {
+                    entity_type: MapEntityType::Barrel,
+                    position: BARREL_DIAMETER * pos,
+                });
+                x += 1;
+            }
+            EMPTY_CHAR => {
+                // place nothing
+                x += 1;
             }
-            match byte.unwrap() as char {
-                BARREL_CHAR => {
-                    // spawn a barrel
-                    let pos = Vec2::new((x + MAP_OFFSET.0) as f32, -(y + MAP_OFFSET.1) as f32);
-                    entities::place_barrel(&mut commands, pos);
-                    x += 1;
-                }
-                EMPTY_CHAR => {
-                    // spawn nothing
-                    x += 1;
-                }
-                '\n' => {
-                    // just step to next row
-                    x = 0;
-                    y += 1;
-                }
-                '\r' => {}
-                other => return Err(InvalidMapCharacter(other, x, y)),
+            '\n' => {
+                // just step to next row
+                x = 0;
+                y += 1;
             }
+            '\r' => {}
+            other => return Err(InvalidMapCharacter(other, x, y)),
         }
-
-        Ok(())
-    } else {
-        Err(SeedError(seed))
     }
+
+    Ok(map_entities)
 }
 
 #[derive(Debug)]
@@ -81,3 +97,39 @@ impl Display for MapError {
 }
 
 impl std::error::Error for MapError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn barrels_are_placed_by_their_characters() {
+        let map = "x \r\n x\n";
+        let map_entities = parse_map(map.as_bytes(), Path::new("test.wmap")).unwrap();
+        assert_eq!(
+            map_entities,
+            vec![
+                MapEntity {
+                    entity_type: MapEntityType::Barrel,
+                    position: MeterVec2::from_raw(-32.0, 32.0),
+                },
+                MapEntity {
+                    entity_type: MapEntityType::Barrel,
+                    position: MeterVec2::from_raw(-31.0, 31.0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_characters_are_rejected() {
+        let result = parse_map("x\n  ?".as_bytes(), Path::new("test.wmap"));
+        assert!(matches!(result, Err(InvalidMapCharacter('?', 2, 1))));
+    }
+
+    #[test]
+    fn only_the_default_seed_has_a_map() {
+        let result = read_map(Seed(42), Path::new("."));
+        assert!(matches!(result, Err(SeedError(Seed(42)))));
+    }
+}
@@ -1,9 +1,11 @@
 pub use audio::{AudioQueue, SoundId};
-pub use map::build_map;
+pub use map::{read_map, MapEntity};
+pub use simulation::SimulationTime;
 
 mod audio;
 pub mod collision;
 pub mod map;
+mod simulation;
 pub mod weapon;
 
 use serde::{Deserialize, Serialize};
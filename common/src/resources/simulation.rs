@@ -0,0 +1,42 @@
+use crate::metric_dimension::Second;
+use crate::network::Tick;
+use bevy::prelude::Resource;
+use std::time::Duration;
+
+/// Current tick of the simulation and the time step it has advanced by.
+/// The server advances it by a fixed step, so the simulation does not depend on its frame rate.
+#[derive(Debug, Default, Resource)]
+pub struct SimulationTime {
+    tick: Tick,
+    step: Duration,
+}
+
+impl SimulationTime {
+    pub fn advance(&mut self, step: Duration) {
+        self.tick = self.tick.wrapping_add(1);
+        self.step = step;
+    }
+
+    pub fn tick(&self) -> Tick {
+        self.tick
+    }
+
+    pub fn delta_seconds(&self) -> Second {
+        Second(self.step.as_secs_f32())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn advance_increments_tick_and_sets_step() {
+        let mut simulation_time = SimulationTime::default();
+        simulation_time.advance(Duration::from_millis(20));
+        simulation_time.advance(Duration::from_millis(50));
+
+        assert_eq!(simulation_time.tick(), 2);
+        assert_eq!(simulation_time.delta_seconds(), Second(0.05));
+    }
+}
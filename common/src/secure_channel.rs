@@ -0,0 +1,986 @@
+//! Encryption of the traffic after the connection handshake.
+//!
+//! The client sends an X25519 key share in its `ConnectionRequest`. The server answers with its
+//! own share, signed with its Ed25519 identity key, in front of every datagram it sends to the
+//! client until the client has proven to have the session (the first sealed datagram of the
+//! client). This way the share arrives with the `ConnectionResponse` (or the `AuthChallenge`)
+//! whichever datagram makes it through first.
+//! Both peers derive a key for each direction from the shared secret with HKDF-SHA256, then every
+//! datagram is sealed with ChaCha20-Poly1305. The sequence number of a datagram is its nonce, the
+//! ones received are remembered so a replayed datagram is rejected. The reliable datagrams are
+//! resent by the transport as they were sealed, so they have their own sequence numbers, none of
+//! which is too old to be received.
+//!
+//! The layer works on the serialized datagrams, between the transport and the systems, which are
+//! unaware of it.
+
+use crate::network::PacketType;
+use crate::serialization::{deserialize, CONNECTION_REQUEST_TAG};
+use bevy::ecs::event::ManualEventReader;
+use bevy::prelude::{Events, Local, ResMut, Resource};
+use blaminar::simulation::{DeliveryRequirement, NetworkSimulationEvent, TransportResource};
+use blaminar::Bytes;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use x25519_dalek::EphemeralSecret;
+
+/// X25519 public key sent by each peer in the key exchange
+pub type KeyShare = [u8; 32];
+/// Ed25519 public key of the server, the clients can pin it
+pub type IdentityKey = [u8; 32];
+
+/// First byte of a sealed datagram, followed by its `Lane`, the sequence number as a little endian
+/// `u64` and the ciphertext with its authentication tag
+pub const SEALED_TAG: u8 = b'S';
+/// First byte of a sealed datagram of the server in front of which the key share is offered,
+/// followed by the identity key, the key share and its signature, then the sealed datagram
+pub const OFFER_TAG: u8 = b'K';
+
+/// File in the resources directory with the hex encoded identity key of the server. The server
+/// writes its key into it when it starts, the clients sharing the directory pin that server.
+pub const SERVER_KEY_FILE: &str = "server_key.txt";
+
+/// The server forgets the sessions of the addresses which have not joined within this period
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Unreliable sequence numbers this much behind the highest one received are rejected
+const REPLAY_WINDOW: u64 = 64;
+/// Reliable datagrams received ahead of a missing one; when there are more, the missing ones are
+/// not waited for anymore
+const MAX_RELIABLE_GAP: usize = 1024;
+
+const SEQUENCE_SIZE: usize = std::mem::size_of::<u64>();
+const HEADER_SIZE: usize = 2 + SEQUENCE_SIZE;
+const SIGNATURE_SIZE: usize = 64;
+const OFFER_SIZE: usize = 1 + 32 + 32 + SIGNATURE_SIZE;
+const AUTH_TAG_SIZE: usize = 16;
+
+/// Upper bound of the bytes sealing adds to a datagram, the offer in front of it included
+pub const MAX_SEAL_OVERHEAD: usize = OFFER_SIZE + HEADER_SIZE + AUTH_TAG_SIZE;
+
+const SIGNATURE_CONTEXT: &[u8] = b"westiny key exchange";
+const CLIENT_TO_SERVER: &[u8] = b"westiny client to server";
+const SERVER_TO_CLIENT: &[u8] = b"westiny server to client";
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum ChannelError {
+    #[error("Datagram is truncated")]
+    Truncated,
+    #[error("Datagram could not be authenticated")]
+    Forged,
+    #[error("Datagram {0} has already been received or it is too old")]
+    Replayed(u64),
+    #[error("Datagram is not sealed, but there is a session with the peer")]
+    Unsealed,
+    #[error("Datagram is sealed, but there is no session with the peer")]
+    NoSession,
+    #[error("Key share is invalid")]
+    InvalidKeyShare,
+    #[error("Key share is not signed by the trusted server")]
+    UntrustedServer,
+}
+
+impl ChannelError {
+    /// Whether an honest peer may have sent the datagram, e.g. duplicated by the network or sealed
+    /// in a session which has already been forgotten
+    pub fn is_benign(&self) -> bool {
+        matches!(self, ChannelError::Replayed(_) | ChannelError::NoSession)
+    }
+}
+
+/// Long-term key of the server, the key shares of the server are signed with it
+#[derive(Clone)]
+pub struct ServerIdentity(SigningKey);
+
+impl ServerIdentity {
+    pub fn generate() -> Self {
+        ServerIdentity(SigningKey::generate(&mut OsRng))
+    }
+
+    /// The hex encoded secret key
+    pub fn from_hex(hex: &str) -> anyhow::Result<Self> {
+        Ok(ServerIdentity(SigningKey::from_bytes(&decode_hex(hex)?)))
+    }
+
+    pub fn public_key(&self) -> IdentityKey {
+        self.0.verifying_key().to_bytes()
+    }
+}
+
+/// The servers a client makes a session with
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ServerTrust {
+    /// Only the server with this identity key
+    Pinned(IdentityKey),
+    /// Any server, the sessions are not protected from a man in the middle
+    Any,
+}
+
+impl ServerTrust {
+    /// `WESTINY_SERVER_KEY` (hex), otherwise the key in `SERVER_KEY_FILE` of the resources
+    /// directory. Any server is trusted only if `WESTINY_TRUST_ANY_SERVER` is set and no key is
+    /// given. An invalid or missing key is an error, it never falls back to trusting any server.
+    pub fn from_env(resources_dir: &Path) -> anyhow::Result<Self> {
+        if let Ok(hex) = std::env::var("WESTINY_SERVER_KEY") {
+            return decode_hex(&hex)
+                .map(ServerTrust::Pinned)
+                .map_err(|err| anyhow::anyhow!("Invalid WESTINY_SERVER_KEY: {}", err));
+        }
+        if std::env::var_os("WESTINY_TRUST_ANY_SERVER").is_some() {
+            log::warn!("WESTINY_TRUST_ANY_SERVER is set, the server is not authenticated");
+            return Ok(ServerTrust::Any);
+        }
+        let path = resources_dir.join(SERVER_KEY_FILE);
+        let hex = std::fs::read_to_string(&path).map_err(|err| {
+            anyhow::anyhow!(
+                "No identity key of the server in {}: {}. Set WESTINY_SERVER_KEY to the key \
+                 logged by the server, or WESTINY_TRUST_ANY_SERVER to trust any server",
+                path.display(),
+                err
+            )
+        })?;
+        decode_hex(&hex)
+            .map(ServerTrust::Pinned)
+            .map_err(|err| anyhow::anyhow!("Invalid identity key in {}: {}", path.display(), err))
+    }
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// 32 bytes in hex, e.g. an `IdentityKey`
+pub fn decode_hex(hex: &str) -> anyhow::Result<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        anyhow::bail!("Expected 64 hex digits, got: {}", hex);
+    }
+    let mut bytes = [0; 32];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits)?, 16)?;
+    }
+    Ok(bytes)
+}
+
+/// Sequence numbers of the sealed datagrams, separate for each way they are delivered
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Lane {
+    Unreliable,
+    Reliable,
+}
+
+impl Lane {
+    fn of(delivery: DeliveryRequirement) -> Self {
+        match delivery {
+            DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_) => {
+                Lane::Unreliable
+            }
+            _ => Lane::Reliable,
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Lane::Unreliable => b'U',
+            Lane::Reliable => b'R',
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, ChannelError> {
+        match tag {
+            b'U' => Ok(Lane::Unreliable),
+            b'R' => Ok(Lane::Reliable),
+            _ => Err(ChannelError::Forged),
+        }
+    }
+}
+
+/// The unreliable datagrams of the last 64 sequence numbers which have been received. Older ones
+/// are rejected, a datagram delayed that much is not worth the memory it takes to accept it.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `n` is set if `highest - n` has been received
+    received: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, sequence: u64) -> Result<(), ChannelError> {
+        match self.highest {
+            Some(highest) if sequence <= highest => {
+                let age = highest - sequence;
+                if age >= REPLAY_WINDOW || self.received & (1 << age) != 0 {
+                    Err(ChannelError::Replayed(sequence))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn mark(&mut self, sequence: u64) {
+        match self.highest {
+            Some(highest) if sequence <= highest => self.received |= 1 << (highest - sequence),
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.received = if shift >= REPLAY_WINDOW {
+                    1
+                } else {
+                    (self.received << shift) | 1
+                };
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.received = 1;
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+/// The reliable datagrams which have been received. A lost one is resent by the transport with
+/// the same sequence number, so it is accepted however late it arrives.
+#[derive(Debug, Default)]
+struct ReliableWindow {
+    /// Every sequence number below it has been received
+    floor: u64,
+    /// The ones received above the floor
+    received: BTreeSet<u64>,
+}
+
+impl ReliableWindow {
+    fn check(&self, sequence: u64) -> Result<(), ChannelError> {
+        if sequence < self.floor || self.received.contains(&sequence) {
+            Err(ChannelError::Replayed(sequence))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn mark(&mut self, sequence: u64) {
+        self.received.insert(sequence);
+        if self.received.len() > MAX_RELIABLE_GAP {
+            if let Some(&lowest) = self.received.first() {
+                self.floor = lowest;
+            }
+        }
+        while self.received.remove(&self.floor) {
+            self.floor += 1;
+        }
+    }
+}
+
+/// The signed key share of the server
+#[derive(Clone)]
+struct KeyOffer {
+    identity: IdentityKey,
+    share: KeyShare,
+    signature: [u8; SIGNATURE_SIZE],
+}
+
+impl KeyOffer {
+    fn write(&self, datagram: &mut Vec<u8>) {
+        datagram.push(OFFER_TAG);
+        datagram.extend(self.identity);
+        datagram.extend(self.share);
+        datagram.extend(self.signature);
+    }
+
+    /// The offer and the sealed datagram following it
+    fn read(datagram: &[u8]) -> Result<(KeyOffer, &[u8]), ChannelError> {
+        if datagram.len() < OFFER_SIZE {
+            return Err(ChannelError::Truncated);
+        }
+        let (offer, sealed) = datagram[1..].split_at(OFFER_SIZE - 1);
+        let (identity, rest) = offer.split_at(32);
+        let (share, signature) = rest.split_at(32);
+        Ok((
+            KeyOffer {
+                identity: identity.try_into().unwrap(),
+                share: share.try_into().unwrap(),
+                signature: signature.try_into().unwrap(),
+            },
+            sealed,
+        ))
+    }
+
+    fn verify(&self, client_share: &KeyShare) -> Result<(), ChannelError> {
+        let identity =
+            VerifyingKey::from_bytes(&self.identity).map_err(|_| ChannelError::UntrustedServer)?;
+        identity
+            .verify_strict(
+                &signed_message(client_share, &self.share),
+                &Signature::from_bytes(&self.signature),
+            )
+            .map_err(|_| ChannelError::UntrustedServer)
+    }
+}
+
+fn signed_message(client_share: &KeyShare, server_share: &KeyShare) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, &client_share[..], &server_share[..]].concat()
+}
+
+/// Sealing and opening keys of one peer
+struct Session {
+    /// Key share of the client, tells a repeated connection request from a new one
+    client_share: KeyShare,
+    sealer: ChaCha20Poly1305,
+    opener: ChaCha20Poly1305,
+    next_unreliable: u64,
+    next_reliable: u64,
+    unreliable_received: ReplayWindow,
+    reliable_received: ReliableWindow,
+    /// Sent in front of the datagrams of the server until the client has used the session
+    offer: Option<Box<KeyOffer>>,
+    since: Duration,
+}
+
+impl Session {
+    /// Directional keys from the shared secret, the key shares of both peers are bound to them
+    fn new(
+        is_server: bool,
+        shared_secret: &[u8; 32],
+        client_share: KeyShare,
+        server_share: &KeyShare,
+        since: Duration,
+    ) -> Self {
+        let salt = [client_share, *server_share].concat();
+        let hkdf = Hkdf::<Sha256>::new(Some(salt.as_slice()), shared_secret);
+        let key = |info: &[u8]| {
+            let mut key = [0; 32];
+            hkdf.expand(info, &mut key).expect("32 bytes is a valid HKDF-SHA256 output length");
+            ChaCha20Poly1305::new(&key.into())
+        };
+        let (sealer, opener) = if is_server {
+            (key(SERVER_TO_CLIENT), key(CLIENT_TO_SERVER))
+        } else {
+            (key(CLIENT_TO_SERVER), key(SERVER_TO_CLIENT))
+        };
+        Session {
+            client_share,
+            sealer,
+            opener,
+            next_unreliable: 0,
+            next_reliable: 0,
+            unreliable_received: ReplayWindow::default(),
+            reliable_received: ReliableWindow::default(),
+            offer: None,
+            since,
+        }
+    }
+
+    fn seal(&mut self, payload: &[u8], lane: Lane) -> Vec<u8> {
+        let next = match lane {
+            Lane::Unreliable => &mut self.next_unreliable,
+            Lane::Reliable => &mut self.next_reliable,
+        };
+        let sequence = *next;
+        *next += 1;
+
+        let mut datagram = Vec::with_capacity(MAX_SEAL_OVERHEAD + payload.len());
+        if let Some(offer) = &self.offer {
+            offer.write(&mut datagram);
+        }
+        let header_start = datagram.len();
+        datagram.push(SEALED_TAG);
+        datagram.push(lane.tag());
+        datagram.extend(sequence.to_le_bytes());
+        let ciphertext = self
+            .sealer
+            .encrypt(
+                &nonce(lane, sequence).into(),
+                Payload {
+                    msg: payload,
+                    aad: &datagram[header_start..],
+                },
+            )
+            .expect("Encryption does not fail below the size limit of a datagram");
+        datagram.extend(ciphertext);
+        datagram
+    }
+
+    /// The sequence number is recorded only if the datagram is authentic
+    fn open(&mut self, datagram: &[u8]) -> Result<Vec<u8>, ChannelError> {
+        if datagram.len() < HEADER_SIZE {
+            return Err(ChannelError::Truncated);
+        }
+        let (header, ciphertext) = datagram.split_at(HEADER_SIZE);
+        let lane = Lane::from_tag(header[1])?;
+        let sequence = u64::from_le_bytes(header[2..].try_into().unwrap());
+        match lane {
+            Lane::Unreliable => self.unreliable_received.check(sequence)?,
+            Lane::Reliable => self.reliable_received.check(sequence)?,
+        }
+        let payload = self
+            .opener
+            .decrypt(
+                &nonce(lane, sequence).into(),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| ChannelError::Forged)?;
+        match lane {
+            Lane::Unreliable => self.unreliable_received.mark(sequence),
+            Lane::Reliable => self.reliable_received.mark(sequence),
+        }
+        Ok(payload)
+    }
+}
+
+/// The lanes have their own sequence numbers, the nonces of the lanes must differ
+fn nonce(lane: Lane, sequence: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[0] = lane.tag();
+    nonce[4..].copy_from_slice(&sequence.to_le_bytes());
+    nonce
+}
+
+/// The client waiting for the key share of the server
+struct Handshake {
+    secret: EphemeralSecret,
+    share: KeyShare,
+}
+
+enum Peer {
+    Handshake(Handshake),
+    Session(Session),
+}
+
+enum Role {
+    Server(ServerIdentity),
+    Client(ServerTrust),
+}
+
+/// Secure sessions with the peers, by their address
+#[derive(Resource)]
+pub struct SecureChannels {
+    role: Role,
+    peers: HashMap<SocketAddr, Peer>,
+}
+
+impl SecureChannels {
+    pub fn server(identity: ServerIdentity) -> Self {
+        SecureChannels {
+            role: Role::Server(identity),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn client(trust: ServerTrust) -> Self {
+        SecureChannels {
+            role: Role::Client(trust),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Identity key of the server, `None` on a client
+    pub fn identity_key(&self) -> Option<IdentityKey> {
+        match &self.role {
+            Role::Server(identity) => Some(identity.public_key()),
+            Role::Client(_) => None,
+        }
+    }
+
+    /// Key share of the client for the connection request, the same until the session is
+    /// forgotten, so the repeated requests are recognized
+    pub fn key_share(&mut self, server: SocketAddr) -> KeyShare {
+        let peer = self.peers.entry(server).or_insert_with(|| {
+            let secret = EphemeralSecret::random_from_rng(OsRng);
+            let share = x25519_dalek::PublicKey::from(&secret).to_bytes();
+            Peer::Handshake(Handshake { secret, share })
+        });
+        match peer {
+            Peer::Handshake(handshake) => handshake.share,
+            Peer::Session(session) => session.client_share,
+        }
+    }
+
+    /// Key share of the client the session with the peer has been made from
+    pub fn client_share(&self, addr: &SocketAddr) -> Option<KeyShare> {
+        match self.peers.get(addr) {
+            Some(Peer::Session(session)) => Some(session.client_share),
+            _ => None,
+        }
+    }
+
+    /// Makes a session with the client on the server, replacing its previous one unless it is
+    /// made from the same key share (a repeated connection request)
+    pub fn accept(
+        &mut self,
+        addr: SocketAddr,
+        client_share: KeyShare,
+        now: Duration,
+    ) -> Result<(), ChannelError> {
+        let identity = match &self.role {
+            Role::Server(identity) => identity,
+            Role::Client(_) => panic!("Only the server accepts key exchanges"),
+        };
+        if self.client_share(&addr) == Some(client_share) {
+            return Ok(());
+        }
+
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_share = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        let shared_secret = secret.diffie_hellman(&client_share.into());
+        if !shared_secret.was_contributory() {
+            return Err(ChannelError::InvalidKeyShare);
+        }
+        let signature = identity
+            .0
+            .sign(&signed_message(&client_share, &server_share))
+            .to_bytes();
+
+        let mut session = Session::new(
+            true,
+            shared_secret.as_bytes(),
+            client_share,
+            &server_share,
+            now,
+        );
+        session.offer = Some(Box::new(KeyOffer {
+            identity: identity.public_key(),
+            share: server_share,
+            signature,
+        }));
+        self.peers.insert(addr, Peer::Session(session));
+        Ok(())
+    }
+
+    /// Whether the datagrams to the peer are sealed
+    pub fn has_session(&self, addr: &SocketAddr) -> bool {
+        matches!(self.peers.get(addr), Some(Peer::Session(_)))
+    }
+
+    pub fn forget(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+    }
+
+    /// Keeps the sessions of the peers for which `keep` holds and the ones made within
+    /// `HANDSHAKE_TIMEOUT`
+    pub fn retain<F>(&mut self, now: Duration, keep: F)
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        self.peers.retain(|addr, peer| match peer {
+            Peer::Session(session) => {
+                keep(addr) || now.saturating_sub(session.since) < HANDSHAKE_TIMEOUT
+            }
+            Peer::Handshake(_) => true,
+        });
+    }
+
+    /// The sealed datagram if there is a session with the peer, `None` otherwise
+    pub fn seal(
+        &mut self,
+        addr: &SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+    ) -> Option<Vec<u8>> {
+        match self.peers.get_mut(addr) {
+            Some(Peer::Session(session)) => Some(session.seal(payload, Lane::of(delivery))),
+            _ => None,
+        }
+    }
+
+    /// The opened datagram if it is sealed, `None` if it is not sealed and may be accepted as it
+    /// is. The server accepts anything before the session with the peer and the connection
+    /// requests of a new handshake. The client accepts only the refusals of the connection before
+    /// the session, as everything else the server sends is sealed.
+    pub fn open(
+        &mut self,
+        addr: &SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Option<Vec<u8>>, ChannelError> {
+        match datagram.first() {
+            Some(&OFFER_TAG) => self.open_offered(addr, datagram).map(Some),
+            Some(&SEALED_TAG) => match self.peers.get_mut(addr) {
+                Some(Peer::Session(session)) => {
+                    let payload = session.open(datagram)?;
+                    // The client has the session, it does not need the offer anymore
+                    session.offer = None;
+                    Ok(Some(payload))
+                }
+                _ => Err(ChannelError::NoSession),
+            },
+            // Decoded by the server, which answers the requests of another protocol version too
+            Some(&CONNECTION_REQUEST_TAG) if matches!(self.role, Role::Server(_)) => Ok(None),
+            _ if self.has_session(addr) => Err(ChannelError::Unsealed),
+            _ if matches!(self.role, Role::Server(_)) => Ok(None),
+            // The server refuses in plain the clients it has not made a session with
+            _ if is_refusal(datagram) => Ok(None),
+            _ => Err(ChannelError::Unsealed),
+        }
+    }
+
+    /// Makes the session of the client from the offer of the server, if it has not been made yet
+    fn open_offered(
+        &mut self,
+        addr: &SocketAddr,
+        datagram: &[u8],
+    ) -> Result<Vec<u8>, ChannelError> {
+        let trust = match self.role {
+            Role::Client(trust) => trust,
+            Role::Server(_) => return Err(ChannelError::Forged),
+        };
+        let (offer, sealed) = KeyOffer::read(datagram)?;
+
+        let pending_share = match self.peers.get(addr) {
+            Some(Peer::Handshake(handshake)) => Some(handshake.share),
+            _ => None,
+        };
+        if let Some(client_share) = pending_share {
+            if matches!(trust, ServerTrust::Pinned(trusted) if trusted != offer.identity) {
+                return Err(ChannelError::UntrustedServer);
+            }
+            offer.verify(&client_share)?;
+            let Some(Peer::Handshake(handshake)) = self.peers.remove(addr) else {
+                unreachable!("The peer is in handshake")
+            };
+            let shared_secret = handshake.secret.diffie_hellman(&offer.share.into());
+            if !shared_secret.was_contributory() {
+                return Err(ChannelError::InvalidKeyShare);
+            }
+            log::info!("Secure session with server {}", encode_hex(&offer.identity));
+            let session = Session::new(
+                false,
+                shared_secret.as_bytes(),
+                handshake.share,
+                &offer.share,
+                Duration::ZERO,
+            );
+            self.peers.insert(*addr, Peer::Session(session));
+        }
+
+        match self.peers.get_mut(addr) {
+            Some(Peer::Session(session)) if sealed.first() == Some(&SEALED_TAG) => {
+                session.open(sealed)
+            }
+            Some(Peer::Session(_)) => Err(ChannelError::Forged),
+            _ => Err(ChannelError::NoSession),
+        }
+    }
+}
+
+fn is_refusal(datagram: &[u8]) -> bool {
+    matches!(deserialize(datagram), Ok(PacketType::ConnectionResponse(Err(_))))
+}
+
+/// Seals the datagrams queued to the peers with a session.
+/// Must run after every system sending packets, the transport sends them in the next frame.
+pub fn seal_packets(mut channels: ResMut<SecureChannels>, mut net: ResMut<TransportResource>) {
+    for message in net.drain_messages(|_| true) {
+        match channels.seal(&message.destination, &message.payload, message.delivery) {
+            Some(sealed) => net.send_with_requirements(
+                message.destination,
+                &sealed,
+                message.delivery,
+                message.urgency,
+            ),
+            None => net.send_with_requirements(
+                message.destination,
+                &message.payload,
+                message.delivery,
+                message.urgency,
+            ),
+        }
+    }
+}
+
+/// Opens the sealed datagrams received, the ones which cannot be opened are dropped.
+/// Must run after the transport has received them and before any system reading them.
+pub fn open_packets(
+    mut channels: ResMut<SecureChannels>,
+    mut events: ResMut<Events<NetworkSimulationEvent>>,
+    mut reader: Local<ManualEventReader<NetworkSimulationEvent>>,
+) {
+    replace_received(&mut events, &mut reader, |addr, datagram| {
+        match channels.open(addr, &datagram) {
+            Ok(Some(payload)) => Some(payload.into()),
+            Ok(None) => Some(datagram),
+            Err(err) => {
+                log::debug!("Datagram from {} dropped: {}", addr, err);
+                None
+            }
+        }
+    });
+}
+
+/// Replaces the datagrams received since the last run with what `open` makes of them, the ones
+/// it returns `None` for are dropped. The other events are sent again as they are.
+pub fn replace_received<F>(
+    events: &mut Events<NetworkSimulationEvent>,
+    reader: &mut ManualEventReader<NetworkSimulationEvent>,
+    mut open: F,
+) where
+    F: FnMut(&SocketAddr, Bytes) -> Option<Bytes>,
+{
+    // The events of the previous frames have already been read, only the new ones are sent again
+    let new_events = reader.iter(events).count();
+    let events_received: Vec<_> = events.drain().collect();
+    let old_events = events_received.len() - new_events;
+
+    for event in events_received.into_iter().skip(old_events) {
+        match event {
+            NetworkSimulationEvent::Message(addr, datagram) => {
+                if let Some(payload) = open(&addr, datagram) {
+                    events.send(NetworkSimulationEvent::Message(addr, payload))
+                }
+            }
+            event => events.send(event),
+        }
+    }
+    // Not new for the next run
+    reader.iter(events).for_each(|_| ());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::network::{self, AuthChallenge, ErrorKind};
+    use crate::serialization::serialize;
+    use DeliveryRequirement::{Reliable, Unreliable};
+
+    const SERVER: ([u8; 4], u16) = ([127, 0, 0, 1], 5745);
+    const CLIENT: ([u8; 4], u16) = ([127, 0, 0, 1], 4557);
+
+    /// Server and client channels after the server has accepted the key share of the client
+    fn handshake(trust: ServerTrust) -> (SecureChannels, SecureChannels) {
+        let identity = ServerIdentity::generate();
+        let mut server = SecureChannels::server(identity);
+        let mut client = SecureChannels::client(trust);
+        let share = client.key_share(SERVER.into());
+        server.accept(CLIENT.into(), share, Duration::ZERO).unwrap();
+        (server, client)
+    }
+
+    fn connected() -> (SecureChannels, SecureChannels) {
+        let (mut server, mut client) = handshake(ServerTrust::Any);
+        let offered = server.seal(&CLIENT.into(), b"welcome", Unreliable).unwrap();
+        assert_eq!(client.open(&SERVER.into(), &offered), Ok(Some(b"welcome".to_vec())));
+        (server, client)
+    }
+
+    #[test]
+    fn sealed_datagrams_are_opened_in_both_directions() {
+        let (mut server, mut client) = handshake(ServerTrust::Any);
+
+        let offered = server.seal(&CLIENT.into(), b"welcome", Unreliable).unwrap();
+        assert_eq!(offered[0], OFFER_TAG);
+        assert_eq!(offered.len(), b"welcome".len() + MAX_SEAL_OVERHEAD);
+        assert_eq!(client.open(&SERVER.into(), &offered), Ok(Some(b"welcome".to_vec())));
+
+        let sealed = client.seal(&SERVER.into(), b"input", Unreliable).unwrap();
+        assert_eq!(sealed[0], SEALED_TAG);
+        assert!(!sealed.windows(5).any(|window| window == b"input"));
+        assert_eq!(server.open(&CLIENT.into(), &sealed), Ok(Some(b"input".to_vec())));
+
+        // The client has proven to have the session, the offer is not sent anymore
+        let sealed = server.seal(&CLIENT.into(), b"snapshot", Unreliable).unwrap();
+        assert_eq!(sealed[0], SEALED_TAG);
+        assert_eq!(client.open(&SERVER.into(), &sealed), Ok(Some(b"snapshot".to_vec())));
+    }
+
+    #[test]
+    fn tampered_datagram_is_rejected() {
+        let (mut server, mut client) = connected();
+        let sealed = client.seal(&SERVER.into(), b"input", Unreliable).unwrap();
+
+        for index in [1, HEADER_SIZE, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert!(server.open(&CLIENT.into(), &tampered).is_err());
+        }
+        assert_eq!(
+            server.open(&CLIENT.into(), &sealed[..sealed.len() - 1]),
+            Err(ChannelError::Forged)
+        );
+        // The authentic one is still accepted after the forged ones of the same sequence
+        assert_eq!(server.open(&CLIENT.into(), &sealed), Ok(Some(b"input".to_vec())));
+    }
+
+    #[test]
+    fn replayed_datagram_is_rejected() {
+        let (mut server, mut client) = connected();
+        let first = client.seal(&SERVER.into(), b"first", Unreliable).unwrap();
+        let second = client.seal(&SERVER.into(), b"second", Unreliable).unwrap();
+
+        // Reordered datagrams are accepted once
+        assert!(server.open(&CLIENT.into(), &second).is_ok());
+        assert!(server.open(&CLIENT.into(), &first).is_ok());
+        assert_eq!(server.open(&CLIENT.into(), &first), Err(ChannelError::Replayed(0)));
+        assert_eq!(server.open(&CLIENT.into(), &second), Err(ChannelError::Replayed(1)));
+    }
+
+    #[test]
+    fn datagram_older_than_replay_window_is_rejected() {
+        let (mut server, mut client) = connected();
+        let old = client.seal(&SERVER.into(), b"old", Unreliable).unwrap();
+        for _ in 0..REPLAY_WINDOW {
+            let sealed = client.seal(&SERVER.into(), b"new", Unreliable).unwrap();
+            server.open(&CLIENT.into(), &sealed).unwrap();
+        }
+
+        assert_eq!(server.open(&CLIENT.into(), &old), Err(ChannelError::Replayed(0)));
+    }
+
+    #[test]
+    fn reliable_datagram_is_opened_however_late() {
+        let (mut server, mut client) = connected();
+        let lost = client.seal(&SERVER.into(), b"lost", Reliable).unwrap();
+        for _ in 0..2 * REPLAY_WINDOW {
+            for delivery in [Reliable, Unreliable] {
+                let sealed = client.seal(&SERVER.into(), b"new", delivery).unwrap();
+                server.open(&CLIENT.into(), &sealed).unwrap();
+            }
+        }
+
+        // The transport resends the lost datagram as it was sealed
+        assert_eq!(server.open(&CLIENT.into(), &lost), Ok(Some(b"lost".to_vec())));
+        assert_eq!(server.open(&CLIENT.into(), &lost), Err(ChannelError::Replayed(0)));
+    }
+
+    #[test]
+    fn lanes_have_their_own_sequence_numbers() {
+        let (mut server, mut client) = connected();
+        let unreliable = client.seal(&SERVER.into(), b"unreliable", Unreliable).unwrap();
+        let reliable = client.seal(&SERVER.into(), b"reliable", Reliable).unwrap();
+
+        assert_eq!(server.open(&CLIENT.into(), &reliable), Ok(Some(b"reliable".to_vec())));
+        assert_eq!(server.open(&CLIENT.into(), &unreliable), Ok(Some(b"unreliable".to_vec())));
+
+        // The lane is authenticated
+        let mut moved = client.seal(&SERVER.into(), b"moved", Reliable).unwrap();
+        moved[1] = Lane::Unreliable.tag();
+        assert_eq!(server.open(&CLIENT.into(), &moved), Err(ChannelError::Forged));
+    }
+
+    #[test]
+    fn datagram_of_another_session_is_rejected() {
+        let (mut server, _) = connected();
+        let (_, mut other_client) = connected();
+        let sealed = other_client.seal(&SERVER.into(), b"input", Unreliable).unwrap();
+
+        assert_eq!(server.open(&CLIENT.into(), &sealed), Err(ChannelError::Forged));
+    }
+
+    #[test]
+    fn unsealed_datagram_is_rejected_within_session() {
+        let (mut server, _) = connected();
+        let plain = serialize(&PacketType::SnapshotAck { snapshot: 1 }).unwrap();
+        assert_eq!(server.open(&CLIENT.into(), &plain), Err(ChannelError::Unsealed));
+
+        // A new handshake can be started
+        let request = serialize(&PacketType::ConnectionRequest {
+            player_name: "Clint".to_string(),
+            build_hash: String::new(),
+            resume_session: false,
+            codec: Default::default(),
+            compression: false,
+            key_share: [9; 32],
+        })
+        .unwrap();
+        assert_eq!(server.open(&CLIENT.into(), &request), Ok(None));
+
+        let mut stranger = SecureChannels::server(ServerIdentity::generate());
+        assert_eq!(stranger.open(&CLIENT.into(), &plain), Ok(None));
+    }
+
+    #[test]
+    fn client_accepts_only_refusals_unsealed() {
+        let (_, mut client) = handshake(ServerTrust::Any);
+        let challenge = serialize(&PacketType::AuthChallenge(AuthChallenge {
+            nonce: [7; 32],
+            password_required: true,
+            secret_required: false,
+            session_required: false,
+        }))
+        .unwrap();
+        assert_eq!(client.open(&SERVER.into(), &challenge), Err(ChannelError::Unsealed));
+
+        let refusal = serialize(&PacketType::ConnectionResponse(Err(network::Error::new(
+            ErrorKind::ServerFull,
+        ))))
+        .unwrap();
+        assert_eq!(client.open(&SERVER.into(), &refusal), Ok(None));
+
+        let (_, mut client) = connected();
+        assert_eq!(client.open(&SERVER.into(), &refusal), Err(ChannelError::Unsealed));
+    }
+
+    #[test]
+    fn offer_of_untrusted_server_is_rejected() {
+        let (mut server, mut client) = handshake(ServerTrust::Pinned([1; 32]));
+        let offered = server.seal(&CLIENT.into(), b"welcome", Unreliable).unwrap();
+
+        assert_eq!(
+            client.open(&SERVER.into(), &offered),
+            Err(ChannelError::UntrustedServer)
+        );
+        assert!(!client.has_session(&SERVER.into()));
+    }
+
+    #[test]
+    fn offer_of_pinned_server_is_accepted() {
+        let identity = ServerIdentity::generate();
+        let mut client = SecureChannels::client(ServerTrust::Pinned(identity.public_key()));
+        let mut server = SecureChannels::server(identity);
+        let share = client.key_share(SERVER.into());
+        server.accept(CLIENT.into(), share, Duration::ZERO).unwrap();
+
+        let offered = server.seal(&CLIENT.into(), b"welcome", Unreliable).unwrap();
+        assert_eq!(client.open(&SERVER.into(), &offered), Ok(Some(b"welcome".to_vec())));
+    }
+
+    #[test]
+    fn offer_with_forged_signature_is_rejected() {
+        let (mut server, mut client) = handshake(ServerTrust::Any);
+        let mut offered = server.seal(&CLIENT.into(), b"welcome", Unreliable).unwrap();
+        offered[1 + 32 + 32] ^= 1;
+
+        assert_eq!(
+            client.open(&SERVER.into(), &offered),
+            Err(ChannelError::UntrustedServer)
+        );
+    }
+
+    #[test]
+    fn repeated_request_keeps_session() {
+        let (mut server, mut client) = connected();
+        let share = client.key_share(SERVER.into());
+        server.accept(CLIENT.into(), share, Duration::ZERO).unwrap();
+
+        let sealed = client.seal(&SERVER.into(), b"input", Unreliable).unwrap();
+        assert_eq!(server.open(&CLIENT.into(), &sealed), Ok(Some(b"input".to_vec())));
+    }
+
+    #[test]
+    fn sessions_expire_after_handshake_timeout() {
+        let (mut server, _) = handshake(ServerTrust::Any);
+        server.retain(HANDSHAKE_TIMEOUT / 2, |_| false);
+        assert!(server.has_session(&CLIENT.into()));
+
+        server.retain(HANDSHAKE_TIMEOUT, |addr| *addr != SocketAddr::from(CLIENT));
+        assert!(!server.has_session(&CLIENT.into()));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let identity = ServerIdentity::generate();
+        let hex = encode_hex(&identity.public_key());
+        assert_eq!(decode_hex(&hex).unwrap(), identity.public_key());
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex(&"zz".repeat(32)).is_err());
+    }
+}
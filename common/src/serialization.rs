@@ -1,63 +1,587 @@
-use crate::network::PacketType;
+use crate::components::{EntityType, NetworkId};
+use crate::compression::{decompress, Compression, COMPRESSED_TAG};
+use crate::metric_dimension::length::{Meter, MeterVec2};
+use crate::network::{EncodedPacket, EntityState, PacketType, PROTOCOL_VERSION};
 use anyhow::Result;
+use bincode::Options;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::f32::consts::TAU;
+use std::str::FromStr;
 use thiserror::Error;
 
+type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
 #[derive(Error, Debug)]
-#[error("Could not encode packet: {0:?}")]
-pub struct EncodeError(rmp_serde::encode::Error);
+#[error("Could not encode packet: {0}")]
+pub struct EncodeError(BoxedError);
 
 #[derive(Error, Debug)]
-#[error("Could not decode packet: {0:?}")]
-pub struct DecodeError(rmp_serde::decode::Error);
+pub enum DecodeError {
+    #[error("Empty packet")]
+    Empty,
+    #[error("Unknown packet codec: {0:#04x}")]
+    UnknownCodec(u8),
+    #[error("Could not decode packet: {0}")]
+    Malformed(BoxedError),
+    #[error("Connection request of protocol version {0}")]
+    VersionMismatch(u16),
+}
+
+/// First byte of a connection request, followed by the protocol version of the client as a
+/// little endian `u16`, then the request as any other packet. This header never changes, so the
+/// version is read even if the request of the client has another layout.
+pub const CONNECTION_REQUEST_TAG: u8 = b'C';
+const CONNECTION_REQUEST_HEADER_SIZE: usize = 1 + std::mem::size_of::<u16>();
+
+/// Encoding of the packets. Each datagram starts with the tag of its codec, so it can be decoded
+/// whatever codec the peer uses.
+pub trait Codec {
+    fn encode(&self, packet: &PacketType) -> Result<Vec<u8>, EncodeError>;
+    fn decode(&self, buf: &[u8]) -> Result<PacketType, DecodeError>;
+}
+
+/// MessagePack, the default encoding
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode(&self, packet: &PacketType) -> Result<Vec<u8>, EncodeError> {
+        rmp_serde::to_vec(packet).map_err(|err| EncodeError(err.into()))
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<PacketType, DecodeError> {
+        rmp_serde::from_read_ref(buf).map_err(|err| DecodeError::Malformed(err.into()))
+    }
+}
+
+/// Bincode with varint integers, without any field names or type markers
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn encode(&self, packet: &PacketType) -> Result<Vec<u8>, EncodeError> {
+        bincode::DefaultOptions::new()
+            .serialize(packet)
+            .map_err(|err| EncodeError(err.into()))
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<PacketType, DecodeError> {
+        bincode::DefaultOptions::new()
+            .deserialize(buf)
+            .map_err(|err| DecodeError::Malformed(err.into()))
+    }
+}
+
+/// JSON, to read the packets in captures and dumps. Not for playing, as it is several times the
+/// size of the others, and it cannot carry infinite or NaN numbers.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode(&self, packet: &PacketType) -> Result<Vec<u8>, EncodeError> {
+        serde_json::to_vec(packet).map_err(|err| EncodeError(err.into()))
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<PacketType, DecodeError> {
+        serde_json::from_slice(buf).map_err(|err| DecodeError::Malformed(err.into()))
+    }
+}
+
+/// Selects a `Codec`. The client asks for one in its connection request, the server answers
+/// with the one it has accepted. The handshake itself is always `MessagePack`.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum PacketCodec {
+    #[default]
+    MessagePack,
+    Binary,
+    Json,
+}
+
+impl PacketCodec {
+    pub const ALL: [PacketCodec; 3] = [
+        PacketCodec::MessagePack,
+        PacketCodec::Binary,
+        PacketCodec::Json,
+    ];
+
+    pub fn codec(self) -> &'static dyn Codec {
+        match self {
+            PacketCodec::MessagePack => &MessagePackCodec,
+            PacketCodec::Binary => &BinaryCodec,
+            PacketCodec::Json => &JsonCodec,
+        }
+    }
+
+    /// First byte of the datagrams, printable so the JSON packets stay readable in a dump
+    pub fn tag(self) -> u8 {
+        match self {
+            PacketCodec::MessagePack => b'M',
+            PacketCodec::Binary => b'B',
+            PacketCodec::Json => b'J',
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<PacketCodec> {
+        PacketCodec::ALL.into_iter().find(|codec| codec.tag() == tag)
+    }
+}
+
+impl FromStr for PacketCodec {
+    type Err = anyhow::Error;
 
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "messagepack" | "msgpack" => Ok(PacketCodec::MessagePack),
+            "binary" => Ok(PacketCodec::Binary),
+            "json" => Ok(PacketCodec::Json),
+            _ => Err(anyhow::anyhow!("Unknown packet codec: {}", name)),
+        }
+    }
+}
+
+/// Serializes with the default codec, as the handshake packets are
 pub fn serialize(packet: &PacketType) -> Result<Vec<u8>, EncodeError> {
-    rmp_serde::to_vec(packet).map_err(EncodeError)
+    serialize_with(PacketCodec::default(), packet)
 }
 
+pub fn serialize_with(codec: PacketCodec, packet: &PacketType) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+    if let PacketType::ConnectionRequest { .. } = packet {
+        buf.push(CONNECTION_REQUEST_TAG);
+        buf.extend(PROTOCOL_VERSION.to_le_bytes());
+    }
+    buf.push(codec.tag());
+    buf.extend(codec.codec().encode(packet)?);
+    Ok(buf)
+}
+
+/// How the packets to a peer are encoded, agreed on connection
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WireFormat {
+    pub codec: PacketCodec,
+    /// Applied to whole datagrams, `None` if the peer has not asked for it
+    pub compression: Option<Compression>,
+}
+
+/// Size of the value when it is encoded with the codec as a part of a packet
+pub fn encoded_size<T: Serialize>(codec: PacketCodec, value: &T) -> Result<usize, EncodeError> {
+    match codec {
+        PacketCodec::MessagePack => rmp_serde::to_vec(value)
+            .map(|buf| buf.len())
+            .map_err(|err| EncodeError(err.into())),
+        PacketCodec::Binary => bincode::DefaultOptions::new()
+            .serialized_size(value)
+            .map(|size| size as usize)
+            .map_err(|err| EncodeError(err.into())),
+        PacketCodec::Json => serde_json::to_vec(value)
+            .map(|buf| buf.len())
+            .map_err(|err| EncodeError(err.into())),
+    }
+}
+
+/// Decodes the packet with the codec of its tag, decompressing it first if it is compressed.
+/// A connection request is decoded only if it is of the same protocol version.
 pub fn deserialize(buf: &[u8]) -> Result<PacketType, DecodeError> {
-    rmp_serde::from_read_ref(buf).map_err(DecodeError)
+    match buf.split_first() {
+        Some((&COMPRESSED_TAG, body)) => decode_uncompressed(&decompress(body)?),
+        _ => decode_uncompressed(buf),
+    }
+}
+
+/// Only the whole datagram may be compressed, so at most `MAX_DECOMPRESSED_SIZE` bytes are
+/// decompressed for a datagram, whatever it holds
+fn decode_uncompressed(buf: &[u8]) -> Result<PacketType, DecodeError> {
+    match buf.split_first() {
+        Some((&COMPRESSED_TAG, _)) => Err(DecodeError::Malformed("Nested compression".into())),
+        Some((&CONNECTION_REQUEST_TAG, _)) => decode_connection_request(buf),
+        _ => match decode(buf)? {
+            PacketType::ConnectionRequest { .. } => Err(DecodeError::Malformed(
+                "Connection request without protocol version".into(),
+            )),
+            packet => Ok(packet),
+        },
+    }
+}
+
+fn decode_connection_request(buf: &[u8]) -> Result<PacketType, DecodeError> {
+    if buf.len() < CONNECTION_REQUEST_HEADER_SIZE {
+        return Err(DecodeError::Malformed("Truncated protocol version".into()));
+    }
+    let (header, body) = buf.split_at(CONNECTION_REQUEST_HEADER_SIZE);
+    let version = u16::from_le_bytes([header[1], header[2]]);
+    if version != PROTOCOL_VERSION {
+        return Err(DecodeError::VersionMismatch(version));
+    }
+    match decode(body)? {
+        request @ PacketType::ConnectionRequest { .. } => Ok(request),
+        _ => Err(DecodeError::Malformed("Protocol version in front of another packet".into())),
+    }
+}
+
+fn decode(buf: &[u8]) -> Result<PacketType, DecodeError> {
+    let (&tag, body) = buf.split_first().ok_or(DecodeError::Empty)?;
+    let codec = PacketCodec::from_tag(tag).ok_or(DecodeError::UnknownCodec(tag))?;
+    codec.codec().decode(body)
+}
+
+/// Deserializes the packet, or each packet of a `Bundle`
+pub fn deserialize_all(buf: &[u8]) -> Result<Vec<PacketType>, DecodeError> {
+    match deserialize(buf)? {
+        PacketType::Bundle(packets) => packets
+            .iter()
+            .map(|EncodedPacket(payload)| decode_uncompressed(payload))
+            .collect(),
+        packet => Ok(vec![packet]),
+    }
+}
+
+/// Quantization of the entity states on the wire
+#[derive(Copy, Clone, Debug)]
+pub struct EntityStateCodec {
+    /// Positions are expected within `-bound..=bound` on both axes, outside they are clamped
+    pub bound: Meter,
+    /// Positions are sent with at most this much error
+    pub precision: Meter,
+    /// Angles are sent in this many bits
+    pub angle_bits: u32,
+}
+
+/// The map spans 64 meters, the bound leaves room around it
+pub const ENTITY_STATE_CODEC: EntityStateCodec = EntityStateCodec {
+    bound: Meter(64.0),
+    precision: Meter(1.0 / 256.0),
+    angle_bits: 10,
+};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CodecError {
+    #[error("Entity state ended unexpectedly")]
+    UnexpectedEnd,
+    #[error("Varint does not fit in 32 bits")]
+    VarintOverflow,
+    #[error("Unknown entity type: {0}")]
+    UnknownEntityType(u32),
+}
+
+impl EntityStateCodec {
+    /// Bits of a quantized coordinate, enough to have steps not larger than the precision
+    pub fn position_bits(&self) -> u32 {
+        let steps = (2.0 * self.bound.0 / self.precision.0).ceil() as u64;
+        (u64::BITS - steps.leading_zeros()).min(32)
+    }
+
+    fn position_steps(&self) -> u32 {
+        max_value(self.position_bits())
+    }
+
+    pub fn quantize_coordinate(&self, coordinate: Meter) -> u32 {
+        let normalized = (coordinate.0.clamp(-self.bound.0, self.bound.0) + self.bound.0)
+            / (2.0 * self.bound.0);
+        (normalized * self.position_steps() as f32).round() as u32
+    }
+
+    pub fn dequantize_coordinate(&self, quantized: u32) -> Meter {
+        let normalized = quantized as f32 / self.position_steps() as f32;
+        Meter(normalized * 2.0 * self.bound.0 - self.bound.0)
+    }
+
+    /// The angle is wrapped into `0..TAU`
+    pub fn quantize_angle(&self, angle: f32) -> u32 {
+        let steps = 1_u64 << self.angle_bits;
+        ((angle.rem_euclid(TAU) / TAU * steps as f32).round() as u64 % steps) as u32
+    }
+
+    pub fn dequantize_angle(&self, quantized: u32) -> f32 {
+        quantized as f32 / (1_u64 << self.angle_bits) as f32 * TAU
+    }
+
+    /// The state as it is after a round trip through the codec
+    pub fn quantize(&self, state: &EntityState) -> EntityState {
+        EntityState {
+            network_id: state.network_id,
+            position: MeterVec2 {
+                x: self.dequantize_coordinate(self.quantize_coordinate(state.position.x)),
+                y: self.dequantize_coordinate(self.quantize_coordinate(state.position.y)),
+            },
+            angle: self.dequantize_angle(self.quantize_angle(state.angle)),
+        }
+    }
+
+    /// The network ID as varints, then the quantized position and angle packed into bits
+    pub fn encode(&self, state: &EntityState) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12);
+        write_varint(&mut buf, entity_type_to_wire(state.network_id.entity_type));
+        write_varint(&mut buf, state.network_id.id);
+
+        let position_bits = self.position_bits();
+        let mut bits = BitWriter::new(&mut buf);
+        bits.write(self.quantize_coordinate(state.position.x), position_bits);
+        bits.write(self.quantize_coordinate(state.position.y), position_bits);
+        bits.write(self.quantize_angle(state.angle), self.angle_bits);
+        bits.finish();
+        buf
+    }
+
+    pub fn decode(&self, buf: &[u8]) -> Result<EntityState, CodecError> {
+        let mut buf = buf;
+        let entity_type = read_varint(&mut buf)?;
+        let entity_type =
+            entity_type_from_wire(entity_type).ok_or(CodecError::UnknownEntityType(entity_type))?;
+        let id = read_varint(&mut buf)?;
+
+        let position_bits = self.position_bits();
+        let mut bits = BitReader::new(buf);
+        let x = bits.read(position_bits)?;
+        let y = bits.read(position_bits)?;
+        let angle = bits.read(self.angle_bits)?;
+        Ok(EntityState {
+            network_id: NetworkId::new(entity_type, id),
+            position: MeterVec2 {
+                x: self.dequantize_coordinate(x),
+                y: self.dequantize_coordinate(y),
+            },
+            angle: self.dequantize_angle(angle),
+        })
+    }
+}
+
+/// Field by field form of the entity state in the human readable formats
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "EntityState")]
+struct ReadableEntityState {
+    network_id: NetworkId,
+    position: MeterVec2,
+    angle: f32,
+}
+
+/// Human readable formats get the fields on the quantization grid, so every codec delivers the
+/// same state
+impl Serialize for EntityState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let state = ENTITY_STATE_CODEC.quantize(self);
+            ReadableEntityState {
+                network_id: state.network_id,
+                position: state.position,
+                angle: state.angle,
+            }
+            .serialize(serializer)
+        } else {
+            serializer.serialize_bytes(&ENTITY_STATE_CODEC.encode(self))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for EntityState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let state = ReadableEntityState::deserialize(deserializer)?;
+            Ok(ENTITY_STATE_CODEC.quantize(&EntityState {
+                network_id: state.network_id,
+                position: state.position,
+                angle: state.angle,
+            }))
+        } else {
+            let buf = serde_bytes::ByteBuf::deserialize(deserializer)?;
+            ENTITY_STATE_CODEC.decode(&buf).map_err(de::Error::custom)
+        }
+    }
+}
+
+fn entity_type_to_wire(entity_type: EntityType) -> u32 {
+    match entity_type {
+        EntityType::Player => 0,
+        EntityType::Barrel => 1,
+    }
+}
+
+fn entity_type_from_wire(value: u32) -> Option<EntityType> {
+    match value {
+        0 => Some(EntityType::Player),
+        1 => Some(EntityType::Barrel),
+        _ => None,
+    }
+}
+
+fn max_value(bits: u32) -> u32 {
+    (1_u64 << bits).wrapping_sub(1) as u32
+}
+
+/// LEB128: 7 bits in each byte, the highest bit is set while more bytes follow
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn read_varint(buf: &mut &[u8]) -> Result<u32, CodecError> {
+    let mut value = 0_u32;
+    for shift in (0..32).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or(CodecError::UnexpectedEnd)?;
+        *buf = rest;
+        let bits = (byte & 0x7f) as u32;
+        if shift == 28 && bits > 0x0f {
+            return Err(CodecError::VarintOverflow);
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(CodecError::VarintOverflow)
+}
+
+/// Appends values of arbitrary bit width, least significant bit first
+struct BitWriter<'a> {
+    buf: &'a mut Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(buf: &'a mut Vec<u8>) -> Self {
+        BitWriter {
+            buf,
+            pending: 0,
+            pending_bits: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        self.pending |= ((value & max_value(bits)) as u64) << self.pending_bits;
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.buf.push(self.pending as u8);
+            self.pending >>= 8;
+            self.pending_bits -= 8;
+        }
+    }
+
+    /// Writes the remaining bits, padded to a whole byte
+    fn finish(self) {
+        if self.pending_bits > 0 {
+            self.buf.push(self.pending as u8);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        BitReader {
+            buf,
+            pending: 0,
+            pending_bits: 0,
+        }
+    }
+
+    fn read(&mut self, bits: u32) -> Result<u32, CodecError> {
+        while self.pending_bits < bits {
+            let (&byte, rest) = self.buf.split_first().ok_or(CodecError::UnexpectedEnd)?;
+            self.buf = rest;
+            self.pending |= (byte as u64) << self.pending_bits;
+            self.pending_bits += 8;
+        }
+        let value = self.pending as u32 & max_value(bits);
+        self.pending >>= bits;
+        self.pending_bits -= bits;
+        Ok(value)
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use crate::components::{EntityType, Input, InputFlags, NetworkId};
-    use crate::metric_dimension::length::MeterVec2;
-    use crate::network::EntityState;
+    use crate::components::{Input, InputFlags};
+    use crate::network::{
+        ComponentUpdate, ComponentValue, EntityStateUpdate, SequencedInput, SnapshotChunk,
+    };
     use proptest::prelude::*;
+    use std::time::Duration;
 
     fn packet_enum_strategy() -> impl Strategy<Value = PacketType> {
         prop_oneof![
-            any::<String>().prop_map(|name| PacketType::ConnectionRequest { player_name: name }),
+            connection_request_gen(),
             input_state_gen(),
-            entity_state_update_gen()
+            entity_state_update_gen(),
+            component_update_gen(),
+            any::<u32>().prop_map(|snapshot| PacketType::SnapshotAck { snapshot }),
+            prop::collection::vec(prop::collection::vec(any::<u8>(), 0..64), 0..4).prop_map(
+                |payloads| PacketType::Bundle(payloads.into_iter().map(EncodedPacket).collect())
+            ),
         ]
     }
 
+    prop_compose! {
+        fn connection_request_gen()(player_name in any::<String>(),
+                                    build_hash in any::<String>(),
+                                    resume_session in any::<bool>(),
+                                    codec in codec_strategy(),
+                                    compression in any::<bool>(),
+                                    key_share in any::<[u8; 32]>()) -> PacketType {
+            PacketType::ConnectionRequest {
+                player_name,
+                build_hash,
+                resume_session,
+                codec,
+                compression,
+                key_share,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn component_update_gen()(tick in any::<u32>(),
+                                  network_id in network_id_gen(),
+                                  kind in any::<u16>(),
+                                  data in prop::collection::vec(any::<u8>(), 0..16)) -> PacketType {
+            PacketType::ComponentUpdate(ComponentUpdate {
+                tick,
+                components: vec![ComponentValue { network_id, kind, data }],
+            })
+        }
+    }
+
     prop_compose! {
         fn input_state_gen()(p in arb_point2(),
-                             flags in 0..=InputFlags::all().bits()) -> PacketType {
+                             flags in 0..=InputFlags::all().bits(),
+                             sequence in any::<u32>(),
+                             view_time_ms in any::<Option<u32>>()) -> PacketType {
+            let input = Input {
+                flags: InputFlags::from_bits(flags).unwrap(),
+                cursor: p
+            };
             PacketType::InputState {
-                input: Input {
-                    flags: InputFlags::from_bits(flags).unwrap(),
-                    cursor: p
-                }
+                inputs: vec![SequencedInput { sequence, input }],
+                view_time: view_time_ms.map(|ms| Duration::from_millis(ms as u64)),
             }
         }
     }
 
     prop_compose! {
-        fn entity_state_update_gen()(id in network_id_gen(),
-                                     pos in arb_point2(),
-                                     ang in any::<f32>()) -> PacketType {
-            PacketType::EntityStateUpdate(
-                vec![EntityState {
-                        network_id: id,
-                        position: pos,
-                        angle: ang,
-                    }]
-            )
+        fn entity_state_update_gen()(snapshot in any::<u32>(),
+                                     tick in any::<u32>(),
+                                     server_time_ms in any::<u32>(),
+                                     baseline in any::<Option<u32>>(),
+                                     state in quantized_entity_state_gen(),
+                                     removed_id in network_id_gen(),
+                                     last_processed_input in any::<Option<u32>>(),
+                                     (index, count) in any::<(u8, u8)>()) -> PacketType {
+            PacketType::EntityStateUpdate(EntityStateUpdate {
+                snapshot,
+                tick,
+                server_time: Duration::from_millis(server_time_ms as u64),
+                baseline,
+                states: vec![state],
+                removed: vec![removed_id],
+                last_processed_input,
+                chunk: SnapshotChunk { index, count },
+            })
         }
     }
 
@@ -67,20 +591,289 @@ mod tests {
         }
     }
 
+    fn header_size(packet: &PacketType) -> usize {
+        match packet {
+            PacketType::ConnectionRequest { .. } => CONNECTION_REQUEST_HEADER_SIZE,
+            _ => 0,
+        }
+    }
+
+    fn codec_strategy() -> impl Strategy<Value = PacketCodec> {
+        prop::sample::select(PacketCodec::ALL.to_vec())
+    }
+
     fn entity_type_strategy() -> impl Strategy<Value = EntityType> {
-        prop_oneof![Just(EntityType::Player),]
+        prop_oneof![Just(EntityType::Player), Just(EntityType::Barrel)]
     }
 
     prop_compose! {
-        fn arb_point2()(x in any::<f32>(), y in any::<f32>()) -> MeterVec2 {
+        /// Finite, as JSON has no infinity nor NaN
+        fn arb_point2()(x in -1.0e6_f32..1.0e6, y in -1.0e6_f32..1.0e6) -> MeterVec2 {
             MeterVec2::from_raw(x, y)
         }
     }
 
+    prop_compose! {
+        /// Already on the quantization grid, so it survives the codec unchanged
+        fn quantized_entity_state_gen()(network_id in network_id_gen(),
+                                        x in 0..=ENTITY_STATE_CODEC.position_steps(),
+                                        y in 0..=ENTITY_STATE_CODEC.position_steps(),
+                                        angle in 0..1_u32 << ENTITY_STATE_CODEC.angle_bits)
+                                        -> EntityState {
+            let codec = ENTITY_STATE_CODEC;
+            EntityState {
+                network_id,
+                position: MeterVec2 {
+                    x: codec.dequantize_coordinate(x),
+                    y: codec.dequantize_coordinate(y),
+                },
+                angle: codec.dequantize_angle(angle),
+            }
+        }
+    }
+
+    fn angle_difference(a: f32, b: f32) -> f32 {
+        let difference = (a - b).rem_euclid(TAU);
+        difference.min(TAU - difference)
+    }
+
     proptest! {
+        #[test]
+        fn entity_state_error_is_bounded(network_id in network_id_gen(),
+                                         x in -64.0_f32..=64.0,
+                                         y in -64.0_f32..=64.0,
+                                         angle in -10.0_f32..10.0) {
+            let codec = ENTITY_STATE_CODEC;
+            let state = EntityState {
+                network_id,
+                position: MeterVec2::from_raw(x, y),
+                angle,
+            };
+            let decoded = codec.decode(&codec.encode(&state)).unwrap();
+
+            let max_position_error = codec.precision.0 / 2.0 + 1e-5;
+            prop_assert_eq!(decoded.network_id, network_id);
+            prop_assert!((decoded.position.x.0 - x).abs() <= max_position_error);
+            prop_assert!((decoded.position.y.0 - y).abs() <= max_position_error);
+            prop_assert!((0.0..TAU).contains(&decoded.angle));
+            let max_angle_error = TAU / (1_u32 << codec.angle_bits) as f32 / 2.0 + 1e-5;
+            prop_assert!(angle_difference(decoded.angle, angle) <= max_angle_error);
+        }
+
+        #[test]
+        fn entity_state_is_clamped_to_bounds(x in -1.0e6_f32..1.0e6, y in -1.0e6_f32..1.0e6) {
+            let codec = ENTITY_STATE_CODEC;
+            let state = EntityState {
+                network_id: NetworkId::new(EntityType::Player, 0),
+                position: MeterVec2::from_raw(x, y),
+                angle: 0.0,
+            };
+            let decoded = codec.decode(&codec.encode(&state)).unwrap();
+
+            let max_error = codec.precision.0 / 2.0 + 1e-5;
+            let clamp = |value: f32| value.clamp(-codec.bound.0, codec.bound.0);
+            prop_assert!((decoded.position.x.0 - clamp(x)).abs() <= max_error);
+            prop_assert!((decoded.position.y.0 - clamp(y)).abs() <= max_error);
+        }
+
+        #[test]
+        fn varint_round_trip(values in prop::collection::vec(any::<u32>(), 0..8)) {
+            let mut buf = Vec::new();
+            for value in values.iter() {
+                write_varint(&mut buf, *value);
+            }
+            let mut reader = buf.as_slice();
+            for value in values.iter() {
+                prop_assert_eq!(read_varint(&mut reader).unwrap(), *value);
+            }
+            prop_assert!(reader.is_empty());
+        }
+
+        #[test]
+        fn truncated_entity_state_is_rejected(state in quantized_entity_state_gen(),
+                                              cut in 1_usize..4) {
+            let encoded = ENTITY_STATE_CODEC.encode(&state);
+            let truncated = &encoded[..encoded.len() - cut];
+            prop_assert!(ENTITY_STATE_CODEC.decode(truncated).is_err());
+        }
+
         #[test]
         fn encode_decode(packet in packet_enum_strategy()) {
-            assert_eq!(packet, deserialize(&serialize(&packet).unwrap()).unwrap());
+            for codec in PacketCodec::ALL {
+                let encoded = serialize_with(codec, &packet).unwrap();
+                prop_assert_eq!(encoded[header_size(&packet)], codec.tag());
+                prop_assert_eq!(&packet, &deserialize(&encoded).unwrap());
+            }
         }
+
+        #[test]
+        fn encoded_size_matches_encoding(packet in packet_enum_strategy()) {
+            for codec in PacketCodec::ALL {
+                let encoded = serialize_with(codec, &packet).unwrap();
+                prop_assert_eq!(
+                    header_size(&packet) + encoded_size(codec, &packet).unwrap() + 1,
+                    encoded.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn entity_state_is_compact() {
+        let state = EntityState {
+            network_id: NetworkId::new(EntityType::Player, 5),
+            position: MeterVec2::from_raw(-12.5, 30.25),
+            angle: 1.0,
+        };
+        // type and id varints, then 16 + 16 + 10 bits
+        assert_eq!(ENTITY_STATE_CODEC.position_bits(), 16);
+        assert_eq!(ENTITY_STATE_CODEC.encode(&state).len(), 2 + 6);
+    }
+
+    #[test]
+    fn varint_overflow_is_rejected() {
+        let mut buf: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0x1f];
+        assert_eq!(read_varint(&mut buf), Err(CodecError::VarintOverflow));
+    }
+
+    #[test]
+    fn deserialize_all_unpacks_bundle() {
+        let packets = vec![
+            PacketType::SnapshotAck { snapshot: 1 },
+            PacketType::Ping { sequence: 2 },
+        ];
+        for codec in PacketCodec::ALL {
+            let bundle = PacketType::Bundle(
+                packets
+                    .iter()
+                    .map(|packet| EncodedPacket(serialize_with(codec, packet).unwrap()))
+                    .collect(),
+            );
+
+            assert_eq!(
+                deserialize_all(&serialize_with(codec, &bundle).unwrap()).unwrap(),
+                packets
+            );
+            assert_eq!(
+                deserialize_all(&serialize_with(codec, &packets[0]).unwrap()).unwrap(),
+                vec![PacketType::SnapshotAck { snapshot: 1 }]
+            );
+        }
+    }
+
+    #[test]
+    fn packet_of_unknown_codec_is_rejected() {
+        assert!(matches!(deserialize(&[]), Err(DecodeError::Empty)));
+        assert!(matches!(
+            deserialize(&[b'X', 0x01]),
+            Err(DecodeError::UnknownCodec(b'X'))
+        ));
+    }
+
+    fn connection_request() -> PacketType {
+        PacketType::ConnectionRequest {
+            player_name: "Clint".to_string(),
+            build_hash: String::new(),
+            resume_session: false,
+            codec: PacketCodec::Binary,
+            compression: false,
+            key_share: [9; 32],
+        }
+    }
+
+    #[test]
+    fn connection_request_of_another_version_is_told_apart() {
+        let encoded = serialize(&connection_request()).unwrap();
+        assert_eq!(encoded[0], CONNECTION_REQUEST_TAG);
+
+        // Whatever follows the version, it is not decoded
+        let other_version = PROTOCOL_VERSION.wrapping_add(1);
+        let mut other = vec![CONNECTION_REQUEST_TAG];
+        other.extend(other_version.to_le_bytes());
+        other.extend(b"Mnot a request of this layout");
+        assert!(matches!(
+            deserialize(&other),
+            Err(DecodeError::VersionMismatch(version)) if version == other_version
+        ));
+        assert!(matches!(
+            deserialize(&[CONNECTION_REQUEST_TAG, 1]),
+            Err(DecodeError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn connection_request_without_version_is_rejected() {
+        let encoded = serialize(&connection_request()).unwrap();
+        let unversioned = &encoded[CONNECTION_REQUEST_HEADER_SIZE..];
+        assert!(matches!(deserialize(unversioned), Err(DecodeError::Malformed(_))));
+
+        let mut versioned_ack = encoded[..CONNECTION_REQUEST_HEADER_SIZE].to_vec();
+        versioned_ack.extend(serialize(&PacketType::SnapshotAck { snapshot: 1 }).unwrap());
+        assert!(matches!(deserialize(&versioned_ack), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn json_packet_is_readable() {
+        let packet = PacketType::SnapshotAck { snapshot: 7 };
+        let encoded = serialize_with(PacketCodec::Json, &packet).unwrap();
+        assert_eq!(
+            String::from_utf8(encoded).unwrap(),
+            r#"J{"SnapshotAck":{"snapshot":7}}"#
+        );
+    }
+
+    #[test]
+    fn compressed_packet_is_decompressed() {
+        let packet = PacketType::Notification(crate::network::PlayerNotification {
+            message: "Howdy! ".repeat(50),
+        });
+        let mut compressor = crate::compression::Compressor::default();
+        for codec in PacketCodec::ALL {
+            let payload = serialize_with(codec, &packet).unwrap();
+            let compressed = compressor.compress(payload, Some(Compression { threshold: 1 }));
+
+            assert_eq!(compressed[0], COMPRESSED_TAG);
+            assert_eq!(deserialize(&compressed).unwrap(), packet);
+        }
+    }
+
+    /// Compressed whether it gets smaller or not
+    fn compressed(payload: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![COMPRESSED_TAG];
+        compressed.extend((payload.len() as u32).to_le_bytes());
+        compressed.extend(lz4_flex::block::compress(payload));
+        compressed
+    }
+
+    #[test]
+    fn compressed_connection_request_without_version_is_rejected() {
+        let encoded = serialize(&connection_request()).unwrap();
+        let unversioned = compressed(&encoded[CONNECTION_REQUEST_HEADER_SIZE..]);
+        assert!(matches!(deserialize(&unversioned), Err(DecodeError::Malformed(_))));
+
+        // The version is checked the same after decompression
+        assert_eq!(deserialize(&compressed(&encoded)).unwrap(), connection_request());
+    }
+
+    #[test]
+    fn nested_compression_is_rejected() {
+        let ack = compressed(&serialize(&PacketType::SnapshotAck { snapshot: 1 }).unwrap());
+        assert_eq!(deserialize(&ack).unwrap(), PacketType::SnapshotAck { snapshot: 1 });
+        assert!(matches!(deserialize(&compressed(&ack)), Err(DecodeError::Malformed(_))));
+
+        let bundle = PacketType::Bundle(vec![EncodedPacket(ack.clone()), EncodedPacket(ack)]);
+        let bundle = serialize(&bundle).unwrap();
+        assert!(matches!(deserialize_all(&bundle), Err(DecodeError::Malformed(_))));
+        assert!(matches!(deserialize_all(&compressed(&bundle)), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn codec_is_parsed_from_name() {
+        assert_eq!(
+            "MessagePack".parse::<PacketCodec>().unwrap(),
+            PacketCodec::MessagePack
+        );
+        assert_eq!("json".parse::<PacketCodec>().unwrap(), PacketCodec::Json);
+        assert!("xml".parse::<PacketCodec>().is_err());
     }
 }
@@ -0,0 +1,431 @@
+use crate::components::NetworkId;
+use crate::network::{EntityState, EntityStateUpdate, SnapshotId};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// State of every networked entity at a given moment, as it is known by one of the peers.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    entities: HashMap<NetworkId, EntityState>,
+}
+
+impl Snapshot {
+    pub fn new<I>(states: I) -> Self
+    where
+        I: IntoIterator<Item = EntityState>,
+    {
+        Snapshot {
+            entities: states
+                .into_iter()
+                .map(|state| (state.network_id, state))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, network_id: &NetworkId) -> Option<&EntityState> {
+        self.entities.get(network_id)
+    }
+
+    pub fn states(&self) -> impl Iterator<Item = &EntityState> {
+        self.entities.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Returns the entity states which are new or changed compared to `baseline`
+    /// and the network IDs which are present in `baseline` but not in this snapshot.
+    pub fn delta_from(&self, baseline: &Snapshot) -> (Vec<EntityState>, Vec<NetworkId>) {
+        let changed = self
+            .entities
+            .values()
+            .filter(|state| baseline.get(&state.network_id) != Some(state))
+            .cloned()
+            .collect();
+
+        let removed = baseline
+            .entities
+            .keys()
+            .filter(|network_id| !self.entities.contains_key(network_id))
+            .copied()
+            .collect();
+
+        (changed, removed)
+    }
+
+    /// Reconstructs a snapshot from this baseline and a delta created by `delta_from`
+    pub fn apply_delta(&self, changed: &[EntityState], removed: &[NetworkId]) -> Snapshot {
+        let mut snapshot = self.clone();
+        for network_id in removed {
+            snapshot.entities.remove(network_id);
+        }
+        for state in changed {
+            snapshot.entities.insert(state.network_id, state.clone());
+        }
+        snapshot
+    }
+}
+
+/// Stores the last few snapshots so deltas can be made against (or applied to) any of them.
+/// The oldest snapshot is dropped when capacity is exceeded.
+#[derive(Debug)]
+pub struct SnapshotHistory {
+    capacity: usize,
+    snapshots: VecDeque<(SnapshotId, Snapshot)>,
+}
+
+impl SnapshotHistory {
+    pub fn new(capacity: usize) -> Self {
+        SnapshotHistory {
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(&mut self, id: SnapshotId, snapshot: Snapshot) {
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((id, snapshot));
+    }
+
+    pub fn get(&self, id: SnapshotId) -> Option<&Snapshot> {
+        self.snapshots
+            .iter()
+            .find(|(snapshot_id, _)| *snapshot_id == id)
+            .map(|(_, snapshot)| snapshot)
+    }
+
+    pub fn latest_id(&self) -> Option<SnapshotId> {
+        self.snapshots.back().map(|(id, _)| *id)
+    }
+}
+
+/// Number of received snapshots kept as possible baselines of the upcoming delta updates
+const SNAPSHOT_HISTORY_SIZE: usize = 64;
+
+/// A snapshot of which some chunks have been received
+struct PendingSnapshot {
+    id: SnapshotId,
+    snapshot: Snapshot,
+    received: Vec<bool>,
+    /// Entities updated by the chunks received so far
+    updated: HashSet<NetworkId>,
+}
+
+/// What a received entity state update has changed
+pub struct ReceivedUpdate<'a> {
+    /// The current states of the entities updated by the chunk. When the chunk completes the
+    /// snapshot, the states of the entities not updated by any chunk are included as well.
+    pub states: Vec<EntityState>,
+    /// The full snapshot and the network IDs which disappeared since the previous snapshot
+    /// (deleted or got out of the interest radius), when the chunk completes the snapshot
+    pub completed: Option<(&'a Snapshot, Vec<NetworkId>)>,
+}
+
+/// Snapshots received from the server, used to reconstruct the delta compressed entity state updates
+#[derive(bevy::prelude::Resource)]
+pub struct ReceivedSnapshots {
+    history: SnapshotHistory,
+    pending: Option<PendingSnapshot>,
+}
+
+impl Default for ReceivedSnapshots {
+    fn default() -> Self {
+        ReceivedSnapshots {
+            history: SnapshotHistory::new(SNAPSHOT_HISTORY_SIZE),
+            pending: None,
+        }
+    }
+}
+
+impl ReceivedSnapshots {
+    /// The latest snapshot of which every chunk has been received
+    pub fn latest_id(&self) -> Option<SnapshotId> {
+        self.history.latest_id()
+    }
+
+    /// Applies the chunk to its snapshot, and stores the snapshot once all of its chunks have
+    /// been received. The chunks of a snapshot are dropped when a chunk of a newer one arrives.
+    /// Returns `None` if the update is older than the latest received one, it has already been
+    /// received or its baseline is not known anymore.
+    pub fn receive(&mut self, update: &EntityStateUpdate) -> Option<ReceivedUpdate> {
+        if self
+            .latest_id()
+            .is_some_and(|latest| update.snapshot <= latest)
+        {
+            return None;
+        }
+
+        let mut pending = match self.pending.take() {
+            Some(pending) if pending.id == update.snapshot => pending,
+            Some(pending) if pending.id > update.snapshot => {
+                self.pending = Some(pending);
+                return None;
+            }
+            _ => PendingSnapshot {
+                id: update.snapshot,
+                snapshot: match update.baseline {
+                    Some(baseline_id) => self.history.get(baseline_id)?.clone(),
+                    None => Snapshot::default(),
+                },
+                received: vec![false; update.chunk.count as usize],
+                updated: HashSet::new(),
+            },
+        };
+
+        match pending.received.get_mut(update.chunk.index as usize) {
+            Some(received) if !*received => *received = true,
+            _ => {
+                self.pending = Some(pending);
+                return None;
+            }
+        }
+        pending.snapshot = pending
+            .snapshot
+            .apply_delta(&update.states, &update.removed);
+
+        if pending.received.iter().any(|received| !received) {
+            pending
+                .updated
+                .extend(update.states.iter().map(|state| state.network_id));
+            self.pending = Some(pending);
+            return Some(ReceivedUpdate {
+                states: update.states.clone(),
+                completed: None,
+            });
+        }
+
+        let states = pending
+            .snapshot
+            .states()
+            .filter(|state| !pending.updated.contains(&state.network_id))
+            .cloned()
+            .collect();
+        let disappeared = self
+            .latest_id()
+            .and_then(|latest| self.history.get(latest))
+            .map(|previous| pending.snapshot.delta_from(previous).1)
+            .unwrap_or_default();
+
+        self.history.push(update.snapshot, pending.snapshot);
+        let snapshot = self.history.get(update.snapshot)?;
+        Some(ReceivedUpdate {
+            states,
+            completed: Some((snapshot, disappeared)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::EntityType;
+    use crate::metric_dimension::length::MeterVec2;
+    use crate::network::SnapshotChunk;
+
+    fn state(id: u32, x: f32, angle: f32) -> EntityState {
+        EntityState {
+            network_id: NetworkId::new(EntityType::Player, id),
+            position: MeterVec2::from_raw(x, 0.0),
+            angle,
+        }
+    }
+
+    #[test]
+    fn delta_contains_only_changes() {
+        let baseline = Snapshot::new(vec![
+            state(0, 1.0, 0.0),
+            state(1, 2.0, 0.0),
+            state(2, 3.0, 0.0),
+        ]);
+        let current = Snapshot::new(vec![
+            state(0, 1.0, 0.0),
+            state(1, 2.5, 0.0),
+            state(3, 4.0, 1.0),
+        ]);
+
+        let (mut changed, removed) = current.delta_from(&baseline);
+        changed.sort_by_key(|state| state.network_id.id);
+
+        assert_eq!(changed, vec![state(1, 2.5, 0.0), state(3, 4.0, 1.0)]);
+        assert_eq!(removed, vec![NetworkId::new(EntityType::Player, 2)]);
+    }
+
+    #[test]
+    fn applied_delta_reconstructs_snapshot() {
+        let baseline = Snapshot::new(vec![
+            state(0, 1.0, 0.0),
+            state(1, 2.0, 0.0),
+            state(2, 3.0, 0.0),
+        ]);
+        let current = Snapshot::new(vec![
+            state(0, 1.0, 0.5),
+            state(1, 2.0, 0.0),
+            state(3, 4.0, 1.0),
+        ]);
+
+        let (changed, removed) = current.delta_from(&baseline);
+
+        assert_eq!(baseline.apply_delta(&changed, &removed), current);
+    }
+
+    #[test]
+    fn delta_from_empty_baseline_is_full_snapshot() {
+        let current = Snapshot::new(vec![state(0, 1.0, 0.0), state(1, 2.0, 0.0)]);
+
+        let (changed, removed) = current.delta_from(&Snapshot::default());
+
+        assert_eq!(changed.len(), 2);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn history_drops_oldest_snapshot() {
+        let mut history = SnapshotHistory::new(2);
+        history.push(1, Snapshot::new(vec![state(0, 1.0, 0.0)]));
+        history.push(2, Snapshot::new(vec![state(0, 2.0, 0.0)]));
+        history.push(3, Snapshot::new(vec![state(0, 3.0, 0.0)]));
+
+        assert!(history.get(1).is_none());
+        assert_eq!(
+            history.get(2),
+            Some(&Snapshot::new(vec![state(0, 2.0, 0.0)]))
+        );
+        assert_eq!(history.latest_id(), Some(3));
+    }
+
+    fn update(
+        snapshot: SnapshotId,
+        baseline: Option<SnapshotId>,
+        states: Vec<EntityState>,
+        removed: Vec<NetworkId>,
+    ) -> EntityStateUpdate {
+        EntityStateUpdate {
+            snapshot,
+            tick: 0,
+            server_time: std::time::Duration::ZERO,
+            baseline,
+            states,
+            removed,
+            last_processed_input: None,
+            chunk: SnapshotChunk::WHOLE,
+        }
+    }
+
+    fn chunk(update: EntityStateUpdate, index: u8, count: u8) -> EntityStateUpdate {
+        EntityStateUpdate {
+            chunk: SnapshotChunk { index, count },
+            ..update
+        }
+    }
+
+    #[test]
+    fn received_delta_is_applied_to_baseline() {
+        let mut received = ReceivedSnapshots::default();
+        received.receive(&update(
+            0,
+            None,
+            vec![state(0, 1.0, 0.0), state(1, 1.0, 0.0)],
+            vec![],
+        ));
+
+        let (snapshot, disappeared) = received
+            .receive(&update(
+                1,
+                Some(0),
+                vec![state(0, 2.0, 0.0)],
+                vec![NetworkId::new(EntityType::Player, 1)],
+            ))
+            .unwrap()
+            .completed
+            .unwrap();
+
+        assert_eq!(snapshot, &Snapshot::new(vec![state(0, 2.0, 0.0)]));
+        assert_eq!(disappeared, vec![NetworkId::new(EntityType::Player, 1)]);
+    }
+
+    #[test]
+    fn outdated_update_is_dropped() {
+        let mut received = ReceivedSnapshots::default();
+        received.receive(&update(1, None, vec![state(0, 1.0, 0.0)], vec![]));
+
+        assert!(received
+            .receive(&update(0, None, vec![state(0, 2.0, 0.0)], vec![]))
+            .is_none());
+        assert_eq!(received.latest_id(), Some(1));
+    }
+
+    #[test]
+    fn chunks_are_applied_one_by_one() {
+        let mut received = ReceivedSnapshots::default();
+        received.receive(&update(
+            0,
+            None,
+            vec![state(0, 1.0, 0.0), state(1, 1.0, 0.0), state(2, 1.0, 0.0)],
+            vec![],
+        ));
+
+        let first = received
+            .receive(&chunk(
+                update(1, Some(0), vec![state(1, 2.0, 0.0)], vec![]),
+                1,
+                2,
+            ))
+            .unwrap();
+        assert_eq!(first.states, vec![state(1, 2.0, 0.0)]);
+        assert!(first.completed.is_none());
+        assert_eq!(received.latest_id(), Some(0));
+
+        let mut last = received
+            .receive(&chunk(
+                update(
+                    1,
+                    Some(0),
+                    vec![state(0, 2.0, 0.0)],
+                    vec![NetworkId::new(EntityType::Player, 2)],
+                ),
+                0,
+                2,
+            ))
+            .unwrap();
+        // The entities not updated by the previous chunks
+        last.states.sort_by_key(|state| state.network_id.id);
+        assert_eq!(last.states, vec![state(0, 2.0, 0.0)]);
+        let (snapshot, disappeared) = last.completed.unwrap();
+        assert_eq!(
+            snapshot,
+            &Snapshot::new(vec![state(0, 2.0, 0.0), state(1, 2.0, 0.0)])
+        );
+        assert_eq!(disappeared, vec![NetworkId::new(EntityType::Player, 2)]);
+        assert_eq!(received.latest_id(), Some(1));
+    }
+
+    #[test]
+    fn incomplete_snapshot_is_dropped_for_newer_one() {
+        let mut received = ReceivedSnapshots::default();
+        received.receive(&chunk(update(0, None, vec![state(0, 1.0, 0.0)], vec![]), 0, 2));
+
+        let newer = received
+            .receive(&update(1, None, vec![state(0, 2.0, 0.0)], vec![]))
+            .unwrap();
+        assert!(newer.completed.is_some());
+        assert!(received
+            .receive(&chunk(update(0, None, vec![state(1, 1.0, 0.0)], vec![]), 1, 2))
+            .is_none());
+        assert_eq!(received.latest_id(), Some(1));
+    }
+
+    #[test]
+    fn duplicate_chunk_is_dropped() {
+        let mut received = ReceivedSnapshots::default();
+        let first = chunk(update(0, None, vec![state(0, 1.0, 0.0)], vec![]), 0, 2);
+
+        assert!(received.receive(&first).is_some());
+        assert!(received.receive(&first).is_none());
+    }
+}
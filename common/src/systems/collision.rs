@@ -1,20 +1,50 @@
 use crate::collision::{check_body_collision, check_projectile_collision, Collider};
-use crate::components::{BoundingCircle, Damage, Health, Projectile, Velocity};
+use crate::components::{
+    BoundingCircle, Damage, Health, LagCompensation, PositionHistory, Projectile, Velocity,
+};
 use crate::events::{DamageEvent, EntityDelete};
 use crate::resources::collision::{
-    Collision, Collisions, ProjectileCollision, ProjectileCollisions,
+    Collision, Collisions, LagCompensationConfig, ProjectileCollision, ProjectileCollisions,
 };
+use bevy::ecs::schedule::StageLabelId;
 use bevy::prelude::*;
 
-pub struct CollisionPlugin;
+/// Adds the collision systems to the stage of the `physics` system
+pub struct CollisionPlugin {
+    stage: StageLabelId,
+}
+
+impl CollisionPlugin {
+    pub fn new(stage: impl StageLabel) -> Self {
+        CollisionPlugin {
+            stage: stage.as_label(),
+        }
+    }
+}
+
+impl Default for CollisionPlugin {
+    fn default() -> Self {
+        CollisionPlugin::new(CoreStage::Update)
+    }
+}
 
 impl bevy::app::Plugin for CollisionPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<Collisions>()
-            .init_resource::<ProjectileCollisions>();
+            .init_resource::<ProjectileCollisions>()
+            .init_resource::<LagCompensationConfig>();
 
-        app.add_system_set(collision_system_set().after("physics"));
-        app.add_system_set(projectile_collision_system_set().after("physics"));
+        app.add_system_set_to_stage(self.stage, collision_system_set().after("physics"));
+        app.add_system_set_to_stage(
+            self.stage,
+            projectile_collision_system_set().after("physics"),
+        );
+        app.add_system_to_stage(
+            self.stage,
+            record_position_history
+                .label("record_position_history")
+                .after("collision"),
+        );
     }
 }
 
@@ -81,20 +111,63 @@ fn handle_obstacle_collisions(
     }
 }
 
+/// Moving bodies get their position history recorded, so the hits of lag compensated
+/// projectiles can be checked against their past positions. Static bodies do not need it.
+#[allow(clippy::type_complexity)]
+fn record_position_history(
+    mut commands: Commands,
+    time: Res<Time>,
+    config: Res<LagCompensationConfig>,
+    mut bodies: Query<
+        (Entity, &Transform, Option<&mut PositionHistory>),
+        (With<BoundingCircle>, With<Velocity>, Without<Projectile>),
+    >,
+) {
+    let keep = config.max_rewind.into_duration();
+    for (entity, transform, maybe_history) in bodies.iter_mut() {
+        match maybe_history {
+            Some(mut history) => history.record(time.elapsed(), transform.translation, keep),
+            None => {
+                let mut history = PositionHistory::default();
+                history.record(time.elapsed(), transform.translation, keep);
+                commands.entity(entity).insert(history);
+            }
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
 fn collect_projectile_collisions(
     mut collision_res: ResMut<ProjectileCollisions>,
-    projectile_query: Query<(Entity, &Transform), With<Projectile>>,
-    maybe_collidee_query: Query<(Entity, &Transform, &BoundingCircle), Without<Projectile>>,
+    time: Res<Time>,
+    projectile_query: Query<(Entity, &Transform, Option<&LagCompensation>), With<Projectile>>,
+    maybe_collidee_query: Query<
+        (
+            Entity,
+            &Transform,
+            &BoundingCircle,
+            Option<&PositionHistory>,
+        ),
+        Without<Projectile>,
+    >,
 ) {
     collision_res.0.clear();
-    for (projectile_id, projectile_transform) in projectile_query.iter() {
-        for (maybe_collidee_id, maybe_collidee_transform, maybe_collidee_bounds) in
+    for (projectile_id, projectile_transform, maybe_lag_compensation) in projectile_query.iter() {
+        for (maybe_collidee_id, maybe_collidee_transform, maybe_collidee_bounds, maybe_history) in
             maybe_collidee_query.iter()
         {
+            let collidee_transform = rewound_transform(
+                maybe_collidee_id,
+                maybe_collidee_transform,
+                maybe_history,
+                maybe_lag_compensation,
+                time.elapsed(),
+            );
+
             if let Some(collision) = check_projectile_collision(
                 projectile_transform,
                 Collider {
-                    transform: maybe_collidee_transform,
+                    transform: &collidee_transform,
                     bound: maybe_collidee_bounds,
                 },
             ) {
@@ -108,6 +181,28 @@ fn collect_projectile_collisions(
     }
 }
 
+/// Returns the transform of the body at the time the shooter saw it
+fn rewound_transform(
+    entity: Entity,
+    transform: &Transform,
+    maybe_history: Option<&PositionHistory>,
+    maybe_lag_compensation: Option<&LagCompensation>,
+    now: std::time::Duration,
+) -> Transform {
+    match (maybe_history, maybe_lag_compensation) {
+        (Some(history), Some(lag_compensation)) if lag_compensation.shooter != entity => {
+            let mut rewound = *transform;
+            if let Some(translation) =
+                history.position_at(now.saturating_sub(lag_compensation.rewind))
+            {
+                rewound.translation = translation;
+            }
+            rewound
+        }
+        _ => *transform,
+    }
+}
+
 // Here Projectile components are not explicitly filtered. ProjectCollisionSystem is expected
 // to put proper entities in `collision.projectile`
 fn handle_projectile_collisions(
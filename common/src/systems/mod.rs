@@ -1,22 +1,22 @@
 pub use collision::CollisionPlugin;
 pub use lifespan::lifespan_system;
 pub use physics::physics;
+pub use player_movement::{apply_input, simulate_input};
 
 mod collision;
 mod lifespan;
 mod physics;
+mod player_movement;
 
-use crate::resources;
-use bevy::prelude::{Commands, Res};
+use crate::components::MapPosition;
+use bevy::prelude::{Changed, Query, Transform};
 
-pub fn build_map(
-    commands: Commands,
-    seed: Res<resources::Seed>,
-    res_dir: Res<resources::ResourcesDir>,
+/// Moves the map entities to their changed position
+pub fn move_map_entities(
+    mut map_entities: Query<(&MapPosition, &mut Transform), Changed<MapPosition>>,
 ) {
-    let res = resources::map::build_map(commands, *seed, &res_dir.common_resources.join("map"));
-    match res {
-        Ok(()) => bevy::log::info!("Map built"),
-        Err(err) => bevy::log::error!("{}", err),
-    };
+    for (position, mut transform) in map_entities.iter_mut() {
+        transform.translation.x = position.0.x.into_pixel();
+        transform.translation.y = position.0.y.into_pixel();
+    }
 }
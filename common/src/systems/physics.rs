@@ -1,10 +1,14 @@
 use crate::components::Velocity;
 use crate::metric_dimension::Second;
+use crate::resources::SimulationTime;
 use bevy::prelude::*;
 
-pub fn physics(time: Res<Time>, mut query: Query<(&mut Transform, &Velocity)>) {
+pub fn physics(
+    simulation_time: Res<SimulationTime>,
+    mut query: Query<(&mut Transform, &Velocity)>,
+) {
     for (mut transform, velocity) in query.iter_mut() {
-        update_position(&mut transform, velocity, Second(time.delta_seconds()))
+        update_position(&mut transform, velocity, simulation_time.delta_seconds())
     }
 }
 
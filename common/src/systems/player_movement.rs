@@ -1,18 +1,30 @@
+use crate::components::{Input, InputFlags, Velocity};
+use crate::metric_dimension::{MeterPerSec, MeterPerSecVec2, Second};
+use crate::utilities::rotate_toward_point;
+use crate::MoveDirection;
 use bevy::prelude::*;
-use westiny_common::components::{Input, InputFlags, Velocity};
-use westiny_common::metric_dimension::{MeterPerSec, MeterPerSecVec2};
-use westiny_common::utilities::rotate_toward_point;
-use westiny_common::MoveDirection;
 
-pub fn apply_input(mut query: Query<(&GlobalTransform, &mut Transform, &mut Velocity, &Input)>) {
-    for (global_transform, mut transform, mut velocity, input) in query.iter_mut() {
-        rotate_toward_point(&mut transform, &input.cursor.into_pixel_vec());
-
-        let move_inputs = move_directions_from_input(input);
-        *velocity = get_velocity(&global_transform.to_scale_rotation_translation().1, &move_inputs);
+pub fn apply_input(mut query: Query<(&mut Transform, &mut Velocity, &Input)>) {
+    for (mut transform, mut velocity, input) in query.iter_mut() {
+        *velocity = apply_rotation_and_get_velocity(&mut transform, input);
     }
 }
 
+/// Moves the transform as `apply_input` and `physics` would do on the server during `delta_time`.
+/// Used by the client to predict the movement of its own player.
+pub fn simulate_input(transform: &mut Transform, input: &Input, delta_time: Second) {
+    let velocity = apply_rotation_and_get_velocity(transform, input);
+    let delta = delta_time * velocity.0;
+    transform.translation += delta.into_pixel_vec().extend(0.0);
+}
+
+fn apply_rotation_and_get_velocity(transform: &mut Transform, input: &Input) -> Velocity {
+    rotate_toward_point(transform, &input.cursor.into_pixel_vec());
+
+    let move_inputs = move_directions_from_input(input);
+    get_velocity(&transform.rotation, &move_inputs)
+}
+
 fn move_directions_from_input(input: &Input) -> Vec<MoveDirection> {
     let mut directions = Vec::new();
     if input.flags.intersects(InputFlags::FORWARD) {
@@ -74,6 +86,7 @@ fn as_vector2(move_dir: MoveDirection) -> MeterPerSecVec2 {
 mod test {
     use super::*;
     use bevy::prelude::Transform;
+    use crate::metric_dimension::length::{Meter, MeterVec2};
     use std::f32::consts::PI;
     use westiny_test::assert_delta;
 
@@ -113,4 +126,19 @@ mod test {
             fwd_right: FACING_RIGHT, vec!{Forward}, (PLAYER_MAX_WALK_SPEED, MeterPerSec(0.0)),
         }
     }
+
+    #[test]
+    fn simulated_input_moves_toward_cursor() {
+        let mut transform = Transform::default();
+        let input = Input {
+            flags: InputFlags::FORWARD,
+            cursor: MeterVec2::from_raw(10.0, 0.0),
+        };
+
+        simulate_input(&mut transform, &input, Second(0.5));
+
+        let [x, y, _] = transform.translation.to_array();
+        assert_delta!(Meter(2.0).into_pixel(), x, 0.0001);
+        assert_delta!(0.0, y, 0.0001);
+    }
 }
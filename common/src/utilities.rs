@@ -1,5 +1,8 @@
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::fmt::Debug;
 use std::io::Read;
+use std::path::Path;
 
 pub fn read_ron<T>(ron_path: &std::path::Path) -> anyhow::Result<T>
 where
@@ -19,6 +22,27 @@ where
     Ok(deserialized)
 }
 
+/// Reads the `what` configuration from `file` of `dir`, falling back to the default if it cannot
+/// be read
+pub fn load_config<T>(dir: &Path, file: &str, what: &str) -> T
+where
+    T: DeserializeOwned + Default + Debug,
+{
+    let ron_path = dir.join(file);
+    read_ron::<T>(&ron_path).unwrap_or_else(|err| {
+        let config = T::default();
+        log::warn!(
+            "Failed to read {} configuration file: {}, error: [{}] \
+            Using default configuration ({:?})",
+            what,
+            ron_path.display(),
+            err,
+            config
+        );
+        config
+    })
+}
+
 pub fn rotate_toward_point(
     transform: &mut bevy::transform::components::Transform,
     vector: &bevy::math::Vec2,
@@ -105,4 +129,15 @@ mod test {
             assert_delta!(angle, actual, 0.0001);
         }
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_config_falls_back_to_default() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // The path is logged even if it is not valid UTF-8
+        let dir = std::path::Path::new(std::ffi::OsStr::from_bytes(b"missing \xff"));
+        let config: Vec<u32> = crate::utilities::load_config(dir, "missing.ron", "missing");
+        assert!(config.is_empty());
+    }
 }
@@ -0,0 +1,111 @@
+use bevy::ecs::component::Component;
+use std::collections::VecDeque;
+use westiny_common::network::{InputSequence, SequencedInput};
+
+/// When the client sends faster than the server processes, the oldest inputs are dropped
+/// above this limit
+const MAX_QUEUED_INPUTS: usize = 32;
+
+/// Inputs received from the client, waiting to be applied to its player one per tick
+#[derive(Debug, Default, Component)]
+pub struct InputQueue {
+    inputs: VecDeque<SequencedInput>,
+    last_received: Option<InputSequence>,
+}
+
+impl InputQueue {
+    /// Enqueues the inputs which have not been received yet (e.g. as a redundant copy)
+    pub fn enqueue<I>(&mut self, inputs: I)
+    where
+        I: IntoIterator<Item = SequencedInput>,
+    {
+        let mut inputs: Vec<_> = inputs.into_iter().collect();
+        inputs.sort_by_key(|input| input.sequence);
+
+        for input in inputs {
+            if self
+                .last_received
+                .is_some_and(|last| input.sequence <= last)
+            {
+                continue;
+            }
+            if self.inputs.len() >= MAX_QUEUED_INPUTS {
+                self.inputs.pop_front();
+            }
+            self.last_received = Some(input.sequence);
+            self.inputs.push_back(input);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<SequencedInput> {
+        self.inputs.pop_front()
+    }
+
+    /// Drops the queued inputs, the ones already received are still not accepted again
+    pub fn clear(&mut self) {
+        self.inputs.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use westiny_common::components::{Input, InputFlags};
+
+    fn input(sequence: InputSequence, flags: InputFlags) -> SequencedInput {
+        SequencedInput {
+            sequence,
+            input: Input {
+                flags,
+                ..Input::default()
+            },
+        }
+    }
+
+    #[test]
+    fn redundant_inputs_are_enqueued_once() {
+        let mut queue = InputQueue::default();
+        queue.enqueue(vec![input(0, InputFlags::NOP), input(1, InputFlags::SHOOT)]);
+        queue.enqueue(vec![
+            input(0, InputFlags::NOP),
+            input(1, InputFlags::SHOOT),
+            input(2, InputFlags::NOP),
+        ]);
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.pop(), Some(input(0, InputFlags::NOP)));
+        assert_eq!(queue.pop(), Some(input(1, InputFlags::SHOOT)));
+        assert_eq!(queue.pop(), Some(input(2, InputFlags::NOP)));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn outdated_inputs_are_dropped() {
+        let mut queue = InputQueue::default();
+        queue.enqueue(vec![input(5, InputFlags::NOP)]);
+        queue.pop();
+        queue.enqueue(vec![
+            input(3, InputFlags::SHOOT),
+            input(4, InputFlags::SHOOT),
+        ]);
+
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn oldest_inputs_are_dropped_when_full() {
+        let mut queue = InputQueue::default();
+        queue.enqueue((0..MAX_QUEUED_INPUTS as u32 + 2).map(|seq| input(seq, InputFlags::NOP)));
+
+        assert_eq!(queue.len(), MAX_QUEUED_INPUTS);
+        assert_eq!(queue.pop().map(|input| input.sequence), Some(2));
+    }
+}
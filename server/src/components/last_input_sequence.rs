@@ -0,0 +1,6 @@
+use bevy::ecs::component::Component;
+use westiny_common::network::InputSequence;
+
+/// Sequence number of the latest input of the client which has been applied to its player
+#[derive(Copy, Clone, Debug, Default, Component)]
+pub struct LastInputSequence(pub Option<InputSequence>);
@@ -1,4 +1,10 @@
 pub(crate) use client::Client;
+pub(crate) use input_queue::InputQueue;
+pub(crate) use last_input_sequence::LastInputSequence;
+pub(crate) use view_time::ViewTime;
 pub(crate) use westiny_common::components::*;
 
 mod client;
+mod input_queue;
+mod last_input_sequence;
+mod view_time;
@@ -0,0 +1,6 @@
+use bevy::ecs::component::Component;
+use std::time::Duration;
+
+/// Estimated server time of the world displayed by the client at its latest input
+#[derive(Copy, Clone, Debug, Default, Component)]
+pub struct ViewTime(pub Option<Duration>);
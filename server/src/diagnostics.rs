@@ -1,4 +1,4 @@
-use crate::resources::ClientRegistry;
+use crate::resources::{ClientRegistry, Compressor, PacketGuard};
 use bevy::app::PluginGroupBuilder;
 use bevy::diagnostic::{
     Diagnostic, DiagnosticId, Diagnostics, FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin,
@@ -12,16 +12,58 @@ pub struct WestinyDiagnosticsPlugin;
 impl Plugin for WestinyDiagnosticsPlugin {
     fn build(&self, app: &mut App) {
         app.add_startup_system(Self::setup_system)
-            .add_system(Self::log_clients);
+            .add_system(Self::log_clients)
+            .add_system(Self::log_packet_guard)
+            .add_system(Self::log_compression);
     }
 }
 
 impl WestinyDiagnosticsPlugin {
     pub const CLIENT_DIAG_ID: DiagnosticId =
         DiagnosticId::from_u128(11365578623599151819941644670456314399);
+    pub const MEAN_RTT_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(48478912317648229543767890418318754219);
+    pub const OVERSIZED_PACKETS_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(88697473201723984094255615947914746876);
+    pub const RATE_LIMITED_PACKETS_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(155341255361163503976631628506497413733);
+    pub const MALFORMED_PACKETS_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(9348953601635021016961448568313396755);
+    pub const BANNED_PACKETS_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(25053008995986949086574252110677169905);
+    pub const ACTIVE_BANS_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(62491586426985041564217003825579084376);
+    pub const COMPRESSION_RATIO_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(41279153721212558919706098700045333951);
+    pub const COMPRESSION_TIME_DIAG_ID: DiagnosticId =
+        DiagnosticId::from_u128(76075813686191944178184773060673647909);
 
     fn log_clients(mut diag: ResMut<Diagnostics>, registry: Res<ClientRegistry>) {
         diag.add_measurement(Self::CLIENT_DIAG_ID, || registry.client_count() as f64);
+        if let Some(rtt) = registry.mean_rtt() {
+            diag.add_measurement(Self::MEAN_RTT_DIAG_ID, || rtt.as_secs_f64() * 1000.0);
+        }
+    }
+
+    /// The packet counters are totals since the server has started
+    fn log_packet_guard(mut diag: ResMut<Diagnostics>, guard: Res<PacketGuard>) {
+        let stats = guard.stats();
+        diag.add_measurement(Self::OVERSIZED_PACKETS_DIAG_ID, || stats.oversized as f64);
+        diag.add_measurement(Self::RATE_LIMITED_PACKETS_DIAG_ID, || stats.rate_limited as f64);
+        diag.add_measurement(Self::MALFORMED_PACKETS_DIAG_ID, || stats.malformed as f64);
+        diag.add_measurement(Self::BANNED_PACKETS_DIAG_ID, || stats.from_banned as f64);
+        diag.add_measurement(Self::ACTIVE_BANS_DIAG_ID, || guard.active_bans() as f64);
+    }
+
+    /// Totals since the server has started, the ratio is the sent size relative to the raw size
+    fn log_compression(mut diag: ResMut<Diagnostics>, compressor: Res<Compressor>) {
+        let stats = compressor.stats();
+        if let Some(ratio) = stats.ratio() {
+            diag.add_measurement(Self::COMPRESSION_RATIO_DIAG_ID, || ratio * 100.0);
+        }
+        if let Some(time) = stats.mean_time() {
+            diag.add_measurement(Self::COMPRESSION_TIME_DIAG_ID, || time.as_secs_f64() * 1e6);
+        }
     }
 
     fn setup_system(mut diagnostics: ResMut<Diagnostics>) {
@@ -30,6 +72,43 @@ impl WestinyDiagnosticsPlugin {
             "Number of online players",
             1,
         ));
+        diagnostics.add(
+            Diagnostic::new(Self::MEAN_RTT_DIAG_ID, "Mean round trip time of players", 20)
+                .with_suffix("ms"),
+        );
+        diagnostics.add(Diagnostic::new(
+            Self::OVERSIZED_PACKETS_DIAG_ID,
+            "Oversized packets dropped",
+            1,
+        ));
+        diagnostics.add(Diagnostic::new(
+            Self::RATE_LIMITED_PACKETS_DIAG_ID,
+            "Rate limited packets dropped",
+            1,
+        ));
+        diagnostics.add(Diagnostic::new(
+            Self::MALFORMED_PACKETS_DIAG_ID,
+            "Malformed packets received",
+            1,
+        ));
+        diagnostics.add(Diagnostic::new(
+            Self::BANNED_PACKETS_DIAG_ID,
+            "Packets of banned addresses dropped",
+            1,
+        ));
+        diagnostics.add(Diagnostic::new(
+            Self::ACTIVE_BANS_DIAG_ID,
+            "Banned addresses",
+            1,
+        ));
+        diagnostics.add(
+            Diagnostic::new(Self::COMPRESSION_RATIO_DIAG_ID, "Compression ratio", 1)
+                .with_suffix("%"),
+        );
+        diagnostics.add(
+            Diagnostic::new(Self::COMPRESSION_TIME_DIAG_ID, "Mean compression time", 1)
+                .with_suffix("us"),
+        );
     }
 }
 
@@ -43,6 +122,14 @@ impl PluginGroup for DiagnosticPlugins {
                 filter: Some(vec![
                     FrameTimeDiagnosticsPlugin::FPS,
                     WestinyDiagnosticsPlugin::CLIENT_DIAG_ID,
+                    WestinyDiagnosticsPlugin::MEAN_RTT_DIAG_ID,
+                    WestinyDiagnosticsPlugin::OVERSIZED_PACKETS_DIAG_ID,
+                    WestinyDiagnosticsPlugin::RATE_LIMITED_PACKETS_DIAG_ID,
+                    WestinyDiagnosticsPlugin::MALFORMED_PACKETS_DIAG_ID,
+                    WestinyDiagnosticsPlugin::BANNED_PACKETS_DIAG_ID,
+                    WestinyDiagnosticsPlugin::ACTIVE_BANS_DIAG_ID,
+                    WestinyDiagnosticsPlugin::COMPRESSION_RATIO_DIAG_ID,
+                    WestinyDiagnosticsPlugin::COMPRESSION_TIME_DIAG_ID,
                 ]),
                 debug: false,
                 wait_duration: Duration::from_secs(3),
@@ -0,0 +1,327 @@
+use std::path::{Path, PathBuf};
+
+use westiny_common::demo::DemoHeader;
+use westiny_common::network::PROTOCOL_VERSION;
+use westiny_common::replication::{self, ReplicationBuffer};
+use westiny_common::resources::collision::LagCompensationConfig;
+use westiny_common::secure_channel::{self, encode_hex};
+use westiny_common::utilities::{load_config, read_ron};
+
+use bevy::prelude::*;
+use bevy::time::{FixedTimestep, FixedTimesteps};
+
+use crate::resources::{ClientNetworkEvent, ClientRegistry, NetworkCommand};
+
+pub mod components;
+pub mod diagnostics;
+pub mod resources;
+pub mod systems;
+
+const WEAPONS_DIR: &str = "assets/weapons";
+
+/// Stage of the game simulation, run with a fixed timestep
+pub const SIMULATION_STAGE: &str = "simulation";
+/// Stage of the entity state snapshots, run with a fixed timestep
+pub const SNAPSHOT_STAGE: &str = "snapshot";
+
+/// The game server without the transport layer, time and logging.
+/// Expects `TransportResource`, `NetworkSimulationEvent` and `Time` to be provided by the app.
+pub struct ServerPlugin {
+    pub resources_dir: PathBuf,
+    pub interest_radius: resources::InterestRadius,
+    pub lag_compensation: LagCompensationConfig,
+    pub simulation: resources::SimulationConfig,
+    /// Credentials asked from the connecting clients
+    pub auth: resources::AuthConfig,
+    /// Limits of the packets accepted from one address
+    pub packet_guard: resources::PacketGuardConfig,
+    /// Size limits of the entity state updates sent to a client
+    pub snapshot_budget: resources::SnapshotBudget,
+    /// Codecs the clients may ask for
+    pub codecs: resources::CodecConfig,
+    /// Compression of the large packets, for the clients which ask for it
+    pub compression: resources::CompressionConfig,
+    /// Signs the key exchange of the secure sessions
+    pub identity: resources::ServerIdentity,
+    /// Seed of the randomness of the game logic
+    pub rng_seed: u64,
+    /// The session is recorded to this demo file, if set
+    pub demo_path: Option<PathBuf>,
+}
+
+impl ServerPlugin {
+    /// Reads the configuration files of `resources_dir`, falling back to the defaults
+    pub fn load(resources_dir: &Path) -> Self {
+        let interest_radius = load_config(resources_dir, "interest.ron", "interest");
+        let lag_compensation =
+            load_config(resources_dir, "lag_compensation.ron", "lag compensation");
+        let simulation = load_config(resources_dir, "simulation.ron", "simulation");
+        let packet_guard = load_config(resources_dir, "packet_guard.ron", "packet guard");
+        let snapshot_budget = load_config(resources_dir, "snapshot_budget.ron", "snapshot budget");
+        let codecs = load_config(resources_dir, "codecs.ron", "codec");
+        let compression = load_config(resources_dir, "compression.ron", "compression");
+
+        // A broken key must not change the identity of the server by accident
+        let identity = {
+            let config: resources::SecurityConfig =
+                load_config(resources_dir, "security.ron", "security");
+            config.identity().unwrap_or_else(|err| {
+                panic!(
+                    "Invalid identity key in {}: {}",
+                    resources_dir.join("security.ron").display(),
+                    err
+                )
+            })
+        };
+        log::info!("Server identity: {}", encode_hex(&identity.public_key()));
+
+        // A missing file means an open server, but a broken one must not open it by accident
+        let auth = {
+            let ron_path = resources_dir.join("auth.ron");
+            if ron_path.exists() {
+                read_ron::<resources::AuthConfig>(&ron_path).unwrap_or_else(|err| {
+                    panic!(
+                        "Failed to read authentication configuration file: {}, error: [{}]",
+                        ron_path.display(),
+                        err
+                    )
+                })
+            } else {
+                log::info!("No authentication configuration, the server is open to anyone");
+                resources::AuthConfig::default()
+            }
+        };
+
+        ServerPlugin {
+            resources_dir: resources_dir.to_path_buf(),
+            interest_radius,
+            lag_compensation,
+            simulation,
+            auth,
+            packet_guard,
+            snapshot_budget,
+            codecs,
+            compression,
+            identity,
+            rng_seed: rand::random(),
+            demo_path: None,
+        }
+    }
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        let weapons_path = self.resources_dir.join(WEAPONS_DIR);
+        let gun_resource =
+            resources::weapon::GunResource::load(&weapons_path).unwrap_or_else(|_| {
+                panic!(
+                    "Unable to load weapons from directory: {:?}",
+                    std::fs::canonicalize(weapons_path).unwrap()
+                )
+            });
+
+        let map_seed = resources::Seed(0); // Hard-coded seed for now
+
+        app.insert_resource(ClientRegistry::new(64))
+            .insert_resource(resources::Authenticator::new(self.auth.clone()))
+            .insert_resource(resources::PacketGuard::new(self.packet_guard))
+            .insert_resource(resources::ClientSnapshots::new(self.snapshot_budget))
+            .insert_resource(self.codecs.clone())
+            .insert_resource(self.compression)
+            .init_resource::<resources::Compressor>()
+            .insert_resource(resources::SecureChannels::server(self.identity.clone()))
+            .init_resource::<resources::Outbox>()
+            .init_resource::<ReplicationBuffer>()
+            .insert_resource(resources::InterestManager::new(self.interest_radius))
+            .insert_resource(self.lag_compensation)
+            .insert_resource(self.simulation)
+            .init_resource::<resources::SimulationTime>()
+            .init_resource::<resources::ShutdownSignal>()
+            .insert_resource(map_seed)
+            .insert_resource(resources::NetworkIdSupplier::new())
+            .insert_resource(resources::GameRng::new(self.rng_seed))
+            .insert_resource(gun_resource)
+            .insert_resource(resources::ResourcesDir {
+                common_resources: self.resources_dir.clone(),
+                crate_resources: self.resources_dir.clone(),
+            })
+            .add_event::<ClientNetworkEvent>()
+            .add_event::<NetworkCommand>()
+            .add_event::<systems::SpawnPlayerEvent>()
+            .add_event::<westiny_common::events::DamageEvent>()
+            .add_event::<westiny_common::events::EntityDelete>()
+            .add_plugin(TransformPlugin)
+            .init_resource::<FixedTimesteps>()
+            .add_stage_after(
+                CoreStage::Update,
+                SIMULATION_STAGE,
+                SystemStage::parallel().with_run_criteria(FixedTimestep::step(
+                    self.simulation.tick_step().as_secs_f64(),
+                )),
+            )
+            .add_stage_after(
+                SIMULATION_STAGE,
+                SNAPSHOT_STAGE,
+                SystemStage::parallel().with_run_criteria(FixedTimestep::step(
+                    self.simulation.snapshot_step().as_secs_f64(),
+                )),
+            )
+            .add_startup_system(systems::build_map)
+            .add_system(systems::open_client_packets.label("open_packets"))
+            .add_system(
+                systems::read_network_messages
+                    .label("network_input")
+                    .after("open_packets"),
+            )
+            .add_system(systems::expire_secure_sessions.after("network_input"))
+            .add_system(
+                systems::expire_suspended_clients
+                    .label("expire_suspended_clients")
+                    .after("network_input")
+                    .before("introduce_client"),
+            )
+            .add_system(
+                systems::introduce_new_clients
+                    .label("introduce_client")
+                    .after("network_input"),
+            )
+            .add_system(
+                systems::spawn_player
+                    .label("spawn_player")
+                    .after("introduce_client"),
+            )
+            .add_system(systems::shut_down_on_signal.after("introduce_client"))
+            .add_system(systems::ping_clients.after("network_input"))
+            .add_system(
+                systems::transform_commands
+                    .label("transform_commands")
+                    .after("network_input"),
+            )
+            .add_system(
+                systems::receive_snapshot_acks
+                    .label("snapshot_acks")
+                    .after("network_input"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::advance_simulation_tick.label("simulation_tick"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::dequeue_inputs
+                    .label("dequeue_inputs")
+                    .after("simulation_tick"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::apply_input
+                    .label("apply_input")
+                    .after("dequeue_inputs"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::physics.label("physics").after("apply_input"),
+            )
+            .add_plugin(systems::CollisionPlugin::new(SIMULATION_STAGE))
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::handle_damage
+                    .label("health")
+                    .after("projectile_collision"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::handle_death.label("death").after("health"),
+            )
+            .add_system_set_to_stage(
+                SIMULATION_STAGE,
+                systems::weapon_handler_system_set()
+                    .label("weapon_handler")
+                    .after("dequeue_inputs"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::lifespan_system
+                    .label("lifespan")
+                    .after("simulation_tick"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::respawn_player.label("respawn").after("health"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::move_map_entities.after("simulation_tick"),
+            )
+            .add_system_to_stage(
+                SIMULATION_STAGE,
+                systems::send_component_updates
+                    .label("send_component_updates")
+                    .after("collect_replicated"),
+            )
+            .add_system(systems::introduce_replicated_components.after("introduce_client"))
+            .add_system_to_stage(
+                SNAPSHOT_STAGE,
+                systems::update_interest_centers.label("interest"),
+            )
+            .add_system_to_stage(
+                SNAPSHOT_STAGE,
+                systems::broadcast_entity_state
+                    .label("broadcast_entity_state")
+                    .after("interest"),
+            )
+            // Deletions are sent every frame, as they are requested from all the stages
+            .add_system_set_to_stage(
+                CoreStage::PostUpdate,
+                systems::entity_delete_system_set().label("entity_delete_ss"),
+            )
+            // The messages of every stage are serialized and handed to the transport at once
+            .add_system_to_stage(
+                CoreStage::Last,
+                systems::flush_outbox.label("flush_outbox"),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                secure_channel::seal_packets
+                    .label("seal_packets")
+                    .after("flush_outbox"),
+            );
+
+        // A system for each replicated component, after the ones changing them
+        let replicated = replication::replicated_components();
+        for system in replicated.collect_systems() {
+            app.add_system_to_stage(
+                SIMULATION_STAGE,
+                system.label("collect_replicated").after("respawn"),
+            );
+        }
+        app.insert_resource(replicated);
+
+        if let Some(demo_path) = &self.demo_path {
+            let header = DemoHeader {
+                protocol_version: PROTOCOL_VERSION,
+                tick_rate: self.simulation.tick_rate,
+                map_seed,
+                rng_seed: self.rng_seed,
+            };
+            let recorder =
+                resources::DemoRecorder::create(demo_path, &header).unwrap_or_else(|err| {
+                    panic!("Unable to create demo file {:?}: {}", demo_path, err)
+                });
+            log::info!("Recording demo to {:?}", demo_path);
+
+            app.insert_resource(recorder)
+                .add_system(
+                    systems::record_inbound
+                        .label("record_inbound")
+                        .after("network_input"),
+                )
+                .add_system_to_stage(
+                    CoreStage::Last,
+                    systems::record_outbound
+                        .after("flush_outbox")
+                        .before("seal_packets"),
+                );
+        }
+    }
+}
@@ -3,23 +3,19 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 use westiny_common::resources::ServerAddress;
+use westiny_common::secure_channel::{encode_hex, SERVER_KEY_FILE};
 use westiny_common::utilities::read_ron;
 use westiny_common::NetworkConfig;
 
 use blaminar::prelude::LaminarPlugin;
 
+use bevy::app::ScheduleRunnerSettings;
 use bevy::log::LogPlugin;
 use bevy::prelude::*;
 
-use crate::diagnostics::DiagnosticPlugins;
-use crate::resources::{ClientNetworkEvent, ClientRegistry, NetworkCommand};
-
-pub mod components;
-pub mod diagnostics;
-pub mod resources;
-pub mod systems;
-
-const WEAPONS_DIR: &str = "assets/weapons";
+use westiny_server::diagnostics::DiagnosticPlugins;
+use westiny_server::resources::ShutdownSignal;
+use westiny_server::ServerPlugin;
 
 fn main() {
     let resources_dir = PathBuf::from("resources");
@@ -55,90 +51,31 @@ fn main() {
             })
     };
 
-    let weapons_path = resources_dir.join(WEAPONS_DIR);
-    let gun_resource = resources::weapon::GunResource::load(&weapons_path).unwrap_or_else(|_| {
-        panic!(
-            "Unable to load weapons from directory: {:?}",
-            std::fs::canonicalize(weapons_path).unwrap()
-        )
-    });
+    let shutdown_signal = ShutdownSignal::default();
+    {
+        let shutdown_signal = shutdown_signal.clone();
+        ctrlc::set_handler(move || shutdown_signal.request())
+            .unwrap_or_else(|err| log::warn!("Unable to handle termination signals: {}", err));
+    }
+
+    let mut server_plugin = ServerPlugin::load(&resources_dir);
+    // The clients sharing the resources directory pin this server
+    let key_path = resources_dir.join(SERVER_KEY_FILE);
+    if let Err(err) = std::fs::write(&key_path, encode_hex(&server_plugin.identity.public_key())) {
+        log::warn!("Unable to write the identity key to {}: {}", key_path.display(), err);
+    }
+    server_plugin.demo_path = std::env::var_os("WESTINY_RECORD_DEMO").map(PathBuf::from);
 
     App::new()
-        .insert_resource(ClientRegistry::new(64))
-        .insert_resource(resources::Seed(0)) // Hard-coded seed for now
-        .insert_resource(resources::NetworkIdSupplier::new())
-        .insert_resource(gun_resource)
-        .insert_resource(resources::ResourcesDir {
-            common_resources: resources_dir.clone(),
-            crate_resources: resources_dir,
-        })
-        .add_event::<ClientNetworkEvent>()
-        .add_event::<NetworkCommand>()
-        .add_event::<systems::SpawnPlayerEvent>()
-        .add_event::<westiny_common::events::DamageEvent>()
-        .add_event::<westiny_common::events::EntityDelete>()
+        // The loop does not need to spin faster than the simulation
+        .insert_resource(ScheduleRunnerSettings::run_loop(
+            server_plugin.simulation.tick_step(),
+        ))
         .add_plugins(MinimalPlugins)
         .add_plugins(DiagnosticPlugins)
         .add_plugin(LogPlugin::default())
-        .add_plugin(TransformPlugin)
         .add_plugin(LaminarPlugin::new(socket_address, laminar_config))
-        .add_startup_system(systems::build_map)
-        .add_system(systems::read_network_messages.label("network_input"))
-        .add_system(
-            systems::introduce_new_clients
-                .label("introduce_client")
-                .after("network_input"),
-        )
-        .add_system(
-            systems::spawn_player
-                .label("spawn_player")
-                .after("introduce_client"),
-        )
-        .add_system(
-            systems::transform_commands
-                .label("transform_commands")
-                .after("network_input"),
-        )
-        .add_system(
-            systems::apply_input
-                .label("apply_input")
-                .after("transform_commands"),
-        )
-        .add_system(
-            systems::physics
-                .label("physics")
-                .after("apply_input")
-        )
-        .add_system(
-            systems::broadcast_entity_state
-                .label("broadcast_entity_state")
-                .after("introduce_client")
-                .after("physics"),
-        )
-        .add_plugin(systems::CollisionPlugin)
-        .add_system(
-            systems::handle_damage
-                .label("health")
-                .after("projectile_collision"),
-        )
-        .add_system(systems::handle_death.label("death").after("health"))
-        .add_system_set(systems::weapon_handler_system_set().label("weapon_handler"))
-        .add_system(systems::lifespan_system.label("lifespan"))
-        .add_system(systems::respawn_player.label("respawn").after("health"))
-
-        .add_system(
-            systems::send_health_update_on_change
-                .label("send_health_update")
-                .after("respawn")
-        )
-        .add_system_set(
-            systems::entity_delete_system_set()
-                .label("entity_delete_ss")
-                .after("projectile_collision")
-                .after("death")
-                .after("respawn")
-                .after("introduce_client")
-                .after("lifespan"),
-        )
+        .insert_resource(shutdown_signal)
+        .add_plugin(server_plugin)
         .run();
 }
@@ -0,0 +1,382 @@
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use westiny_common::auth::{self, AuthProof, Credential, Nonce};
+use westiny_common::compression::Compression;
+use westiny_common::network::{AuthChallenge, SessionToken};
+use westiny_common::secure_channel::HANDSHAKE_TIMEOUT;
+use westiny_common::serialization::PacketCodec;
+
+/// Challenges not answered within this are forgotten, as the session of their key exchange is
+const CHALLENGE_TIMEOUT: Duration = HANDSHAKE_TIMEOUT;
+/// Challenges waiting for an answer are forgotten beyond this, the oldest first
+const MAX_PENDING_CHALLENGES: usize = 1024;
+/// Challenges waiting for an answer from the addresses of one IP are forgotten beyond this, the
+/// oldest of the IP first, so one IP cannot push out the challenges of the others
+const MAX_PENDING_CHALLENGES_PER_IP: usize = 4;
+
+/// Credentials asked from the connecting clients. Nothing is asked by default.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Every client must know this password
+    pub password: Option<String>,
+    /// The players listed here must know their secret to use their name
+    pub player_secrets: HashMap<String, String>,
+}
+
+/// The connection request of a client, accepted after the client has answered the challenge
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectionAttempt {
+    pub player_name: String,
+    /// The client has a session to resume, it is asked to prove it has the token
+    pub resume_session: bool,
+    /// Negotiated from the codec asked by the client
+    pub codec: PacketCodec,
+    /// Negotiated from whether the client can decompress
+    pub compression: Option<Compression>,
+}
+
+/// The answer of the client to the challenge of the session it resumes. Only the client which
+/// has received the token in its `ConnectionResponse` can make it, the token is never sent back.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SessionProof {
+    nonce: Nonce,
+    proof: AuthProof,
+}
+
+impl SessionProof {
+    pub fn proves(&self, session_token: SessionToken, player_name: &str) -> bool {
+        auth::verify(
+            Credential::SessionToken,
+            &session_token.to_string(),
+            &self.nonce,
+            player_name,
+            &self.proof,
+        )
+    }
+}
+
+#[derive(Debug)]
+struct PendingChallenge {
+    addr: SocketAddr,
+    attempt: ConnectionAttempt,
+    nonce: Nonce,
+    issued: Duration,
+}
+
+#[derive(Debug, bevy::prelude::Resource)]
+pub struct Authenticator {
+    config: AuthConfig,
+    pending: VecDeque<PendingChallenge>,
+}
+
+impl Authenticator {
+    pub fn new(config: AuthConfig) -> Self {
+        Authenticator {
+            config,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Returns the challenge to be answered before the connection is accepted,
+    /// or None if no credential is required from the player and it does not resume a session.
+    /// A repeated request gets the same nonce, so the answer to the earlier challenge is valid.
+    pub fn challenge(
+        &mut self,
+        addr: &SocketAddr,
+        attempt: ConnectionAttempt,
+        now: Duration,
+    ) -> Option<AuthChallenge> {
+        let password_required = self.config.password.is_some();
+        let secret_required = self
+            .config
+            .player_secrets
+            .contains_key(&attempt.player_name);
+        let session_required = attempt.resume_session;
+        if !password_required && !secret_required && !session_required {
+            return None;
+        }
+
+        self.expire(now);
+        let nonce = match self.pending.iter_mut().find(|pending| pending.addr == *addr) {
+            Some(pending) if pending.attempt.player_name == attempt.player_name => {
+                pending.attempt = attempt;
+                pending.nonce
+            }
+            _ => {
+                self.pending.retain(|pending| pending.addr != *addr);
+                self.make_room(addr.ip());
+                let nonce = rand::random();
+                self.pending.push_back(PendingChallenge {
+                    addr: *addr,
+                    attempt,
+                    nonce,
+                    issued: now,
+                });
+                nonce
+            }
+        };
+
+        Some(AuthChallenge {
+            nonce,
+            password_required,
+            secret_required,
+            session_required,
+        })
+    }
+
+    /// Checks the answer to the challenge of the address. The challenge is used up either way.
+    /// Returns the connection request which may be accepted, or None if the proofs are wrong
+    /// or there was no challenge within `CHALLENGE_TIMEOUT`. The proof of the session is returned
+    /// with the request, it is checked against the token of the session the client resumes.
+    pub fn verify(
+        &mut self,
+        addr: &SocketAddr,
+        password_proof: Option<AuthProof>,
+        secret_proof: Option<AuthProof>,
+        session_proof: Option<AuthProof>,
+        now: Duration,
+    ) -> Option<(ConnectionAttempt, Option<SessionProof>)> {
+        self.expire(now);
+        let index = self
+            .pending
+            .iter()
+            .position(|pending| pending.addr == *addr)?;
+        let PendingChallenge { attempt, nonce, .. } = self.pending.remove(index)?;
+        let session_proof = session_proof.map(|proof| SessionProof { nonce, proof });
+
+        let proven = |credential, key: Option<&String>, proof: Option<AuthProof>| match key {
+            Some(key) => proof.map_or(false, |proof| {
+                auth::verify(credential, key, &nonce, &attempt.player_name, &proof)
+            }),
+            None => true,
+        };
+        let password = self.config.password.as_ref();
+        let secret = self.config.player_secrets.get(&attempt.player_name);
+        if proven(Credential::ServerPassword, password, password_proof)
+            && proven(Credential::PlayerSecret, secret, secret_proof)
+        {
+            Some((attempt, session_proof))
+        } else {
+            None
+        }
+    }
+
+    fn expire(&mut self, now: Duration) {
+        self.pending
+            .retain(|pending| now.saturating_sub(pending.issued) < CHALLENGE_TIMEOUT);
+    }
+
+    /// Forgets the oldest challenge of the IP, or of anyone, if there are too many pending
+    fn make_room(&mut self, ip: IpAddr) {
+        let of_ip = self
+            .pending
+            .iter()
+            .filter(|pending| pending.addr.ip() == ip)
+            .count();
+        let index = if of_ip >= MAX_PENDING_CHALLENGES_PER_IP {
+            self.pending.iter().position(|pending| pending.addr.ip() == ip)
+        } else if self.pending.len() >= MAX_PENDING_CHALLENGES {
+            Some(0)
+        } else {
+            None
+        };
+        if let Some(evicted) = index.and_then(|index| self.pending.remove(index)) {
+            log::warn!(
+                "Too many pending authentication challenges, the one of {} is forgotten",
+                evicted.addr
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use westiny_common::auth::prove;
+
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 4242))
+    }
+
+    fn attempt(player_name: &str) -> ConnectionAttempt {
+        ConnectionAttempt {
+            player_name: player_name.to_string(),
+            resume_session: false,
+            codec: PacketCodec::default(),
+            compression: None,
+        }
+    }
+
+    fn authenticator() -> Authenticator {
+        Authenticator::new(AuthConfig {
+            password: Some("sesame".to_string()),
+            player_secrets: [("Clint".to_string(), "poncho".to_string())]
+                .into_iter()
+                .collect(),
+        })
+    }
+
+    #[test]
+    fn nothing_is_asked_without_credentials() {
+        let mut authenticator = Authenticator::new(AuthConfig::default());
+
+        assert_eq!(
+            authenticator.challenge(&addr(), attempt("Clint"), Duration::ZERO),
+            None
+        );
+    }
+
+    #[test]
+    fn correct_proofs_are_accepted() {
+        let mut authenticator = authenticator();
+        let challenge = authenticator
+            .challenge(&addr(), attempt("Clint"), Duration::ZERO)
+            .unwrap();
+        assert!(challenge.password_required);
+        assert!(challenge.secret_required);
+
+        let password_proof = prove(Credential::ServerPassword, "sesame", &challenge.nonce, "Clint");
+        let secret_proof = prove(Credential::PlayerSecret, "poncho", &challenge.nonce, "Clint");
+
+        assert_eq!(
+            authenticator.verify(
+                &addr(),
+                Some(password_proof),
+                Some(secret_proof),
+                None,
+                Duration::ZERO
+            ),
+            Some((attempt("Clint"), None))
+        );
+    }
+
+    #[test]
+    fn missing_or_wrong_proofs_are_refused() {
+        let mut authenticator = authenticator();
+        let challenge = authenticator
+            .challenge(&addr(), attempt("Clint"), Duration::ZERO)
+            .unwrap();
+        let password_proof = prove(Credential::ServerPassword, "sesame", &challenge.nonce, "Clint");
+        assert_eq!(
+            authenticator.verify(&addr(), Some(password_proof), None, None, Duration::ZERO),
+            None
+        );
+
+        let challenge = authenticator
+            .challenge(&addr(), attempt("Tuco"), Duration::ZERO)
+            .unwrap();
+        assert!(!challenge.secret_required);
+        let password_proof = prove(Credential::ServerPassword, "simsim", &challenge.nonce, "Tuco");
+        assert_eq!(
+            authenticator.verify(&addr(), Some(password_proof), None, None, Duration::ZERO),
+            None
+        );
+    }
+
+    #[test]
+    fn challenge_is_used_once() {
+        let mut authenticator = authenticator();
+        let challenge = authenticator
+            .challenge(&addr(), attempt("Tuco"), Duration::ZERO)
+            .unwrap();
+        let password_proof = prove(Credential::ServerPassword, "sesame", &challenge.nonce, "Tuco");
+
+        assert!(authenticator
+            .verify(&addr(), Some(password_proof), None, None, Duration::ZERO)
+            .is_some());
+        assert!(authenticator
+            .verify(&addr(), Some(password_proof), None, None, Duration::ZERO)
+            .is_none());
+    }
+
+    #[test]
+    fn repeated_request_gets_same_nonce() {
+        let mut authenticator = authenticator();
+        let first = authenticator
+            .challenge(&addr(), attempt("Tuco"), Duration::ZERO)
+            .unwrap();
+        let second = authenticator
+            .challenge(&addr(), attempt("Tuco"), Duration::from_secs(1))
+            .unwrap();
+
+        assert_eq!(first.nonce, second.nonce);
+    }
+
+    #[test]
+    fn challenge_expires() {
+        let mut authenticator = authenticator();
+        let challenge = authenticator
+            .challenge(&addr(), attempt("Tuco"), Duration::ZERO)
+            .unwrap();
+        let password_proof = prove(Credential::ServerPassword, "sesame", &challenge.nonce, "Tuco");
+
+        assert_eq!(
+            authenticator.verify(&addr(), Some(password_proof), None, None, CHALLENGE_TIMEOUT),
+            None
+        );
+        assert!(authenticator.pending.is_empty());
+    }
+
+    #[test]
+    fn challenges_of_one_ip_do_not_push_out_the_others() {
+        let mut authenticator = authenticator();
+        let challenge = authenticator
+            .challenge(&addr(), attempt("Tuco"), Duration::ZERO)
+            .unwrap();
+        for port in 0..2 * MAX_PENDING_CHALLENGES_PER_IP as u16 {
+            let spoofed = SocketAddr::from(([10, 0, 0, 1], port));
+            authenticator.challenge(&spoofed, attempt("Tuco"), Duration::ZERO);
+        }
+        assert_eq!(authenticator.pending.len(), 1 + MAX_PENDING_CHALLENGES_PER_IP);
+
+        let password_proof = prove(Credential::ServerPassword, "sesame", &challenge.nonce, "Tuco");
+        assert!(authenticator
+            .verify(&addr(), Some(password_proof), None, None, Duration::ZERO)
+            .is_some());
+    }
+
+    #[test]
+    fn oldest_challenge_is_forgotten_beyond_limit() {
+        let mut authenticator = authenticator();
+        for index in 0..=MAX_PENDING_CHALLENGES as u32 {
+            let ip = Ipv4Addr::from(0x0a00_0000 + index);
+            authenticator.challenge(&SocketAddr::from((ip, 4242)), attempt("Tuco"), Duration::ZERO);
+        }
+
+        assert_eq!(authenticator.pending.len(), MAX_PENDING_CHALLENGES);
+        assert_eq!(
+            authenticator.pending[0].addr,
+            SocketAddr::from(([10, 0, 0, 1], 4242))
+        );
+    }
+
+    #[test]
+    fn session_resume_is_challenged_and_its_proof_is_returned() {
+        let mut authenticator = Authenticator::new(AuthConfig::default());
+        let attempt = ConnectionAttempt {
+            resume_session: true,
+            ..attempt("Tuco")
+        };
+        let challenge = authenticator
+            .challenge(&addr(), attempt.clone(), Duration::ZERO)
+            .unwrap();
+        assert!(challenge.session_required);
+        assert!(!challenge.password_required);
+
+        let token: SessionToken = rand::random();
+        let proof = prove(Credential::SessionToken, &token.to_string(), &challenge.nonce, "Tuco");
+        let (verified, session_proof) = authenticator
+            .verify(&addr(), None, None, Some(proof), Duration::ZERO)
+            .unwrap();
+
+        assert_eq!(verified, attempt);
+        let session_proof = session_proof.unwrap();
+        assert!(session_proof.proves(token, "Tuco"));
+        assert!(!session_proof.proves(token.wrapping_add(1), "Tuco"));
+        assert!(!session_proof.proves(token, "Clint"));
+    }
+}
@@ -0,0 +1,49 @@
+use super::client_registry::{ClientHandle, ClientID, ClientRegistry};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{Res, ResMut};
+use std::marker::PhantomData;
+use westiny_common::outbox::{Message, Outbox};
+
+/// Queues messages to the registered clients in the `Outbox`
+#[derive(SystemParam)]
+pub struct ClientOutbox<'w, 's> {
+    client_registry: Res<'w, ClientRegistry>,
+    outbox: ResMut<'w, Outbox>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> ClientOutbox<'w, 's> {
+    pub fn client_registry(&self) -> &ClientRegistry {
+        &self.client_registry
+    }
+
+    pub fn send<M: Message>(&mut self, client_id: ClientID, message: M) -> anyhow::Result<()> {
+        let addr = self
+            .client_registry
+            .find_client(client_id)
+            .map(|handle| handle.addr)
+            .ok_or_else(|| anyhow::anyhow!("Client [id: {:?}] not found in registry", client_id))?;
+        self.outbox.send(addr, message);
+        Ok(())
+    }
+
+    /// Sends the message to each client accepted by the filter
+    pub fn send_where<M, F>(&mut self, mut filter: F, message: M)
+    where
+        M: Message,
+        F: FnMut(&ClientHandle) -> bool,
+    {
+        let addrs = self
+            .client_registry
+            .get_clients()
+            .into_iter()
+            .filter(|&handle| filter(handle))
+            .map(|handle| handle.addr);
+        self.outbox.send_to_many(addrs, message);
+    }
+
+    pub fn broadcast<M: Message>(&mut self, message: M) {
+        self.send_where(|_| true, message);
+    }
+}
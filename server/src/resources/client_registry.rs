@@ -1,19 +1,52 @@
 use std::fmt;
 use std::net::SocketAddr;
+use std::time::Duration;
 use thiserror::Error;
+use westiny_common::compression::Compression;
+use westiny_common::network::{ErrorKind, SessionToken};
+use westiny_common::ping::PingTracker;
+use westiny_common::serialization::{PacketCodec, WireFormat};
 use westiny_common::PlayerName;
 
+/// A client whose connection timed out can reconnect to its session within this period
+pub const RECONNECT_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 /// An ID that uniquely identifies a network client.
 /// Can be used in game logic to match relevant entities to network clients.
-#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+#[derive(Eq, PartialEq, Debug, Copy, Clone, Hash)]
 pub struct ClientID(pub u32);
 
 #[derive(Debug)]
 pub struct ClientHandle {
     pub id: ClientID,
     pub addr: SocketAddr,
-    /// Right now it is used as a user_name, but no further authentication done.
+    /// Used as a user name. It is authenticated only if the server has a password or the player
+    /// has a secret configured.
     pub player_name: PlayerName,
+    /// Presented by the client to reconnect to this session from another address
+    pub session_token: SessionToken,
+    /// Round trip time, jitter and loss of the connection of the client
+    pub link: PingTracker,
+    /// Negotiated on connection, the packets to the client are encoded with it
+    pub codec: PacketCodec,
+    /// Negotiated on connection, the large packets to the client are compressed with it
+    pub compression: Option<Compression>,
+}
+
+impl ClientHandle {
+    pub fn wire_format(&self) -> WireFormat {
+        WireFormat {
+            codec: self.codec,
+            compression: self.compression,
+        }
+    }
+}
+
+/// A client whose connection has timed out. Its slot is kept until the grace period is over.
+#[derive(Debug)]
+struct SuspendedClient {
+    handle: ClientHandle,
+    since: Duration,
 }
 
 #[derive(Debug, bevy::prelude::Resource)]
@@ -21,17 +54,32 @@ pub struct ClientRegistry {
     max_slots: usize,
     next_id: u32,
     clients: Vec<ClientHandle>,
+    suspended: Vec<SuspendedClient>,
 }
 
 #[derive(Error, Debug)]
 pub enum AddError {
-    #[error("Player with already connected with same address xor name, not authorizing. Possibly malicious attempt?")]
+    #[error("Address already connected with a different player name, not authorizing. Possibly malicious attempt?")]
     Unauthorized,
 
+    #[error("Player name is already taken by a client with different address")]
+    NameTaken,
+
     #[error("Server is full")]
     ServerIsFull,
 }
 
+impl AddError {
+    /// The reason of the rejection as it is reported to the client
+    pub fn error_kind(&self) -> ErrorKind {
+        match self {
+            AddError::Unauthorized => ErrorKind::AlreadyConnected,
+            AddError::NameTaken => ErrorKind::NameTaken,
+            AddError::ServerIsFull => ErrorKind::ServerFull,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum RemoveError {
     #[error("No such registered address")]
@@ -44,21 +92,95 @@ impl ClientRegistry {
             max_slots,
             next_id: 0,
             clients: vec![],
+            suspended: vec![],
         }
     }
 
     pub fn add(&mut self, addr: &SocketAddr, player_name: &str) -> Result<ClientID, AddError> {
-        if self.clients.len() >= self.max_slots {
-            return Err(AddError::ServerIsFull);
-        }
-
         match self.find_by_addr_or_name(addr, player_name) {
             Some(h) if h.player_name.0 == player_name && &h.addr == addr => Ok(h.id),
-            Some(_) => Err(AddError::Unauthorized),
+            Some(h) if &h.addr == addr => Err(AddError::Unauthorized),
+            Some(_) => Err(AddError::NameTaken),
+            None if self.is_suspended(player_name) => Err(AddError::NameTaken),
+            None if self.clients.len() + self.suspended.len() >= self.max_slots => {
+                Err(AddError::ServerIsFull)
+            }
             None => Ok(self.add_new_client(*addr, player_name)),
         }
     }
 
+    /// Gives the session back to its client, which may have connected from a new address.
+    /// Returns `None` when there is no such session (e.g. its grace period is over), or the
+    /// address is used by another client.
+    pub fn reconnect(
+        &mut self,
+        addr: &SocketAddr,
+        player_name: &str,
+        session_token: SessionToken,
+    ) -> Option<ClientID> {
+        let is_session = |handle: &ClientHandle| {
+            handle.session_token == session_token && handle.player_name.0 == player_name
+        };
+        // Addresses identify the clients, they must not be shared
+        if self.find_by_addr(addr).map_or(false, |handle| !is_session(handle)) {
+            return None;
+        }
+
+        if let Some(handle) = self.clients.iter_mut().find(|handle| is_session(handle)) {
+            handle.addr = *addr;
+            handle.link = PingTracker::default();
+            return Some(handle.id);
+        }
+
+        let index = self
+            .suspended
+            .iter()
+            .position(|suspended| is_session(&suspended.handle))?;
+        let mut handle = self.suspended.remove(index).handle;
+        handle.addr = *addr;
+        handle.link = PingTracker::default();
+        let id = handle.id;
+        self.clients.push(handle);
+        Some(id)
+    }
+
+    /// The token of the session of the player, connected or suspended. Never sent to anyone but
+    /// the client itself, the clients resuming their session prove they have it.
+    pub fn session_token_of(&self, player_name: &str) -> Option<SessionToken> {
+        self.clients
+            .iter()
+            .chain(self.suspended.iter().map(|suspended| &suspended.handle))
+            .find(|handle| handle.player_name.0 == player_name)
+            .map(|handle| handle.session_token)
+    }
+
+    /// The connection of the client has timed out, its slot is kept for `RECONNECT_GRACE_PERIOD`
+    pub fn suspend(&mut self, addr: &SocketAddr, now: Duration) -> Result<ClientID, RemoveError> {
+        let index = self
+            .clients
+            .iter()
+            .position(|handle| &handle.addr == addr)
+            .ok_or(RemoveError::NoSuchClient)?;
+        let handle = self.clients.remove(index);
+        let id = handle.id;
+        self.suspended.push(SuspendedClient { handle, since: now });
+        Ok(id)
+    }
+
+    /// Removes the suspended clients whose grace period is over and returns them
+    pub fn expire_suspended(&mut self, now: Duration) -> Vec<ClientHandle> {
+        let (expired, suspended): (Vec<_>, Vec<_>) = std::mem::take(&mut self.suspended)
+            .into_iter()
+            .partition(|suspended| {
+                now.saturating_sub(suspended.since) >= RECONNECT_GRACE_PERIOD
+            });
+        self.suspended = suspended;
+        expired
+            .into_iter()
+            .map(|suspended| suspended.handle)
+            .collect()
+    }
+
     pub fn get_clients(&self) -> Vec<&ClientHandle> {
         self.clients.iter().collect()
     }
@@ -71,6 +193,40 @@ impl ClientRegistry {
         self.clients.iter().find(|&handle| &handle.addr == addr)
     }
 
+    pub fn find_by_addr_mut(&mut self, addr: &SocketAddr) -> Option<&mut ClientHandle> {
+        self.clients.iter_mut().find(|handle| &handle.addr == addr)
+    }
+
+    /// Codec of the packets to the address, the default one for the unregistered addresses
+    pub fn codec_of(&self, addr: &SocketAddr) -> PacketCodec {
+        self.find_by_addr(addr)
+            .map_or_else(PacketCodec::default, |handle| handle.codec)
+    }
+
+    /// Wire format of the packets to the address, uncompressed for the unregistered addresses
+    pub fn wire_format_of(&self, addr: &SocketAddr) -> WireFormat {
+        self.find_by_addr(addr)
+            .map_or_else(WireFormat::default, ClientHandle::wire_format)
+    }
+
+    pub fn clients_mut(&mut self) -> impl Iterator<Item = &mut ClientHandle> {
+        self.clients.iter_mut()
+    }
+
+    /// Mean of the smoothed round trip times of the clients which have answered a ping
+    pub fn mean_rtt(&self) -> Option<Duration> {
+        let rtts: Vec<_> = self
+            .clients
+            .iter()
+            .filter_map(|handle| handle.link.rtt())
+            .collect();
+        if rtts.is_empty() {
+            None
+        } else {
+            Some(rtts.iter().sum::<Duration>() / rtts.len() as u32)
+        }
+    }
+
     pub fn remove(&mut self, addr: &SocketAddr) -> Result<ClientID, RemoveError> {
         if let Some(index) = self.clients.iter().position(|handle| &handle.addr == addr) {
             let removed_id = self.clients[index].id;
@@ -92,10 +248,20 @@ impl ClientRegistry {
             id,
             addr,
             player_name: PlayerName(player_name.into()),
+            session_token: rand::random(),
+            link: PingTracker::default(),
+            codec: PacketCodec::default(),
+            compression: None,
         });
         id
     }
 
+    fn is_suspended(&self, player_name: &str) -> bool {
+        self.suspended
+            .iter()
+            .any(|suspended| suspended.handle.player_name.0 == player_name)
+    }
+
     fn find_by_addr_or_name(&self, addr: &SocketAddr, name: &str) -> Option<&ClientHandle> {
         self.clients
             .iter()
@@ -109,8 +275,13 @@ impl fmt::Display for ClientRegistry {
         for handle in &self.clients {
             write!(
                 f,
-                "\n  - ID={}, address={}, player_name={}",
-                handle.id.0, handle.addr, handle.player_name.0
+                "\n  - ID={}, address={}, player_name={}, rtt={:?}, jitter={:?}, loss={:.0}%",
+                handle.id.0,
+                handle.addr,
+                handle.player_name.0,
+                handle.link.rtt(),
+                handle.link.jitter(),
+                handle.link.loss() * 100.0
             )?;
         }
         Ok(())
@@ -235,7 +406,7 @@ mod test {
             .add(&make_addr("1.1.1.1", 1234), "NariFeco")
             .expect_err("could added another NariFeco?");
 
-        assert!(matches!(err, AddError::Unauthorized));
+        assert!(matches!(err, AddError::NameTaken));
     }
 
     #[test]
@@ -261,4 +432,108 @@ mod test {
 
         assert!(matches!(err, RemoveError::NoSuchClient));
     }
+
+    fn session_token(reg: &ClientRegistry, id: ClientID) -> SessionToken {
+        reg.find_client(id).unwrap().session_token
+    }
+
+    #[test]
+    fn test_reconnect_with_session_token_from_new_address_keeps_id() {
+        let mut reg = ClientRegistry::new(2);
+        let old_addr = make_addr("8.8.8.8", 1234);
+        let new_addr = make_addr("8.8.8.8", 4321);
+        let id = reg.add(&old_addr, "NariFeco").unwrap();
+        let token = session_token(&reg, id);
+
+        assert_eq!(reg.reconnect(&new_addr, "NariFeco", token), Some(id));
+        assert!(reg.find_by_addr(&old_addr).is_none());
+        assert_eq!(reg.find_by_addr(&new_addr).unwrap().id, id);
+        assert_eq!(reg.client_count(), 1);
+    }
+
+    #[test]
+    fn test_reconnect_to_address_of_another_client_is_refused() {
+        let mut reg = ClientRegistry::new(2);
+        let addr = make_addr("8.8.8.8", 1234);
+        let other_addr = make_addr("1.1.1.1", 1234);
+        let id = reg.add(&addr, "NariFeco").unwrap();
+        let other_id = reg.add(&other_addr, "BananJoe").unwrap();
+        let token = session_token(&reg, id);
+
+        assert_eq!(reg.reconnect(&other_addr, "NariFeco", token), None);
+        assert_eq!(reg.find_by_addr(&addr).unwrap().id, id);
+        assert_eq!(reg.find_by_addr(&other_addr).unwrap().id, other_id);
+
+        // Neither from a suspended session
+        reg.suspend(&addr, Duration::from_secs(10)).unwrap();
+        assert_eq!(reg.reconnect(&other_addr, "NariFeco", token), None);
+        assert_eq!(reg.find_by_addr(&other_addr).unwrap().id, other_id);
+        assert_eq!(reg.reconnect(&addr, "NariFeco", token), Some(id));
+    }
+
+    #[test]
+    fn test_reconnect_with_wrong_token_or_name_is_refused() {
+        let mut reg = ClientRegistry::new(2);
+        let addr = make_addr("8.8.8.8", 1234);
+        let id = reg.add(&addr, "NariFeco").unwrap();
+        let token = session_token(&reg, id);
+
+        let new_addr = make_addr("1.1.1.1", 1234);
+        assert_eq!(reg.reconnect(&new_addr, "NariFeco", token.wrapping_add(1)), None);
+        assert_eq!(reg.reconnect(&new_addr, "BananJoe", token), None);
+        assert_eq!(reg.find_by_addr(&addr).unwrap().id, id);
+    }
+
+    #[test]
+    fn test_session_token_of_connected_and_suspended_players() {
+        let mut reg = ClientRegistry::new(2);
+        let addr = make_addr("8.8.8.8", 1234);
+        let id = reg.add(&addr, "NariFeco").unwrap();
+        let token = session_token(&reg, id);
+
+        assert_eq!(reg.session_token_of("NariFeco"), Some(token));
+        reg.suspend(&addr, Duration::from_secs(10)).unwrap();
+        assert_eq!(reg.session_token_of("NariFeco"), Some(token));
+        assert_eq!(reg.session_token_of("BananJoe"), None);
+    }
+
+    #[test]
+    fn test_suspended_client_keeps_its_slot_and_name_until_expired() {
+        let mut reg = ClientRegistry::new(1);
+        let addr = make_addr("8.8.8.8", 1234);
+        let id = reg.add(&addr, "NariFeco").unwrap();
+        let token = session_token(&reg, id);
+
+        assert_eq!(reg.suspend(&addr, Duration::from_secs(10)).unwrap(), id);
+        assert!(reg.find_client(id).is_none());
+        assert!(matches!(
+            reg.add(&make_addr("8.8.8.8", 4321), "NariFeco"),
+            Err(AddError::NameTaken)
+        ));
+        assert!(matches!(
+            reg.add(&make_addr("1.1.1.1", 1234), "BananJoe"),
+            Err(AddError::ServerIsFull)
+        ));
+
+        assert!(reg.expire_suspended(Duration::from_secs(39)).is_empty());
+        let expired = reg.expire_suspended(Duration::from_secs(40));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, id);
+        assert_eq!(reg.reconnect(&addr, "NariFeco", token), None);
+        assert!(reg.add(&addr, "BananJoe").is_ok());
+    }
+
+    #[test]
+    fn test_suspended_client_can_reconnect_within_grace_period() {
+        let mut reg = ClientRegistry::new(2);
+        let addr = make_addr("8.8.8.8", 1234);
+        let id = reg.add(&addr, "NariFeco").unwrap();
+        let token = session_token(&reg, id);
+        reg.suspend(&addr, Duration::from_secs(10)).unwrap();
+
+        let new_addr = make_addr("8.8.8.8", 4321);
+        assert_eq!(reg.reconnect(&new_addr, "NariFeco", token), Some(id));
+        assert_eq!(reg.find_client(id).unwrap().addr, new_addr);
+        assert!(reg.expire_suspended(Duration::from_secs(100)).is_empty());
+    }
 }
@@ -0,0 +1,441 @@
+use crate::resources::ClientID;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use westiny_common::components::NetworkId;
+use westiny_common::network::{
+    EntityState, EntityStateUpdate, PacketType, SnapshotChunk, SnapshotId, Tick,
+};
+use westiny_common::serialization::{encoded_size, PacketCodec};
+use westiny_common::snapshot::{Snapshot, SnapshotHistory};
+
+/// Number of snapshots kept per client as possible delta baselines.
+/// When the last acknowledged snapshot of a client is older than that, a full snapshot is sent.
+const SNAPSHOT_HISTORY_SIZE: usize = 64;
+
+/// Upper bound of the encoding of the length of the state and removal lists
+const LIST_HEADER_SIZE: usize = 5;
+
+/// Size limits of the entity state updates sent to a client
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct SnapshotBudget {
+    /// A snapshot is split into chunks of at most this many bytes, so each fits in a datagram
+    pub max_chunk_size: usize,
+    /// The changes which do not fit into this many chunks are left for the next snapshots
+    pub max_chunks: u8,
+}
+
+impl Default for SnapshotBudget {
+    fn default() -> Self {
+        SnapshotBudget {
+            max_chunk_size: 1024,
+            max_chunks: 4,
+        }
+    }
+}
+
+struct ClientSnapshotState {
+    history: SnapshotHistory,
+    acknowledged: Option<SnapshotId>,
+}
+
+impl ClientSnapshotState {
+    fn new() -> Self {
+        ClientSnapshotState {
+            history: SnapshotHistory::new(SNAPSHOT_HISTORY_SIZE),
+            acknowledged: None,
+        }
+    }
+}
+
+/// Keeps track of the entity state snapshots sent to the clients and the latest snapshot
+/// acknowledged by each of them, so clients receive only the changes since that snapshot.
+#[derive(Default, bevy::prelude::Resource)]
+pub struct ClientSnapshots {
+    budget: SnapshotBudget,
+    next_snapshot: SnapshotId,
+    clients: HashMap<ClientID, ClientSnapshotState>,
+}
+
+impl ClientSnapshots {
+    pub fn new(budget: SnapshotBudget) -> Self {
+        ClientSnapshots {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    pub fn next_snapshot_id(&mut self) -> SnapshotId {
+        let id = self.next_snapshot;
+        self.next_snapshot = self.next_snapshot.wrapping_add(1);
+        id
+    }
+
+    /// Only snapshots which were sent to the client and are newer than its last acknowledged
+    /// one are taken into account.
+    pub fn acknowledge(&mut self, client_id: ClientID, snapshot: SnapshotId) {
+        if let Some(state) = self.clients.get_mut(&client_id) {
+            let is_newer = state.acknowledged.is_none_or(|acked| snapshot > acked);
+            if is_newer && state.history.get(snapshot).is_some() {
+                state.acknowledged = Some(snapshot);
+            }
+        }
+    }
+
+    /// Creates the updates of `snapshot` for the given client: a delta from its last
+    /// acknowledged snapshot if that is still in the history, otherwise the full snapshot.
+    /// The delta is split into chunks within the budget. Removals come first, then the states
+    /// in ascending order of `priority`; what does not fit is left for the next snapshots.
+    /// The snapshot as the client will know it is stored as a possible baseline of later updates.
+    /// The sizes are measured in the codec of the client.
+    #[allow(clippy::too_many_arguments)]
+    pub fn make_updates<F>(
+        &mut self,
+        client_id: ClientID,
+        codec: PacketCodec,
+        snapshot_id: SnapshotId,
+        tick: Tick,
+        server_time: Duration,
+        snapshot: Snapshot,
+        priority: F,
+    ) -> Vec<EntityStateUpdate>
+    where
+        F: Fn(&EntityState) -> f32,
+    {
+        let budget = self.budget;
+        let state = self
+            .clients
+            .entry(client_id)
+            .or_insert_with(ClientSnapshotState::new);
+
+        let baseline = state
+            .acknowledged
+            .and_then(|acked| state.history.get(acked).map(|baseline| (acked, baseline)));
+        let (baseline_id, baseline) = match baseline {
+            Some((baseline_id, baseline)) => (Some(baseline_id), baseline.clone()),
+            None => (None, Snapshot::default()),
+        };
+
+        let (mut states, removed) = snapshot.delta_from(&baseline);
+        states.sort_by(|a, b| priority(a).total_cmp(&priority(b)));
+
+        let header = EntityStateUpdate {
+            snapshot: snapshot_id,
+            tick,
+            server_time,
+            baseline: baseline_id,
+            states: vec![],
+            removed: vec![],
+            last_processed_input: None,
+            chunk: SnapshotChunk::WHOLE,
+        };
+        let chunks = split_into_chunks(&header, states, removed, budget, codec);
+
+        let count = chunks.len() as u8;
+        let updates: Vec<_> = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, (states, removed))| EntityStateUpdate {
+                states,
+                removed,
+                chunk: SnapshotChunk {
+                    index: index as u8,
+                    count,
+                },
+                ..header.clone()
+            })
+            .collect();
+
+        let sent = updates.iter().fold(baseline, |known, update| {
+            known.apply_delta(&update.states, &update.removed)
+        });
+        if sent != snapshot {
+            log::debug!(
+                "Snapshot {} to {:?} exceeds the budget, some changes are left for later",
+                snapshot_id,
+                client_id
+            );
+        }
+        state.history.push(snapshot_id, sent);
+        updates
+    }
+
+    /// The next update of the client is a full snapshot
+    pub fn forget(&mut self, client_id: ClientID) {
+        self.clients.remove(&client_id);
+    }
+
+    /// Forgets the snapshots of clients for which `is_connected` returns false
+    pub fn retain_clients<F>(&mut self, mut is_connected: F)
+    where
+        F: FnMut(&ClientID) -> bool,
+    {
+        self.clients.retain(|client_id, _| is_connected(client_id));
+    }
+}
+
+enum Change {
+    Removal(NetworkId),
+    State(EntityState),
+}
+
+/// Packs the removals and then the states into at most `max_chunks` chunks of
+/// `max_chunk_size` bytes. There is always at least one chunk, even if it is empty.
+fn split_into_chunks(
+    header: &EntityStateUpdate,
+    states: Vec<EntityState>,
+    removed: Vec<NetworkId>,
+    budget: SnapshotBudget,
+    codec: PacketCodec,
+) -> Vec<(Vec<EntityState>, Vec<NetworkId>)> {
+    let header_size = encoded_size(codec, &PacketType::EntityStateUpdate(header.clone()))
+        .expect("EntityStateUpdate could not be encoded")
+        + 2 * LIST_HEADER_SIZE;
+    let max_chunks = budget.max_chunks.max(1) as usize;
+
+    let mut chunks = vec![(vec![], vec![])];
+    let mut size = header_size;
+    let changes = removed
+        .into_iter()
+        .map(Change::Removal)
+        .chain(states.into_iter().map(Change::State));
+    for change in changes {
+        let change_size = match &change {
+            Change::Removal(network_id) => encoded_size(codec, network_id),
+            Change::State(state) => encoded_size(codec, state),
+        }
+        .expect("Entity state could not be encoded");
+
+        let (chunk_states, chunk_removed): &(Vec<_>, Vec<_>) = chunks.last().unwrap();
+        let is_empty = chunk_states.is_empty() && chunk_removed.is_empty();
+        if size + change_size > budget.max_chunk_size && !is_empty {
+            if chunks.len() >= max_chunks {
+                break;
+            }
+            chunks.push((vec![], vec![]));
+            size = header_size;
+        }
+
+        size += change_size;
+        let (chunk_states, chunk_removed) = chunks.last_mut().unwrap();
+        match change {
+            Change::Removal(network_id) => chunk_removed.push(network_id),
+            Change::State(state) => chunk_states.push(state),
+        }
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use westiny_common::components::EntityType;
+    use westiny_common::metric_dimension::length::MeterVec2;
+    use westiny_common::serialization::serialize_with;
+
+    fn no_priority(_: &EntityState) -> f32 {
+        0.0
+    }
+
+    fn only_update(mut updates: Vec<EntityStateUpdate>) -> EntityStateUpdate {
+        assert_eq!(updates.len(), 1);
+        updates.pop().unwrap()
+    }
+
+    fn snapshot(positions: &[(u32, f32)]) -> Snapshot {
+        Snapshot::new(positions.iter().map(|&(id, x)| EntityState {
+            network_id: NetworkId::new(EntityType::Player, id),
+            position: MeterVec2::from_raw(x, 0.0),
+            angle: 0.0,
+        }))
+    }
+
+    #[test]
+    fn full_snapshot_is_sent_until_acknowledged() {
+        let mut snapshots = ClientSnapshots::default();
+        let client = ClientID(0);
+
+        let first_id = snapshots.next_snapshot_id();
+        let first = only_update(snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            first_id,
+            0,
+            Duration::ZERO,
+            snapshot(&[(0, 1.0), (1, 1.0)]),
+            no_priority,
+        ));
+        let second_id = snapshots.next_snapshot_id();
+        let second = only_update(snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            second_id,
+            0,
+            Duration::ZERO,
+            snapshot(&[(0, 1.0), (1, 1.0)]),
+            no_priority,
+        ));
+
+        assert_eq!(first.baseline, None);
+        assert_eq!(first.states.len(), 2);
+        assert_eq!(second.baseline, None);
+        assert_eq!(second.states.len(), 2);
+    }
+
+    #[test]
+    fn delta_is_made_against_acknowledged_snapshot() {
+        let mut snapshots = ClientSnapshots::default();
+        let client = ClientID(0);
+
+        let first_id = snapshots.next_snapshot_id();
+        snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            first_id,
+            0,
+            Duration::ZERO,
+            snapshot(&[(0, 1.0), (1, 1.0), (2, 1.0)]),
+            no_priority,
+        );
+        snapshots.acknowledge(client, first_id);
+
+        let second_id = snapshots.next_snapshot_id();
+        let update = only_update(snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            second_id,
+            0,
+            Duration::ZERO,
+            snapshot(&[(0, 1.0), (1, 2.0)]),
+            no_priority,
+        ));
+
+        assert_eq!(update.snapshot, second_id);
+        assert_eq!(update.baseline, Some(first_id));
+        assert_eq!(update.states.len(), 1);
+        assert_eq!(update.states[0].network_id.id, 1);
+        assert_eq!(update.removed, vec![NetworkId::new(EntityType::Player, 2)]);
+    }
+
+    #[test]
+    fn acknowledging_unknown_snapshot_is_ignored() {
+        let mut snapshots = ClientSnapshots::default();
+        let client = ClientID(0);
+
+        let first_id = snapshots.next_snapshot_id();
+        let first = snapshot(&[(0, 1.0)]);
+        snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            first_id,
+            0,
+            Duration::ZERO,
+            first,
+            no_priority,
+        );
+        snapshots.acknowledge(client, first_id + 100);
+
+        let second_id = snapshots.next_snapshot_id();
+        let second = snapshot(&[(0, 1.0)]);
+        let update = only_update(snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            second_id,
+            0,
+            Duration::ZERO,
+            second,
+            no_priority,
+        ));
+
+        assert_eq!(update.baseline, None);
+    }
+
+    #[test]
+    fn large_snapshot_is_split_into_chunks() {
+        let budget = SnapshotBudget {
+            max_chunk_size: 200,
+            max_chunks: 255,
+        };
+        let positions: Vec<_> = (0..20).map(|id| (id, id as f32)).collect();
+
+        for codec in PacketCodec::ALL {
+            let mut snapshots = ClientSnapshots::new(budget);
+            let snapshot_id = snapshots.next_snapshot_id();
+            let updates = snapshots.make_updates(
+                ClientID(0),
+                codec,
+                snapshot_id,
+                0,
+                Duration::ZERO,
+                snapshot(&positions),
+                no_priority,
+            );
+
+            assert!(updates.len() > 1);
+            for (index, update) in updates.iter().enumerate() {
+                assert_eq!(update.chunk.index as usize, index);
+                assert_eq!(update.chunk.count as usize, updates.len());
+                let size = serialize_with(codec, &PacketType::EntityStateUpdate(update.clone()))
+                    .unwrap()
+                    .len();
+                assert!(size <= budget.max_chunk_size, "{:?} exceeds the budget", codec);
+            }
+            let sent = Snapshot::default().apply_delta(
+                &updates
+                    .iter()
+                    .flat_map(|update| update.states.iter().cloned())
+                    .collect::<Vec<_>>(),
+                &[],
+            );
+            assert_eq!(sent, snapshot(&positions));
+        }
+    }
+
+    #[test]
+    fn prioritized_changes_are_sent_when_budget_is_exceeded() {
+        let mut snapshots = ClientSnapshots::new(SnapshotBudget {
+            max_chunk_size: 200,
+            max_chunks: 1,
+        });
+        let client = ClientID(0);
+        let positions: Vec<_> = (0..20).map(|id| (id, id as f32)).collect();
+        let by_distance = |state: &EntityState| state.position.x.0;
+
+        let first_id = snapshots.next_snapshot_id();
+        let first = only_update(snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            first_id,
+            0,
+            Duration::ZERO,
+            snapshot(&positions),
+            by_distance,
+        ));
+        assert!(first.states.len() < positions.len());
+        let nearest = first
+            .states
+            .iter()
+            .map(|state| state.network_id.id)
+            .max()
+            .unwrap();
+        assert_eq!(nearest as usize, first.states.len() - 1);
+
+        // The rest is sent in the delta from the partially sent snapshot
+        snapshots.acknowledge(client, first_id);
+        let second_id = snapshots.next_snapshot_id();
+        let second = only_update(snapshots.make_updates(
+            client,
+            PacketCodec::MessagePack,
+            second_id,
+            0,
+            Duration::ZERO,
+            snapshot(&positions),
+            by_distance,
+        ));
+        assert_eq!(second.baseline, Some(first_id));
+        assert!(second
+            .states
+            .iter()
+            .all(|state| state.network_id.id > nearest));
+    }
+}
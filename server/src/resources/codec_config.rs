@@ -0,0 +1,41 @@
+use serde::Deserialize;
+use westiny_common::serialization::PacketCodec;
+
+/// Codecs the clients may ask for. The default codec is always accepted, as the handshake uses it.
+#[derive(Clone, Debug, Deserialize, bevy::prelude::Resource)]
+pub struct CodecConfig {
+    pub accepted: Vec<PacketCodec>,
+}
+
+impl CodecConfig {
+    /// The codec asked by the client if it is accepted, the default one otherwise
+    pub fn negotiate(&self, requested: PacketCodec) -> PacketCodec {
+        if self.accepted.contains(&requested) {
+            requested
+        } else {
+            PacketCodec::default()
+        }
+    }
+}
+
+impl Default for CodecConfig {
+    fn default() -> Self {
+        CodecConfig {
+            accepted: PacketCodec::ALL.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn requested_codec_is_used_if_accepted() {
+        let config = CodecConfig {
+            accepted: vec![PacketCodec::MessagePack, PacketCodec::Binary],
+        };
+        assert_eq!(config.negotiate(PacketCodec::Binary), PacketCodec::Binary);
+        assert_eq!(config.negotiate(PacketCodec::Json), PacketCodec::MessagePack);
+    }
+}
@@ -0,0 +1,46 @@
+use serde::Deserialize;
+use westiny_common::compression::Compression;
+
+/// Compression of the large packets, for the clients which ask for it
+#[derive(Copy, Clone, Debug, Deserialize, bevy::prelude::Resource)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    /// Smaller datagrams are not worth compressing
+    pub threshold: u32,
+}
+
+impl CompressionConfig {
+    /// The compression used for a client, `requested` tells whether it can decompress
+    pub fn negotiate(&self, requested: bool) -> Option<Compression> {
+        (self.enabled && requested).then_some(Compression {
+            threshold: self.threshold.max(1),
+        })
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            threshold: 256,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_is_used_if_both_peers_enable_it() {
+        let config = CompressionConfig::default();
+        assert_eq!(config.negotiate(true), Some(Compression { threshold: 256 }));
+        assert_eq!(config.negotiate(false), None);
+
+        let disabled = CompressionConfig {
+            enabled: false,
+            ..config
+        };
+        assert_eq!(disabled.negotiate(true), None);
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use westiny_common::demo::{DemoFrame, DemoHeader, DemoWriter};
+
+/// Writes the session to a demo file, when the server has been asked to record it
+#[derive(bevy::prelude::Resource)]
+pub struct DemoRecorder {
+    writer: DemoWriter<BufWriter<File>>,
+    failed: bool,
+}
+
+impl DemoRecorder {
+    pub fn create(path: &Path, header: &DemoHeader) -> anyhow::Result<Self> {
+        let file = File::create(path)?;
+        Ok(DemoRecorder {
+            writer: DemoWriter::new(BufWriter::new(file), header)?,
+            failed: false,
+        })
+    }
+
+    /// Recording stops at the first error, so the file stays readable up to that point
+    pub fn record(&mut self, frame: &DemoFrame) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.writer.write(frame) {
+            log::error!("Demo recording failed, stopped recording: {}", err);
+            self.failed = true;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.failed {
+            return;
+        }
+        if let Err(err) = self.writer.flush() {
+            log::error!("Demo recording failed, stopped recording: {}", err);
+            self.failed = true;
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use super::ClientID;
 
-use westiny_common::components::Input;
+use std::time::Duration;
+use westiny_common::network::{SequencedInput, SnapshotId};
 use westiny_common::PlayerName;
 
 #[derive(Debug, Eq, PartialEq)]
@@ -13,5 +14,17 @@ pub enum ClientNetworkEvent {
 #[derive(Debug, PartialEq)]
 #[cfg_attr(test, derive(bevy::prelude::Resource))]
 pub enum NetworkCommand {
-    Input { id: ClientID, input: Input },
+    Input {
+        id: ClientID,
+        inputs: Vec<SequencedInput>,
+        view_time: Option<Duration>,
+    },
+    SnapshotAck {
+        id: ClientID,
+        snapshot: SnapshotId,
+    },
+    /// The connection of the client has timed out, its player stops until it reconnects
+    ConnectionLost {
+        id: ClientID,
+    },
 }
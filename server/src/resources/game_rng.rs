@@ -0,0 +1,19 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Source of randomness of the game logic, e.g. spawn positions and bullet spread.
+/// It is seeded, so a game can be reproduced.
+#[derive(bevy::prelude::Resource)]
+pub struct GameRng(pub StdRng);
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        GameRng(StdRng::seed_from_u64(seed))
+    }
+}
+
+impl Default for GameRng {
+    fn default() -> Self {
+        GameRng(StdRng::from_entropy())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::resources::ClientID;
+use serde::Deserialize;
+use std::collections::HashMap;
+use westiny_common::metric_dimension::length::{Meter, MeterVec2};
+
+/// Radius around the player of a client in which the client is informed about
+/// the entity states and events of the game.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct InterestRadius(pub Meter);
+
+impl Default for InterestRadius {
+    fn default() -> Self {
+        InterestRadius(Meter(30.0))
+    }
+}
+
+/// Stores the center of interest (the last known position of the player) of every client
+/// to filter what is worth sending them.
+#[derive(Debug, Default, bevy::prelude::Resource)]
+pub struct InterestManager {
+    radius: InterestRadius,
+    centers: HashMap<ClientID, MeterVec2>,
+}
+
+impl InterestManager {
+    pub fn new(radius: InterestRadius) -> Self {
+        InterestManager {
+            radius,
+            centers: HashMap::new(),
+        }
+    }
+
+    pub fn update_center(&mut self, client_id: ClientID, position: MeterVec2) {
+        self.centers.insert(client_id, position);
+    }
+
+    pub fn center(&self, client_id: ClientID) -> Option<MeterVec2> {
+        self.centers.get(&client_id).copied()
+    }
+
+    /// Forgets the center of clients for which `is_connected` returns false
+    pub fn retain_clients<F>(&mut self, mut is_connected: F)
+    where
+        F: FnMut(&ClientID) -> bool,
+    {
+        self.centers.retain(|client_id, _| is_connected(client_id));
+    }
+
+    /// Returns true if the position is within the interest radius of the client.
+    /// Nothing is relevant for a client which has not had any position yet.
+    pub fn is_relevant(&self, client_id: ClientID, position: &MeterVec2) -> bool {
+        self.centers.get(&client_id).is_some_and(|center| {
+            let dx = center.x.0 - position.x.0;
+            let dy = center.y.0 - position.y.0;
+            let radius = (self.radius.0).0;
+            dx * dx + dy * dy <= radius * radius
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn positions_within_radius_are_relevant() {
+        let mut interest = InterestManager::new(InterestRadius(Meter(10.0)));
+        interest.update_center(ClientID(0), MeterVec2::from_raw(5.0, 5.0));
+
+        assert!(interest.is_relevant(ClientID(0), &MeterVec2::from_raw(5.0, 5.0)));
+        assert!(interest.is_relevant(ClientID(0), &MeterVec2::from_raw(11.0, 13.0)));
+        assert!(!interest.is_relevant(ClientID(0), &MeterVec2::from_raw(15.1, 5.0)));
+    }
+
+    #[test]
+    fn nothing_is_relevant_for_unknown_client() {
+        let mut interest = InterestManager::new(InterestRadius(Meter(10.0)));
+        interest.update_center(ClientID(0), MeterVec2::from_raw(0.0, 0.0));
+
+        assert!(!interest.is_relevant(ClientID(1), &MeterVec2::from_raw(0.0, 0.0)));
+
+        interest.retain_clients(|_| false);
+        assert!(!interest.is_relevant(ClientID(0), &MeterVec2::from_raw(0.0, 0.0)));
+    }
+}
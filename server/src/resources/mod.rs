@@ -1,12 +1,37 @@
 pub(crate) use client_registry::ClientID;
 pub(crate) use event::{ClientNetworkEvent, NetworkCommand};
 
-pub use client_registry::ClientRegistry;
+pub use authenticator::{AuthConfig, Authenticator, ConnectionAttempt, SessionProof};
+pub use client_outbox::ClientOutbox;
+pub use client_registry::{ClientRegistry, RECONNECT_GRACE_PERIOD};
+pub use client_snapshots::{ClientSnapshots, SnapshotBudget};
+pub use codec_config::CodecConfig;
+pub use compression_config::CompressionConfig;
+pub use demo_recorder::DemoRecorder;
+pub use game_rng::GameRng;
+pub use interest::{InterestManager, InterestRadius};
 pub use network_id_supplier::NetworkIdSupplier;
-pub use network_stream_id::StreamId;
+pub use packet_guard::{PacketGuard, PacketGuardConfig, PacketGuardStats, Rejection};
+pub use security_config::SecurityConfig;
+pub use shutdown::ShutdownSignal;
+pub use simulation_config::SimulationConfig;
+pub use westiny_common::compression::Compressor;
+pub use westiny_common::outbox::{Outbox, StreamId};
+pub use westiny_common::secure_channel::{SecureChannels, ServerIdentity};
 pub use westiny_common::resources::*;
 
+mod authenticator;
+mod client_outbox;
 mod client_registry;
+mod client_snapshots;
+mod codec_config;
+mod compression_config;
+mod demo_recorder;
 mod event;
+mod game_rng;
+mod interest;
 mod network_id_supplier;
-mod network_stream_id;
+mod packet_guard;
+mod security_config;
+mod shutdown;
+mod simulation_config;
@@ -38,7 +38,14 @@ mod test {
             assert_eq!(expected, actual, "With Player entity")
         }
 
-        // TODO place here another network EntitiyType when we have other than the Player
+        for i in 0..100 {
+            let actual = supplier.next(EntityType::Barrel);
+            let expected = NetworkId {
+                entity_type: EntityType::Barrel,
+                id: i,
+            };
+            assert_eq!(expected, actual, "With Barrel entity")
+        }
 
         for i in 1000..1100 {
             let actual = supplier.next(EntityType::Player);
@@ -0,0 +1,348 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use westiny_common::metric_dimension::Second;
+
+/// Addresses not heard of for this long are forgotten when there are too many of them
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TRACKED_ADDRESSES: usize = 4096;
+/// A banned IP address is told of its ban at most this often, so the answers cannot be used
+/// to flood a spoofed address
+const BAN_NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits of the packets accepted from one address
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct PacketGuardConfig {
+    /// Larger datagrams are dropped before they are opened
+    pub max_payload_size: usize,
+    /// Packets per second, and the number of packets above the rate in a burst
+    pub packet_rate: f32,
+    pub packet_burst: f32,
+    /// Bytes per second, and the number of bytes above the rate in a burst
+    pub byte_rate: f32,
+    pub byte_burst: f32,
+    /// The IP address is banned after this many malformed packets within `malformed_window`
+    pub max_malformed: u32,
+    pub malformed_window: Second,
+    pub ban_duration: Second,
+}
+
+impl Default for PacketGuardConfig {
+    fn default() -> Self {
+        PacketGuardConfig {
+            max_payload_size: 1024,
+            packet_rate: 200.0,
+            packet_burst: 400.0,
+            byte_rate: 65536.0,
+            byte_burst: 131072.0,
+            max_malformed: 10,
+            malformed_window: Second(10.0),
+            ban_duration: Second(60.0),
+        }
+    }
+}
+
+/// Why a packet is dropped before it is decoded
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Rejection {
+    Oversized,
+    RateLimited,
+    Banned,
+}
+
+/// Number of the packets dropped since the server has started
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PacketGuardStats {
+    pub oversized: u64,
+    pub rate_limited: u64,
+    pub malformed: u64,
+    pub from_banned: u64,
+    pub bans: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f32,
+    updated: Duration,
+}
+
+impl TokenBucket {
+    fn full(burst: f32, now: Duration) -> Self {
+        TokenBucket {
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f32, burst: f32, now: Duration) {
+        let elapsed = now.saturating_sub(self.updated).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct AddressState {
+    packets: TokenBucket,
+    bytes: TokenBucket,
+    malformed: u32,
+    first_malformed: Duration,
+    last_seen: Duration,
+}
+
+#[derive(Debug)]
+struct Ban {
+    until: Duration,
+    last_notice: Option<Duration>,
+}
+
+/// Drops the packets of the addresses sending too much, too large or malformed packets,
+/// before they are decoded
+#[derive(Debug, bevy::prelude::Resource)]
+pub struct PacketGuard {
+    config: PacketGuardConfig,
+    addresses: HashMap<SocketAddr, AddressState>,
+    /// Banned IP addresses, the only record of the bans on the server
+    bans: HashMap<IpAddr, Ban>,
+    stats: PacketGuardStats,
+}
+
+impl PacketGuard {
+    pub fn new(config: PacketGuardConfig) -> Self {
+        PacketGuard {
+            config,
+            addresses: HashMap::new(),
+            bans: HashMap::new(),
+            stats: PacketGuardStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PacketGuardStats {
+        &self.stats
+    }
+
+    pub fn active_bans(&self) -> usize {
+        self.bans.len()
+    }
+
+    /// Checks the packet against the limits of its sender. A rejected packet must be dropped.
+    pub fn admit(
+        &mut self,
+        addr: &SocketAddr,
+        payload_size: usize,
+        now: Duration,
+    ) -> Result<(), Rejection> {
+        if self.is_banned(&addr.ip(), now) {
+            self.stats.from_banned += 1;
+            return Err(Rejection::Banned);
+        }
+        if payload_size > self.config.max_payload_size {
+            self.stats.oversized += 1;
+            return Err(Rejection::Oversized);
+        }
+
+        if self.addresses.len() >= MAX_TRACKED_ADDRESSES {
+            self.addresses
+                .retain(|_, state| now.saturating_sub(state.last_seen) < IDLE_TIMEOUT);
+        }
+        let config = &self.config;
+        let state = self.addresses.entry(*addr).or_insert_with(|| AddressState {
+            packets: TokenBucket::full(config.packet_burst, now),
+            bytes: TokenBucket::full(config.byte_burst, now),
+            malformed: 0,
+            first_malformed: now,
+            last_seen: now,
+        });
+        state.last_seen = now;
+        state
+            .packets
+            .refill(config.packet_rate, config.packet_burst, now);
+        state.bytes.refill(config.byte_rate, config.byte_burst, now);
+
+        let size = payload_size as f32;
+        if state.packets.tokens < 1.0 || state.bytes.tokens < size {
+            self.stats.rate_limited += 1;
+            return Err(Rejection::RateLimited);
+        }
+        state.packets.tokens -= 1.0;
+        state.bytes.tokens -= size;
+        Ok(())
+    }
+
+    /// Counts a packet which could not be decoded or is not expected from a client.
+    /// Returns true if the IP address of the sender has been banned for it.
+    pub fn record_malformed(&mut self, addr: &SocketAddr, now: Duration) -> bool {
+        self.stats.malformed += 1;
+        let state = match self.addresses.get_mut(addr) {
+            Some(state) => state,
+            None => return false,
+        };
+
+        let window = self.config.malformed_window.into_duration();
+        if state.malformed == 0 || now.saturating_sub(state.first_malformed) > window {
+            state.malformed = 0;
+            state.first_malformed = now;
+        }
+        state.malformed += 1;
+        if state.malformed < self.config.max_malformed {
+            return false;
+        }
+
+        state.malformed = 0;
+        let duration = self.config.ban_duration.into_duration();
+        self.bans.insert(
+            addr.ip(),
+            Ban {
+                until: now + duration,
+                last_notice: None,
+            },
+        );
+        self.stats.bans += 1;
+        // Every client behind the same IP address (e.g. a shared NAT) is banned with the sender
+        log::warn!(
+            "IP address {} is banned for {:?} for sending malformed messages from {}",
+            addr.ip(),
+            duration,
+            addr
+        );
+        true
+    }
+
+    /// Whether a connection request of the banned IP address should be answered with the ban.
+    /// True at most once per `BAN_NOTICE_INTERVAL`.
+    pub fn notify_ban(&mut self, ip: &IpAddr, now: Duration) -> bool {
+        let ban = match self.bans.get_mut(ip) {
+            Some(ban) => ban,
+            None => return false,
+        };
+        let recently_notified = ban
+            .last_notice
+            .map_or(false, |last| now.saturating_sub(last) < BAN_NOTICE_INTERVAL);
+        if recently_notified {
+            return false;
+        }
+        ban.last_notice = Some(now);
+        true
+    }
+
+    fn is_banned(&mut self, ip: &IpAddr, now: Duration) -> bool {
+        match self.bans.get(ip) {
+            Some(ban) if now < ban.until => true,
+            Some(_) => {
+                self.bans.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn guard() -> PacketGuard {
+        PacketGuard::new(PacketGuardConfig {
+            max_payload_size: 100,
+            packet_rate: 10.0,
+            packet_burst: 5.0,
+            byte_rate: 1000.0,
+            byte_burst: 200.0,
+            max_malformed: 3,
+            malformed_window: Second(1.0),
+            ban_duration: Second(5.0),
+        })
+    }
+
+    #[test]
+    fn oversized_payload_is_rejected() {
+        let mut guard = guard();
+
+        assert_eq!(guard.admit(&addr(1), 100, Duration::ZERO), Ok(()));
+        assert_eq!(
+            guard.admit(&addr(1), 101, Duration::ZERO),
+            Err(Rejection::Oversized)
+        );
+        assert_eq!(guard.stats().oversized, 1);
+    }
+
+    #[test]
+    fn packets_above_rate_are_rejected_until_refilled() {
+        let mut guard = guard();
+        for _ in 0..5 {
+            assert_eq!(guard.admit(&addr(1), 10, Duration::ZERO), Ok(()));
+        }
+        assert_eq!(
+            guard.admit(&addr(1), 10, Duration::ZERO),
+            Err(Rejection::RateLimited)
+        );
+        // Other addresses have their own limits
+        assert_eq!(guard.admit(&addr(2), 10, Duration::ZERO), Ok(()));
+
+        assert_eq!(guard.admit(&addr(1), 10, Duration::from_millis(100)), Ok(()));
+        assert_eq!(guard.stats().rate_limited, 1);
+    }
+
+    #[test]
+    fn bytes_above_rate_are_rejected() {
+        let mut guard = guard();
+
+        assert_eq!(guard.admit(&addr(1), 100, Duration::ZERO), Ok(()));
+        assert_eq!(guard.admit(&addr(1), 100, Duration::ZERO), Ok(()));
+        assert_eq!(
+            guard.admit(&addr(1), 1, Duration::ZERO),
+            Err(Rejection::RateLimited)
+        );
+    }
+
+    #[test]
+    fn repeated_malformed_packets_ban_temporarily() {
+        let mut guard = guard();
+        guard.admit(&addr(1), 10, Duration::ZERO).unwrap();
+
+        assert!(!guard.record_malformed(&addr(1), Duration::ZERO));
+        assert!(!guard.record_malformed(&addr(1), Duration::ZERO));
+        assert!(guard.record_malformed(&addr(1), Duration::ZERO));
+
+        // The ban applies to every port of the IP address
+        assert_eq!(
+            guard.admit(&addr(2), 10, Duration::from_secs(1)),
+            Err(Rejection::Banned)
+        );
+        assert_eq!(guard.active_bans(), 1);
+        assert_eq!(guard.admit(&addr(1), 10, Duration::from_secs(5)), Ok(()));
+        assert_eq!(guard.active_bans(), 0);
+    }
+
+    #[test]
+    fn ban_is_notified_at_most_once_per_interval() {
+        let mut guard = guard();
+        assert!(!guard.notify_ban(&addr(1).ip(), Duration::ZERO));
+
+        guard.admit(&addr(1), 10, Duration::ZERO).unwrap();
+        for _ in 0..3 {
+            guard.record_malformed(&addr(1), Duration::ZERO);
+        }
+
+        assert!(guard.notify_ban(&addr(1).ip(), Duration::ZERO));
+        assert!(!guard.notify_ban(&addr(2).ip(), Duration::from_millis(500)));
+        assert!(guard.notify_ban(&addr(2).ip(), BAN_NOTICE_INTERVAL));
+    }
+
+    #[test]
+    fn sparse_malformed_packets_are_tolerated() {
+        let mut guard = guard();
+        guard.admit(&addr(1), 10, Duration::ZERO).unwrap();
+
+        for second in 0..10 {
+            assert!(!guard.record_malformed(&addr(1), Duration::from_secs(second)));
+        }
+        assert_eq!(guard.stats().malformed, 10);
+        assert_eq!(guard.stats().bans, 0);
+    }
+}
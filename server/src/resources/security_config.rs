@@ -0,0 +1,39 @@
+use serde::Deserialize;
+use westiny_common::secure_channel::ServerIdentity;
+
+/// Identity of the server in the key exchange of the secure sessions
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct SecurityConfig {
+    /// Hex encoded Ed25519 secret key. Without it a new identity is made on every start, only the
+    /// clients sharing the resources directory can pin it.
+    pub identity_key: Option<String>,
+}
+
+impl SecurityConfig {
+    pub fn identity(&self) -> anyhow::Result<ServerIdentity> {
+        match &self.identity_key {
+            Some(key) => ServerIdentity::from_hex(key),
+            None => Ok(ServerIdentity::generate()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn configured_identity_is_kept() {
+        let config = SecurityConfig {
+            identity_key: Some("07".repeat(32)),
+        };
+        assert_eq!(
+            config.identity().unwrap().public_key(),
+            config.identity().unwrap().public_key()
+        );
+        let broken = SecurityConfig {
+            identity_key: Some("07".to_string()),
+        };
+        assert!(broken.identity().is_err());
+    }
+}
@@ -0,0 +1,50 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Rates of the fixed timestep stages of the server, in Hz
+#[derive(Copy, Clone, Debug, Deserialize, bevy::prelude::Resource)]
+pub struct SimulationConfig {
+    /// Simulation ticks per second
+    pub tick_rate: u32,
+    /// Entity state snapshots sent to the clients per second
+    pub snapshot_rate: u32,
+}
+
+impl SimulationConfig {
+    pub fn tick_step(&self) -> Duration {
+        step(self.tick_rate)
+    }
+
+    pub fn snapshot_step(&self) -> Duration {
+        step(self.snapshot_rate)
+    }
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            tick_rate: 60,
+            snapshot_rate: 20,
+        }
+    }
+}
+
+fn step(rate: u32) -> Duration {
+    Duration::from_secs_f64(1.0 / rate.max(1) as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn steps_are_reciprocal_of_rates() {
+        let config = SimulationConfig {
+            tick_rate: 50,
+            snapshot_rate: 0,
+        };
+
+        assert_eq!(config.tick_step(), Duration::from_millis(20));
+        assert_eq!(config.snapshot_step(), Duration::from_secs(1));
+    }
+}
@@ -7,7 +7,10 @@ use westiny_common::{
 
 use crate::{
     components::{Client, EntityType, NetworkId},
-    resources::{ClientID, ClientNetworkEvent, ClientRegistry, NetworkIdSupplier},
+    resources::{
+        ClientID, ClientNetworkEvent, ClientOutbox, ClientSnapshots, NetworkIdSupplier,
+        SimulationConfig,
+    },
     systems::spawn::SpawnPlayerEvent,
 };
 use bevy::prelude::{Entity, EventReader, EventWriter, Query, Res, ResMut};
@@ -19,9 +22,11 @@ pub fn introduce_new_clients(
     mut entity_delete_ec: EventWriter<EntityDelete>,
     mut spawn_player_ec: EventWriter<SpawnPlayerEvent>,
     mut net: ResMut<TransportResource>,
-    client_registry: Res<ClientRegistry>,
+    mut outbox: ClientOutbox,
     seed: Res<Seed>,
+    simulation_config: Res<SimulationConfig>,
     mut network_id_supplier: ResMut<NetworkIdSupplier>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
     spawned_clients_query: Query<(&NetworkId, &Client)>,
     clients_query: Query<(Entity, &Client)>,
 ) {
@@ -32,9 +37,13 @@ pub fn introduce_new_clients(
     for client_network_event in client_network_ec.iter() {
         match client_network_event {
             ClientNetworkEvent::ClientConnected(client_id) => {
-                let client_handle = client_registry.find_client(*client_id).unwrap_or_else(|| {
-                    panic!("Client [client_id: {:?}] not found in registry", client_id)
-                });
+                let mut reconnected = false;
+                let client_handle = outbox
+                    .client_registry()
+                    .find_client(*client_id)
+                    .unwrap_or_else(|| {
+                        panic!("Client [client_id: {:?}] not found in registry", client_id)
+                    });
 
                 let entity_network_id = if let Some((_, net_id)) =
                     added_clients.iter().find(|(cli_id, _)| cli_id == client_id)
@@ -46,23 +55,20 @@ pub fn introduce_new_clients(
                         net_id
                     );
                     *net_id
+                } else if let Some((net_id, _)) = spawned_clients_query
+                    .iter()
+                    .find(|(_, client)| client.id == *client_id)
+                {
+                    // The client has reconnected to its session, its player is kept as it is
+                    log::info!(
+                        "{:?} already connected, its entity already spawned: {:?}",
+                        client_id,
+                        net_id
+                    );
+                    reconnected = true;
+                    *net_id
                 } else {
-                    // check if player has been spawned already earlier
-                    let net_id = (|| {
-                        for (net_id, client) in spawned_clients_query.iter() {
-                            if client.id == *client_id {
-                                log::info!(
-                                    "{:?} already connected, its entity already spawned: {:?}",
-                                    client.id,
-                                    net_id
-                                );
-                                return Some(*net_id);
-                            }
-                        }
-                        None
-                    })()
-                    .unwrap_or_else(|| network_id_supplier.next(EntityType::Player));
-
+                    let net_id = network_id_supplier.next(EntityType::Player);
                     spawn_player_ec.send(SpawnPlayerEvent {
                         client: Client { id: *client_id },
                         network_id: net_id,
@@ -82,7 +88,15 @@ pub fn introduce_new_clients(
                 let connection_response = PacketType::ConnectionResponse(Ok(ClientInitialData {
                     player_network_id: entity_network_id,
                     seed: *seed,
+                    tick_rate: simulation_config.tick_rate,
+                    session_token: client_handle.session_token,
+                    codec: client_handle.codec,
+                    compression: client_handle.compression,
                 }));
+                // The client starts over without any of the snapshots sent earlier
+                client_snapshots.forget(*client_id);
+                // Not through the `Outbox`: the connecting client expects the response alone,
+                // in the default wire format, as it learns the negotiated one from it
                 net.send_with_requirements(
                     client_handle.addr,
                     &serialize(&connection_response).unwrap(),
@@ -90,13 +104,12 @@ pub fn introduce_new_clients(
                     UrgencyRequirement::OnTick,
                 );
 
-                broadcast_notification(
-                    &mut net,
-                    &client_registry,
-                    PlayerNotification {
-                        message: format!("{} joined.", &client_handle.player_name),
-                    },
-                );
+                let message = if reconnected {
+                    format!("{} reconnected.", &client_handle.player_name)
+                } else {
+                    format!("{} joined.", &client_handle.player_name)
+                };
+                outbox.broadcast(PlayerNotification { message });
             }
             ClientNetworkEvent::ClientDisconnected(client_id, player_name) => {
                 log::debug!(
@@ -105,35 +118,14 @@ pub fn introduce_new_clients(
                 );
                 despawn_player(&clients_query, &mut entity_delete_ec, client_id);
 
-                broadcast_notification(
-                    &mut net,
-                    &client_registry,
-                    PlayerNotification {
-                        message: format!("{} left the game.", &player_name),
-                    },
-                );
+                outbox.broadcast(PlayerNotification {
+                    message: format!("{} left the game.", &player_name),
+                });
             }
         }
     }
 }
 
-fn broadcast_notification(
-    net: &mut TransportResource,
-    client_registry: &ClientRegistry,
-    notification: PlayerNotification,
-) {
-    let msg = serialize(&PacketType::Notification(notification))
-        .expect("PlayerNotification could not be serialized");
-    for &handle in client_registry.get_clients().iter() {
-        net.send_with_requirements(
-            handle.addr,
-            &msg,
-            DeliveryRequirement::Reliable,
-            UrgencyRequirement::OnTick,
-        )
-    }
-}
-
 fn despawn_player(
     query: &Query<(Entity, &Client)>,
     entity_delete_channel: &mut EventWriter<EntityDelete>,
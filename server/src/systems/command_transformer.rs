@@ -1,31 +1,196 @@
-use crate::components;
+use crate::components::{self, InputFlags};
 use crate::resources::{ClientID, NetworkCommand};
 use bevy::log::debug;
 use bevy::prelude::{EventReader, Query};
+use std::time::Duration;
+use westiny_common::network::SequencedInput;
+
+type InputQueueQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static components::Client,
+        &'static mut components::InputQueue,
+        &'static mut components::ViewTime,
+        &'static mut components::Input,
+    ),
+>;
 
 pub fn transform_commands(
     mut network_commands: EventReader<NetworkCommand>,
-    mut query: Query<(&components::Client, &mut components::Input)>,
+    mut query: InputQueueQuery,
 ) {
     for net_command in network_commands.iter() {
         match net_command {
-            NetworkCommand::Input { id, input } => apply_client_input(id, input, &mut query),
+            NetworkCommand::Input {
+                id,
+                inputs,
+                view_time,
+            } => enqueue_client_inputs(id, inputs, *view_time, &mut query),
+            NetworkCommand::ConnectionLost { id } => stop_client_player(id, &mut query),
+            // Acknowledgements are consumed by the entity state broadcaster
+            NetworkCommand::SnapshotAck { .. } => {}
         }
     }
 }
 
-fn apply_client_input(
+fn enqueue_client_inputs(
     id: &ClientID,
-    new_input: &components::Input,
-    query: &mut Query<(&components::Client, &mut components::Input)>,
+    inputs: &[SequencedInput],
+    view_time: Option<Duration>,
+    query: &mut InputQueueQuery,
 ) {
-    for (client, mut input) in query.iter_mut() {
+    for (client, mut queue, mut last_view_time, _) in query.iter_mut() {
         if &client.id == id {
             debug!(
-                "Assigning new input to client id={:?}, new input={:?}",
-                &id, &new_input
+                "Enqueueing inputs of client id={:?}, inputs={:?}",
+                &id, inputs
             );
-            *input = *new_input;
+            queue.enqueue(inputs.iter().copied());
+            last_view_time.0 = view_time;
+        }
+    }
+}
+
+/// The last input would be applied until the client reconnects, the player is stopped instead.
+/// Only the movement is stopped, the cursor is kept.
+fn stop_client_player(id: &ClientID, query: &mut InputQueueQuery) {
+    for (client, mut queue, _, mut input) in query.iter_mut() {
+        if &client.id == id {
+            queue.clear();
+            input.flags = InputFlags::NOP;
+        }
+    }
+}
+
+/// Applies one queued input per tick, so each input of the client takes effect,
+/// even when several of them arrive at once.
+/// The last applied input is kept when the queue is empty.
+pub fn dequeue_inputs(
+    mut query: Query<(
+        &mut components::InputQueue,
+        &mut components::Input,
+        &mut components::LastInputSequence,
+    )>,
+) {
+    for (mut queue, mut input, mut last_sequence) in query.iter_mut() {
+        if let Some(queued) = queue.pop() {
+            *input = queued.input;
+            last_sequence.0 = Some(queued.sequence);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::{Input, InputFlags, InputQueue, LastInputSequence, ViewTime};
+    use crate::resources::ClientID;
+    use bevy::prelude::*;
+
+    fn sequenced(sequence: u32, flags: InputFlags) -> SequencedInput {
+        SequencedInput {
+            sequence,
+            input: Input {
+                flags,
+                ..Input::default()
+            },
         }
     }
+
+    fn make_app() -> (App, Entity) {
+        let mut app = App::new();
+        app.add_event::<NetworkCommand>()
+            .add_system(transform_commands.label("transform_commands"))
+            .add_system(dequeue_inputs.after("transform_commands"));
+        let entity = app
+            .world
+            .spawn((
+                components::Client::new(ClientID(0)),
+                Input::default(),
+                InputQueue::default(),
+                LastInputSequence::default(),
+                ViewTime::default(),
+            ))
+            .id();
+        (app, entity)
+    }
+
+    #[test]
+    fn inputs_are_applied_one_per_tick() {
+        let (mut app, entity) = make_app();
+        app.world.send_event(NetworkCommand::Input {
+            id: ClientID(0),
+            inputs: vec![
+                sequenced(0, InputFlags::SHOOT),
+                sequenced(1, InputFlags::NOP),
+            ],
+            view_time: None,
+        });
+
+        app.update();
+        assert_eq!(
+            app.world.get::<Input>(entity).unwrap().flags,
+            InputFlags::SHOOT
+        );
+        assert_eq!(
+            app.world.get::<LastInputSequence>(entity).unwrap().0,
+            Some(0)
+        );
+
+        app.update();
+        assert_eq!(
+            app.world.get::<Input>(entity).unwrap().flags,
+            InputFlags::NOP
+        );
+        assert_eq!(
+            app.world.get::<LastInputSequence>(entity).unwrap().0,
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn last_input_is_kept_when_queue_is_empty() {
+        let (mut app, entity) = make_app();
+        app.world.send_event(NetworkCommand::Input {
+            id: ClientID(0),
+            inputs: vec![sequenced(0, InputFlags::FORWARD)],
+            view_time: Some(Duration::from_millis(100)),
+        });
+
+        app.update();
+        app.update();
+        assert_eq!(
+            app.world.get::<Input>(entity).unwrap().flags,
+            InputFlags::FORWARD
+        );
+        assert_eq!(
+            app.world.get::<ViewTime>(entity).unwrap().0,
+            Some(Duration::from_millis(100))
+        );
+    }
+
+    #[test]
+    fn player_stops_when_connection_is_lost() {
+        let (mut app, entity) = make_app();
+        app.world.send_event(NetworkCommand::Input {
+            id: ClientID(0),
+            inputs: vec![
+                sequenced(0, InputFlags::FORWARD),
+                sequenced(1, InputFlags::FORWARD),
+            ],
+            view_time: None,
+        });
+        app.update();
+
+        app.world
+            .send_event(NetworkCommand::ConnectionLost { id: ClientID(0) });
+        app.update();
+        app.update();
+        assert_eq!(
+            app.world.get::<Input>(entity).unwrap().flags,
+            InputFlags::NOP
+        );
+        assert!(app.world.get::<InputQueue>(entity).unwrap().is_empty());
+    }
 }
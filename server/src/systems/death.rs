@@ -1,21 +1,21 @@
 use crate::components::{Client, Eliminated};
-use crate::resources::{ClientRegistry, StreamId};
-use bevy::prelude::{Entity, EventWriter, Query, Res, ResMut, Transform, With};
-use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
+use crate::resources::{ClientOutbox, InterestManager, SimulationTime};
+use bevy::prelude::{Entity, EventWriter, Query, Res, Transform, With};
 use westiny_common::events::EntityDelete;
 use westiny_common::metric_dimension::length::MeterVec2;
-use westiny_common::network::{PacketType, PlayerDeath};
-use westiny_common::serialization::serialize;
+use westiny_common::network::PlayerDeath;
 
 pub fn handle_death(
     eliminateds: Query<(Entity, &Transform, Option<&Client>), With<Eliminated>>,
-    client_registry: Res<ClientRegistry>,
-    mut net: ResMut<TransportResource>,
+    interest: Res<InterestManager>,
+    simulation_time: Res<SimulationTime>,
+    mut outbox: ClientOutbox,
     mut entity_delete: EventWriter<EntityDelete>,
 ) {
     for (entity, transform, maybe_client) in eliminateds.iter() {
         if let Some(client) = maybe_client {
-            let player_name = client_registry
+            let player_name = outbox
+                .client_registry()
                 .find_client(client.id)
                 .unwrap()
                 .player_name
@@ -25,20 +25,15 @@ pub fn handle_death(
             // Dead player must be removed
             entity_delete.send(EntityDelete { entity_id: entity });
 
-            let death_event_msg = serialize(&PacketType::PlayerDeath(PlayerDeath {
-                player_name,
-                position: MeterVec2::from_pixel_vec(transform.translation.truncate()),
-            }))
-            .expect("Could not serialize PlayerDeath");
-
-            client_registry.get_clients().iter().for_each(|&handle| {
-                net.send_with_requirements(
-                    handle.addr,
-                    &death_event_msg,
-                    DeliveryRequirement::ReliableSequenced(StreamId::PlayerDeath.into()),
-                    UrgencyRequirement::OnTick,
-                );
-            });
+            let position = MeterVec2::from_pixel_vec(transform.translation.truncate());
+            outbox.send_where(
+                |handle| interest.is_relevant(handle.id, &position),
+                PlayerDeath {
+                    player_name,
+                    position,
+                    tick: simulation_time.tick(),
+                },
+            );
         }
     }
 }
@@ -0,0 +1,81 @@
+use crate::resources::{
+    ClientNetworkEvent, ClientRegistry, DemoRecorder, NetworkCommand, SimulationTime,
+};
+use bevy::prelude::{EventReader, Res, ResMut, Time};
+use blaminar::simulation::TransportResource;
+use westiny_common::demo::{DemoFrame, DemoRecord};
+
+fn frame(simulation_time: &SimulationTime, time: &Time, record: DemoRecord) -> DemoFrame {
+    DemoFrame {
+        tick: simulation_time.tick(),
+        server_time: time.elapsed(),
+        record,
+    }
+}
+
+/// Records the accepted clients and the inputs received from them
+pub fn record_inbound(
+    mut recorder: ResMut<DemoRecorder>,
+    client_registry: Res<ClientRegistry>,
+    simulation_time: Res<SimulationTime>,
+    time: Res<Time>,
+    mut client_network_events: EventReader<ClientNetworkEvent>,
+    mut network_commands: EventReader<NetworkCommand>,
+) {
+    for event in client_network_events.iter() {
+        if let ClientNetworkEvent::ClientConnected(client_id) = event {
+            if let Some(handle) = client_registry.find_client(*client_id) {
+                recorder.record(&frame(
+                    &simulation_time,
+                    &time,
+                    DemoRecord::ClientJoined {
+                        address: handle.addr,
+                        player_name: handle.player_name.0.clone(),
+                    },
+                ));
+            }
+        }
+    }
+
+    for command in network_commands.iter() {
+        if let NetworkCommand::Input {
+            id,
+            inputs,
+            view_time,
+        } = command
+        {
+            if let Some(handle) = client_registry.find_client(*id) {
+                recorder.record(&frame(
+                    &simulation_time,
+                    &time,
+                    DemoRecord::Input {
+                        source: handle.addr,
+                        inputs: inputs.clone(),
+                        view_time: *view_time,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// Records the packets queued in this frame.
+/// Must run after every system sending packets, the transport sends them in the next frame.
+pub fn record_outbound(
+    mut recorder: ResMut<DemoRecorder>,
+    net: Res<TransportResource>,
+    simulation_time: Res<SimulationTime>,
+    time: Res<Time>,
+) {
+    for message in net.get_messages() {
+        recorder.record(&frame(
+            &simulation_time,
+            &time,
+            DemoRecord::Outbound {
+                destination: message.destination,
+                payload: message.payload.to_vec(),
+            },
+        ));
+    }
+    recorder.flush();
+}
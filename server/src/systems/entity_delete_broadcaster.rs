@@ -1,20 +1,16 @@
 use crate::components::NetworkId;
-use crate::resources::ClientRegistry;
+use crate::resources::{ClientOutbox, SimulationTime};
 use bevy::prelude::{
-    Commands, Entity, EventReader, IntoSystemDescriptor, Query, Res,
-    ResMut, SystemSet,
+    Commands, Entity, EventReader, IntoSystemDescriptor, Query, Res, ResMut, SystemSet,
 };
-use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
 use westiny_common::events::EntityDelete;
-use westiny_common::{network, serialization::serialize};
+use westiny_common::network;
+use westiny_common::replication::ReplicationBuffer;
 
 pub fn entity_delete_system_set() -> SystemSet {
     SystemSet::new()
         .label("entity_delete")
-        .with_system(
-            broadcast_net_id_deletion
-                .label("entity_delete_broadcaster"),
-        )
+        .with_system(broadcast_net_id_deletion.label("entity_delete_broadcaster"))
         .with_system(
             delete_entities
                 .label("entity_delete")
@@ -25,22 +21,16 @@ pub fn entity_delete_system_set() -> SystemSet {
 fn broadcast_net_id_deletion(
     mut entity_deletions: EventReader<EntityDelete>,
     network_ids: Query<&NetworkId>,
-    clients: Res<ClientRegistry>,
-    mut net: ResMut<TransportResource>,
+    simulation_time: Res<SimulationTime>,
+    mut replication: ResMut<ReplicationBuffer>,
+    mut outbox: ClientOutbox,
 ) {
     for EntityDelete { entity_id: entity } in entity_deletions.iter() {
         if let Ok(&network_id) = network_ids.get(*entity) {
-            let network_entity_delete = network::NetworkEntityDelete { network_id };
-            let message = serialize(&network::PacketType::EntityDelete(network_entity_delete))
-                .expect("NetworkEntityDelete could not be serialized");
-
-            clients.get_clients().iter().for_each(|&client| {
-                net.send_with_requirements(
-                    client.addr,
-                    &message,
-                    DeliveryRequirement::Reliable,
-                    UrgencyRequirement::OnTick,
-                )
+            replication.forget(&network_id);
+            outbox.broadcast(network::NetworkEntityDelete {
+                network_id,
+                tick: simulation_time.tick(),
             });
         }
     }
@@ -1,38 +1,101 @@
 use crate::components;
-use crate::resources::{ClientRegistry, StreamId};
-use bevy::prelude::{Query, Res, ResMut, GlobalTransform};
-use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
+use crate::resources::{
+    ClientOutbox, ClientRegistry, ClientSnapshots, InterestManager, NetworkCommand, SimulationTime,
+};
+use bevy::prelude::{EventReader, Query, Res, ResMut, Time, Transform, Without};
+use std::collections::HashMap;
 use westiny_common::metric_dimension::length::{Meter, MeterVec2};
-use westiny_common::{network, serialization::serialize, utilities::get_angle};
+use westiny_common::snapshot::Snapshot;
+use westiny_common::{network, utilities::get_angle};
+
+/// Acknowledgements are read every frame, as snapshots are sent less frequently and the events
+/// would be dropped in between.
+pub fn receive_snapshot_acks(
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    mut network_commands: EventReader<NetworkCommand>,
+) {
+    for command in network_commands.iter() {
+        if let NetworkCommand::SnapshotAck { id, snapshot } = command {
+            client_snapshots.acknowledge(*id, *snapshot);
+        }
+    }
+}
 
 /// This system is responsible for sending the transform of all the entities that has NetworkID
-/// to every connected clients
+/// to every connected clients, except the map entities, as their position is replicated.
+/// Each client receives only the entities within its interest radius and only the changes since
+/// the last snapshot it has acknowledged. Entities leaving the radius are reported as removed.
+/// The sequence of the last input applied to the client's player is echoed for reconciliation.
+/// Snapshots over the budget are split into chunks, the own player and the nearest entities first.
+#[allow(clippy::too_many_arguments)]
 pub fn broadcast_entity_state(
     client_registry: Res<ClientRegistry>,
-    mut net: ResMut<TransportResource>,
-    query: Query<(&components::NetworkId, &GlobalTransform)>,
+    interest: Res<InterestManager>,
+    time: Res<Time>,
+    simulation_time: Res<SimulationTime>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    mut outbox: ClientOutbox,
+    query: Query<(&components::NetworkId, &Transform), Without<components::MapPosition>>,
+    input_sequences: Query<(&components::Client, &components::LastInputSequence)>,
+    player_ids: Query<(&components::Client, &components::NetworkId)>,
 ) {
-    let mut network_entities = Vec::new();
-    for (network_id, transform) in query.iter() {
-        let entity_state = network::EntityState {
+    client_snapshots.retain_clients(|id| client_registry.find_client(*id).is_some());
+
+    let entity_states: Vec<_> = query
+        .iter()
+        .map(|(network_id, transform)| network::EntityState {
             network_id: *network_id,
             position: MeterVec2 {
-                x: Meter::from_pixel(transform.translation().x),
-                y: Meter::from_pixel(transform.translation().y),
+                x: Meter::from_pixel(transform.translation.x),
+                y: Meter::from_pixel(transform.translation.y),
             },
-            angle: get_angle(transform.to_scale_rotation_translation().1),
-        };
-        network_entities.push(entity_state);
-    }
+            angle: get_angle(transform.rotation),
+        })
+        .collect();
+    let last_inputs: HashMap<_, _> = input_sequences
+        .iter()
+        .filter_map(|(client, sequence)| sequence.0.map(|sequence| (client.id, sequence)))
+        .collect();
+    let players: HashMap<_, _> = player_ids
+        .iter()
+        .map(|(client, network_id)| (client.id, *network_id))
+        .collect();
+    let snapshot_id = client_snapshots.next_snapshot_id();
 
-    let msg = serialize(&network::PacketType::EntityStateUpdate(network_entities))
-        .expect("entity state update could not be serialized");
     client_registry.get_clients().iter().for_each(|&handle| {
-        net.send_with_requirements(
-            handle.addr,
-            &msg,
-            DeliveryRequirement::UnreliableSequenced(StreamId::EntityStateUpdate.into()),
-            UrgencyRequirement::OnTick,
-        )
+        let snapshot = Snapshot::new(
+            entity_states
+                .iter()
+                .filter(|state| interest.is_relevant(handle.id, &state.position))
+                .cloned(),
+        );
+        // The own player is the most important, then the nearer an entity is the more
+        let player = players.get(&handle.id);
+        let center = interest.center(handle.id);
+        let priority = |state: &network::EntityState| {
+            if Some(&state.network_id) == player {
+                return f32::NEG_INFINITY;
+            }
+            center.map_or(0.0, |center| {
+                let dx = center.x.0 - state.position.x.0;
+                let dy = center.y.0 - state.position.y.0;
+                dx * dx + dy * dy
+            })
+        };
+        let updates = client_snapshots.make_updates(
+            handle.id,
+            handle.codec,
+            snapshot_id,
+            simulation_time.tick(),
+            time.elapsed(),
+            snapshot,
+            priority,
+        );
+        for mut update in updates {
+            update.last_processed_input = last_inputs.get(&handle.id).copied();
+            if let Err(err) = outbox.send(handle.id, update) {
+                log::error!("Could not send entity state update: {}", err);
+            }
+        }
     })
 }
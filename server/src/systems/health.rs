@@ -1,13 +1,7 @@
-use crate::components::{Client, Eliminated, Health};
-use crate::resources::{ClientID, ClientRegistry, StreamId};
+use crate::components::{Eliminated, Health};
 use bevy::ecs::system::Insert;
 use bevy::prelude::*;
-use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
 use westiny_common::events::DamageEvent;
-use westiny_common::network::{PacketType, PlayerUpdate};
-use westiny_common::serialization::serialize;
-
-use anyhow;
 
 pub fn handle_damage(
     mut commands: Commands,
@@ -29,47 +23,6 @@ pub fn handle_damage(
             } else {
                 *health -= damage_event.damage;
             }
-
-        }
-    }
-}
-
-#[allow(clippy::type_complexity)]
-pub fn send_health_update_on_change(
-    client_registry: Res<ClientRegistry>,
-    mut transport: ResMut<TransportResource>,
-    changed_healths: Query<(&Health, &Client), Or<(Changed<Health>, Added<Health>)>>
-) {
-    for (health, client) in changed_healths.iter() {
-        if let Err(err) = notify_client(&client_registry, &mut transport, *health, &client.id) {
-            log::error!("Error while sending Health update to client: {}", err);
         }
     }
 }
-
-fn notify_client(
-    client_registry: &ClientRegistry,
-    transport: &mut TransportResource,
-    new_health: Health,
-    client: &ClientID,
-) -> anyhow::Result<()> {
-    let client_handle = {
-        client_registry
-            .find_client(*client)
-            .ok_or_else(|| anyhow::anyhow!("Client [id: {:?}] not found in registry", client))?
-    };
-
-    let payload = serialize(&PacketType::PlayerUpdate(PlayerUpdate::HealthUpdate(
-        new_health,
-    )))
-    .map_err(anyhow::Error::new)?;
-
-    transport.send_with_requirements(
-        client_handle.addr,
-        &payload,
-        DeliveryRequirement::ReliableSequenced(StreamId::HealthUpdate.into()),
-        UrgencyRequirement::OnTick,
-    );
-
-    Ok(())
-}
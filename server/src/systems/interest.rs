@@ -0,0 +1,21 @@
+use crate::components::Client;
+use crate::resources::{ClientRegistry, InterestManager};
+use bevy::prelude::{GlobalTransform, Query, Res, ResMut};
+use westiny_common::metric_dimension::length::MeterVec2;
+
+/// Moves the center of interest of every client to its player's current position.
+/// Clients without a player (e.g. waiting for respawn) keep their last known center.
+pub fn update_interest_centers(
+    client_registry: Res<ClientRegistry>,
+    mut interest: ResMut<InterestManager>,
+    players: Query<(&Client, &GlobalTransform)>,
+) {
+    interest.retain_clients(|id| client_registry.find_client(*id).is_some());
+
+    for (client, transform) in players.iter() {
+        interest.update_center(
+            client.id,
+            MeterVec2::from_pixel_vec(transform.translation().truncate()),
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::components::{MapPosition, Replicate};
+use crate::resources::{NetworkIdSupplier, ResourcesDir, Seed};
+use bevy::prelude::{Commands, Res, ResMut};
+use westiny_common::entities::insert_map_entity;
+use westiny_common::resources::read_map;
+
+/// Places the entities of the map. They get network ids and their position is replicated,
+/// so the clients receive the map from the server.
+pub fn build_map(
+    mut commands: Commands,
+    seed: Res<Seed>,
+    res_dir: Res<ResourcesDir>,
+    mut network_id_supplier: ResMut<NetworkIdSupplier>,
+) {
+    let map_entities = match read_map(*seed, &res_dir.common_resources.join("map")) {
+        Ok(map_entities) => map_entities,
+        Err(err) => {
+            log::error!("{}", err);
+            return;
+        }
+    };
+
+    for map_entity in map_entities.iter() {
+        let mut entity = commands.spawn((
+            network_id_supplier.next(map_entity.entity_type.into()),
+            MapPosition(map_entity.position),
+            Replicate,
+        ));
+        insert_map_entity(&mut entity, map_entity.entity_type, map_entity.position);
+    }
+    log::info!("Map built with {} entities", map_entities.len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::{EntityType, NetworkId};
+    use bevy::prelude::{App, Query, Transform};
+    use std::collections::HashSet;
+    use std::path::Path;
+    use w_bevy_test::TestApp;
+
+    #[test]
+    fn map_entities_are_replicated_barrels() {
+        let resources_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../resources");
+        App::new()
+            .insert_resource(Seed(0))
+            .insert_resource(ResourcesDir {
+                common_resources: resources_dir.clone(),
+                crate_resources: resources_dir,
+            })
+            .insert_resource(NetworkIdSupplier::new())
+            .add_startup_system(build_map)
+            .add_assert_system(
+                |barrels: Query<(&NetworkId, &MapPosition, &Transform, &Replicate)>| {
+                    let ids: HashSet<_> = barrels.iter().map(|(id, ..)| *id).collect();
+                    assert!(!ids.is_empty());
+                    assert_eq!(ids.len(), barrels.iter().count());
+                    for (network_id, position, transform, _) in barrels.iter() {
+                        assert_eq!(network_id.entity_type, EntityType::Barrel);
+                        assert_eq!(position.0.into_pixel_vec(), transform.translation.truncate());
+                    }
+                },
+            )
+            .run();
+    }
+}
@@ -1,22 +1,38 @@
 pub use client_introduction::introduce_new_clients;
-pub use command_transformer::transform_commands;
+pub use command_transformer::{dequeue_inputs, transform_commands};
 pub use death::handle_death;
+pub use demo_recording::{record_inbound, record_outbound};
 pub use entity_delete_broadcaster::entity_delete_system_set;
-pub use entity_state_broadcaster::broadcast_entity_state;
-pub use health::{handle_damage, send_health_update_on_change};
-pub use network_messenger::read_network_messages;
-pub use player_movement::apply_input;
+pub use entity_state_broadcaster::{broadcast_entity_state, receive_snapshot_acks};
+pub use health::handle_damage;
+pub use interest::update_interest_centers;
+pub use map::build_map;
+pub use network_messenger::{
+    expire_secure_sessions, expire_suspended_clients, open_client_packets, read_network_messages,
+};
+pub use outbox::flush_outbox;
+pub use ping::ping_clients;
+pub use replication::{introduce_replicated_components, send_component_updates};
 pub use shooter::weapon_handler_system_set;
+pub use shutdown::shut_down_on_signal;
+pub use simulation::advance_simulation_tick;
 pub use spawn::{respawn_player, spawn_player, SpawnPlayerEvent};
 pub use westiny_common::systems::*;
 
 mod client_introduction;
 mod command_transformer;
 mod death;
+mod demo_recording;
 mod entity_delete_broadcaster;
 mod entity_state_broadcaster;
 mod health;
+mod interest;
+mod map;
 mod network_messenger;
-mod player_movement;
+mod outbox;
+mod ping;
+mod replication;
 mod shooter;
+mod shutdown;
+mod simulation;
 mod spawn;
@@ -1,17 +1,45 @@
 use anyhow::Result;
 use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
 
-use westiny_common::{network::PacketType, serialization::deserialize};
+use westiny_common::network::{self, ErrorKind, PacketType, BUILD_HASH, PROTOCOL_VERSION};
+use westiny_common::serialization::{
+    deserialize, serialize, DecodeError, WireFormat, CONNECTION_REQUEST_TAG,
+};
 
-use crate::resources::{ClientNetworkEvent, ClientRegistry, NetworkCommand};
-use bevy::prelude::{EventReader, EventWriter, ResMut};
-use blaminar::simulation::NetworkSimulationEvent;
+use crate::resources::{
+    Authenticator, ClientNetworkEvent, ClientRegistry, CodecConfig, CompressionConfig,
+    ConnectionAttempt, NetworkCommand, PacketGuard, Rejection, SecureChannels, SessionProof,
+    RECONNECT_GRACE_PERIOD,
+};
+use westiny_common::outbox::{LinkProbe, Outbox};
+use westiny_common::secure_channel::replace_received;
+use bevy::ecs::event::ManualEventReader;
+use bevy::prelude::{EventReader, EventWriter, Events, Local, Res, ResMut, Time};
+use blaminar::simulation::{
+    DeliveryRequirement, NetworkSimulationEvent, TransportResource, UrgencyRequirement,
+};
 
+/// A packet which is never sent by a client
+#[derive(Error, Debug)]
+#[error("Unexpected packet type from {0}")]
+struct UnexpectedPacket(SocketAddr);
+
+#[allow(clippy::too_many_arguments)]
 pub fn read_network_messages(
     mut client_registry: ResMut<ClientRegistry>,
+    mut authenticator: ResMut<Authenticator>,
+    mut packet_guard: ResMut<PacketGuard>,
+    mut secure_channels: ResMut<SecureChannels>,
+    codec_config: Res<CodecConfig>,
+    compression_config: Res<CompressionConfig>,
+    mut outbox: ResMut<Outbox>,
+    mut net: ResMut<TransportResource>,
     mut network_sim_ec: EventReader<NetworkSimulationEvent>,
     mut client_network_ec: EventWriter<ClientNetworkEvent>,
     mut network_command_ec: EventWriter<NetworkCommand>,
+    time: Res<Time>,
 ) {
     for event in network_sim_ec.iter() {
         match event {
@@ -20,21 +48,47 @@ pub fn read_network_messages(
                 addr
             ),
             NetworkSimulationEvent::Disconnect(addr) => {
-                if let Err(e) =
-                    disconnect_client(addr, &mut client_registry, &mut client_network_ec)
-                {
-                    log::error!("Error during disconnect_client: {}", e);
+                match client_registry.suspend(addr, time.elapsed()) {
+                    Ok(id) => {
+                        log::info!(
+                            "Connection of {:?} timed out, it may reconnect within {:?}",
+                            addr,
+                            RECONNECT_GRACE_PERIOD
+                        );
+                        network_command_ec.send(NetworkCommand::ConnectionLost { id });
+                    }
+                    // The client may have already left with a Disconnect packet
+                    Err(_) => {
+                        log::debug!("Connection of {:?} timed out, it has already left", addr)
+                    }
                 }
             }
             NetworkSimulationEvent::Message(addr, payload) => {
                 match process_payload(
                     addr,
                     payload,
+                    time.elapsed(),
                     &mut client_registry,
+                    &mut authenticator,
+                    &mut secure_channels,
+                    &codec_config,
+                    &compression_config,
+                    &mut outbox,
+                    &mut net,
                     &mut client_network_ec,
                     &mut network_command_ec,
                 ) {
                     Ok(_) => log::debug!("Message from {} processed successfully.", addr),
+                    Err(e) if e.is::<DecodeError>() || e.is::<UnexpectedPacket>() => {
+                        log::warn!("Malformed message from {}! {}", addr, e);
+                        record_malformed(
+                            addr,
+                            time.elapsed(),
+                            &mut packet_guard,
+                            &mut client_registry,
+                            &mut client_network_ec,
+                        );
+                    }
                     Err(e) => {
                         log::error!("Could not process message! {}, payload: {:?}", e, payload)
                     }
@@ -44,6 +98,97 @@ pub fn read_network_messages(
         }
     }
 }
+/// Opens the sealed datagrams of the clients, like `secure_channel::open_packets`, after the
+/// `PacketGuard` has admitted them. The ones which cannot be opened count as malformed.
+/// Must run after the transport has received them and before any system reading them.
+#[allow(clippy::too_many_arguments)]
+pub fn open_client_packets(
+    mut secure_channels: ResMut<SecureChannels>,
+    mut packet_guard: ResMut<PacketGuard>,
+    mut client_registry: ResMut<ClientRegistry>,
+    mut net: ResMut<TransportResource>,
+    mut events: ResMut<Events<NetworkSimulationEvent>>,
+    mut reader: Local<ManualEventReader<NetworkSimulationEvent>>,
+    mut client_network_ec: EventWriter<ClientNetworkEvent>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed();
+    replace_received(&mut events, &mut reader, |addr, datagram| {
+        if let Err(rejection) = packet_guard.admit(addr, datagram.len(), now) {
+            // Only the header is looked at, the datagram of a banned address is not opened
+            if rejection == Rejection::Banned
+                && datagram.first() == Some(&CONNECTION_REQUEST_TAG)
+                && packet_guard.notify_ban(&addr.ip(), now)
+            {
+                refuse_connection(addr, ErrorKind::Banned, &mut net);
+            }
+            log::debug!(
+                "Datagram of {} bytes from {} dropped: {:?}",
+                datagram.len(),
+                addr,
+                rejection
+            );
+            return None;
+        }
+        match secure_channels.open(addr, &datagram) {
+            Ok(Some(payload)) => Some(payload.into()),
+            Ok(None) => Some(datagram),
+            Err(err) => {
+                log::debug!("Datagram from {} dropped: {}", addr, err);
+                if !err.is_benign() {
+                    record_malformed(
+                        addr,
+                        now,
+                        &mut packet_guard,
+                        &mut client_registry,
+                        &mut client_network_ec,
+                    );
+                }
+                None
+            }
+        }
+    });
+}
+
+/// Forgets the secure sessions of the addresses which have not joined or have left
+pub fn expire_secure_sessions(
+    mut secure_channels: ResMut<SecureChannels>,
+    client_registry: Res<ClientRegistry>,
+    time: Res<Time>,
+) {
+    secure_channels.retain(time.elapsed(), |addr| client_registry.find_by_addr(addr).is_some());
+}
+
+/// Disconnects the clients which have not reconnected within the grace period
+pub fn expire_suspended_clients(
+    mut client_registry: ResMut<ClientRegistry>,
+    mut client_network_ec: EventWriter<ClientNetworkEvent>,
+    time: Res<Time>,
+) {
+    for handle in client_registry.expire_suspended(time.elapsed()) {
+        log::info!("{} has not reconnected, disconnecting", handle.player_name);
+        client_network_ec.send(ClientNetworkEvent::ClientDisconnected(
+            handle.id,
+            handle.player_name,
+        ));
+    }
+}
+
+/// Counts the malformed message, the client is disconnected if its address is banned for it
+fn record_malformed(
+    addr: &SocketAddr,
+    now: Duration,
+    packet_guard: &mut PacketGuard,
+    registry: &mut ClientRegistry,
+    client_event_channel: &mut EventWriter<ClientNetworkEvent>,
+) {
+    if packet_guard.record_malformed(addr, now) && registry.find_by_addr(addr).is_some() {
+        if let Err(err) = disconnect_client(addr, registry, client_event_channel) {
+            log::error!("Could not disconnect banned client: {}", err);
+        }
+    }
+}
+
 fn disconnect_client(
     addr: &SocketAddr,
     registry: &mut ClientRegistry,
@@ -59,35 +204,136 @@ fn disconnect_client(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_payload(
     addr: &SocketAddr,
     payload: &[u8],
+    now: Duration,
     registry: &mut ClientRegistry,
+    authenticator: &mut Authenticator,
+    secure_channels: &mut SecureChannels,
+    codec_config: &CodecConfig,
+    compression_config: &CompressionConfig,
+    outbox: &mut Outbox,
+    net: &mut TransportResource,
     client_net_event_channel: &mut EventWriter<ClientNetworkEvent>,
     command_channel: &mut EventWriter<NetworkCommand>,
 ) -> Result<()> {
     log::debug!("Message: {:02x?}", payload);
-    match deserialize(payload)? {
-        PacketType::ConnectionRequest { player_name } => {
-            log::debug!("Connection request received: {}, {}", addr, player_name);
-            // TODO response errors from registry
-            let client_id = registry.add(addr, player_name.as_str())?;
-            log::info!(
-                "Client from {} as player {} connection request accepted. ClientID={:?}",
+    let packet = match deserialize(payload) {
+        Err(DecodeError::VersionMismatch(protocol_version)) => {
+            log::warn!(
+                "Client from {} uses protocol version {}, server uses {}. Refusing connection.",
+                addr,
+                protocol_version,
+                PROTOCOL_VERSION
+            );
+            refuse_connection(
+                addr,
+                ErrorKind::VersionMismatch {
+                    server_version: PROTOCOL_VERSION,
+                },
+                net,
+            );
+            return Ok(());
+        }
+        result => result?,
+    };
+    match packet {
+        PacketType::ConnectionRequest {
+            player_name,
+            build_hash,
+            resume_session,
+            codec,
+            compression,
+            key_share,
+        } => {
+            log::debug!(
+                "Connection request received: {}, {}, build: {}, codec: {:?}, compression: {}",
                 addr,
                 player_name,
-                client_id
+                build_hash,
+                codec,
+                compression
             );
 
-            client_net_event_channel.send(ClientNetworkEvent::ClientConnected(client_id));
+            // The session of a client is not replaced by anyone sending from its address
+            if registry.find_by_addr(addr).is_some()
+                && secure_channels.client_share(addr) != Some(key_share)
+            {
+                log::warn!("{} is connected, its new key exchange is ignored", addr);
+                return Ok(());
+            }
+            if let Err(err) = secure_channels.accept(*addr, key_share, now) {
+                log::warn!("Key exchange with {} failed: {}", addr, err);
+                return Ok(());
+            }
+
+            if build_hash != BUILD_HASH {
+                log::warn!(
+                    "Client from {} is built from {}, server is built from {}",
+                    addr,
+                    build_hash,
+                    BUILD_HASH
+                );
+            }
+
+            let attempt = ConnectionAttempt {
+                player_name,
+                resume_session,
+                codec: codec_config.negotiate(codec),
+                compression: compression_config.negotiate(compression),
+            };
+            match authenticator.challenge(addr, attempt.clone(), now) {
+                Some(challenge) => {
+                    log::debug!("Authentication challenge sent to {}", addr);
+                    // Not through the `Outbox`, the address is not a registered client yet
+                    let message = serialize(&PacketType::AuthChallenge(challenge))
+                        .expect("AuthChallenge could not be serialized");
+                    net.send_with_requirements(
+                        *addr,
+                        &message,
+                        DeliveryRequirement::Reliable,
+                        UrgencyRequirement::OnTick,
+                    );
+                }
+                None => join(addr, attempt, None, registry, net, client_net_event_channel),
+            }
             Ok(())
         }
-        PacketType::InputState { input } => registry
+        PacketType::AuthResponse {
+            password_proof,
+            secret_proof,
+            session_proof,
+        } => {
+            // Answer to a repeated challenge, arriving after the client has joined
+            if registry.find_by_addr(addr).is_some() {
+                log::debug!("{} has already joined, authentication response ignored", addr);
+                return Ok(());
+            }
+            match authenticator.verify(addr, password_proof, secret_proof, session_proof, now) {
+                Some((attempt, session_proof)) => join(
+                    addr,
+                    attempt,
+                    session_proof,
+                    registry,
+                    net,
+                    client_net_event_channel,
+                ),
+                None => {
+                    log::warn!("Authentication of {} failed. Refusing connection.", addr);
+                    refuse_connection(addr, ErrorKind::AuthenticationFailed, net);
+                }
+            }
+            Ok(())
+        }
+        PacketType::InputState { inputs, view_time } => registry
             .find_by_addr(addr)
             .map(|handle| {
                 command_channel.send(NetworkCommand::Input {
                     id: handle.id,
-                    input,
+                    inputs,
+                    view_time,
                 })
             })
             .ok_or_else(|| {
@@ -96,14 +342,129 @@ fn process_payload(
                     addr
                 )
             }),
-        _ => Err(anyhow::anyhow!(
-            "Unexpected message from {}, payload={:02x?}",
+        PacketType::SnapshotAck { snapshot } => registry
+            .find_by_addr(addr)
+            .map(|handle| {
+                command_channel.send(NetworkCommand::SnapshotAck {
+                    id: handle.id,
+                    snapshot,
+                })
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Snapshot acknowledgement from unregistered client! Address: {:?}",
+                    addr
+                )
+            }),
+        PacketType::Ping { sequence } => {
+            // Only the clients are answered, others could use the server to flood someone
+            if registry.find_by_addr(addr).is_some() {
+                outbox.send(
+                    *addr,
+                    LinkProbe::Pong {
+                        sequence,
+                        time: now,
+                    },
+                );
+            }
+            Ok(())
+        }
+        PacketType::Pong { sequence, .. } => {
+            if let Some(handle) = registry.find_by_addr_mut(addr) {
+                handle.link.pong(sequence, now);
+            }
+            Ok(())
+        }
+        PacketType::Disconnect { reason } => {
+            log::info!("Client from {} left: {}", addr, reason);
+            disconnect_client(addr, registry, client_net_event_channel)
+        }
+        _ => Err(UnexpectedPacket(*addr).into()),
+    }
+}
+
+/// Admits the client, either to its session if it has proven to have the token of the session,
+/// or as a new player
+fn join(
+    addr: &SocketAddr,
+    attempt: ConnectionAttempt,
+    session_proof: Option<SessionProof>,
+    registry: &mut ClientRegistry,
+    net: &mut TransportResource,
+    client_net_event_channel: &mut EventWriter<ClientNetworkEvent>,
+) {
+    let ConnectionAttempt {
+        player_name,
+        codec,
+        compression,
+        ..
+    } = attempt;
+    let format = WireFormat { codec, compression };
+
+    let session_token = registry
+        .session_token_of(&player_name)
+        .filter(|&token| session_proof.map_or(false, |proof| proof.proves(token, &player_name)));
+    if let Some(client_id) =
+        session_token.and_then(|token| registry.reconnect(addr, player_name.as_str(), token))
+    {
+        log::info!(
+            "Client from {} as player {} reconnected to its session. ClientID={:?}",
             addr,
-            payload
-        )),
+            player_name,
+            client_id
+        );
+        use_wire_format(registry, addr, format);
+        client_net_event_channel.send(ClientNetworkEvent::ClientConnected(client_id));
+        return;
+    }
+
+    let client_id = match registry.add(addr, player_name.as_str()) {
+        Ok(client_id) => client_id,
+        Err(err) => {
+            log::warn!(
+                "Connection request of {} from {} refused: {}",
+                player_name,
+                addr,
+                err
+            );
+            refuse_connection(addr, err.error_kind(), net);
+            return;
+        }
+    };
+    log::info!(
+        "Client from {} as player {} connection request accepted. ClientID={:?}",
+        addr,
+        player_name,
+        client_id
+    );
+
+    use_wire_format(registry, addr, format);
+    client_net_event_channel.send(ClientNetworkEvent::ClientConnected(client_id));
+}
+
+/// The packets to the client are encoded in the negotiated format from the connection response
+fn use_wire_format(registry: &mut ClientRegistry, addr: &SocketAddr, format: WireFormat) {
+    if let Some(handle) = registry.find_by_addr_mut(addr) {
+        handle.codec = format.codec;
+        handle.compression = format.compression;
     }
 }
 
+/// Sent directly, not through the `Outbox`, as the refused address is not a registered client
+/// and has no negotiated wire format
+fn refuse_connection(addr: &SocketAddr, reason: ErrorKind, net: &mut TransportResource) {
+    let response = serialize(&PacketType::ConnectionResponse(Err(network::Error::new(
+        reason,
+    ))))
+    .expect("ConnectionResponse could not be serialized");
+    net.send_with_requirements(
+        *addr,
+        &response,
+        DeliveryRequirement::Reliable,
+        UrgencyRequirement::OnTick,
+    );
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -113,6 +474,11 @@ mod test {
     use bevy::prelude::*;
     use std::net::{IpAddr, SocketAddr};
     use w_bevy_test::{assertion, TestApp};
+    use westiny_common::auth::{prove, Credential};
+    use westiny_common::compression::Compression;
+    use westiny_common::network::{DisconnectReason, SessionToken};
+    use westiny_common::secure_channel::{ServerIdentity, ServerTrust, SEALED_TAG};
+    use westiny_common::serialization::PacketCodec;
     use westiny_common::PlayerName;
 
     fn make_socket_addr(ip: &str, port: u16) -> SocketAddr {
@@ -131,14 +497,29 @@ mod test {
         for (addr, name) in params.preloaded_clients {
             client_registry.add(&addr, &name).unwrap();
         }
+        make_testapp_with_registry(client_registry, params.send_event)
+    }
 
+    fn make_testapp_with_registry(
+        client_registry: ClientRegistry,
+        send_event: NetworkSimulationEvent,
+    ) -> App {
         let mut appl = App::new();
         appl.add_event::<ClientNetworkEvent>()
             .add_event::<NetworkCommand>()
             .insert_resource(client_registry)
+            .insert_resource(resources::Authenticator::new(Default::default()))
+            .insert_resource(resources::PacketGuard::new(Default::default()))
+            .insert_resource(CodecConfig::default())
+            .insert_resource(CompressionConfig::default())
+            .insert_resource(SecureChannels::server(ServerIdentity::generate()))
+            .insert_resource(TransportResource::new())
+            .init_resource::<Outbox>()
             .insert_resource(resources::NetworkIdSupplier::new())
-            .add_system(read_network_messages)
-            .send_events(vec![Some(params.send_event)]);
+            .init_resource::<Time>()
+            .add_system(open_client_packets.label("open_packets"))
+            .add_system(read_network_messages.after("open_packets"))
+            .send_events(vec![Some(send_event)]);
         appl
     }
 
@@ -159,7 +540,7 @@ mod test {
     }
 
     #[test]
-    fn test_disconnect_client_happy_path() {
+    fn test_timed_out_client_is_suspended() {
         let disconnecting_addr = make_socket_addr("127.0.0.1", 1111);
 
         let params = TestAppParams {
@@ -172,27 +553,196 @@ mod test {
         };
 
         make_testapp(params)
-            .add_assert_system(assertion::assert_event_count::<ClientNetworkEvent>(1))
-            // ClientDisconnected event sent
+            // The player is kept until the grace period is over
+            .add_assert_system(assertion::assert_event_count::<ClientNetworkEvent>(0))
+            .add_assert_system(assertion::assert_event(NetworkCommand::ConnectionLost {
+                id: ClientID(1),
+            }))
+            .add_assert_system(assert_client_in_registry(disconnecting_addr, false))
+            .run();
+    }
+
+    /// The authenticator has challenged the client resuming its session from the address,
+    /// the answer to the challenge is proven by the given token
+    fn session_response(
+        addr: SocketAddr,
+        session_token: SessionToken,
+    ) -> (resources::Authenticator, NetworkSimulationEvent) {
+        let mut authenticator = resources::Authenticator::new(Default::default());
+        let attempt = ConnectionAttempt {
+            player_name: "Westwood".to_string(),
+            resume_session: true,
+            codec: PacketCodec::default(),
+            compression: None,
+        };
+        let challenge = authenticator
+            .challenge(&addr, attempt, Duration::ZERO)
+            .unwrap();
+        let proof = prove(
+            Credential::SessionToken,
+            &session_token.to_string(),
+            &challenge.nonce,
+            "Westwood",
+        );
+        let payload = serialize(&PacketType::AuthResponse {
+            password_proof: None,
+            secret_proof: None,
+            session_proof: Some(proof),
+        })
+        .unwrap();
+        (
+            authenticator,
+            NetworkSimulationEvent::Message(addr, blaminar::Bytes::from(payload)),
+        )
+    }
+
+    #[test]
+    fn client_reconnects_to_its_session_from_new_address() {
+        let old_addr = make_socket_addr("127.0.0.1", 1111);
+        let new_addr = make_socket_addr("127.0.0.1", 2222);
+        let mut client_registry = ClientRegistry::new(1);
+        let id = client_registry.add(&old_addr, "Westwood").unwrap();
+        let session_token = client_registry.find_client(id).unwrap().session_token;
+        let (authenticator, response) = session_response(new_addr, session_token);
+
+        make_testapp_with_registry(client_registry, response)
+            .insert_resource(authenticator)
+            .add_assert_system(assertion::assert_event(
+                ClientNetworkEvent::ClientConnected(id),
+            ))
+            .add_assert_system(assert_client_in_registry(new_addr, true))
+            .add_assert_system(assert_client_in_registry(old_addr, false))
+            .run();
+    }
+
+    #[test]
+    fn session_is_not_resumed_without_proof_of_its_token() {
+        let old_addr = make_socket_addr("127.0.0.1", 1111);
+        let new_addr = make_socket_addr("127.0.0.1", 2222);
+        let mut client_registry = ClientRegistry::new(2);
+        let id = client_registry.add(&old_addr, "Westwood").unwrap();
+        let session_token = client_registry.find_client(id).unwrap().session_token;
+        let (authenticator, response) = session_response(new_addr, session_token.wrapping_add(1));
+
+        make_testapp_with_registry(client_registry, response)
+            .insert_resource(authenticator)
+            .add_assert_system(assertion::assert_event_count::<ClientNetworkEvent>(0))
+            .add_assert_system(assert_client_in_registry(old_addr, true))
+            .add_assert_system(assert_client_in_registry(new_addr, false))
+            .add_assert_system(assert_connection_refused(new_addr, ErrorKind::NameTaken))
+            .run();
+    }
+
+    #[test]
+    fn session_resume_is_challenged() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: make_connection_request(connecting_addr, true, PacketCodec::default()),
+        };
+        make_testapp(params)
+            .add_assert_system(assert_client_in_registry(connecting_addr, false))
+            .add_assert_system(move |net: Res<TransportResource>| {
+                let messages = net.get_messages();
+                assert_eq!(messages.len(), 1);
+                match deserialize(&messages[0].payload).expect("failed to deserialize") {
+                    PacketType::AuthChallenge(challenge) => {
+                        assert!(challenge.session_required);
+                        assert!(!challenge.password_required);
+                    }
+                    other => panic!("Unexpected message: {:?}", other),
+                }
+            })
+            .run();
+    }
+
+    #[test]
+    fn disconnect_packet_disconnects_client() {
+        let disconnecting_addr = make_socket_addr("127.0.0.1", 1111);
+        let payload = westiny_common::serialization::serialize(&PacketType::Disconnect {
+            reason: DisconnectReason::ClientQuit,
+        })
+        .unwrap();
+
+        let params = TestAppParams {
+            client_registry_capacity: 1,
+            preloaded_clients: vec![(disconnecting_addr, "Tuco".to_string())],
+            send_event: NetworkSimulationEvent::Message(
+                disconnecting_addr,
+                blaminar::Bytes::from(payload),
+            ),
+        };
+
+        make_testapp(params)
             .add_assert_system(assertion::assert_event(
                 ClientNetworkEvent::ClientDisconnected(
-                    ClientID(1),
-                    PlayerName("masik".to_string()),
+                    ClientID(0),
+                    PlayerName("Tuco".to_string()),
                 ),
             ))
-            // Disconnected client removed from registry
             .add_assert_system(assert_client_in_registry(disconnecting_addr, false))
             .run();
     }
 
+    #[test]
+    fn timeout_of_left_client_is_ignored() {
+        let params = TestAppParams {
+            client_registry_capacity: 1,
+            preloaded_clients: vec![],
+            send_event: NetworkSimulationEvent::Disconnect(make_socket_addr("127.0.0.1", 1111)),
+        };
+
+        make_testapp(params)
+            .add_assert_system(assertion::assert_event_count::<ClientNetworkEvent>(0))
+            .run();
+    }
+
     fn connection_request_event(requesting_addr: SocketAddr) -> NetworkSimulationEvent {
+        make_connection_request(requesting_addr, false, PacketCodec::default())
+    }
+
+    fn codec_connection_request_event(
+        requesting_addr: SocketAddr,
+        codec: PacketCodec,
+    ) -> NetworkSimulationEvent {
+        make_connection_request(requesting_addr, false, codec)
+    }
+
+    fn make_connection_request(
+        requesting_addr: SocketAddr,
+        resume_session: bool,
+        codec: PacketCodec,
+    ) -> NetworkSimulationEvent {
         let payload = westiny_common::serialization::serialize(&PacketType::ConnectionRequest {
             player_name: "Westwood".to_string(),
+            build_hash: BUILD_HASH.to_string(),
+            resume_session,
+            codec,
+            compression: true,
+            key_share: [9; 32],
         })
         .unwrap();
         NetworkSimulationEvent::Message(requesting_addr, blaminar::Bytes::from(payload))
     }
 
+    fn assert_connection_refused(
+        client_addr: SocketAddr,
+        expected_reason: ErrorKind,
+    ) -> SystemDescriptor {
+        (move |net: Res<TransportResource>| {
+            let messages = net.get_messages();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].destination, client_addr);
+            match deserialize(&messages[0].payload).expect("failed to deserialize") {
+                PacketType::ConnectionResponse(Err(err)) => assert_eq!(err.kind(), expected_reason),
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        })
+        .into_descriptor()
+    }
+
     #[test]
     fn connection_request_client_registered() {
         let connecting_addr = make_socket_addr("0.1.2.3", 1234);
@@ -212,6 +762,68 @@ mod test {
             .run();
     }
 
+    #[test]
+    fn connection_request_negotiates_accepted_codec() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: codec_connection_request_event(connecting_addr, PacketCodec::Json),
+        };
+        make_testapp(params)
+            .add_assert_system(move |registry: Res<ClientRegistry>| {
+                assert_eq!(registry.codec_of(&connecting_addr), PacketCodec::Json);
+            })
+            .run();
+    }
+
+    #[test]
+    fn connection_request_negotiates_compression() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: connection_request_event(connecting_addr),
+        };
+        make_testapp(params)
+            .insert_resource(CompressionConfig {
+                enabled: true,
+                threshold: 100,
+            })
+            .add_assert_system(move |registry: Res<ClientRegistry>| {
+                assert_eq!(
+                    registry.wire_format_of(&connecting_addr).compression,
+                    Some(Compression { threshold: 100 })
+                );
+            })
+            .run();
+    }
+
+    #[test]
+    fn connection_request_falls_back_to_default_codec() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: codec_connection_request_event(connecting_addr, PacketCodec::Json),
+        };
+        make_testapp(params)
+            .insert_resource(CodecConfig {
+                accepted: vec![PacketCodec::MessagePack, PacketCodec::Binary],
+            })
+            .add_assert_system(move |registry: Res<ClientRegistry>| {
+                assert!(registry.find_by_addr(&connecting_addr).is_some());
+                assert_eq!(
+                    registry.codec_of(&connecting_addr),
+                    PacketCodec::MessagePack
+                );
+            })
+            .run();
+    }
+
     #[test]
     fn connection_request_registry_full() {
         let connecting_addr = make_socket_addr("0.1.2.3", 1234);
@@ -223,6 +835,62 @@ mod test {
         };
         make_testapp(params)
             .add_assert_system(assert_client_in_registry(connecting_addr, false))
+            .add_assert_system(assert_connection_refused(
+                connecting_addr,
+                ErrorKind::ServerFull,
+            ))
+            .run();
+    }
+
+    #[test]
+    fn connection_request_name_taken() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![(
+                make_socket_addr("192.168.0.15", 3333),
+                "Westwood".to_string(),
+            )],
+            send_event: connection_request_event(connecting_addr),
+        };
+        make_testapp(params)
+            .add_assert_system(assert_client_in_registry(connecting_addr, false))
+            .add_assert_system(assertion::assert_event_count::<ClientNetworkEvent>(0))
+            .add_assert_system(assert_connection_refused(
+                connecting_addr,
+                ErrorKind::NameTaken,
+            ))
+            .run();
+    }
+
+    #[test]
+    fn connection_request_protocol_version_mismatch() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+        // A request of another version, whose fields are not the ones of this version
+        let mut payload = vec![CONNECTION_REQUEST_TAG];
+        payload.extend(PROTOCOL_VERSION.wrapping_add(1).to_le_bytes());
+        payload.extend(
+            serialize(&PacketType::SnapshotAck { snapshot: 1 }).expect("failed to serialize"),
+        );
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: NetworkSimulationEvent::Message(
+                connecting_addr,
+                blaminar::Bytes::from(payload),
+            ),
+        };
+        make_testapp(params)
+            .add_assert_system(assert_client_in_registry(connecting_addr, false))
+            .add_assert_system(|guard: Res<PacketGuard>| assert_eq!(guard.stats().malformed, 0))
+            .add_assert_system(assert_connection_refused(
+                connecting_addr,
+                ErrorKind::VersionMismatch {
+                    server_version: PROTOCOL_VERSION,
+                },
+            ))
             .run();
     }
 
@@ -239,14 +907,193 @@ mod test {
         make_testapp(params)
             .add_assert_system(|reg: Res<ClientRegistry>| assert_eq!(reg.client_count(), 1))
             .add_assert_system(assert_client_in_registry(connecting_addr, true))
+            // Anyone could send from the address of a client
+            .add_assert_system(move |channels: Res<SecureChannels>| {
+                assert!(!channels.has_session(&connecting_addr))
+            })
+            .run();
+    }
+
+    #[test]
+    fn connection_request_makes_secure_session() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: connection_request_event(connecting_addr),
+        };
+        make_testapp(params)
+            .add_assert_system(move |channels: Res<SecureChannels>| {
+                assert_eq!(channels.client_share(&connecting_addr), Some([9; 32]));
+            })
+            .run();
+    }
+
+    #[test]
+    fn connection_request_challenged_by_password_server() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: connection_request_event(connecting_addr),
+        };
+        make_testapp(params)
+            .insert_resource(resources::Authenticator::new(resources::AuthConfig {
+                password: Some("sesame".to_string()),
+                ..Default::default()
+            }))
+            .add_assert_system(assert_client_in_registry(connecting_addr, false))
+            .add_assert_system(assertion::assert_event_count::<ClientNetworkEvent>(0))
+            .add_assert_system(move |net: Res<TransportResource>| {
+                let messages = net.get_messages();
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].destination, connecting_addr);
+                match deserialize(&messages[0].payload).expect("failed to deserialize") {
+                    PacketType::AuthChallenge(challenge) => {
+                        assert!(challenge.password_required);
+                        assert!(!challenge.secret_required);
+                    }
+                    other => panic!("Unexpected message: {:?}", other),
+                }
+            })
+            .run();
+    }
+
+    #[test]
+    fn unexpected_auth_response_refused() {
+        let connecting_addr = make_socket_addr("0.1.2.3", 1234);
+        let payload = westiny_common::serialization::serialize(&PacketType::AuthResponse {
+            password_proof: Some([0; 32]),
+            secret_proof: None,
+            session_proof: None,
+        })
+        .unwrap();
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: NetworkSimulationEvent::Message(
+                connecting_addr,
+                blaminar::Bytes::from(payload),
+            ),
+        };
+        make_testapp(params)
+            .add_assert_system(assert_client_in_registry(connecting_addr, false))
+            .add_assert_system(assert_connection_refused(
+                connecting_addr,
+                ErrorKind::AuthenticationFailed,
+            ))
+            .run();
+    }
+
+    #[test]
+    fn oversized_message_dropped_before_decoding() {
+        let addr = make_socket_addr("0.1.2.3", 1111);
+        let params = TestAppParams {
+            client_registry_capacity: 1,
+            preloaded_clients: vec![(addr, "Bacsi".to_string())],
+            send_event: NetworkSimulationEvent::Message(
+                addr,
+                blaminar::Bytes::from(vec![0xc1; 1025]),
+            ),
+        };
+
+        make_testapp(params)
+            .add_assert_system(|guard: Res<PacketGuard>| {
+                assert_eq!(guard.stats().oversized, 1);
+                assert_eq!(guard.stats().malformed, 0);
+            })
+            .run();
+    }
+
+    #[test]
+    fn repeated_malformed_messages_disconnect_and_ban_client() {
+        let addr = make_socket_addr("0.1.2.3", 1111);
+        let params = TestAppParams {
+            client_registry_capacity: 1,
+            preloaded_clients: vec![(addr, "Bacsi".to_string())],
+            send_event: NetworkSimulationEvent::Message(addr, blaminar::Bytes::from(vec![0xc1])),
+        };
+
+        make_testapp(params)
+            .insert_resource(resources::PacketGuard::new(resources::PacketGuardConfig {
+                max_malformed: 1,
+                ..Default::default()
+            }))
+            .add_assert_system(assertion::assert_event(
+                ClientNetworkEvent::ClientDisconnected(
+                    ClientID(0),
+                    PlayerName("Bacsi".to_string()),
+                ),
+            ))
+            .add_assert_system(assert_client_in_registry(addr, false))
+            .add_assert_system(|guard: Res<PacketGuard>| {
+                assert_eq!(guard.stats().malformed, 1);
+                assert_eq!(guard.active_bans(), 1);
+            })
+            .run();
+    }
+
+    #[test]
+    fn datagram_which_cannot_be_opened_is_malformed() {
+        let addr = make_socket_addr("0.1.2.3", 1111);
+        let mut secure_channels = SecureChannels::server(ServerIdentity::generate());
+        let share = SecureChannels::client(ServerTrust::Any).key_share(addr);
+        secure_channels.accept(addr, share, Duration::ZERO).unwrap();
+
+        let params = TestAppParams {
+            client_registry_capacity: 1,
+            preloaded_clients: vec![(addr, "Bacsi".to_string())],
+            send_event: NetworkSimulationEvent::Message(
+                addr,
+                blaminar::Bytes::from(vec![SEALED_TAG; 40]),
+            ),
+        };
+
+        make_testapp(params)
+            .insert_resource(secure_channels)
+            .add_assert_system(|guard: Res<PacketGuard>| {
+                assert_eq!(guard.stats().malformed, 1);
+            })
+            .run();
+    }
+
+    #[test]
+    fn connection_request_from_banned_address_is_refused() {
+        let banned_addr = make_socket_addr("0.1.2.3", 1111);
+        let connecting_addr = make_socket_addr("0.1.2.3", 2222);
+        let mut guard = resources::PacketGuard::new(resources::PacketGuardConfig {
+            max_malformed: 1,
+            ..Default::default()
+        });
+        guard.admit(&banned_addr, 1, Duration::ZERO).unwrap();
+        assert!(guard.record_malformed(&banned_addr, Duration::ZERO));
+
+        let params = TestAppParams {
+            client_registry_capacity: 2,
+            preloaded_clients: vec![],
+            send_event: connection_request_event(connecting_addr),
+        };
+
+        make_testapp(params)
+            .insert_resource(guard)
+            .add_assert_system(assert_connection_refused(connecting_addr, ErrorKind::Banned))
+            .add_assert_system(assert_client_in_registry(connecting_addr, false))
             .run();
     }
 
     use crate::components::{Input, InputFlags};
+    use std::time::Duration;
+    use westiny_common::network::SequencedInput;
 
     fn network_input(input: Input) -> blaminar::Bytes {
-        let payload =
-            westiny_common::serialization::serialize(&PacketType::InputState { input }).unwrap();
+        let payload = westiny_common::serialization::serialize(&PacketType::InputState {
+            inputs: vec![SequencedInput { sequence: 7, input }],
+            view_time: Some(Duration::from_millis(1500)),
+        })
+        .unwrap();
         blaminar::Bytes::from(payload)
     }
 
@@ -267,7 +1114,8 @@ mod test {
             .add_assert_system(assertion::assert_event_count::<NetworkCommand>(1))
             .add_assert_system(assertion::assert_event(NetworkCommand::Input {
                 id: ClientID(0),
-                input,
+                inputs: vec![SequencedInput { sequence: 7, input }],
+                view_time: Some(Duration::from_millis(1500)),
             }))
             .run();
     }
@@ -289,4 +1137,25 @@ mod test {
             .add_assert_system(assertion::assert_event_count::<NetworkCommand>(0))
             .run();
     }
+
+    #[test]
+    fn snapshot_acks_forwarded() {
+        let addr = make_socket_addr("0.1.2.3", 1111);
+        let payload =
+            westiny_common::serialization::serialize(&PacketType::SnapshotAck { snapshot: 42 })
+                .unwrap();
+        let params = TestAppParams {
+            client_registry_capacity: 1,
+            preloaded_clients: vec![(addr.clone(), "Bacsi".to_string())],
+            send_event: NetworkSimulationEvent::Message(addr, blaminar::Bytes::from(payload)),
+        };
+
+        make_testapp(params)
+            .add_assert_system(assertion::assert_event_count::<NetworkCommand>(1))
+            .add_assert_system(assertion::assert_event(NetworkCommand::SnapshotAck {
+                id: ClientID(0),
+                snapshot: 42,
+            }))
+            .run();
+    }
 }
@@ -0,0 +1,14 @@
+use crate::resources::{ClientRegistry, Compressor, Outbox};
+use bevy::prelude::{Res, ResMut};
+use blaminar::simulation::TransportResource;
+
+/// Sends the messages of the `Outbox` in the wire format of each client.
+/// Must run after every system sending with the `Outbox`.
+pub fn flush_outbox(
+    mut outbox: ResMut<Outbox>,
+    client_registry: Res<ClientRegistry>,
+    mut compressor: ResMut<Compressor>,
+    mut net: ResMut<TransportResource>,
+) {
+    outbox.flush(&mut net, &mut compressor, |addr| client_registry.wire_format_of(addr));
+}
@@ -0,0 +1,47 @@
+use crate::resources::ClientRegistry;
+use bevy::prelude::{Res, ResMut, Time};
+use westiny_common::outbox::{LinkProbe, Outbox};
+
+/// Pings every client at `PING_INTERVAL`, the answers are processed with the other messages.
+/// The `ClientOutbox` can not be used, as the registry is changed by the pings.
+pub fn ping_clients(
+    mut client_registry: ResMut<ClientRegistry>,
+    mut outbox: ResMut<Outbox>,
+    time: Res<Time>,
+) {
+    for handle in client_registry.clients_mut() {
+        if let Some(sequence) = handle.link.next_ping(time.elapsed()) {
+            outbox.send(handle.addr, LinkProbe::Ping { sequence });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bevy::prelude::App;
+    use std::net::SocketAddr;
+    use w_bevy_test::TestApp;
+    use westiny_common::network::PacketType;
+
+    #[test]
+    fn clients_are_pinged() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        let mut client_registry = ClientRegistry::new(1);
+        client_registry.add(&addr, "Tuco").unwrap();
+
+        App::new()
+            .insert_resource(client_registry)
+            .init_resource::<Outbox>()
+            .init_resource::<Time>()
+            .add_system(ping_clients)
+            .add_assert_system(move |outbox: Res<Outbox>| {
+                assert_eq!(outbox.queued().len(), 1);
+                assert_eq!(
+                    outbox.queued_for(addr).collect::<Vec<_>>(),
+                    vec![&PacketType::Ping { sequence: 0 }]
+                );
+            })
+            .run();
+    }
+}
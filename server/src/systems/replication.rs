@@ -0,0 +1,302 @@
+use crate::components::{Client, MapPosition, NetworkId};
+use crate::resources::{ClientID, ClientNetworkEvent, ClientOutbox, InterestManager, SimulationTime};
+use bevy::prelude::{EventReader, Query, Res, ResMut, Transform, Without};
+use std::collections::HashMap;
+use westiny_common::metric_dimension::length::MeterVec2;
+use westiny_common::network::{ComponentUpdate, ComponentValue, Tick};
+use westiny_common::replication::{ReplicatedComponents, ReplicationBuffer};
+
+/// Values in a single update, as the map entities alone would not fit in one packet
+const MAX_VALUES_PER_UPDATE: usize = 128;
+
+/// The replicated entities moving around, the map entities are not among them
+type Movables<'w, 's> = Query<
+    'w,
+    's,
+    (&'static NetworkId, &'static Transform, Option<&'static Client>),
+    Without<MapPosition>,
+>;
+
+/// Where a movable entity is and which client plays it, to tell who is interested in it
+#[derive(Copy, Clone, Debug)]
+struct Locality {
+    position: MeterVec2,
+    owner: Option<ClientID>,
+}
+
+impl Locality {
+    /// The client of the player is always interested in it, even before it has a center
+    fn is_relevant(&self, interest: &InterestManager, client_id: ClientID) -> bool {
+        self.owner == Some(client_id) || interest.is_relevant(client_id, &self.position)
+    }
+}
+
+fn localities(movables: &Movables) -> HashMap<NetworkId, Locality> {
+    movables
+        .iter()
+        .map(|(network_id, transform, client)| {
+            let locality = Locality {
+                position: MeterVec2::from_pixel_vec(transform.translation.truncate()),
+                owner: client.map(|client| client.id),
+            };
+            (*network_id, locality)
+        })
+        .collect()
+}
+
+/// Sends the replicated components collected in this tick. The components of the map entities
+/// are sent to every client, the ones of a movable entity only to the clients having it in their
+/// interest radius, like its state in the snapshots. An entity coming into the radius later
+/// gets its values with their next change.
+pub fn send_component_updates(
+    mut buffer: ResMut<ReplicationBuffer>,
+    simulation_time: Res<SimulationTime>,
+    interest: Res<InterestManager>,
+    movables: Movables,
+    mut outbox: ClientOutbox,
+) {
+    let localities = localities(&movables);
+    for (delivery, components) in buffer.take_changed() {
+        let (local, global): (Vec<_>, Vec<_>) = components
+            .into_iter()
+            .partition(|value| localities.contains_key(&value.network_id));
+
+        for update in split_into_updates(simulation_time.tick(), global) {
+            outbox.broadcast(ReplicatedComponents { delivery, update });
+        }
+
+        let mut by_entity: HashMap<NetworkId, Vec<ComponentValue>> = HashMap::new();
+        for value in local {
+            by_entity.entry(value.network_id).or_default().push(value);
+        }
+        for (network_id, values) in by_entity {
+            let locality = localities[&network_id];
+            for update in split_into_updates(simulation_time.tick(), values) {
+                outbox.send_where(
+                    |handle| locality.is_relevant(&interest, handle.id),
+                    ReplicatedComponents { delivery, update },
+                );
+            }
+        }
+    }
+}
+
+/// Sends the latest value of every replicated component the client is interested in to the
+/// connected (or reconnected) clients, as they have missed the earlier updates.
+/// Each value is sent with the delivery of its component, on the same stream as its updates,
+/// so a late introduction never overwrites a newer value.
+pub fn introduce_replicated_components(
+    mut client_network_ec: EventReader<ClientNetworkEvent>,
+    buffer: Res<ReplicationBuffer>,
+    simulation_time: Res<SimulationTime>,
+    interest: Res<InterestManager>,
+    movables: Movables,
+    mut outbox: ClientOutbox,
+) {
+    let mut connected = client_network_ec
+        .iter()
+        .filter_map(|client_network_event| match client_network_event {
+            ClientNetworkEvent::ClientConnected(client_id) => Some(*client_id),
+            _ => None,
+        })
+        .peekable();
+    if connected.peek().is_none() {
+        return;
+    }
+
+    let localities = localities(&movables);
+    let current = buffer.current();
+    'clients: for client_id in connected {
+        for (delivery, components) in &current {
+            let components = components
+                .iter()
+                .filter(|value| {
+                    localities
+                        .get(&value.network_id)
+                        .map_or(true, |locality| locality.is_relevant(&interest, client_id))
+                })
+                .cloned()
+                .collect();
+            for update in split_into_updates(simulation_time.tick(), components) {
+                let message = ReplicatedComponents {
+                    delivery: *delivery,
+                    update,
+                };
+                if let Err(err) = outbox.send(client_id, message) {
+                    log::error!("Error while sending replicated components to client: {}", err);
+                    continue 'clients;
+                }
+            }
+        }
+    }
+}
+
+fn split_into_updates(tick: Tick, components: Vec<ComponentValue>) -> Vec<ComponentUpdate> {
+    components
+        .chunks(MAX_VALUES_PER_UPDATE)
+        .map(|chunk| ComponentUpdate {
+            tick,
+            components: chunk.to_vec(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::resources::{ClientRegistry, InterestRadius, Outbox};
+    use bevy::prelude::App;
+    use blaminar::simulation::DeliveryRequirement;
+    use std::net::SocketAddr;
+    use w_bevy_test::TestApp;
+    use westiny_common::components::{EntityType, Health};
+    use westiny_common::metric_dimension::length::Meter;
+    use westiny_common::network::PacketType;
+    use westiny_common::replication::ReplicationDelivery;
+
+    fn health(id: u32, hp: u16) -> ComponentValue {
+        ComponentValue::encode(NetworkId::new(EntityType::Player, id), 0, &Health(hp)).unwrap()
+    }
+
+    #[test]
+    fn new_client_gets_current_components() {
+        let addrs = [
+            SocketAddr::from(([127, 0, 0, 1], 1111)),
+            SocketAddr::from(([127, 0, 0, 1], 2222)),
+        ];
+        let mut client_registry = ClientRegistry::new(2);
+        client_registry.add(&addrs[0], "Tuco").unwrap();
+        let new_client = client_registry.add(&addrs[1], "Blondie").unwrap();
+        let mut buffer = ReplicationBuffer::default();
+        buffer.push(ReplicationDelivery::Reliable, health(1, 100));
+        buffer.push(ReplicationDelivery::Reliable, health(1, 42));
+
+        App::new()
+            .insert_resource(client_registry)
+            .insert_resource(buffer)
+            .init_resource::<SimulationTime>()
+            .init_resource::<InterestManager>()
+            .init_resource::<Outbox>()
+            .add_event::<ClientNetworkEvent>()
+            .send_events(vec![Some(ClientNetworkEvent::ClientConnected(new_client))])
+            .add_system(introduce_replicated_components)
+            .add_assert_system(move |outbox: Res<Outbox>| {
+                assert_eq!(outbox.queued_for(addrs[0]).count(), 0);
+                let packets: Vec<_> = outbox.queued_for(addrs[1]).collect();
+                assert_eq!(packets.len(), 1);
+                match packets[0] {
+                    PacketType::ComponentUpdate(update) => {
+                        assert_eq!(update.components, vec![health(1, 42)])
+                    }
+                    other => panic!("Unexpected packet: {:?}", other),
+                }
+            })
+            .run();
+    }
+
+    #[test]
+    fn introduction_is_sent_with_registered_delivery() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1111));
+        let mut client_registry = ClientRegistry::new(1);
+        let client = client_registry.add(&addr, "Tuco").unwrap();
+        let mut buffer = ReplicationBuffer::default();
+        buffer.push(ReplicationDelivery::Reliable, health(1, 42));
+        buffer.push(ReplicationDelivery::Unreliable, health(2, 7));
+
+        App::new()
+            .insert_resource(client_registry)
+            .insert_resource(buffer)
+            .init_resource::<SimulationTime>()
+            .init_resource::<InterestManager>()
+            .init_resource::<Outbox>()
+            .add_event::<ClientNetworkEvent>()
+            .send_events(vec![Some(ClientNetworkEvent::ClientConnected(client))])
+            .add_system(introduce_replicated_components)
+            .add_assert_system(|outbox: Res<Outbox>| {
+                let mut deliveries: Vec<_> = outbox
+                    .queued()
+                    .iter()
+                    .map(|message| match &message.packet {
+                        PacketType::ComponentUpdate(update) => {
+                            (update.components[0].network_id.id, message.delivery)
+                        }
+                        other => panic!("Unexpected packet: {:?}", other),
+                    })
+                    .collect();
+                deliveries.sort_by_key(|(id, _)| *id);
+                assert!(matches!(
+                    deliveries[..],
+                    [
+                        (1, DeliveryRequirement::ReliableOrdered(_)),
+                        (2, DeliveryRequirement::UnreliableSequenced(_))
+                    ]
+                ));
+            })
+            .run();
+    }
+
+    #[test]
+    fn components_of_distant_player_are_not_sent() {
+        let addrs = [
+            SocketAddr::from(([127, 0, 0, 1], 1111)),
+            SocketAddr::from(([127, 0, 0, 1], 2222)),
+        ];
+        let mut client_registry = ClientRegistry::new(2);
+        let near = client_registry.add(&addrs[0], "Tuco").unwrap();
+        let far = client_registry.add(&addrs[1], "Blondie").unwrap();
+        let mut interest = InterestManager::new(InterestRadius(Meter(10.0)));
+        interest.update_center(near, MeterVec2::from_raw(0.0, 0.0));
+        interest.update_center(far, MeterVec2::from_raw(100.0, 0.0));
+        let player = NetworkId::new(EntityType::Player, 1);
+        let barrel = NetworkId::new(EntityType::Barrel, 2);
+        let mut buffer = ReplicationBuffer::default();
+        buffer.push(ReplicationDelivery::Reliable, health(1, 42));
+        let barrel_position = MapPosition(MeterVec2::from_raw(0.0, 0.0));
+        buffer.push(
+            ReplicationDelivery::Reliable,
+            ComponentValue::encode(barrel, 1, &barrel_position).unwrap(),
+        );
+
+        let mut app = App::new();
+        app.world.spawn((player, Transform::default()));
+        app.world.spawn((barrel, barrel_position, Transform::default()));
+        app.insert_resource(client_registry)
+            .insert_resource(interest)
+            .insert_resource(buffer)
+            .init_resource::<SimulationTime>()
+            .init_resource::<Outbox>()
+            .add_system(send_component_updates)
+            .add_assert_system(move |outbox: Res<Outbox>| {
+                let components = |addr| -> Vec<_> {
+                    outbox
+                        .queued_for(addr)
+                        .flat_map(|packet| match packet {
+                            PacketType::ComponentUpdate(update) => update.components.clone(),
+                            other => panic!("Unexpected packet: {:?}", other),
+                        })
+                        .map(|value| value.network_id)
+                        .collect()
+                };
+                // The map entities are sent to everyone
+                assert_eq!(components(addrs[0]), vec![barrel, player]);
+                assert_eq!(components(addrs[1]), vec![barrel]);
+            })
+            .run();
+    }
+
+    #[test]
+    fn many_components_are_split_into_updates() {
+        let values: Vec<_> = (0..300).map(|id| health(id, 100)).collect();
+        let updates = split_into_updates(7, values.clone());
+        assert_eq!(updates.len(), 3);
+        assert!(updates.iter().all(|update| update.tick == 7));
+        assert!(updates
+            .iter()
+            .all(|update| update.components.len() <= MAX_VALUES_PER_UPDATE));
+        let split: Vec<_> = updates
+            .into_iter()
+            .flat_map(|update| update.components)
+            .collect();
+        assert_eq!(split, values);
+    }
+}
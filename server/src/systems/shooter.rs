@@ -1,14 +1,14 @@
 use crate::components::{
-    weapon::Holster, weapon::Weapon, BoundingCircle, Client, Input, InputFlags, Damage
+    weapon::Holster, weapon::Weapon, BoundingCircle, Client, Damage, Input, InputFlags,
+    LagCompensation, ViewTime,
 };
-use crate::resources::{ClientID, ClientRegistry, StreamId};
-use bevy::prelude::{Commands, Query, Res, ResMut, SystemSet, Time, Transform, Vec3};
-use blaminar::simulation::{DeliveryRequirement, TransportResource, UrgencyRequirement};
+use crate::resources::{ClientOutbox, GameRng, InterestManager, SimulationTime};
+use bevy::prelude::{Commands, Entity, Query, Res, ResMut, SystemSet, Time, Transform, Vec3};
 use std::f32::consts::PI;
 use westiny_common::entities::BulletBundle;
 use westiny_common::metric_dimension::{length::MeterVec2, MeterPerSecVec2};
-use westiny_common::network::{PacketType, PlayerUpdate, ShotEvent};
-use westiny_common::serialization::serialize;
+use westiny_common::network::{PlayerUpdate, ShotEvent, Tick};
+use westiny_common::resources::collision::LagCompensationConfig;
 
 pub fn weapon_handler_system_set() -> SystemSet {
     SystemSet::new()
@@ -23,8 +23,7 @@ mod weapon_switcher {
 
     pub fn switch_weapon(
         time: Res<Time>,
-        client_registry: Res<ClientRegistry>,
-        mut net: ResMut<TransportResource>,
+        mut outbox: ClientOutbox,
         mut input_query: Query<(&Input, &mut Holster, Option<&Client>)>,
     ) {
         for (input, mut holster, maybe_client) in input_query.iter_mut() {
@@ -40,27 +39,19 @@ mod weapon_switcher {
                         gun.reload_started_at = Some(time.elapsed());
                     }
 
-                    if let Some(client) =
-                        maybe_client.and_then(|client| client_registry.find_client(client.id))
-                    {
-                        let payload_packet = PacketType::PlayerUpdate(PlayerUpdate::WeaponSwitch {
+                    if let Some(client) = maybe_client {
+                        let weapon_switch = PlayerUpdate::WeaponSwitch {
                             name: gun_name.to_string(),
                             magazine_size: gun.details.magazine_size,
                             ammo_in_magazine: gun.bullets_left_in_magazine,
-                        });
-
-                        let payload = serialize(&payload_packet).unwrap_or_else(|_| {
-                            panic!(
-                                "Failed to serialize 'WeaponSwitch' packet: {:?}",
-                                payload_packet
-                            )
-                        });
-                        net.send_with_requirements(
-                            client.addr,
-                            &payload,
-                            DeliveryRequirement::ReliableSequenced(StreamId::WeaponSwitch.into()),
-                            UrgencyRequirement::OnTick,
-                        );
+                        };
+                        if let Err(err) = outbox.send(client.id, weapon_switch) {
+                            log::error!(
+                                "Failed to send WeaponSwitch to client {:?}. Error: {}",
+                                client.id,
+                                err
+                            );
+                        }
                     }
                 }
             }
@@ -77,36 +68,12 @@ mod weapon_switcher {
     }
 }
 
-fn send_ammo_update(
-    client_id: &ClientID,
-    client_registry: &ClientRegistry,
-    ammo_in_magazine: u32,
-    net: &mut TransportResource,
-) -> anyhow::Result<()> {
-    let payload = serialize(&PacketType::PlayerUpdate(PlayerUpdate::AmmoUpdate {
-        ammo_in_magazine,
-    }))
-    .map_err(|err| anyhow::anyhow!("Failed to serialize AmmoUpdate: {}", err))?;
-    let address = client_registry
-        .find_client(*client_id)
-        .map(|handle| handle.addr)
-        .ok_or_else(|| anyhow::anyhow!("Client with id {:?} not found in registry", client_id))?;
-    net.send_with_requirements(
-        address,
-        &payload,
-        DeliveryRequirement::ReliableSequenced(StreamId::AmmoUpdate.into()),
-        UrgencyRequirement::OnTick,
-    );
-    Ok(())
-}
-
 mod reloader {
     use super::*;
 
     pub fn reload(
         time: Res<Time>,
-        client_registry: Res<ClientRegistry>,
-        mut net: ResMut<TransportResource>,
+        mut outbox: ClientOutbox,
         mut input_query: Query<(&Input, &mut Holster, Option<&Client>)>,
     ) {
         for (&input, mut holster, maybe_client) in input_query.iter_mut() {
@@ -114,39 +81,27 @@ mod reloader {
             if input.flags.intersects(InputFlags::RELOAD) && weapon.is_allowed_to_reload() {
                 weapon.reload_started_at = Some(time.elapsed())
             } else if let Some(reload_start) = weapon.reload_started_at {
-                check_reload_finish(
-                    &time,
-                    &client_registry,
-                    &mut net,
-                    weapon,
-                    maybe_client,
-                    &reload_start,
-                )
+                check_reload_finish(&time, &mut outbox, weapon, maybe_client, &reload_start)
             }
         }
     }
 
     fn check_reload_finish(
         time: &Time,
-        client_registry: &ClientRegistry,
-        net: &mut TransportResource,
+        outbox: &mut ClientOutbox,
         weapon: &mut Weapon,
         client: Option<&Client>,
         reload_start: &std::time::Duration,
     ) {
-        if time.elapsed_seconds()
-            >= reload_start.as_secs_f32() + weapon.details.reload_time.0
-        {
+        if time.elapsed_seconds() >= reload_start.as_secs_f32() + weapon.details.reload_time.0 {
             weapon.bullets_left_in_magazine = weapon.details.magazine_size;
             weapon.reload_started_at = None;
 
             if let Some(client) = client {
-                if let Err(err) = send_ammo_update(
-                    &client.id,
-                    client_registry,
-                    weapon.bullets_left_in_magazine,
-                    net,
-                ) {
+                let ammo_update = PlayerUpdate::AmmoUpdate {
+                    ammo_in_magazine: weapon.bullets_left_in_magazine,
+                };
+                if let Err(err) = outbox.send(client.id, ammo_update) {
                     log::error!(
                         "Failed to send AmmoUpdate to client {:?}. Error: {}",
                         client.id,
@@ -158,21 +113,46 @@ mod reloader {
     }
 }
 
-#[allow(clippy::type_complexity)]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 pub fn shoot(
     mut commands: Commands,
     time: Res<Time>,
-    client_registry: Res<ClientRegistry>,
-    mut net: ResMut<TransportResource>,
+    simulation_time: Res<SimulationTime>,
+    interest: Res<InterestManager>,
+    lag_compensation_config: Res<LagCompensationConfig>,
+    mut rng: ResMut<GameRng>,
+    mut outbox: ClientOutbox,
     mut query: Query<(
+        Entity,
         &Input,
         &Transform,
         Option<&BoundingCircle>,
         &mut Holster,
         Option<&Client>,
+        Option<&ViewTime>,
     )>,
 ) {
-    for (input, shooter_transform, maybe_bound, mut holster, maybe_client) in query.iter_mut() {
+    for (
+        shooter,
+        input,
+        shooter_transform,
+        maybe_bound,
+        mut holster,
+        maybe_client,
+        maybe_view_time,
+    ) in query.iter_mut()
+    {
+        // Hits are checked in the world the shooter saw, but only up to the configured limit
+        let lag_compensation = maybe_view_time
+            .and_then(|view_time| view_time.0)
+            .map(|view_time| LagCompensation {
+                rewind: time
+                    .elapsed()
+                    .saturating_sub(view_time)
+                    .min(lag_compensation_config.max_rewind.into_duration()),
+                shooter,
+            });
+
         let mut weapon = holster.active_gun_mut();
         if input.flags.intersects(InputFlags::SHOOT) {
             if weapon.is_allowed_to_shoot(time.elapsed()) {
@@ -188,27 +168,30 @@ pub fn shoot(
                 }
 
                 for _pellet_idx in 0..weapon.details.pellet_number {
-                    let velocity_direction = spread_to_quat(weapon.details.spread)
+                    let velocity_direction = spread_to_quat(weapon.details.spread, &mut rng)
                         .mul_vec3(direction3d)
                         .truncate()
                         * -1.0;
                     let velocity = weapon.details.bullet_speed * velocity_direction;
 
-                    commands
-                        .spawn(
-                            BulletBundle::new(
-                                MeterVec2::from_pixel_vec(bullet_transform.translation.truncate()),
-                                velocity,
-                                weapon.bullet_lifespan_sec(),
-                                time.elapsed()))
-                        .insert(Damage(weapon.details.damage));
+                    let mut bullet = commands.spawn(BulletBundle::new(
+                        MeterVec2::from_pixel_vec(bullet_transform.translation.truncate()),
+                        velocity,
+                        weapon.bullet_lifespan_sec(),
+                        time.elapsed(),
+                    ));
+                    bullet.insert(Damage(weapon.details.damage));
+                    if let Some(lag_compensation) = lag_compensation {
+                        bullet.insert(lag_compensation);
+                    }
 
                     broadcast_shot_event(
-                        &client_registry,
-                        &mut net,
+                        &interest,
+                        &mut outbox,
                         weapon,
                         &bullet_transform,
                         &velocity,
+                        simulation_time.tick(),
                     );
                 }
 
@@ -217,12 +200,10 @@ pub fn shoot(
                 weapon.bullets_left_in_magazine -= 1;
 
                 if let Some(client) = maybe_client {
-                    if let Err(err) = send_ammo_update(
-                        &client.id,
-                        &client_registry,
-                        weapon.bullets_left_in_magazine,
-                        &mut net,
-                    ) {
+                    let ammo_update = PlayerUpdate::AmmoUpdate {
+                        ammo_in_magazine: weapon.bullets_left_in_magazine,
+                    };
+                    if let Err(err) = outbox.send(client.id, ammo_update) {
                         bevy::log::error!(
                             "Failed to send ammo update to client {:?}. Error: {}",
                             client.id,
@@ -237,10 +218,10 @@ pub fn shoot(
     }
 }
 
-fn spread_to_quat(spread: f32) -> bevy::math::Quat {
+fn spread_to_quat(spread: f32, rng: &mut GameRng) -> bevy::math::Quat {
     let angle = if spread > 0.0 {
         use rand::Rng;
-        rand::thread_rng().gen_range(-spread..spread) * (PI / 180.0)
+        rng.0.gen_range(-spread..spread) * (PI / 180.0)
     } else {
         0.0
     };
@@ -249,31 +230,23 @@ fn spread_to_quat(spread: f32) -> bevy::math::Quat {
 }
 
 fn broadcast_shot_event(
-    client_registry: &ClientRegistry,
-    net: &mut TransportResource,
+    interest: &InterestManager,
+    outbox: &mut ClientOutbox,
     weapon: &Weapon,
     bullet_transform: &Transform,
     velocity: &MeterPerSecVec2,
+    tick: Tick,
 ) {
-    let payload = serialize(&PacketType::ShotEvent(ShotEvent {
-        position: MeterVec2::from_pixel_vec(bullet_transform.translation.truncate()),
-        velocity: *velocity,
-        bullet_time_limit_secs: weapon.bullet_lifespan_sec(),
-    }))
-    .expect("ShotEvent's serialization failed");
-
-    client_registry
-        .get_clients()
-        .iter()
-        .map(|handle| handle.addr)
-        .for_each(|addr| {
-            net.send_with_requirements(
-                addr,
-                &payload,
-                DeliveryRequirement::ReliableSequenced(StreamId::ShotEvent.into()),
-                UrgencyRequirement::OnTick,
-            );
-        })
+    let position = MeterVec2::from_pixel_vec(bullet_transform.translation.truncate());
+    outbox.send_where(
+        |handle| interest.is_relevant(handle.id, &position),
+        ShotEvent {
+            position,
+            velocity: *velocity,
+            bullet_time_limit_secs: weapon.bullet_lifespan_sec(),
+            tick,
+        },
+    );
 }
 
 #[cfg(test)]
@@ -281,6 +254,7 @@ mod test {
     use super::*;
     use crate::components::weapon::WeaponDetails;
     use crate::components::{weapon, Input, InputFlags};
+    use crate::resources::{ClientID, ClientRegistry, InterestRadius, Outbox};
     use bevy::prelude::{App, Commands, Transform};
     use std::net::SocketAddr;
     use w_bevy_test::TestApp;
@@ -322,7 +296,7 @@ mod test {
 
     #[test]
     fn broadcast_shot_event() {
-        use westiny_common::serialization::deserialize;
+        use westiny_common::network::PacketType;
 
         let mut client_registry = ClientRegistry::new(3);
         client_registry
@@ -344,34 +318,46 @@ mod test {
             )
             .unwrap();
 
+        let mut interest = InterestManager::new(InterestRadius(Meter(10.0)));
+        interest.update_center(ClientID(0), MeterVec2::from_raw(0.0, 0.0));
+        interest.update_center(ClientID(1), MeterVec2::from_raw(5.0, 5.0));
+        interest.update_center(ClientID(2), MeterVec2::from_raw(50.0, 0.0));
+
         let mut time = bevy::prelude::Time::default();
         time.update();
 
         App::new()
             .insert_resource(client_registry)
-            .insert_resource(TransportResource::new())
+            .insert_resource(interest)
+            .init_resource::<LagCompensationConfig>()
+            .init_resource::<SimulationTime>()
+            .init_resource::<GameRng>()
+            .init_resource::<Outbox>()
             .insert_resource(time)
             .add_startup_system(spawn_shooting_player)
             .add_system(shoot)
-            .add_assert_system(|net: ResMut<TransportResource>| {
-                let messages = net.get_messages();
+            .add_assert_system(|outbox: Res<Outbox>| {
+                let messages = outbox.queued();
 
-                assert_eq!(3, messages.len());
+                // The third player is too far to be interested in the shot
+                assert_eq!(1, messages.len());
+                assert_eq!(2, messages[0].destinations.len());
                 let expected_msg = ShotEvent {
                     position: MeterVec2::from_raw(0.0, -1.0),
                     velocity: MeterPerSecVec2::from_raw(0.0, -12.5),
                     bullet_time_limit_secs: Second(0.6),
+                    tick: 0,
                 };
 
                 messages.iter().for_each(|msg| {
-                    let deserialized = deserialize(&msg.payload).expect("failed to deserailize");
-                    if let PacketType::ShotEvent(ev) = deserialized {
+                    if let PacketType::ShotEvent(ev) = &msg.packet {
                         assert_eq!(ev.position, expected_msg.position);
                         assert_eq!(ev.velocity, expected_msg.velocity);
                         assert_eq!(
                             ev.bullet_time_limit_secs,
                             expected_msg.bullet_time_limit_secs
                         );
+                        assert_eq!(ev.tick, expected_msg.tick);
                     } else {
                         panic!("Unexpected message");
                     }
@@ -0,0 +1,30 @@
+use crate::resources::{ClientOutbox, ShutdownSignal};
+use bevy::app::AppExit;
+use bevy::prelude::{EventWriter, Local, Res};
+use westiny_common::network::DisconnectReason;
+
+/// The transport sends the queued packets in the next frame, the server exits only after that
+const FRAMES_BEFORE_EXIT: u32 = 2;
+
+/// Tells the clients that the server is shutting down when it has been asked to stop,
+/// then exits
+pub fn shut_down_on_signal(
+    signal: Res<ShutdownSignal>,
+    mut outbox: ClientOutbox,
+    mut app_exit: EventWriter<AppExit>,
+    mut frames_since_broadcast: Local<Option<u32>>,
+) {
+    match *frames_since_broadcast {
+        None if signal.is_requested() => {
+            log::info!(
+                "Shutting down, disconnecting {} clients",
+                outbox.client_registry().client_count()
+            );
+            outbox.broadcast(DisconnectReason::ServerShutdown);
+            *frames_since_broadcast = Some(0);
+        }
+        None => {}
+        Some(frames) if frames + 1 >= FRAMES_BEFORE_EXIT => app_exit.send(AppExit),
+        Some(frames) => *frames_since_broadcast = Some(frames + 1),
+    }
+}
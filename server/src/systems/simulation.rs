@@ -0,0 +1,10 @@
+use crate::resources::{SimulationConfig, SimulationTime};
+use bevy::prelude::{Res, ResMut};
+
+/// Starts a new tick of the fixed timestep simulation stage
+pub fn advance_simulation_tick(
+    config: Res<SimulationConfig>,
+    mut simulation_time: ResMut<SimulationTime>,
+) {
+    simulation_time.advance(config.tick_step());
+}
@@ -1,5 +1,5 @@
 use crate::components;
-use crate::resources::{weapon::GunResource, ClientRegistry};
+use crate::resources::{weapon::GunResource, ClientRegistry, GameRng};
 use bevy::prelude::*;
 use westiny_common::collision;
 use westiny_common::events::EntityDelete;
@@ -54,11 +54,12 @@ pub fn spawn_player(
     mut spawn_player_ec: EventReader<SpawnPlayerEvent>,
     client_registry: Res<ClientRegistry>,
     gun_resource: Res<GunResource>,
+    mut rng: ResMut<GameRng>,
     mut transforms_boundings_query: Query<(&Transform, &components::BoundingCircle)>,
 ) {
     for spawn_event in spawn_player_ec.iter() {
         if let Some(client) = client_registry.find_client(spawn_event.client.id) {
-            let spawn_pos = find_spawn_pos(&mut transforms_boundings_query);
+            let spawn_pos = find_spawn_pos(&mut transforms_boundings_query, &mut rng);
             info!(
                 "Spawn position found for player at ({},{})",
                 spawn_pos.x.0, spawn_pos.y.0
@@ -72,7 +73,10 @@ pub fn spawn_player(
             );
             info!("Player created for {}", client.player_name);
         } else {
-            error!("Client with id {:?} not found in registry. Spawn refused.", spawn_event.client.id);
+            error!(
+                "Client with id {:?} not found in registry. Spawn refused.",
+                spawn_event.client.id
+            );
         }
     }
 }
@@ -84,11 +88,8 @@ fn create_player_entity(
     network_id: components::NetworkId,
     gun_resource: &GunResource,
 ) {
-    let transform = Transform::from_xyz(
-        initial_pos.x.into_pixel(),
-        initial_pos.y.into_pixel(),
-        0.0,
-    );
+    let transform =
+        Transform::from_xyz(initial_pos.x.into_pixel(), initial_pos.y.into_pixel(), 0.0);
     commands
         .spawn_empty()
         .insert(client)
@@ -97,7 +98,11 @@ fn create_player_entity(
         .insert(GlobalTransform::default())
         .insert(transform)
         .insert(components::Health(100))
+        .insert(components::Replicate)
         .insert(components::Input::default())
+        .insert(components::InputQueue::default())
+        .insert(components::LastInputSequence::default())
+        .insert(components::ViewTime::default())
         .insert(components::Velocity::default())
         .insert(components::BoundingCircle { radius: Meter(0.5) })
         .insert(components::weapon::Holster::new(gun_resource))
@@ -125,6 +130,7 @@ fn has_collision(
 
 fn find_spawn_pos(
     transforms_boundings_query: &mut Query<(&Transform, &components::BoundingCircle)>,
+    rng: &mut GameRng,
 ) -> MeterVec2 {
     use rand::Rng;
 
@@ -138,8 +144,8 @@ fn find_spawn_pos(
 
     for _ in 0..MAX_TRIAL_ITERATION {
         // TODO hardcoded range: should be calculated from map data
-        let x = rand::thread_rng().gen_range(-BOUND..BOUND);
-        let y = rand::thread_rng().gen_range(-BOUND..BOUND);
+        let x = rng.0.gen_range(-BOUND..BOUND);
+        let y = rng.0.gen_range(-BOUND..BOUND);
 
         let candidate_transform = Transform::from_xyz(x, y, 0.0);
         if !has_collision(
@@ -0,0 +1,358 @@
+use bevy::prelude::{Component, EventReader, Local, Res, ResMut, Resource, Time};
+use blaminar::simulation::{
+    DeliveryRequirement, NetworkSimulationEvent, TransportResource, UrgencyRequirement,
+};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Duration;
+use westiny_common::auth::{prove, Credential};
+use westiny_common::components::{Input, NetworkId};
+use westiny_common::network::{
+    self, ClientInitialData, ComponentKind, ComponentValue, DisconnectReason, EntityState,
+    InputSequence, PacketType, PlayerDeath, PlayerUpdate, SequencedInput, SessionToken, ShotEvent,
+    BUILD_HASH,
+};
+use westiny_common::replication::replicated_components;
+use westiny_common::secure_channel::SecureChannels;
+use westiny_common::serialization::{deserialize_all, serialize, serialize_with, PacketCodec};
+use westiny_common::snapshot::{ReceivedSnapshots, Snapshot};
+
+/// Same as the stream of the real client, for the sequenced deliveries to behave the same
+const INPUT_STREAM: u8 = 0;
+const SNAPSHOT_ACK_STREAM: u8 = 1;
+
+/// Number of inputs sent in each packet, like the real client does
+const SENT_INPUT_COUNT: usize = 3;
+
+const CONNECTION_RETRY: Duration = Duration::from_secs(1);
+
+/// Headless client speaking the game protocol, keeping everything it receives for assertions.
+/// It sends `input` in every frame after it has connected.
+#[derive(Resource)]
+pub struct TestClient {
+    pub player_name: String,
+    pub server: SocketAddr,
+    pub input: Input,
+    /// Answers of the authentication challenge, if the server asks for them
+    pub password: Option<String>,
+    pub secret: Option<String>,
+    /// Asked for in the connection request
+    pub codec: PacketCodec,
+    /// Whether the connection request asks for compression
+    pub compression: bool,
+    connection: Option<network::Result<ClientInitialData>>,
+    session_token: Option<SessionToken>,
+    snapshots: ReceivedSnapshots,
+    world: Snapshot,
+    next_sequence: InputSequence,
+    sent_inputs: VecDeque<SequencedInput>,
+    pub shots: Vec<ShotEvent>,
+    pub deaths: Vec<PlayerDeath>,
+    pub deleted: Vec<NetworkId>,
+    pub notifications: Vec<String>,
+    pub player_updates: Vec<PlayerUpdate>,
+    /// Latest value of each replicated component received
+    components: HashMap<(NetworkId, ComponentKind), ComponentValue>,
+    /// Reason of the Disconnect packet received from the server
+    pub disconnection: Option<DisconnectReason>,
+    has_quit: bool,
+}
+
+impl TestClient {
+    pub fn new(player_name: &str, server: SocketAddr) -> Self {
+        TestClient {
+            player_name: player_name.to_string(),
+            server,
+            input: Input::default(),
+            password: None,
+            secret: None,
+            codec: PacketCodec::default(),
+            compression: true,
+            connection: None,
+            session_token: None,
+            snapshots: ReceivedSnapshots::default(),
+            world: Snapshot::default(),
+            next_sequence: 0,
+            sent_inputs: VecDeque::new(),
+            shots: Vec::new(),
+            deaths: Vec::new(),
+            deleted: Vec::new(),
+            notifications: Vec::new(),
+            player_updates: Vec::new(),
+            components: HashMap::new(),
+            disconnection: None,
+            has_quit: false,
+        }
+    }
+
+    /// Asks for the session back, like the real client does after the connection has been lost
+    pub fn reconnect(&mut self) {
+        self.connection = None;
+        self.snapshots = ReceivedSnapshots::default();
+    }
+
+    /// Sends a Disconnect packet, like the real client does when its window is closed.
+    /// Nothing is sent after that.
+    pub fn quit(&mut self, net: &mut TransportResource) {
+        let message = serialize_with(
+            self.negotiated_codec(),
+            &PacketType::Disconnect {
+                reason: DisconnectReason::ClientQuit,
+            },
+        )
+        .expect("Disconnect could not be serialized");
+        net.send_with_requirements(
+            self.server,
+            &message,
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        self.has_quit = true;
+    }
+
+    /// The initial data of the accepted connection
+    pub fn initial_data(&self) -> Option<&ClientInitialData> {
+        self.connection
+            .as_ref()
+            .and_then(|result| result.as_ref().ok())
+    }
+
+    /// Codec accepted by the server, the packets after the handshake are encoded with it
+    pub fn negotiated_codec(&self) -> PacketCodec {
+        self.initial_data()
+            .map_or_else(PacketCodec::default, |data| data.codec)
+    }
+
+    pub fn refusal(&self) -> Option<network::ErrorKind> {
+        self.connection
+            .as_ref()
+            .and_then(|result| result.as_ref().err())
+            .map(|err| err.kind())
+    }
+
+    pub fn player_network_id(&self) -> Option<NetworkId> {
+        self.initial_data().map(|data| data.player_network_id)
+    }
+
+    /// Entity states of the latest snapshot received
+    pub fn world(&self) -> &Snapshot {
+        &self.world
+    }
+
+    /// Latest value of the replicated component of the entity
+    pub fn replicated<C: Component + DeserializeOwned>(&self, network_id: NetworkId) -> Option<C> {
+        let kind = replicated_components().kind_of::<C>()?;
+        self.components
+            .get(&(network_id, kind))
+            .map(|value| value.decode().expect("Replicated component could not be decoded"))
+    }
+
+    /// Latest values of the replicated component of every entity
+    pub fn all_replicated<C: Component + DeserializeOwned>(&self) -> Vec<(NetworkId, C)> {
+        let kind = match replicated_components().kind_of::<C>() {
+            Some(kind) => kind,
+            None => return Vec::new(),
+        };
+        self.components
+            .iter()
+            .filter(|((_, value_kind), _)| *value_kind == kind)
+            .map(|((network_id, _), value)| {
+                let component = value.decode().expect("Replicated component could not be decoded");
+                (*network_id, component)
+            })
+            .collect()
+    }
+
+    /// State of this client's player in the latest snapshot
+    pub fn player_state(&self) -> Option<&EntityState> {
+        self.player_network_id()
+            .and_then(|network_id| self.world.get(&network_id))
+    }
+}
+
+pub fn send_connection_request(
+    client: Res<TestClient>,
+    mut secure_channels: ResMut<SecureChannels>,
+    mut net: ResMut<TransportResource>,
+    time: Res<Time>,
+    mut last_request: Local<Option<Duration>>,
+) {
+    if client.connection.is_some()
+        || client.has_quit
+        || last_request.is_some_and(|last| time.elapsed() - last < CONNECTION_RETRY)
+    {
+        return;
+    }
+    *last_request = Some(time.elapsed());
+
+    let message = serialize(&PacketType::ConnectionRequest {
+        player_name: client.player_name.clone(),
+        build_hash: BUILD_HASH.to_string(),
+        resume_session: client.session_token.is_some(),
+        codec: client.codec,
+        compression: client.compression,
+        key_share: secure_channels.key_share(client.server),
+    })
+    .expect("ConnectionRequest could not be serialized");
+    net.send_with_requirements(
+        client.server,
+        &message,
+        DeliveryRequirement::ReliableSequenced(None),
+        UrgencyRequirement::OnTick,
+    );
+}
+
+pub fn receive_packets(
+    mut client: ResMut<TestClient>,
+    mut net: ResMut<TransportResource>,
+    mut network_events: EventReader<NetworkSimulationEvent>,
+    time: Res<Time>,
+) {
+    let mut acknowledged = None;
+    for event in network_events.iter() {
+        let payload = match event {
+            NetworkSimulationEvent::Message(addr, payload) if *addr == client.server => payload,
+            _ => continue,
+        };
+        let packets = match deserialize_all(payload) {
+            Ok(packets) => packets,
+            Err(err) => {
+                log::error!(
+                    "{} could not deserialize packet: {}",
+                    client.player_name,
+                    err
+                );
+                continue;
+            }
+        };
+
+        for packet in packets {
+            match packet {
+                PacketType::ConnectionResponse(response) => {
+                    if let Ok(data) = &response {
+                        client.session_token = Some(data.session_token);
+                    }
+                    client.connection = Some(response);
+                }
+                PacketType::AuthChallenge(challenge) => {
+                    let proof = |required, credential, key: &Option<String>| {
+                        key.as_ref()
+                            .filter(|_| required)
+                            .map(|key| {
+                                prove(credential, key, &challenge.nonce, &client.player_name)
+                            })
+                    };
+                    let message = serialize(&PacketType::AuthResponse {
+                        password_proof: proof(
+                            challenge.password_required,
+                            Credential::ServerPassword,
+                            &client.password,
+                        ),
+                        secret_proof: proof(
+                            challenge.secret_required,
+                            Credential::PlayerSecret,
+                            &client.secret,
+                        ),
+                        session_proof: proof(
+                            challenge.session_required,
+                            Credential::SessionToken,
+                            &client.session_token.map(|token| token.to_string()),
+                        ),
+                    })
+                    .expect("AuthResponse could not be serialized");
+                    net.send_with_requirements(
+                        client.server,
+                        &message,
+                        DeliveryRequirement::ReliableSequenced(None),
+                        UrgencyRequirement::OnTick,
+                    );
+                }
+                PacketType::EntityStateUpdate(update) => {
+                    let completed = client
+                        .snapshots
+                        .receive(&update)
+                        .and_then(|received| received.completed);
+                    if let Some((snapshot, _)) = completed {
+                        let snapshot = snapshot.clone();
+                        client.world = snapshot;
+                        acknowledged = Some(update.snapshot);
+                    }
+                }
+                PacketType::EntityDelete(delete) => client.deleted.push(delete.network_id),
+                PacketType::ShotEvent(shot) => client.shots.push(shot),
+                PacketType::PlayerDeath(death) => client.deaths.push(death),
+                PacketType::Notification(notification) => {
+                    client.notifications.push(notification.message)
+                }
+                PacketType::PlayerUpdate(update) => client.player_updates.push(update),
+                PacketType::ComponentUpdate(update) => {
+                    for value in update.components {
+                        client.components.insert((value.network_id, value.kind), value);
+                    }
+                }
+                PacketType::Disconnect { reason } => client.disconnection = Some(reason),
+                PacketType::Ping { sequence } => {
+                    let message = serialize_with(
+                        client.negotiated_codec(),
+                        &PacketType::Pong {
+                            sequence,
+                            time: time.elapsed(),
+                        },
+                    )
+                    .expect("Pong could not be serialized");
+                    net.send_with_requirements(
+                        client.server,
+                        &message,
+                        DeliveryRequirement::Unreliable,
+                        UrgencyRequirement::OnTick,
+                    );
+                }
+                other => log::warn!("Unexpected packet from server: {:?}", other),
+            }
+        }
+    }
+
+    if let (Some(snapshot), false) = (acknowledged, client.has_quit) {
+        let message =
+            serialize_with(client.negotiated_codec(), &PacketType::SnapshotAck { snapshot })
+                .expect("SnapshotAck could not be serialized");
+        net.send_with_requirements(
+            client.server,
+            &message,
+            DeliveryRequirement::UnreliableSequenced(Some(SNAPSHOT_ACK_STREAM)),
+            UrgencyRequirement::OnTick,
+        );
+    }
+}
+
+pub fn send_input(mut client: ResMut<TestClient>, mut net: ResMut<TransportResource>) {
+    if client.initial_data().is_none() || client.has_quit {
+        return;
+    }
+
+    let input = SequencedInput {
+        sequence: client.next_sequence,
+        input: client.input,
+    };
+    client.next_sequence = client.next_sequence.wrapping_add(1);
+    if client.sent_inputs.len() >= SENT_INPUT_COUNT {
+        client.sent_inputs.pop_front();
+    }
+    client.sent_inputs.push_back(input);
+
+    let message = serialize_with(
+        client.negotiated_codec(),
+        &PacketType::InputState {
+            inputs: client.sent_inputs.iter().copied().collect(),
+            view_time: None,
+        },
+    )
+    .expect("InputState could not be serialized");
+    net.send_with_requirements(
+        client.server,
+        &message,
+        DeliveryRequirement::UnreliableSequenced(Some(INPUT_STREAM)),
+        UrgencyRequirement::OnTick,
+    );
+}
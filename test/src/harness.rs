@@ -0,0 +1,199 @@
+use crate::client::{self, TestClient};
+use crate::loopback::{LinkConditions, LoopbackNetwork, LoopbackTransportPlugin};
+use bevy::prelude::{App, CoreStage, IntoSystemDescriptor, Mut, Time};
+use blaminar::simulation::TransportResource;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use westiny_common::secure_channel::{self, SecureChannels, ServerTrust};
+
+pub const SERVER_ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5745);
+const FIRST_CLIENT_PORT: u16 = 10000;
+
+/// Runs a server app and any number of client apps in one process, connected by a
+/// `LoopbackNetwork`. The time of every app is driven by a shared virtual clock, advanced by
+/// a fixed frame time in each step, so runs are deterministic.
+///
+/// The apps must not contain `TimePlugin` (nor `MinimalPlugins`), their `Time` is inserted here.
+pub struct LoopbackHarness {
+    network: LoopbackNetwork,
+    frame_time: Duration,
+    start: Instant,
+    elapsed: Duration,
+    server: App,
+    clients: Vec<(SocketAddr, App)>,
+    next_port: u16,
+}
+
+impl LoopbackHarness {
+    /// The server app needs `LoopbackTransportPlugin` or equivalent resources
+    pub fn new(
+        mut server: App,
+        conditions: LinkConditions,
+        seed: u64,
+        frame_time: Duration,
+    ) -> Self {
+        let start = Instant::now();
+        init_time(&mut server, start);
+        LoopbackHarness {
+            network: LoopbackNetwork::new(conditions, seed),
+            frame_time,
+            start,
+            elapsed: Duration::ZERO,
+            server,
+            clients: Vec::new(),
+            next_port: FIRST_CLIENT_PORT,
+        }
+    }
+
+    /// Adds a headless `TestClient` and returns its index
+    pub fn add_client(&mut self, player_name: &str) -> usize {
+        // The clients pin the server, like the real ones do
+        let server_key = self
+            .server
+            .world
+            .resource::<SecureChannels>()
+            .identity_key()
+            .expect("The server has no identity");
+        let mut app = App::new();
+        app.add_plugin(LoopbackTransportPlugin)
+            .insert_resource(TestClient::new(player_name, SERVER_ADDRESS))
+            .insert_resource(SecureChannels::client(ServerTrust::Pinned(server_key)))
+            .add_system(secure_channel::open_packets.label("open_packets"))
+            .add_system(
+                client::receive_packets
+                    .label("receive_packets")
+                    .after("open_packets"),
+            )
+            .add_system(client::send_connection_request.after("receive_packets"))
+            .add_system(client::send_input.after("receive_packets"))
+            .add_system_to_stage(CoreStage::Last, secure_channel::seal_packets);
+        init_time(&mut app, self.start + self.elapsed);
+
+        let address = self.next_address();
+        self.clients.push((address, app));
+        self.clients.len() - 1
+    }
+
+    fn next_address(&mut self) -> SocketAddr {
+        let port = self.next_port;
+        self.next_port += 1;
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn network_mut(&mut self) -> &mut LoopbackNetwork {
+        &mut self.network
+    }
+
+    pub fn server(&self) -> &App {
+        &self.server
+    }
+
+    pub fn server_mut(&mut self) -> &mut App {
+        &mut self.server
+    }
+
+    pub fn client_address(&self, index: usize) -> SocketAddr {
+        self.clients[index].0
+    }
+
+    pub fn client(&self, index: usize) -> &TestClient {
+        self.clients[index].1.world.resource::<TestClient>()
+    }
+
+    pub fn client_mut(&mut self, index: usize) -> Mut<'_, TestClient> {
+        self.clients[index].1.world.resource_mut::<TestClient>()
+    }
+
+    /// Disconnects the client as if its connection had timed out. The client is not updated anymore.
+    pub fn disconnect(&mut self, index: usize) {
+        let (address, _) = self.clients[index];
+        self.network
+            .disconnect(address, SERVER_ADDRESS, &mut self.server);
+    }
+
+    /// The client continues from a new address (e.g. its NAT port has changed), while the
+    /// connection of its old address times out on the server. Returns the new address.
+    /// The secure session is bound to the old address, the client makes a new one.
+    pub fn move_client(&mut self, index: usize) -> SocketAddr {
+        self.disconnect(index);
+        self.clients[index]
+            .1
+            .world
+            .resource_mut::<SecureChannels>()
+            .forget(&SERVER_ADDRESS);
+        let address = self.next_address();
+        self.clients[index].0 = address;
+        address
+    }
+
+    /// The client leaves the game with a Disconnect packet. It is still updated, but sends nothing.
+    pub fn quit(&mut self, index: usize) {
+        let world = &mut self.clients[index].1.world;
+        world.resource_scope(|world, mut client: Mut<TestClient>| {
+            client.quit(&mut world.resource_mut::<TransportResource>())
+        });
+    }
+
+    /// Advances the clock by one frame, delivers the due messages and updates every app
+    pub fn step(&mut self) {
+        self.elapsed += self.frame_time;
+        let now = self.start + self.elapsed;
+
+        update_app(
+            &mut self.network,
+            SERVER_ADDRESS,
+            &mut self.server,
+            now,
+            self.elapsed,
+        );
+        for (address, app) in self.clients.iter_mut() {
+            update_app(&mut self.network, *address, app, now, self.elapsed);
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        let until = self.elapsed + duration;
+        while self.elapsed < until {
+            self.step();
+        }
+    }
+
+    /// Steps until the condition holds, at most for `timeout`.
+    /// Returns whether the condition has been met.
+    pub fn run_until<F>(&mut self, timeout: Duration, mut condition: F) -> bool
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        let until = self.elapsed + timeout;
+        while !condition(self) {
+            if self.elapsed >= until {
+                return false;
+            }
+            self.step();
+        }
+        true
+    }
+}
+
+fn init_time(app: &mut App, now: Instant) {
+    let mut time = Time::new(now);
+    time.update_with_instant(now);
+    app.insert_resource(time);
+}
+
+fn update_app(
+    network: &mut LoopbackNetwork,
+    address: SocketAddr,
+    app: &mut App,
+    now: Instant,
+    elapsed: Duration,
+) {
+    app.world.resource_mut::<Time>().update_with_instant(now);
+    network.deliver(address, app, elapsed);
+    app.update();
+    network.collect(address, app, elapsed);
+}
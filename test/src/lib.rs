@@ -1,3 +1,10 @@
+pub mod client;
+pub mod harness;
+pub mod loopback;
+
+pub use harness::{LoopbackHarness, SERVER_ADDRESS};
+pub use loopback::{LinkConditions, LoopbackNetwork, LoopbackTransportPlugin};
+
 #[macro_export]
 macro_rules! assert_delta {
     ($x:expr, $y:expr, $delta:expr) => {
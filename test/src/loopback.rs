@@ -0,0 +1,307 @@
+use bevy::prelude::{App, Events, Plugin};
+use blaminar::simulation::{
+    DeliveryRequirement, Message, NetworkSimulationEvent, TransportResource,
+};
+use blaminar::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Provides the resources of the transport layer, without sockets.
+/// The messages are moved between the apps by `LoopbackNetwork`.
+pub struct LoopbackTransportPlugin;
+
+impl Plugin for LoopbackTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TransportResource>()
+            .add_event::<NetworkSimulationEvent>();
+    }
+}
+
+/// Emulated network conditions, applied to every message in both directions
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    /// Random extra delay, at most this much
+    pub jitter: Duration,
+    /// Probability of dropping an unreliable message
+    pub loss: f64,
+    /// Probability of an unreliable message skipping the latency, overtaking the earlier ones
+    pub reorder: f64,
+}
+
+struct InFlight {
+    deliver_at: Duration,
+    sequence: u64,
+    from: SocketAddr,
+    to: SocketAddr,
+    payload: Bytes,
+}
+
+/// In-memory network between apps, driven by a virtual clock.
+/// Randomness comes from a seeded generator, so a run is reproducible.
+///
+/// Reliable messages are never lost and keep their order between two peers, as laminar resends
+/// and orders them. Unreliable messages are subject to loss and reordering.
+pub struct LoopbackNetwork {
+    conditions: LinkConditions,
+    rng: StdRng,
+    in_flight: Vec<InFlight>,
+    next_sequence: u64,
+    last_reliable: HashMap<(SocketAddr, SocketAddr), Duration>,
+    connected: HashSet<(SocketAddr, SocketAddr)>,
+}
+
+impl LoopbackNetwork {
+    pub fn new(conditions: LinkConditions, seed: u64) -> Self {
+        LoopbackNetwork {
+            conditions,
+            rng: StdRng::seed_from_u64(seed),
+            in_flight: Vec::new(),
+            next_sequence: 0,
+            last_reliable: HashMap::new(),
+            connected: HashSet::new(),
+        }
+    }
+
+    pub fn conditions(&self) -> &LinkConditions {
+        &self.conditions
+    }
+
+    pub fn set_conditions(&mut self, conditions: LinkConditions) {
+        self.conditions = conditions;
+    }
+
+    /// Number of messages sent but not delivered yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// Takes the messages sent by the app at `from`
+    pub fn collect(&mut self, from: SocketAddr, app: &mut App, now: Duration) {
+        let messages = app
+            .world
+            .resource_mut::<TransportResource>()
+            .drain_messages(|_| true);
+        for message in messages {
+            self.send(from, message, now);
+        }
+    }
+
+    fn send(&mut self, from: SocketAddr, message: Message, now: Duration) {
+        let jitter = if self.conditions.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            self.rng.gen_range(Duration::ZERO..=self.conditions.jitter)
+        };
+        let mut deliver_at = now + self.conditions.latency + jitter;
+
+        if is_reliable(message.delivery) {
+            let last = self
+                .last_reliable
+                .entry((from, message.destination))
+                .or_default();
+            deliver_at = deliver_at.max(*last);
+            *last = deliver_at;
+        } else {
+            if self.rng.gen_bool(self.conditions.loss) {
+                log::trace!("Message from {} to {} lost", from, message.destination);
+                return;
+            }
+            if self.rng.gen_bool(self.conditions.reorder) {
+                deliver_at = now;
+            }
+        }
+
+        self.in_flight.push(InFlight {
+            deliver_at,
+            sequence: self.next_sequence,
+            from,
+            to: message.destination,
+            payload: message.payload,
+        });
+        self.next_sequence += 1;
+    }
+
+    /// Delivers the messages due by `now` to the app at `to`, as network simulation events.
+    /// The first message from a peer is preceded by a `Connect` event, like laminar does.
+    pub fn deliver(&mut self, to: SocketAddr, app: &mut App, now: Duration) {
+        let mut due: Vec<_> = Vec::new();
+        let mut i = 0;
+        while i < self.in_flight.len() {
+            if self.in_flight[i].to == to && self.in_flight[i].deliver_at <= now {
+                due.push(self.in_flight.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        due.sort_by_key(|message| (message.deliver_at, message.sequence));
+
+        let mut events = app.world.resource_mut::<Events<NetworkSimulationEvent>>();
+        for message in due {
+            if self.connected.insert((message.from, to)) {
+                events.send(NetworkSimulationEvent::Connect(message.from));
+            }
+            events.send(NetworkSimulationEvent::Message(
+                message.from,
+                message.payload,
+            ));
+        }
+    }
+
+    /// Drops the messages between the two peers and notifies `to` of the disconnection of `from`,
+    /// as laminar does when the connection times out
+    pub fn disconnect(&mut self, from: SocketAddr, to: SocketAddr, app: &mut App) {
+        self.in_flight
+            .retain(|message| !(message.from == from && message.to == to));
+        self.in_flight
+            .retain(|message| !(message.from == to && message.to == from));
+        self.connected.remove(&(from, to));
+        self.connected.remove(&(to, from));
+        app.world
+            .resource_mut::<Events<NetworkSimulationEvent>>()
+            .send(NetworkSimulationEvent::Disconnect(from));
+    }
+}
+
+fn is_reliable(delivery: DeliveryRequirement) -> bool {
+    !matches!(
+        delivery,
+        DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blaminar::simulation::UrgencyRequirement;
+
+    const SENDER: ([u8; 4], u16) = ([127, 0, 0, 1], 1000);
+    const RECEIVER: ([u8; 4], u16) = ([127, 0, 0, 1], 2000);
+
+    fn make_app() -> App {
+        let mut app = App::new();
+        app.add_plugin(LoopbackTransportPlugin);
+        app
+    }
+
+    fn send(app: &mut App, payload: u8, delivery: DeliveryRequirement) {
+        app.world
+            .resource_mut::<TransportResource>()
+            .send_with_requirements(
+                SocketAddr::from(RECEIVER),
+                &[payload],
+                delivery,
+                UrgencyRequirement::OnTick,
+            );
+    }
+
+    fn received(app: &mut App) -> Vec<u8> {
+        app.world
+            .resource_mut::<Events<NetworkSimulationEvent>>()
+            .drain()
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::Message(_, payload) => Some(payload[0]),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_delivered_after_latency() {
+        let mut network = LoopbackNetwork::new(
+            LinkConditions {
+                latency: Duration::from_millis(100),
+                ..LinkConditions::default()
+            },
+            0,
+        );
+        let (mut sender, mut receiver) = (make_app(), make_app());
+        send(&mut sender, 1, DeliveryRequirement::Unreliable);
+        network.collect(SocketAddr::from(SENDER), &mut sender, Duration::ZERO);
+
+        network.deliver(
+            SocketAddr::from(RECEIVER),
+            &mut receiver,
+            Duration::from_millis(99),
+        );
+        assert!(received(&mut receiver).is_empty());
+
+        network.deliver(
+            SocketAddr::from(RECEIVER),
+            &mut receiver,
+            Duration::from_millis(100),
+        );
+        assert_eq!(received(&mut receiver), vec![1]);
+    }
+
+    #[test]
+    fn reliable_messages_are_not_lost_nor_reordered() {
+        let mut network = LoopbackNetwork::new(
+            LinkConditions {
+                latency: Duration::from_millis(50),
+                jitter: Duration::from_millis(50),
+                loss: 1.0,
+                reorder: 1.0,
+            },
+            42,
+        );
+        let (mut sender, mut receiver) = (make_app(), make_app());
+        for payload in 0..10 {
+            send(
+                &mut sender,
+                payload,
+                DeliveryRequirement::ReliableOrdered(None),
+            );
+            send(&mut sender, 100, DeliveryRequirement::Unreliable);
+            network.collect(
+                SocketAddr::from(SENDER),
+                &mut sender,
+                Duration::from_millis(payload as u64),
+            );
+        }
+
+        network.deliver(
+            SocketAddr::from(RECEIVER),
+            &mut receiver,
+            Duration::from_secs(1),
+        );
+        assert_eq!(received(&mut receiver), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_seed_gives_same_delivery() {
+        let run = || {
+            let mut network = LoopbackNetwork::new(
+                LinkConditions {
+                    latency: Duration::from_millis(20),
+                    jitter: Duration::from_millis(30),
+                    loss: 0.3,
+                    reorder: 0.2,
+                },
+                7,
+            );
+            let (mut sender, mut receiver) = (make_app(), make_app());
+            for payload in 0..50 {
+                send(&mut sender, payload, DeliveryRequirement::Unreliable);
+                network.collect(
+                    SocketAddr::from(SENDER),
+                    &mut sender,
+                    Duration::from_millis(payload as u64 * 10),
+                );
+            }
+            network.deliver(
+                SocketAddr::from(RECEIVER),
+                &mut receiver,
+                Duration::from_secs(10),
+            );
+            received(&mut receiver)
+        };
+
+        let first = run();
+        assert!(first.len() < 50);
+        assert_eq!(first, run());
+    }
+}
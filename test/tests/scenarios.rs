@@ -0,0 +1,537 @@
+use bevy::app::AppExit;
+use bevy::prelude::{App, Entity, Events, Transform};
+use blaminar::simulation::NetworkSimulationEvent;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use westiny_common::components::{Health, InputFlags, MapPosition, NetworkId};
+use westiny_common::compression::Compression;
+use westiny_common::demo::{Demo, DemoRecord};
+use westiny_common::events::EntityDelete;
+use westiny_common::metric_dimension::length::{Meter, MeterVec2};
+use westiny_common::network::{DisconnectReason, ErrorKind, PacketType};
+use westiny_common::serialization::{deserialize, serialize, PacketCodec};
+use westiny_server::resources::{
+    AuthConfig, ClientRegistry, CodecConfig, CompressionConfig, Compressor, SecureChannels,
+    ShutdownSignal, SimulationConfig,
+};
+use westiny_server::ServerPlugin;
+use westiny_test::{LinkConditions, LoopbackHarness, LoopbackTransportPlugin};
+
+/// 20ms steps are exact in the fixed timestep of the server, so the runs are deterministic
+const FRAME_TIME: Duration = Duration::from_millis(20);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn server_plugin(seed: u64) -> ServerPlugin {
+    let resources_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../resources");
+    let mut server_plugin = ServerPlugin::load(&resources_dir);
+    server_plugin.simulation = SimulationConfig {
+        tick_rate: 50,
+        snapshot_rate: 25,
+    };
+    server_plugin.rng_seed = seed;
+    server_plugin
+}
+
+fn make_server(server_plugin: ServerPlugin) -> App {
+    let mut app = App::new();
+    app.add_plugin(LoopbackTransportPlugin)
+        .add_plugin(server_plugin);
+    app
+}
+
+/// Connects two clients and places their players next to each other
+fn connect_two_clients(conditions: LinkConditions) -> LoopbackHarness {
+    connect_two_clients_to(server_plugin(1), conditions)
+}
+
+fn connect_two_clients_to(
+    server_plugin: ServerPlugin,
+    conditions: LinkConditions,
+) -> LoopbackHarness {
+    let mut harness = LoopbackHarness::new(make_server(server_plugin), conditions, 1, FRAME_TIME);
+    harness.add_client("Alice");
+    harness.add_client("Bob");
+
+    let spawned = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|client| harness.client(client).player_state().is_some())
+    });
+    assert!(spawned, "Players did not spawn");
+
+    let alice = harness.client(0).player_network_id().unwrap();
+    let bob = harness.client(1).player_network_id().unwrap();
+    place_player(&mut harness, alice, MeterVec2::from_raw(0.0, -2.0));
+    place_player(&mut harness, bob, MeterVec2::from_raw(0.0, 2.0));
+
+    let everyone_sees_everyone = harness.run_until(TIMEOUT, |harness| {
+        harness.client(0).world().get(&bob).is_some()
+            && harness.client(1).world().get(&alice).is_some()
+    });
+    assert!(everyone_sees_everyone, "Players could not see each other");
+    harness
+}
+
+/// Moves the player on the server
+fn place_player(harness: &mut LoopbackHarness, player: NetworkId, position: MeterVec2) {
+    let world = &mut harness.server_mut().world;
+    let mut query = world.query::<(&NetworkId, &mut Transform)>();
+    let (_, mut transform) = query
+        .iter_mut(world)
+        .find(|(network_id, _)| **network_id == player)
+        .expect("Player not found on the server");
+    transform.translation = position.into_pixel_vec().extend(transform.translation.z);
+}
+
+/// Sets the health of the player on the server
+fn set_health(harness: &mut LoopbackHarness, player: NetworkId, hp: u16) {
+    let world = &mut harness.server_mut().world;
+    let mut query = world.query::<(&NetworkId, &mut Health)>();
+    let (_, mut health) = query
+        .iter_mut(world)
+        .find(|(network_id, _)| **network_id == player)
+        .expect("Player not found on the server");
+    *health = Health(hp);
+}
+
+#[test]
+fn connected_clients_see_each_other() {
+    let harness = connect_two_clients(LinkConditions::default());
+
+    assert_ne!(
+        harness.client(0).player_network_id(),
+        harness.client(1).player_network_id()
+    );
+    assert!(harness.client(0).refusal().is_none());
+    assert!(harness.client(1).refusal().is_none());
+}
+
+#[test]
+fn health_is_replicated_to_every_client() {
+    let mut harness = connect_two_clients(LinkConditions::default());
+    let bob = harness.client(1).player_network_id().unwrap();
+    let health_of_bob =
+        |harness: &LoopbackHarness, client| harness.client(client).replicated::<Health>(bob);
+
+    set_health(&mut harness, bob, 42);
+    let replicated = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|client| health_of_bob(harness, client).map(|health| health.0) == Some(42))
+    });
+    assert!(replicated, "Health was not replicated");
+
+    // A client connecting later gets the current value, not only the changes
+    let carol = harness.add_client("Carol");
+    let introduced = harness.run_until(TIMEOUT, |harness| {
+        health_of_bob(harness, carol).map(|health| health.0) == Some(42)
+    });
+    assert!(introduced, "Health was not sent to the new client");
+}
+
+#[test]
+fn map_is_replicated_from_the_server() {
+    let mut harness = connect_two_clients(LinkConditions::default());
+    let server_map: HashMap<_, _> = {
+        let world = &mut harness.server_mut().world;
+        let mut query = world.query::<(&NetworkId, &MapPosition)>();
+        query.iter(world).map(|(id, position)| (*id, *position)).collect()
+    };
+    assert!(!server_map.is_empty());
+    let client_map = |harness: &LoopbackHarness, client| -> HashMap<_, _> {
+        harness
+            .client(client)
+            .all_replicated::<MapPosition>()
+            .into_iter()
+            .collect()
+    };
+    let replicated = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|client| client_map(harness, client) == server_map)
+    });
+    assert!(replicated, "Map was not replicated");
+
+    // The map entities are not sent in the snapshots
+    let (&barrel, _) = server_map.iter().next().unwrap();
+    assert!(harness.client(0).world().get(&barrel).is_none());
+
+    let moved_to = MapPosition(MeterVec2::from_raw(100.0, 100.0));
+    let entity = {
+        let world = &mut harness.server_mut().world;
+        let mut query = world.query::<(Entity, &NetworkId, &mut MapPosition)>();
+        let (entity, _, mut position) = query
+            .iter_mut(world)
+            .find(|(_, network_id, _)| **network_id == barrel)
+            .unwrap();
+        *position = moved_to;
+        entity
+    };
+    let moved = harness.run_until(TIMEOUT, |harness| {
+        harness.client(0).replicated::<MapPosition>(barrel) == Some(moved_to)
+    });
+    assert!(moved, "Moved barrel was not replicated");
+
+    harness
+        .server_mut()
+        .world
+        .resource_mut::<Events<EntityDelete>>()
+        .send(EntityDelete::new(entity));
+    let deleted = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|client| harness.client(client).deleted.contains(&barrel))
+    });
+    assert!(deleted, "Barrel was not deleted");
+}
+
+#[test]
+fn shot_player_dies_and_respawns() {
+    let mut harness = connect_two_clients(LinkConditions::default());
+    let target = harness.client(1).player_network_id().unwrap();
+
+    set_health(&mut harness, target, 5);
+    harness.client_mut(0).input.cursor = MeterVec2::from_raw(0.0, 2.0);
+
+    // The revolver shoots once per click and has spread, so it is clicked until the target dies
+    let target_died = |harness: &LoopbackHarness| {
+        harness
+            .client(0)
+            .deaths
+            .iter()
+            .any(|death| death.player_name.0 == "Bob")
+    };
+    let until = harness.elapsed() + TIMEOUT;
+    while !target_died(&harness) && harness.elapsed() < until {
+        harness.client_mut(0).input.flags.toggle(InputFlags::SHOOT);
+        harness.run_for(Duration::from_millis(100));
+    }
+    assert!(target_died(&harness), "Target did not die");
+    harness.client_mut(0).input.flags.remove(InputFlags::SHOOT);
+
+    let deleted = harness.run_until(TIMEOUT, |harness| {
+        harness.client(1).deleted.contains(&target)
+    });
+    assert!(deleted, "Dead player was not deleted");
+
+    let gone = harness.run_until(TIMEOUT, |harness| {
+        harness.client(0).world().get(&target).is_none()
+    });
+    assert!(gone, "Dead player is still replicated");
+
+    // The respawned player keeps its network id
+    let respawned = harness.run_until(Duration::from_secs(10), |harness| {
+        harness.client(0).world().get(&target).is_some()
+    });
+    assert!(respawned, "Target was not respawned");
+}
+
+#[test]
+fn game_is_playable_on_bad_network() {
+    let mut harness = connect_two_clients(LinkConditions {
+        latency: Duration::from_millis(100),
+        jitter: Duration::from_millis(40),
+        loss: 0.1,
+        reorder: 0.05,
+    });
+
+    let start = harness.client(0).player_state().unwrap().position;
+    harness
+        .client_mut(0)
+        .input
+        .flags
+        .insert(InputFlags::FORWARD);
+    harness.run_for(Duration::from_secs(1));
+
+    let end = harness.client(0).player_state().unwrap().position;
+    let moved = Meter::from_pixel(end.into_pixel_vec().distance(start.into_pixel_vec()));
+    assert!(moved.0 > 1.0, "Player did not move");
+}
+
+#[test]
+fn unsealed_packets_of_client_are_dropped() {
+    let mut harness = LoopbackHarness::new(
+        make_server(server_plugin(1)),
+        LinkConditions::default(),
+        1,
+        FRAME_TIME,
+    );
+    harness.add_client("Alice");
+    let spawned = harness.run_until(TIMEOUT, |harness| harness.client(0).player_state().is_some());
+    assert!(spawned, "Player did not spawn");
+    let address = harness.client_address(0);
+    assert!(harness
+        .server()
+        .world
+        .resource::<SecureChannels>()
+        .has_session(&address));
+
+    // Anyone could send it from the address of the client
+    let forged = serialize(&PacketType::Disconnect {
+        reason: DisconnectReason::ClientQuit,
+    })
+    .unwrap();
+    harness
+        .server_mut()
+        .world
+        .resource_mut::<Events<NetworkSimulationEvent>>()
+        .send(NetworkSimulationEvent::Message(address, forged.into()));
+    harness.run_for(Duration::from_millis(100));
+
+    let registry = harness.server().world.resource::<ClientRegistry>();
+    assert!(registry.find_by_addr(&address).is_some());
+}
+
+#[test]
+fn clients_play_with_the_codec_they_ask_for() {
+    let mut plugin = server_plugin(1);
+    plugin.codecs = CodecConfig {
+        accepted: vec![PacketCodec::MessagePack, PacketCodec::Json],
+    };
+    let mut harness =
+        LoopbackHarness::new(make_server(plugin), LinkConditions::default(), 1, FRAME_TIME);
+    let json = harness.add_client("Alice");
+    harness.client_mut(json).codec = PacketCodec::Json;
+    let binary = harness.add_client("Bob");
+    harness.client_mut(binary).codec = PacketCodec::Binary;
+
+    let spawned = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|client| harness.client(client).player_state().is_some())
+    });
+    assert!(spawned, "Players did not spawn");
+    assert_eq!(harness.client(json).negotiated_codec(), PacketCodec::Json);
+    // Not accepted by the server
+    assert_eq!(
+        harness.client(binary).negotiated_codec(),
+        PacketCodec::MessagePack
+    );
+
+    let start = harness.client(json).player_state().unwrap().position;
+    harness
+        .client_mut(json)
+        .input
+        .flags
+        .insert(InputFlags::FORWARD);
+    harness.run_for(Duration::from_secs(1));
+
+    let end = harness.client(json).player_state().unwrap().position;
+    let moved = Meter::from_pixel(end.into_pixel_vec().distance(start.into_pixel_vec()));
+    assert!(moved.0 > 1.0, "Player did not move");
+}
+
+#[test]
+fn large_packets_are_compressed_for_clients_asking_for_it() {
+    let mut plugin = server_plugin(1);
+    plugin.compression = CompressionConfig {
+        enabled: true,
+        threshold: 16,
+    };
+    let mut harness =
+        LoopbackHarness::new(make_server(plugin), LinkConditions::default(), 1, FRAME_TIME);
+    let compressed = harness.add_client("Alice");
+    let raw = harness.add_client("Bob");
+    harness.client_mut(raw).compression = false;
+
+    let spawned = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|client| harness.client(client).player_state().is_some())
+    });
+    assert!(spawned, "Players did not spawn");
+    let compression_of = |client| harness.client(client).initial_data().unwrap().compression;
+    assert_eq!(compression_of(compressed), Some(Compression { threshold: 16 }));
+    assert_eq!(compression_of(raw), None);
+
+    let start = harness.client(compressed).player_state().unwrap().position;
+    harness
+        .client_mut(compressed)
+        .input
+        .flags
+        .insert(InputFlags::FORWARD);
+    harness.run_for(Duration::from_secs(1));
+
+    let end = harness.client(compressed).player_state().unwrap().position;
+    let moved = Meter::from_pixel(end.into_pixel_vec().distance(start.into_pixel_vec()));
+    assert!(moved.0 > 1.0, "Player did not move");
+    let stats = harness.server().world.resource::<Compressor>().stats();
+    assert!(stats.attempted > 0);
+}
+
+#[test]
+fn server_measures_round_trip_time() {
+    let mut harness = connect_two_clients(LinkConditions {
+        latency: Duration::from_millis(50),
+        ..LinkConditions::default()
+    });
+    harness.run_for(Duration::from_secs(2));
+
+    let registry = harness.server().world.resource::<ClientRegistry>();
+    for handle in registry.get_clients() {
+        let rtt = handle.link.rtt().expect("Round trip time was not measured");
+        // Both ways plus a frame of the client and one of the server at most
+        assert!(rtt >= Duration::from_millis(100), "{:?}", rtt);
+        assert!(rtt <= Duration::from_millis(100) + FRAME_TIME * 2, "{:?}", rtt);
+        assert_eq!(handle.link.loss(), 0.0);
+    }
+}
+
+#[test]
+fn quitting_player_leaves_immediately() {
+    let mut harness = connect_two_clients(LinkConditions::default());
+    let alice = harness.client(0).player_network_id().unwrap();
+
+    harness.quit(0);
+    let left = harness.run_until(Duration::from_millis(200), |harness| {
+        harness
+            .client(1)
+            .notifications
+            .iter()
+            .any(|message| message == "Alice left the game.")
+            && harness.client(1).deleted.contains(&alice)
+    });
+    assert!(left, "Leaving was not announced before the connection timed out");
+}
+
+#[test]
+fn player_is_kept_when_reconnecting_from_new_address() {
+    let mut harness = connect_two_clients(LinkConditions::default());
+    let alice = harness.client(0).player_network_id().unwrap();
+    set_health(&mut harness, alice, 42);
+
+    harness.move_client(0);
+    harness.client_mut(0).reconnect();
+    let reconnected = harness.run_until(TIMEOUT, |harness| {
+        harness.client(0).initial_data().is_some() && harness.client(0).player_state().is_some()
+    });
+    assert!(reconnected, "Client could not reconnect");
+
+    assert_eq!(harness.client(0).player_network_id(), Some(alice));
+    assert!(!harness.client(1).deleted.contains(&alice));
+    assert!(harness
+        .client(1)
+        .notifications
+        .iter()
+        .any(|message| message == "Alice reconnected."));
+
+    let world = &mut harness.server_mut().world;
+    let healths: Vec<_> = world
+        .query::<(&NetworkId, &Health)>()
+        .iter(world)
+        .filter(|(network_id, _)| **network_id == alice)
+        .map(|(_, health)| health.0)
+        .collect();
+    assert_eq!(healths, vec![42], "Player has not been kept");
+}
+
+#[test]
+fn password_server_admits_only_clients_knowing_the_password() {
+    let mut plugin = server_plugin(1);
+    plugin.auth = AuthConfig {
+        password: Some("sesame".to_string()),
+        player_secrets: [("Bob".to_string(), "poncho".to_string())]
+            .into_iter()
+            .collect(),
+    };
+    let mut harness =
+        LoopbackHarness::new(make_server(plugin), LinkConditions::default(), 1, FRAME_TIME);
+    let alice = harness.add_client("Alice");
+    harness.client_mut(alice).password = Some("sesame".to_string());
+    let bob = harness.add_client("Bob");
+    harness.client_mut(bob).password = Some("sesame".to_string());
+    harness.client_mut(bob).secret = Some("poncho".to_string());
+    let impostor = harness.add_client("Bob");
+    harness.client_mut(impostor).password = Some("sesame".to_string());
+    harness.client_mut(impostor).secret = Some("sombrero".to_string());
+    let stranger = harness.add_client("Tuco");
+    harness.client_mut(stranger).password = Some("simsim".to_string());
+
+    let answered = harness.run_until(TIMEOUT, |harness| {
+        (0..4).all(|client| {
+            harness.client(client).initial_data().is_some()
+                || harness.client(client).refusal().is_some()
+        })
+    });
+    assert!(answered, "Connection requests were not answered");
+
+    assert!(harness.client(alice).initial_data().is_some());
+    assert!(harness.client(bob).initial_data().is_some());
+    assert_eq!(
+        harness.client(impostor).refusal(),
+        Some(ErrorKind::AuthenticationFailed)
+    );
+    assert_eq!(
+        harness.client(stranger).refusal(),
+        Some(ErrorKind::AuthenticationFailed)
+    );
+}
+
+#[test]
+fn clients_are_told_about_server_shutdown() {
+    let mut harness = connect_two_clients(LinkConditions::default());
+
+    harness
+        .server()
+        .world
+        .resource::<ShutdownSignal>()
+        .request();
+    let told = harness.run_until(TIMEOUT, |harness| {
+        (0..2).all(|index| {
+            harness.client(index).disconnection == Some(DisconnectReason::ServerShutdown)
+        })
+    });
+    assert!(told, "Clients were not told about the shutdown");
+
+    harness.run_for(FRAME_TIME * 3);
+    let exit_events = harness.server().world.resource::<Events<AppExit>>();
+    assert!(!exit_events.is_empty(), "Server did not exit");
+}
+
+#[test]
+fn session_is_recorded_to_demo() {
+    let demo_path = std::env::temp_dir().join(format!("westiny_test_{}.wdemo", std::process::id()));
+    let mut plugin = server_plugin(7);
+    plugin.demo_path = Some(demo_path.clone());
+
+    let mut harness = connect_two_clients_to(plugin, LinkConditions::default());
+    harness
+        .client_mut(0)
+        .input
+        .flags
+        .insert(InputFlags::FORWARD);
+    harness.run_for(Duration::from_millis(500));
+
+    let demo = Demo::load(&demo_path).unwrap();
+    std::fs::remove_file(&demo_path).unwrap();
+
+    assert_eq!(demo.header.rng_seed, 7);
+    assert_eq!(demo.header.tick_rate, 50);
+    let players: Vec<_> = demo.players().collect();
+    assert_eq!(
+        players,
+        vec![
+            ("Alice", harness.client_address(0)),
+            ("Bob", harness.client_address(1))
+        ]
+    );
+
+    let packets_to_alice: Vec<_> = demo
+        .frames
+        .iter()
+        .filter_map(|frame| match &frame.record {
+            DemoRecord::Outbound {
+                destination,
+                payload,
+            } if *destination == harness.client_address(0) => Some(deserialize(payload).unwrap()),
+            _ => None,
+        })
+        .collect();
+    assert!(matches!(
+        packets_to_alice.first(),
+        Some(PacketType::ConnectionResponse(Ok(_)))
+    ));
+    assert!(packets_to_alice
+        .iter()
+        .any(|packet| matches!(packet, PacketType::EntityStateUpdate(_))));
+
+    assert!(demo.frames.iter().any(|frame| matches!(
+        &frame.record,
+        DemoRecord::Input { source, inputs, .. }
+            if *source == harness.client_address(0)
+                && inputs.iter().any(|input| input.input.flags.contains(InputFlags::FORWARD))
+    )));
+    assert!(demo
+        .frames
+        .windows(2)
+        .all(|frames| frames[0].tick <= frames[1].tick));
+}